@@ -0,0 +1,407 @@
+//! A client for the server's API, so that services storing files on it don't have to put
+//! together uploads, ranged downloads and credentials by hand.
+//!
+//! ```no_run
+//! # async fn example() -> simple_file_server_client::Result<()> {
+//! use simple_file_server_client::{Client, Credentials};
+//!
+//! let client = Client::new("https://files.example.com")?
+//!     .with_credentials(Credentials::api_key("X-API-Key", "secret"));
+//! client.upload("reports/q3.pdf", b"%PDF-1.7".to_vec()).await?;
+//! client.download_to("reports/q3.pdf", "q3.pdf".as_ref()).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    fmt, io,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use futures::{Stream, TryStreamExt};
+use percent_encoding::{AsciiSet, CONTROLS, utf8_percent_encode};
+use reqwest::{Body, RequestBuilder, Response, StatusCode, Url, header};
+use serde::Deserialize;
+use tokio::{fs, io::AsyncWriteExt};
+use tokio_util::io::ReaderStream;
+
+// the same characters the server encodes in the paths it hands out
+const PATH_ENCODE_SET: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+// next to the file being downloaded, until it's complete
+const PARTIAL_EXT: &str = "partial";
+const PARTIAL_ETAG_EXT: &str = "partial.etag";
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The request couldn't be sent, or its response couldn't be read
+    Http(reqwest::Error),
+    /// A local file couldn't be read or written
+    Io(io::Error),
+    /// The server answered with an error
+    Status {
+        status: StatusCode,
+        message: String,
+    },
+    InvalidUrl(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Http(err) => write!(f, "request failed: {err}"),
+            Error::Io(err) => write!(f, "{err}"),
+            Error::Status { status, message } if message.is_empty() => write!(f, "{status}"),
+            Error::Status { status, message } => write!(f, "{status}: {message}"),
+            Error::InvalidUrl(url) => write!(f, "invalid URL '{url}'"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Self {
+        Error::Http(err)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+/// How requests prove who they're from, matching the `auth` the server is configured with.
+#[derive(Clone)]
+pub enum Credentials {
+    ApiKey { header: String, key: String },
+    Bearer(String),
+    Basic { username: String, password: String },
+}
+
+impl Credentials {
+    pub fn api_key(header: impl Into<String>, key: impl Into<String>) -> Self {
+        Credentials::ApiKey {
+            header: header.into(),
+            key: key.into(),
+        }
+    }
+
+    fn apply(&self, request: RequestBuilder) -> RequestBuilder {
+        match self {
+            Credentials::ApiKey { header, key } => request.header(header, key),
+            Credentials::Bearer(token) => request.bearer_auth(token),
+            Credentials::Basic { username, password } => {
+                request.basic_auth(username, Some(password))
+            }
+        }
+    }
+}
+
+/// What `GET /api/info/{file}` tells about a file.
+#[derive(Deserialize, Debug, Clone)]
+pub struct FileInfo {
+    pub path: String,
+    pub size_bytes: u64,
+    pub hash: String,
+    pub hash_algorithm: String,
+    pub created_at: Option<DateTime<Utc>>,
+    pub modified_at: Option<DateTime<Utc>>,
+    pub accessed_at: Option<DateTime<Utc>>,
+    pub content_type: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryKind {
+    File,
+    Dir,
+}
+
+/// One entry of a directory listing.
+#[derive(Deserialize, Debug, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub kind: EntryKind,
+    /// Only known for files
+    pub size_bytes: Option<u64>,
+    pub modified_at: Option<DateTime<Utc>>,
+}
+
+/// A download under way, its body read as it's streamed.
+pub struct Download {
+    pub etag: Option<String>,
+    /// How large the body is, as far as the server said
+    pub content_length: Option<u64>,
+    response: Response,
+}
+
+impl Download {
+    pub fn bytes_stream(self) -> impl Stream<Item = Result<Vec<u8>>> {
+        self.response
+            .bytes_stream()
+            .map_ok(|chunk| chunk.to_vec())
+            .map_err(Error::from)
+    }
+}
+
+/// The client of one server, cheap to clone and meant to be shared.
+#[derive(Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    base_url: Url,
+    credentials: Option<Credentials>,
+}
+
+impl Client {
+    /// A client of the server at `base_url`, sending no credentials until told to.
+    pub fn new(base_url: &str) -> Result<Self> {
+        let mut base_url =
+            Url::parse(base_url).map_err(|_| Error::InvalidUrl(base_url.to_string()))?;
+        // joined onto, which would replace the last segment without it
+        if !base_url.path().ends_with('/') {
+            base_url.set_path(&format!("{}/", base_url.path()));
+        }
+
+        Ok(Client {
+            http: reqwest::Client::new(),
+            base_url,
+            credentials: None,
+        })
+    }
+
+    pub fn with_credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    /// Uses `http` for the requests, e.g. one set up with timeouts or a proxy.
+    pub fn with_http_client(mut self, http: reqwest::Client) -> Self {
+        self.http = http;
+        self
+    }
+
+    /// Uploads `body` to `path`, replacing what's there. Returns the hash it's stored with.
+    pub async fn upload(&self, path: &str, body: impl Into<Body>) -> Result<String> {
+        let request = self.request(reqwest::Method::PUT, "", path)?.body(body);
+        let response = checked(request.send().await?).await?;
+        Ok(hash(&response).unwrap_or_default())
+    }
+
+    /// Uploads the local file at `local` to `path`, streaming it rather than reading it all
+    /// into memory first.
+    pub async fn upload_file(&self, path: &str, local: &Path) -> Result<String> {
+        let file = fs::File::open(local).await?;
+        let size = file.metadata().await?.len();
+        let request = self
+            .request(reqwest::Method::PUT, "", path)?
+            .header(header::CONTENT_LENGTH, size)
+            .body(Body::wrap_stream(ReaderStream::new(file)));
+        let response = checked(request.send().await?).await?;
+        Ok(hash(&response).unwrap_or_default())
+    }
+
+    /// Starts downloading the file at `path`.
+    pub async fn download(&self, path: &str) -> Result<Download> {
+        let request = self.request(reqwest::Method::GET, "", path)?;
+        let response = checked(request.send().await?).await?;
+        Ok(Download {
+            etag: etag(&response),
+            content_length: response.content_length(),
+            response,
+        })
+    }
+
+    /// Downloads the file at `path` into `local`, picking up where an earlier attempt that
+    /// was cut short left off as long as the file hasn't changed since. Returns its size.
+    pub async fn download_to(&self, path: &str, local: &Path) -> Result<u64> {
+        let partial_path = with_added_extension(local, PARTIAL_EXT);
+        let etag_path = with_added_extension(local, PARTIAL_ETAG_EXT);
+
+        let resume_from = match fs::metadata(&partial_path).await {
+            Ok(metadata) => metadata.len(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => 0,
+            Err(err) => return Err(err.into()),
+        };
+        let resume_etag = match resume_from {
+            0 => None,
+            _ => fs::read_to_string(&etag_path).await.ok(),
+        };
+
+        let mut request = self.request(reqwest::Method::GET, "", path)?;
+        if let Some(resume_etag) = &resume_etag {
+            // the server sends all of it again should the file have changed in the meantime
+            request = request
+                .header(header::RANGE, format!("bytes={resume_from}-"))
+                .header(header::IF_RANGE, resume_etag);
+        }
+        let response = request.send().await?;
+
+        let (response, file) = match response.status() {
+            // nothing was left to download
+            StatusCode::RANGE_NOT_SATISFIABLE if resume_etag.is_some() => {
+                return finish_download(&partial_path, &etag_path, local).await;
+            }
+            StatusCode::PARTIAL_CONTENT => {
+                let file = fs::OpenOptions::new()
+                    .append(true)
+                    .open(&partial_path)
+                    .await?;
+                (response, file)
+            }
+            _ => {
+                let response = checked(response).await?;
+                if let Some(etag) = etag(&response) {
+                    fs::write(&etag_path, etag).await?;
+                }
+                (response, fs::File::create(&partial_path).await?)
+            }
+        };
+        write_download(response, file, &partial_path, &etag_path, local).await
+    }
+
+    /// What the server knows about the file at `path`, needing the `stat` permission.
+    pub async fn info(&self, path: &str) -> Result<FileInfo> {
+        let request = self.request(reqwest::Method::GET, "api/info/", path)?;
+        Ok(checked(request.send().await?).await?.json().await?)
+    }
+
+    /// The files and directories in the directory at `dir`, needing the `list` permission.
+    pub async fn list(&self, dir: &str) -> Result<Vec<DirEntry>> {
+        let request = self
+            .request(reqwest::Method::GET, "api/list/", dir)?
+            .header(header::ACCEPT, "application/json");
+        Ok(checked(request.send().await?).await?.json().await?)
+    }
+
+    /// Deletes the file at `path`, succeeding if it's already gone too.
+    pub async fn delete(&self, path: &str) -> Result<()> {
+        let request = self.request(reqwest::Method::DELETE, "", path)?;
+        let response = request.send().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(());
+        }
+        checked(response).await?;
+        Ok(())
+    }
+
+    /// The URL of the file at `path` under `route`, e.g. `api/info/`.
+    pub fn url(&self, route: &str, path: &str) -> Result<Url> {
+        let url = format!("{route}{}", encode_path(path));
+        self.base_url.join(&url).map_err(|_| Error::InvalidUrl(url))
+    }
+
+    fn request(&self, method: reqwest::Method, route: &str, path: &str) -> Result<RequestBuilder> {
+        let request = self.http.request(method, self.url(route, path)?);
+        Ok(match &self.credentials {
+            Some(credentials) => credentials.apply(request),
+            None => request,
+        })
+    }
+}
+
+/// Percent-encodes each segment of `path`, leaving the separators between them.
+fn encode_path(path: &str) -> String {
+    path.split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| utf8_percent_encode(segment, PATH_ENCODE_SET).to_string())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn with_added_extension(path: &Path, extension: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".");
+    path.push(extension);
+    PathBuf::from(path)
+}
+
+fn etag(response: &Response) -> Option<String> {
+    response
+        .headers()
+        .get(header::ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .map(str::to_string)
+}
+
+/// The hash of the file the response is for, which its ETag is the quoted form of.
+fn hash(response: &Response) -> Option<String> {
+    etag(response).map(|etag| etag.trim_matches('"').to_string())
+}
+
+/// Turns an error status into an `Error`, with what the server said about it.
+async fn checked(response: Response) -> Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let message = response.text().await.unwrap_or_default();
+    Err(Error::Status { status, message })
+}
+
+async fn write_download(
+    response: Response,
+    mut file: fs::File,
+    partial_path: &Path,
+    etag_path: &Path,
+    local: &Path,
+) -> Result<u64> {
+    let mut stream = response.bytes_stream();
+    while let Some(chunk) = stream.try_next().await? {
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+    drop(file);
+    finish_download(partial_path, etag_path, local).await
+}
+
+async fn finish_download(partial_path: &Path, etag_path: &Path, local: &Path) -> Result<u64> {
+    let size = fs::metadata(partial_path).await?.len();
+    fs::rename(partial_path, local).await?;
+    match fs::remove_file(etag_path).await {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+        _ => {}
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_are_encoded_under_the_base_url() {
+        let client = Client::new("https://files.example.com/cdn").unwrap();
+        let url = |route, path| client.url(route, path).unwrap().to_string();
+
+        assert_eq!(
+            url("", "reports/q3 #1.pdf"),
+            "https://files.example.com/cdn/reports/q3%20%231.pdf"
+        );
+        assert_eq!(
+            url("api/info/", "/docs//a?.txt"),
+            "https://files.example.com/cdn/api/info/docs/a%3F.txt"
+        );
+        assert_eq!(
+            with_added_extension(Path::new("out/q3.pdf"), PARTIAL_EXT),
+            Path::new("out/q3.pdf.partial")
+        );
+        assert!(Client::new("not a url").is_err());
+    }
+}
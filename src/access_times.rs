@@ -0,0 +1,219 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, Utc};
+use rusqlite::{Connection, OptionalExtension, params};
+
+use crate::config::server::AccessTimesConfig;
+
+const ACCESS_TIMES_FILE_NAME: &str = "access_times.db";
+
+pub type SharedAccessTimes = Arc<AccessTimes>;
+
+/// When each file was last downloaded, for telling which ones nobody uses anymore. Downloads
+/// are only noted in memory, and written together by the `access_time_flush` task, so a file
+/// downloaded all the time isn't written to the database every time too.
+pub struct AccessTimes {
+    // nothing is recorded, or even opened, while turned off
+    db: Option<Mutex<Connection>>,
+    tracked_since: Option<DateTime<Utc>>,
+    resolution_secs: i64,
+    pending: Mutex<HashMap<String, i64>>,
+}
+
+impl AccessTimes {
+    pub fn open(data_dir: impl AsRef<Path>, config: &AccessTimesConfig) -> io::Result<Self> {
+        let (db, tracked_since) = if config.enabled {
+            fs::create_dir_all(&data_dir)?;
+            let db = Connection::open(data_dir.as_ref().join(ACCESS_TIMES_FILE_NAME))
+                .map_err(io::Error::other)?;
+            db.execute_batch(
+                "CREATE TABLE IF NOT EXISTS access_times (
+                    path TEXT PRIMARY KEY,
+                    accessed_at INTEGER NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS tracking (
+                    id INTEGER PRIMARY KEY CHECK (id = 0),
+                    started_at INTEGER NOT NULL
+                );",
+            )
+            .map_err(io::Error::other)?;
+            let started_at: i64 = db
+                .query_row(
+                    "INSERT INTO tracking (id, started_at) VALUES (0, ?1)
+                        ON CONFLICT (id) DO UPDATE SET started_at = started_at
+                        RETURNING started_at",
+                    [Utc::now().timestamp()],
+                    |row| row.get(0),
+                )
+                .map_err(io::Error::other)?;
+            (
+                Some(Mutex::new(db)),
+                DateTime::from_timestamp(started_at, 0),
+            )
+        } else {
+            (None, None)
+        };
+
+        Ok(AccessTimes {
+            db,
+            tracked_since,
+            resolution_secs: config.resolution_secs.max(1) as i64,
+            pending: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.db.is_some()
+    }
+
+    /// When downloads first started being recorded, files not downloaded since may still have
+    /// been before.
+    pub fn tracked_since(&self) -> Option<DateTime<Utc>> {
+        self.tracked_since
+    }
+
+    /// Notes that `path` was downloaded just now, to be written with the next flush.
+    pub fn record(&self, path: &Path) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let now = Utc::now().timestamp();
+        let accessed_at = now - now.rem_euclid(self.resolution_secs);
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(path.to_string_lossy().into_owned(), accessed_at);
+    }
+
+    /// When `path` was last downloaded, as far as it's known.
+    pub fn get(&self, path: &Path) -> io::Result<Option<DateTime<Utc>>> {
+        let Some(db) = &self.db else {
+            return Ok(None);
+        };
+
+        let path = path.to_string_lossy();
+        let pending = self.pending.lock().unwrap().get(path.as_ref()).copied();
+        let timestamp = match pending {
+            Some(timestamp) => Some(timestamp),
+            None => db
+                .lock()
+                .unwrap()
+                .query_row(
+                    "SELECT accessed_at FROM access_times WHERE path = ?1",
+                    [path],
+                    |row| row.get(0),
+                )
+                .optional()
+                .map_err(io::Error::other)?,
+        };
+        Ok(timestamp.and_then(|t| DateTime::from_timestamp(t, 0)))
+    }
+
+    /// When each file downloaded since tracking started was last downloaded, keyed by path.
+    pub fn all(&self) -> io::Result<HashMap<String, DateTime<Utc>>> {
+        let Some(db) = &self.db else {
+            return Ok(HashMap::new());
+        };
+
+        let mut all: HashMap<String, i64> = {
+            let db = db.lock().unwrap();
+            let mut statement = db
+                .prepare("SELECT path, accessed_at FROM access_times")
+                .map_err(io::Error::other)?;
+            statement
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+                .map_err(io::Error::other)?
+                .collect::<Result<_, _>>()
+                .map_err(io::Error::other)?
+        };
+        // the ones noted since the last flush are never older than what's written
+        all.extend(self.pending.lock().unwrap().clone());
+
+        Ok(all
+            .into_iter()
+            .filter_map(|(path, t)| Some((path, DateTime::from_timestamp(t, 0)?)))
+            .collect())
+    }
+
+    /// Forgets about `path`, once the file is gone.
+    pub fn remove(&self, path: &Path) -> io::Result<()> {
+        let Some(db) = &self.db else {
+            return Ok(());
+        };
+
+        let path = path.to_string_lossy();
+        self.pending.lock().unwrap().remove(path.as_ref());
+        db.lock()
+            .unwrap()
+            .execute("DELETE FROM access_times WHERE path = ?1", [path])
+            .map_err(io::Error::other)?;
+        Ok(())
+    }
+
+    /// Writes every download noted since the last flush, returning how many files they were of.
+    pub fn flush(&self) -> io::Result<usize> {
+        let Some(db) = &self.db else {
+            return Ok(0);
+        };
+
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        let mut db = db.lock().unwrap();
+        let tx = db.transaction().map_err(io::Error::other)?;
+        for (path, accessed_at) in &pending {
+            tx.execute(
+                "INSERT INTO access_times (path, accessed_at) VALUES (?1, ?2)
+                    ON CONFLICT (path) DO UPDATE SET accessed_at = MAX(accessed_at, ?2)",
+                params![path, accessed_at],
+            )
+            .map_err(io::Error::other)?;
+        }
+        tx.commit().map_err(io::Error::other)?;
+        Ok(pending.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::temp_dir;
+
+    #[test]
+    fn downloads_are_written_in_batches_and_rounded() {
+        let data_dir = temp_dir();
+        let config = AccessTimesConfig {
+            enabled: true,
+            resolution_secs: 60,
+        };
+        let access_times = AccessTimes::open(&data_dir, &config).unwrap();
+        let path = Path::new("a.txt");
+
+        for _ in 0..3 {
+            access_times.record(path);
+        }
+        let accessed_at = access_times.get(path).unwrap().unwrap();
+        assert_eq!(accessed_at.timestamp() % 60, 0);
+        assert!(Utc::now() - accessed_at < chrono::Duration::seconds(60));
+
+        // the same file downloaded over and over is only written once
+        assert_eq!(access_times.flush().unwrap(), 1);
+        assert_eq!(access_times.flush().unwrap(), 0);
+        assert_eq!(access_times.get(path).unwrap(), Some(accessed_at));
+
+        access_times.remove(path).unwrap();
+        assert_eq!(access_times.get(path).unwrap(), None);
+        drop(access_times);
+        let _ = fs::remove_dir_all(&data_dir);
+
+        // and nothing is kept at all when turned off
+        let access_times = AccessTimes::open(&data_dir, &AccessTimesConfig::default()).unwrap();
+        access_times.record(path);
+        assert_eq!(access_times.get(path).unwrap(), None);
+        assert!(!data_dir.exists());
+    }
+}
@@ -0,0 +1,292 @@
+use std::{io, sync::Arc};
+
+use actix_web::web::Bytes;
+use async_stream::try_stream;
+use chrono::{Datelike, Timelike, Utc};
+use futures::StreamExt;
+use serde::Deserialize;
+
+use crate::file_store::{ByteStream, StoredFile, StoredFileCore};
+
+const TAR_BLOCK_LEN: usize = 512;
+// the largest size that fits in the 11 octal digits of a plain tar header
+const TAR_MAX_PLAIN_SIZE: u64 = 0o77777777777;
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ArchiveFormat {
+    #[default]
+    Zip,
+    Tar,
+}
+
+impl ArchiveFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "application/zip",
+            ArchiveFormat::Tar => "application/x-tar",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::Tar => "tar",
+        }
+    }
+
+    /// Streams `files` into an archive of this format, stored without compression. Each
+    /// file is only read once the archive gets to it.
+    pub fn stream(self, files: Vec<(String, Arc<StoredFile>)>) -> io::Result<ByteStream<'static>> {
+        match self {
+            ArchiveFormat::Zip => zip_stream(files),
+            ArchiveFormat::Tar => Ok(tar_stream(files)),
+        }
+    }
+}
+
+/// Passes on the contents of `file`, failing if they don't add up to the size it was
+/// announced with, since the archive already promised that many bytes.
+fn file_contents(file: Arc<StoredFile>) -> ByteStream<'static> {
+    try_stream! {
+        let expected = file.metadata().size_bytes;
+        let mut written = 0;
+
+        let mut source = file.bytes_stream();
+        while let Some(chunk) = source.next().await {
+            let chunk = chunk?;
+            written += chunk.len() as u64;
+            yield chunk;
+        }
+
+        if written != expected {
+            Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "file changed size while being archived",
+            ))?;
+        }
+    }
+    .boxed_local()
+}
+
+// ------------------------
+
+fn tar_stream(files: Vec<(String, Arc<StoredFile>)>) -> ByteStream<'static> {
+    let mtime = Utc::now().timestamp().max(0) as u64;
+
+    try_stream! {
+        for (name, file) in files {
+            let size = file.metadata().size_bytes;
+            yield Bytes::from(tar_headers(&name, size, mtime));
+
+            let mut contents = file_contents(file);
+            while let Some(chunk) = contents.next().await {
+                yield chunk?;
+            }
+
+            yield Bytes::from(vec![0; tar_padding(size)]);
+        }
+
+        // the end of the archive is marked by two empty blocks
+        yield Bytes::from(vec![0; 2 * TAR_BLOCK_LEN]);
+    }
+    .boxed_local()
+}
+
+fn tar_padding(len: u64) -> usize {
+    (TAR_BLOCK_LEN - (len % TAR_BLOCK_LEN as u64) as usize) % TAR_BLOCK_LEN
+}
+
+/// The header for a file, preceded by a pax header when the name or size don't fit the
+/// plain one.
+fn tar_headers(name: &str, size: u64, mtime: u64) -> Vec<u8> {
+    let mut records = String::new();
+    if name.len() > 100 {
+        records.push_str(&pax_record("path", name));
+    }
+    if size > TAR_MAX_PLAIN_SIZE {
+        records.push_str(&pax_record("size", &size.to_string()));
+    }
+
+    let mut headers = Vec::new();
+    if !records.is_empty() {
+        headers.extend(tar_header(b'x', "PaxHeader", records.len() as u64, mtime));
+        headers.extend(records.as_bytes());
+        headers.resize(headers.len() + tar_padding(records.len() as u64), 0);
+    }
+
+    // readers that understand pax take the real name and size from it instead
+    let mut start = name.len().saturating_sub(100);
+    while !name.is_char_boundary(start) {
+        start += 1;
+    }
+    let short_name = &name[start..];
+    headers.extend(tar_header(
+        b'0',
+        short_name,
+        size.min(TAR_MAX_PLAIN_SIZE),
+        mtime,
+    ));
+    headers
+}
+
+/// A record is prefixed by its own length in decimal, including the digits themselves.
+fn pax_record(key: &str, value: &str) -> String {
+    let rest = format!(" {key}={value}\n");
+    let mut len = rest.len() + 1;
+    while (len.to_string().len() + rest.len()) != len {
+        len += 1;
+    }
+    format!("{len}{rest}")
+}
+
+fn tar_header(kind: u8, name: &str, size: u64, mtime: u64) -> [u8; TAR_BLOCK_LEN] {
+    let mut header = [0; TAR_BLOCK_LEN];
+    let mut field = |offset: usize, value: &[u8]| {
+        header[offset..offset + value.len()].copy_from_slice(value);
+    };
+
+    let name = name.as_bytes();
+    field(0, &name[..name.len().min(100)]);
+    field(100, b"0000644\0");
+    field(108, b"0000000\0");
+    field(116, b"0000000\0");
+    field(124, format!("{size:011o}\0").as_bytes());
+    field(136, format!("{mtime:011o}\0").as_bytes());
+    field(156, &[kind]);
+    field(257, b"ustar\0");
+    field(263, b"00");
+
+    // the checksum is summed with its own field filled with spaces
+    header[148..156].fill(b' ');
+    let checksum: u32 = header.iter().map(|&b| b as u32).sum();
+    header[148..156].copy_from_slice(format!("{checksum:06o}\0 ").as_bytes());
+    header
+}
+
+// ------------------------
+
+const ZIP_VERSION: u16 = 20;
+// sizes and crc follow the contents in a data descriptor, and names are utf-8
+const ZIP_FLAGS: u16 = 1 << 3 | 1 << 11;
+
+struct ZipEntry {
+    name: String,
+    crc: u32,
+    size: u32,
+    offset: u32,
+}
+
+fn zip_stream(files: Vec<(String, Arc<StoredFile>)>) -> io::Result<ByteStream<'static>> {
+    // without zip64, every size and offset has to fit in 32 bits
+    let total: u64 = files
+        .iter()
+        .map(|(name, file)| file.metadata().size_bytes + 2 * name.len() as u64 + 128)
+        .sum();
+    if total > u32::MAX as u64 || files.len() > u16::MAX as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "too much to fit in a zip archive, use tar instead",
+        ));
+    }
+
+    let (time, date) = dos_date_time();
+
+    Ok(try_stream! {
+        let mut entries = Vec::new();
+        let mut offset = 0;
+
+        for (name, file) in files {
+            let mut header = Vec::new();
+            header.extend(0x04034b50u32.to_le_bytes());
+            header.extend(ZIP_VERSION.to_le_bytes());
+            header.extend(ZIP_FLAGS.to_le_bytes());
+            header.extend(0u16.to_le_bytes()); // stored
+            header.extend(time.to_le_bytes());
+            header.extend(date.to_le_bytes());
+            header.extend([0; 12]); // crc and sizes, given in the data descriptor
+            header.extend((name.len() as u16).to_le_bytes());
+            header.extend(0u16.to_le_bytes());
+            header.extend(name.as_bytes());
+
+            let entry_offset = offset;
+            offset += header.len() as u32;
+            yield Bytes::from(header);
+
+            let mut hasher = crc32fast::Hasher::new();
+            let size = file.metadata().size_bytes as u32;
+            let mut contents = file_contents(file);
+            while let Some(chunk) = contents.next().await {
+                let chunk = chunk?;
+                hasher.update(&chunk);
+                yield chunk;
+            }
+            offset += size;
+
+            let crc = hasher.finalize();
+            let mut descriptor = Vec::new();
+            descriptor.extend(0x08074b50u32.to_le_bytes());
+            descriptor.extend(crc.to_le_bytes());
+            descriptor.extend(size.to_le_bytes());
+            descriptor.extend(size.to_le_bytes());
+            offset += descriptor.len() as u32;
+            yield Bytes::from(descriptor);
+
+            entries.push(ZipEntry { name, crc, size, offset: entry_offset });
+        }
+
+        let mut directory = Vec::new();
+        for entry in &entries {
+            directory.extend(0x02014b50u32.to_le_bytes());
+            directory.extend(ZIP_VERSION.to_le_bytes()); // made by
+            directory.extend(ZIP_VERSION.to_le_bytes()); // needed
+            directory.extend(ZIP_FLAGS.to_le_bytes());
+            directory.extend(0u16.to_le_bytes());
+            directory.extend(time.to_le_bytes());
+            directory.extend(date.to_le_bytes());
+            directory.extend(entry.crc.to_le_bytes());
+            directory.extend(entry.size.to_le_bytes());
+            directory.extend(entry.size.to_le_bytes());
+            directory.extend((entry.name.len() as u16).to_le_bytes());
+            directory.extend([0; 12]); // extra and comment lengths, disk and attributes
+            directory.extend(entry.offset.to_le_bytes());
+            directory.extend(entry.name.as_bytes());
+        }
+
+        let mut end = Vec::new();
+        end.extend(0x06054b50u32.to_le_bytes());
+        end.extend([0; 4]); // disk numbers
+        end.extend((entries.len() as u16).to_le_bytes());
+        end.extend((entries.len() as u16).to_le_bytes());
+        end.extend((directory.len() as u32).to_le_bytes());
+        end.extend(offset.to_le_bytes());
+        end.extend(0u16.to_le_bytes());
+
+        directory.extend(end);
+        yield Bytes::from(directory);
+    }
+    .boxed_local())
+}
+
+/// The current time the way zip stores it, with two second precision and no time zone.
+fn dos_date_time() -> (u16, u16) {
+    let now = Utc::now();
+    let time = (now.hour() << 11 | now.minute() << 5 | (now.second() / 2)) as u16;
+    let date = ((now.year().max(1980) - 1980) << 9) as u16 | (now.month() << 5 | now.day()) as u16;
+    (time, date)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pax_records_count_their_own_length() {
+        // crossing from one to two length digits, and from two to three
+        for value_len in [0, 1, 85, 86, 87, 200] {
+            let record = pax_record("path", &"a".repeat(value_len));
+            let (len, _) = record.split_once(' ').unwrap();
+            assert_eq!(len.parse::<usize>().unwrap(), record.len());
+        }
+    }
+}
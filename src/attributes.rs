@@ -0,0 +1,203 @@
+use std::{
+    fs, io,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::{Deserialize, Serialize};
+
+use crate::config::server::{UploadProfile, Visibility};
+
+const ATTRIBUTES_FILE_NAME: &str = "attributes.db";
+
+pub type SharedAttributes = Arc<Attributes>;
+
+/// What an upload profile set on a file, beyond its contents.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct FileAttributes {
+    pub tags: Vec<String>,
+    pub visibility: Visibility,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl FileAttributes {
+    /// What an upload made now with `profile` gets.
+    pub fn from_profile(profile: &UploadProfile) -> Self {
+        FileAttributes {
+            tags: profile.tags.clone(),
+            visibility: profile.visibility,
+            expires_at: profile
+                .expires_after_secs
+                .map(|secs| Utc::now() + Duration::from_secs(secs)),
+        }
+    }
+}
+
+/// Attributes of the files that have any, keyed by path and kept apart from the files
+/// themselves, so they work the same whatever the files are stored in.
+pub struct Attributes {
+    db: Mutex<Connection>,
+}
+
+impl Attributes {
+    pub fn open(data_dir: impl AsRef<Path>) -> io::Result<Self> {
+        fs::create_dir_all(&data_dir)?;
+
+        let db = Connection::open(data_dir.as_ref().join(ATTRIBUTES_FILE_NAME))
+            .map_err(io::Error::other)?;
+        db.execute_batch(
+            "CREATE TABLE IF NOT EXISTS attributes (
+                path TEXT PRIMARY KEY,
+                tags TEXT NOT NULL,
+                private INTEGER NOT NULL,
+                expires_at INTEGER
+            );
+            CREATE INDEX IF NOT EXISTS attributes_expiry ON attributes (expires_at);
+            CREATE TABLE IF NOT EXISTS pending_deletes (
+                path TEXT PRIMARY KEY,
+                delete_at INTEGER NOT NULL
+            );",
+        )
+        .map_err(io::Error::other)?;
+
+        Ok(Attributes { db: Mutex::new(db) })
+    }
+
+    pub fn get(&self, path: &Path) -> io::Result<Option<FileAttributes>> {
+        let db = self.db.lock().unwrap();
+        db.query_row(
+            "SELECT tags, private, expires_at FROM attributes WHERE path = ?1",
+            [path.to_string_lossy()],
+            |row| {
+                let tags: String = row.get(0)?;
+                let private: bool = row.get(1)?;
+                let expires_at: Option<i64> = row.get(2)?;
+                Ok(FileAttributes {
+                    tags: serde_json::from_str(&tags).unwrap_or_default(),
+                    visibility: if private {
+                        Visibility::Private
+                    } else {
+                        Visibility::Public
+                    },
+                    expires_at: expires_at.and_then(|t| DateTime::from_timestamp(t, 0)),
+                })
+            },
+        )
+        .optional()
+        .map_err(io::Error::other)
+    }
+
+    pub fn is_private(&self, path: &Path) -> bool {
+        match self.get(path) {
+            Ok(attributes) => {
+                attributes.is_some_and(|attributes| attributes.visibility == Visibility::Private)
+            }
+            Err(err) => {
+                // failing closed, a private file is worse to leak than a public one to hide
+                log::error!("Error looking up attributes of '{}': {err}", path.display());
+                true
+            }
+        }
+    }
+
+    /// Replaces whatever attributes `path` had, and cancels its deletion if one is pending
+    /// since it's being written again.
+    pub fn set(&self, path: &Path, attributes: &FileAttributes) -> io::Result<()> {
+        let db = self.db.lock().unwrap();
+        db.execute(
+            "DELETE FROM pending_deletes WHERE path = ?1",
+            [path.to_string_lossy()],
+        )
+        .map_err(io::Error::other)?;
+        db.execute(
+            "INSERT INTO attributes (path, tags, private, expires_at) VALUES (?1, ?2, ?3, ?4)
+                ON CONFLICT (path) DO UPDATE SET tags = ?2, private = ?3, expires_at = ?4",
+            params![
+                path.to_string_lossy(),
+                serde_json::to_string(&attributes.tags)?,
+                attributes.visibility == Visibility::Private,
+                attributes.expires_at.map(|t| t.timestamp()),
+            ],
+        )
+        .map_err(io::Error::other)?;
+        Ok(())
+    }
+
+    pub fn remove(&self, path: &Path) -> io::Result<()> {
+        let db = self.db.lock().unwrap();
+        for table in ["attributes", "pending_deletes"] {
+            db.execute(
+                &format!("DELETE FROM {table} WHERE path = ?1"),
+                [path.to_string_lossy()],
+            )
+            .map_err(io::Error::other)?;
+        }
+        Ok(())
+    }
+
+    /// Marks `path` to be deleted at `delete_at`, keeping an earlier time if already marked.
+    pub fn mark_deleted(&self, path: &Path, delete_at: DateTime<Utc>) -> io::Result<DateTime<Utc>> {
+        let db = self.db.lock().unwrap();
+        let timestamp: i64 = db
+            .query_row(
+                "INSERT INTO pending_deletes (path, delete_at) VALUES (?1, ?2)
+                    ON CONFLICT (path) DO UPDATE SET delete_at = MIN(delete_at, ?2)
+                    RETURNING delete_at",
+                params![path.to_string_lossy(), delete_at.timestamp()],
+                |row| row.get(0),
+            )
+            .map_err(io::Error::other)?;
+        Ok(DateTime::from_timestamp(timestamp, 0).unwrap_or(delete_at))
+    }
+
+    /// When `path` is going to be deleted, if it's marked to be.
+    pub fn deleting_at(&self, path: &Path) -> io::Result<Option<DateTime<Utc>>> {
+        let db = self.db.lock().unwrap();
+        let timestamp: Option<i64> = db
+            .query_row(
+                "SELECT delete_at FROM pending_deletes WHERE path = ?1",
+                [path.to_string_lossy()],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(io::Error::other)?;
+        Ok(timestamp.and_then(|t| DateTime::from_timestamp(t, 0)))
+    }
+
+    /// Cancels a pending deletion, returning whether there was one.
+    pub fn undelete(&self, path: &Path) -> io::Result<bool> {
+        let db = self.db.lock().unwrap();
+        let removed = db
+            .execute(
+                "DELETE FROM pending_deletes WHERE path = ?1",
+                [path.to_string_lossy()],
+            )
+            .map_err(io::Error::other)?;
+        Ok(removed > 0)
+    }
+
+    /// Paths of the files that have expired by now.
+    pub fn expired(&self) -> io::Result<Vec<String>> {
+        self.paths_due("SELECT path FROM attributes WHERE expires_at <= ?1")
+    }
+
+    /// Paths of the files whose deletion is due by now.
+    pub fn due_deletes(&self) -> io::Result<Vec<String>> {
+        self.paths_due("SELECT path FROM pending_deletes WHERE delete_at <= ?1")
+    }
+
+    fn paths_due(&self, query: &str) -> io::Result<Vec<String>> {
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare(query).map_err(io::Error::other)?;
+        let paths = statement
+            .query_map([Utc::now().timestamp()], |row| row.get(0))
+            .map_err(io::Error::other)?
+            .collect::<Result<_, _>>()
+            .map_err(io::Error::other)?;
+        Ok(paths)
+    }
+}
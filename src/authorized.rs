@@ -1,62 +1,266 @@
-use std::env;
+mod api_key;
+pub mod basic;
+mod jwt;
+mod oidc;
+mod realms;
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use actix_web::{
-    HttpMessage, HttpResponse, Result,
+    HttpMessage, HttpRequest, HttpResponse, Result,
     body::{EitherBody, MessageBody},
     dev::{ServiceRequest, ServiceResponse},
-    http::header,
+    error::{ErrorForbidden, ErrorUnauthorized},
+    http::header::{self, HeaderMap},
     middleware::Next,
+    web::Data,
 };
+use async_trait::async_trait;
 use futures::TryFutureExt;
-use hmac::{Hmac, digest::KeyInit};
-use jwt::VerifyWithKey;
-use serde::Deserialize;
-use sha2::Sha256;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    authorized::{
+        api_key::ApiKeyAuthenticator,
+        basic::BasicAuthenticator,
+        jwt::JwtAuthenticator,
+        oidc::OidcAuthenticator,
+        realms::{MountRealms, file_path_of},
+    },
+    config::server::{AuthConfig, ServerConfig},
+    token_clients::TokenClients,
+};
+
+/// Grants a token can carry beyond plain API access. Downloading known paths doesn't need
+/// a token at all, these are for finding out what's there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// Enumerating directory contents and searching by name
+    List,
+    /// Reading a file's metadata without downloading it
+    Stat,
+    /// Reviewing quarantined uploads, and approving or rejecting them
+    Approve,
+    /// Seeing which tokens are in use and the user agents they're used from
+    Tokens,
+    /// Seeing the uploads and downloads in progress, and aborting them
+    Requests,
+    /// Listing, downloading and restoring the earlier versions of files
+    Versions,
+    /// Seeing the deleted files in the trash, and restoring them
+    Trash,
+    /// Telling a standby mirror which of its upstream's files changed, as its webhook
+    Replicate,
+    /// Promoting a standby mirror to take over from its upstream
+    Promote,
+    /// Seeing which files haven't been downloaded in a while, and deleting them
+    Stale,
+    /// Catching up with the uploads and deletes made since some point, from the changelog
+    Changes,
+}
+
+impl Permission {
+    fn as_str(self) -> &'static str {
+        match self {
+            Permission::List => "list",
+            Permission::Stat => "stat",
+            Permission::Approve => "approve",
+            Permission::Tokens => "tokens",
+            Permission::Requests => "requests",
+            Permission::Versions => "versions",
+            Permission::Trash => "trash",
+            Permission::Replicate => "replicate",
+            Permission::Promote => "promote",
+            Permission::Stale => "stale",
+            Permission::Changes => "changes",
+        }
+    }
+}
+
+/// Grants every permission.
+const WILDCARD_PERMISSION: &str = "*";
 
-#[derive(Debug, Deserialize)]
+/// What a request is allowed to do, whether it comes from a token's claims or the config.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct AuthPayload {
-    #[allow(unused)]
     permissions: Vec<String>,
+    /// Uploads made with the token are quarantined until approved, rather than served
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    quarantine: bool,
+    /// Picks the upload rate limits the token is held to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    class: Option<String>,
+    /// What the token was issued to, which its requests are expected to come from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    client: Option<String>,
 }
 
 impl AuthPayload {
+    #[allow(unused)]
     pub fn permissions(&self) -> &[String] {
         &self.permissions
     }
+
+    pub fn is_quarantined(&self) -> bool {
+        self.quarantine
+    }
+
+    pub fn class(&self) -> Option<&str> {
+        self.class.as_deref()
+    }
+
+    pub fn client(&self) -> Option<&str> {
+        self.client.as_deref()
+    }
+
+    pub fn has(&self, permission: Permission) -> bool {
+        self.permissions
+            .iter()
+            .any(|p| p == WILDCARD_PERMISSION || p == permission.as_str())
+    }
+
+    /// Fails with a 403 unless the token grants `permission`.
+    pub fn require(&self, permission: Permission) -> Result<()> {
+        if self.has(permission) {
+            Ok(())
+        } else {
+            Err(ErrorForbidden(format!(
+                "Missing '{}' permission",
+                permission.as_str()
+            )))
+        }
+    }
+}
+
+/// The outcome of checking the credentials sent with a request.
+pub enum Authentication {
+    /// Nothing was sent, or not in a way the authenticator looks for
+    Missing,
+    /// Credentials were sent, but aren't valid
+    Invalid,
+    /// `id` is what the credentials are known by in logs and the token listing, without
+    /// being usable as them
+    Valid { id: String, payload: AuthPayload },
+}
+
+/// A way for requests to prove who they're from, picked by `auth` in the config. Embedders
+/// can register their own as `Data<dyn Authenticator>` instead.
+#[async_trait(?Send)]
+pub trait Authenticator: Send + Sync {
+    async fn authenticate(&self, req: &HttpRequest) -> Authentication;
+
+    /// Sent as `WWW-Authenticate` when credentials are missing, if the scheme has one.
+    fn challenge(&self) -> Option<&'static str> {
+        None
+    }
+
+    /// The challenge for `req` in particular, for authenticators picking a scheme per request.
+    fn challenge_for(&self, _req: &HttpRequest) -> Option<&'static str> {
+        self.challenge()
+    }
+
+    /// The realm `path` is in, as the prefix of the mount with an `auth` of its own that it's
+    /// under, or `None` for the server's own.
+    fn realm_of(&self, _path: &Path) -> Option<&Path> {
+        None
+    }
+
+    /// Checks the credentials sent with `req` against the realm `path` is in, rather than
+    /// the one its URL is.
+    async fn authenticate_for(&self, req: &HttpRequest, _path: &Path) -> Authentication {
+        self.authenticate(req).await
+    }
+}
+
+/// Lets every request through with the same permissions.
+struct NoAuthenticator(AuthPayload);
+
+#[async_trait(?Send)]
+impl Authenticator for NoAuthenticator {
+    async fn authenticate(&self, _req: &HttpRequest) -> Authentication {
+        Authentication::Valid {
+            id: "anonymous".into(),
+            payload: self.0.clone(),
+        }
+    }
+}
+
+pub fn authenticator(config: &AuthConfig) -> io::Result<Arc<dyn Authenticator>> {
+    Ok(match config {
+        AuthConfig::Jwt { secret_env } => {
+            Arc::new(JwtAuthenticator::from_env(secret_env.as_deref())?)
+        }
+        AuthConfig::ApiKey { header, keys } => Arc::new(ApiKeyAuthenticator::new(header, keys)?),
+        AuthConfig::Basic { users } => Arc::new(BasicAuthenticator::new(users)?),
+        AuthConfig::Oidc(config) => Arc::new(OidcAuthenticator::new(config)?),
+        AuthConfig::None(payload) => Arc::new(NoAuthenticator(payload.clone())),
+    })
+}
+
+/// The authenticator for the server's `auth`, with the mounts that have their own checked
+/// against those instead.
+pub fn server_authenticator(config: &ServerConfig) -> io::Result<Arc<dyn Authenticator>> {
+    MountRealms::wrap(authenticator(&config.auth)?, &config.files_source)
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        // 7 is the length of "Bearer "
+        .filter(|h| h.starts_with("Bearer "))
+        .map(|h| &h[7..])
+}
+
+/// The start of the hash of `secret`, enough to tell credentials apart by.
+fn hashed_id(secret: &str) -> String {
+    let hash = format!("{:x}", Sha256::digest(secret.as_bytes()));
+    hash[..16].to_string()
+}
+
+/// The payload of valid credentials sent with the request for `path`, for routes outside the
+/// API that don't require any.
+pub async fn request_payload(req: &HttpRequest, path: &Path) -> Option<AuthPayload> {
+    let authenticator = req.app_data::<Data<dyn Authenticator>>()?;
+    match authenticator.authenticate_for(req, path).await {
+        Authentication::Valid { payload, .. } => Some(payload),
+        Authentication::Missing | Authentication::Invalid => None,
+    }
 }
 
 pub async fn is_authorized(
     req: ServiceRequest,
     next: Next<impl MessageBody>,
 ) -> Result<ServiceResponse<EitherBody<impl MessageBody>>> {
-    let auth_token = match req
-        .headers()
-        .get(header::AUTHORIZATION)
-        .and_then(|h| h.to_str().ok())
-    {
-        Some(auth_header) if auth_header.starts_with("Bearer ") => {
-            // 7 is the length of "Bearer "
-            &auth_header[7..]
-        }
+    let authenticator = req
+        .app_data::<Data<dyn Authenticator>>()
+        .expect("Authenticator is registered as app data")
+        .clone();
 
-        _ => {
-            return Ok(
-                // this is fun syntax, I had fun writing this actually
-                req.into_response(HttpResponse::Unauthorized().finish().map_into_right_body()),
-            );
+    let (id, payload) = match authenticator.authenticate(req.request()).await {
+        Authentication::Valid { id, payload } => (id, payload),
+        Authentication::Missing => {
+            let mut response = HttpResponse::Unauthorized();
+            if let Some(challenge) = authenticator.challenge_for(req.request()) {
+                response.insert_header((header::WWW_AUTHENTICATE, challenge));
+            }
+            // this is fun syntax, I had fun writing this actually
+            return Ok(req.into_response(response.finish().map_into_right_body()));
+        }
+        Authentication::Invalid => {
+            return Ok(req.into_response(HttpResponse::Forbidden().finish().map_into_right_body()));
         }
     };
 
-    let hmac: Hmac<Sha256> = Hmac::new_from_slice(
-        env::var("JWT_SESSION_SECRET")
-            .expect("missing JWT_SESSION_SECRET environment variable")
-            .as_bytes(),
-    )
-    .unwrap();
-
-    let Ok(payload): Result<AuthPayload, _> = auth_token.verify_with_key(&hmac) else {
-        return Ok(req.into_response(HttpResponse::Forbidden().finish().map_into_right_body()));
-    };
+    if let Err(err) = admit(req.request(), &id, &payload) {
+        return Ok(req.into_response(err.error_response().map_into_right_body()));
+    }
 
     // insert the payload into the request extensions for later use, if wanted
     req.extensions_mut().insert(payload);
@@ -65,3 +269,95 @@ pub async fn is_authorized(
         .map_ok(ServiceResponse::map_into_left_body)
         .await
 }
+
+/// The payload of the credentials sent with `req` in the realm of `path`, the file a handler
+/// went on to resolve. That isn't always the one its URL was checked against by
+/// `is_authorized`, e.g. for a file named like an API route or the files in an archive.
+pub async fn payload_for(req: &HttpRequest, path: &Path) -> Result<AuthPayload> {
+    let authenticator = req
+        .app_data::<Data<dyn Authenticator>>()
+        .expect("Authenticator is registered as app data");
+    let checked = file_path_of(req).unwrap_or_default();
+    if authenticator.realm_of(path) == authenticator.realm_of(&checked)
+        && let Some(payload) = req.extensions().get::<AuthPayload>()
+    {
+        return Ok(payload.clone());
+    }
+
+    match authenticator.authenticate_for(req, path).await {
+        Authentication::Valid { id, payload } => {
+            admit(req, &id, &payload)?;
+            Ok(payload)
+        }
+        Authentication::Missing => Err(ErrorUnauthorized(format!(
+            "'{}' needs credentials of its own",
+            path.display()
+        ))),
+        Authentication::Invalid => Err(ErrorForbidden(format!(
+            "Credentials aren't valid for '{}'",
+            path.display()
+        ))),
+    }
+}
+
+/// Payloads of the credentials sent with a request, for each realm the files it's about are
+/// in, so a directory's worth of them is only checked once per realm.
+pub struct RealmPayloads<'a> {
+    req: &'a HttpRequest,
+    checked: Vec<(Option<PathBuf>, Option<AuthPayload>)>,
+}
+
+impl<'a> RealmPayloads<'a> {
+    pub fn new(req: &'a HttpRequest) -> Self {
+        RealmPayloads {
+            req,
+            checked: Vec::new(),
+        }
+    }
+
+    /// The payload in the realm of `path`, if the credentials are valid there.
+    pub async fn get(&mut self, path: &Path) -> Option<&AuthPayload> {
+        let authenticator = self.req.app_data::<Data<dyn Authenticator>>()?;
+        let realm = authenticator.realm_of(path).map(Path::to_path_buf);
+        let at = match self
+            .checked
+            .iter()
+            .position(|(checked, _)| *checked == realm)
+        {
+            Some(at) => at,
+            None => {
+                let payload = payload_for(self.req, path).await.ok();
+                self.checked.push((realm, payload));
+                self.checked.len() - 1
+            }
+        };
+        self.checked[at].1.as_ref()
+    }
+}
+
+/// Turns away credentials used from another client than the one they were issued to, and
+/// records which one it was either way.
+fn admit(req: &HttpRequest, id: &str, payload: &AuthPayload) -> Result<()> {
+    let token_clients = req
+        .app_data::<Data<TokenClients>>()
+        .expect("TokenClients is registered as app data");
+    let user_agent = req
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or_default();
+    let is_allowed = token_clients.is_allowed(payload.client(), user_agent);
+    token_clients.record(id, payload.client(), user_agent, !is_allowed);
+
+    if !is_allowed {
+        // most likely the token got out and is being used by something else
+        log::warn!(
+            "Turned away token {id} of client '{}' used from user agent '{user_agent}'",
+            payload.client().unwrap_or_default()
+        );
+        return Err(ErrorForbidden(
+            "User agent doesn't match the token's client",
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,52 @@
+use std::{collections::BTreeMap, io};
+
+use actix_web::{HttpRequest, http::header::HeaderName};
+use async_trait::async_trait;
+use sha2::{Digest, Sha256};
+
+use crate::authorized::{AuthPayload, Authentication, Authenticator};
+
+/// Keys sent in a header, each with its own permissions.
+pub struct ApiKeyAuthenticator {
+    header: HeaderName,
+    // by the hash of the key, which is also what the config has
+    keys: BTreeMap<String, AuthPayload>,
+}
+
+impl ApiKeyAuthenticator {
+    pub fn new(header: &str, keys: &BTreeMap<String, AuthPayload>) -> io::Result<Self> {
+        let header = HeaderName::try_from(header).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid API key header name '{header}'"),
+            )
+        })?;
+
+        Ok(ApiKeyAuthenticator {
+            header,
+            keys: keys
+                .iter()
+                .map(|(hash, payload)| (hash.to_ascii_lowercase(), payload.clone()))
+                .collect(),
+        })
+    }
+}
+
+#[async_trait(?Send)]
+impl Authenticator for ApiKeyAuthenticator {
+    async fn authenticate(&self, req: &HttpRequest) -> Authentication {
+        let Some(key) = req.headers().get(&self.header) else {
+            return Authentication::Missing;
+        };
+
+        // looked up by hash, so how long that takes says nothing about the keys
+        let hash = format!("{:x}", Sha256::digest(key.as_bytes()));
+        match self.keys.get(&hash) {
+            Some(payload) => Authentication::Valid {
+                id: hash[..16].to_string(),
+                payload: payload.clone(),
+            },
+            None => Authentication::Invalid,
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use std::{collections::BTreeMap, io};
+
+use actix_web::{HttpRequest, http::header, web};
+use argon2::{
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+    password_hash::{SaltString, rand_core::OsRng},
+};
+use async_trait::async_trait;
+use base64::{Engine, prelude::BASE64_STANDARD};
+
+use crate::{
+    authorized::{Authentication, Authenticator},
+    config::server::BasicUser,
+};
+
+/// Usernames and passwords, sent the way browsers prompt for them.
+pub struct BasicAuthenticator {
+    users: BTreeMap<String, BasicUser>,
+}
+
+impl BasicAuthenticator {
+    pub fn new(users: &BTreeMap<String, BasicUser>) -> io::Result<Self> {
+        for (username, user) in users {
+            if let Err(err) = PasswordHash::new(&user.password_hash) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid password hash for user '{username}': {err}"),
+                ));
+            }
+        }
+
+        Ok(BasicAuthenticator {
+            users: users.clone(),
+        })
+    }
+}
+
+/// Hashes `password` with argon2 and a random salt, as the PHC string `password_hash` takes.
+pub fn hash_password(password: &str) -> io::Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|err| io::Error::other(err.to_string()))
+}
+
+#[async_trait(?Send)]
+impl Authenticator for BasicAuthenticator {
+    async fn authenticate(&self, req: &HttpRequest) -> Authentication {
+        let Some(credentials) = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Basic "))
+        else {
+            return Authentication::Missing;
+        };
+
+        let decoded = BASE64_STANDARD
+            .decode(credentials.trim())
+            .ok()
+            .and_then(|decoded| String::from_utf8(decoded).ok());
+        let Some((username, password)) = decoded.as_deref().and_then(|d| d.split_once(':')) else {
+            return Authentication::Invalid;
+        };
+        let Some(user) = self.users.get(username) else {
+            return Authentication::Invalid;
+        };
+
+        // slow on purpose, far too slow for a worker thread
+        let (hash, password) = (user.password_hash.clone(), password.to_string());
+        let is_valid = web::block(move || {
+            PasswordHash::new(&hash).is_ok_and(|hash| {
+                Argon2::default()
+                    .verify_password(password.as_bytes(), &hash)
+                    .is_ok()
+            })
+        })
+        .await
+        .unwrap_or(false);
+
+        if is_valid {
+            Authentication::Valid {
+                id: username.to_string(),
+                payload: user.grants.clone(),
+            }
+        } else {
+            Authentication::Invalid
+        }
+    }
+
+    fn challenge(&self) -> Option<&'static str> {
+        Some("Basic realm=\"cdn\"")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn passwords_are_checked_against_their_argon2_hash() {
+        let user = BasicUser {
+            password_hash: hash_password("hunter2").unwrap(),
+            grants: serde_json::from_value(serde_json::json!({ "permissions": [] })).unwrap(),
+        };
+        assert!(user.password_hash.starts_with("$argon2id$"));
+        let authenticator =
+            BasicAuthenticator::new(&BTreeMap::from([("ada".to_string(), user)])).unwrap();
+
+        let is_valid = async |credentials: &str| {
+            let req = TestRequest::get()
+                .insert_header((
+                    header::AUTHORIZATION,
+                    format!("Basic {}", BASE64_STANDARD.encode(credentials)),
+                ))
+                .to_http_request();
+            matches!(
+                authenticator.authenticate(&req).await,
+                Authentication::Valid { .. }
+            )
+        };
+        assert!(is_valid("ada:hunter2").await);
+        assert!(!is_valid("ada:hunter3").await);
+        assert!(!is_valid("bob:hunter2").await);
+
+        // the old unsalted SHA-256 hex isn't a password hash
+        let user = BasicUser {
+            password_hash: "f52fbd32b2b3b86ff88ef6c490628285f482af15ddcb29541f94bcf526a3f6c7"
+                .to_string(),
+            grants: serde_json::from_value(serde_json::json!({ "permissions": [] })).unwrap(),
+        };
+        assert!(BasicAuthenticator::new(&BTreeMap::from([("ada".to_string(), user)])).is_err());
+    }
+}
@@ -0,0 +1,55 @@
+use std::{env, io};
+
+use actix_web::HttpRequest;
+use async_trait::async_trait;
+use hmac::{Hmac, digest::KeyInit};
+use jwt::VerifyWithKey;
+use sha2::Sha256;
+
+use crate::authorized::{Authentication, Authenticator, bearer_token, hashed_id};
+
+/// Bearer tokens signed with a secret shared with whatever issues them.
+pub struct JwtAuthenticator {
+    key: Hmac<Sha256>,
+}
+
+const DEFAULT_SECRET_ENV: &str = "JWT_SESSION_SECRET";
+
+impl JwtAuthenticator {
+    /// Takes the secret from the `secret_env` environment variable, `JWT_SESSION_SECRET`
+    /// unless given.
+    pub fn from_env(secret_env: Option<&str>) -> io::Result<Self> {
+        let secret_env = secret_env.unwrap_or(DEFAULT_SECRET_ENV);
+        let secret = env::var(secret_env).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("missing {secret_env} environment variable"),
+            )
+        })?;
+
+        Ok(JwtAuthenticator {
+            key: Hmac::new_from_slice(secret.as_bytes()).map_err(io::Error::other)?,
+        })
+    }
+}
+
+#[async_trait(?Send)]
+impl Authenticator for JwtAuthenticator {
+    async fn authenticate(&self, req: &HttpRequest) -> Authentication {
+        let Some(token) = bearer_token(req.headers()) else {
+            return Authentication::Missing;
+        };
+
+        match token.verify_with_key(&self.key) {
+            Ok(payload) => Authentication::Valid {
+                id: hashed_id(token),
+                payload,
+            },
+            Err(_) => Authentication::Invalid,
+        }
+    }
+
+    fn challenge(&self) -> Option<&'static str> {
+        Some("Bearer")
+    }
+}
@@ -0,0 +1,373 @@
+use std::{
+    collections::HashMap,
+    io,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use actix_web::HttpRequest;
+use async_trait::async_trait;
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use chrono::Utc;
+use reqwest::Client;
+use ring::signature::{
+    ECDSA_P256_SHA256_FIXED, RSA_PKCS1_2048_8192_SHA256, RsaPublicKeyComponents, UnparsedPublicKey,
+};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use tokio::sync::OnceCell;
+
+use crate::{
+    authorized::{AuthPayload, Authentication, Authenticator, bearer_token, hashed_id},
+    config::server::OidcConfig,
+};
+
+// a token signed with a key not seen yet makes the keys be fetched again, but no more often
+// than this, so made up key ids can't be used to hammer the provider
+const KEYS_REFETCH_INTERVAL: Duration = Duration::from_secs(60);
+// the clocks of the provider and the server are never quite the same
+const LEEWAY_SECS: i64 = 60;
+
+#[derive(Clone)]
+enum VerifyingKey {
+    Rsa { n: Vec<u8>, e: Vec<u8> },
+    EcP256 { point: Vec<u8> },
+}
+
+impl VerifyingKey {
+    fn from_jwk(jwk: &Jwk) -> Option<Self> {
+        let decode = |part: &Option<String>| BASE64_URL_SAFE_NO_PAD.decode(part.as_ref()?).ok();
+
+        match (jwk.kty.as_str(), jwk.crv.as_deref()) {
+            ("RSA", _) => Some(VerifyingKey::Rsa {
+                n: decode(&jwk.n)?,
+                e: decode(&jwk.e)?,
+            }),
+            ("EC", Some("P-256")) => {
+                // uncompressed, which is how ring takes it
+                let mut point = vec![0x04];
+                point.extend(decode(&jwk.x)?);
+                point.extend(decode(&jwk.y)?);
+                Some(VerifyingKey::EcP256 { point })
+            }
+            _ => None,
+        }
+    }
+
+    fn verify(&self, alg: &str, message: &[u8], signature: &[u8]) -> bool {
+        match (self, alg) {
+            (VerifyingKey::Rsa { n, e }, "RS256") => RsaPublicKeyComponents { n, e }
+                .verify(&RSA_PKCS1_2048_8192_SHA256, message, signature)
+                .is_ok(),
+            (VerifyingKey::EcP256 { point }, "ES256") => {
+                UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, point)
+                    .verify(message, signature)
+                    .is_ok()
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Jwk {
+    kty: String,
+    #[serde(default)]
+    kid: Option<String>,
+    #[serde(default)]
+    crv: Option<String>,
+    #[serde(default)]
+    n: Option<String>,
+    #[serde(default)]
+    e: Option<String>,
+    #[serde(default)]
+    x: Option<String>,
+    #[serde(default)]
+    y: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct JwtHeader {
+    alg: String,
+    #[serde(default)]
+    kid: Option<String>,
+}
+
+/// Bearer tokens issued by an OpenID Connect provider, checked against the keys it publishes.
+pub struct OidcAuthenticator {
+    config: OidcConfig,
+    client: Client,
+    jwks_url: OnceCell<String>,
+    // keyed by their `kid`, with a key without one under the empty string
+    keys: Mutex<HashMap<String, VerifyingKey>>,
+    fetched_at: tokio::sync::Mutex<Option<Instant>>,
+}
+
+impl OidcAuthenticator {
+    pub fn new(config: &OidcConfig) -> io::Result<Self> {
+        Ok(OidcAuthenticator {
+            config: config.clone(),
+            client: Client::builder().build().map_err(io::Error::other)?,
+            jwks_url: OnceCell::new_with(config.jwks_url.clone()),
+            keys: Mutex::default(),
+            fetched_at: tokio::sync::Mutex::default(),
+        })
+    }
+
+    async fn fetch_json(&self, url: &str) -> reqwest::Result<Value> {
+        self.client
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+    }
+
+    async fn fetch_keys(&self) -> reqwest::Result<HashMap<String, VerifyingKey>> {
+        let jwks_url = self
+            .jwks_url
+            .get_or_try_init(|| async {
+                let discovery_url = format!(
+                    "{}/.well-known/openid-configuration",
+                    self.config.issuer.trim_end_matches('/')
+                );
+                let discovery = self.fetch_json(&discovery_url).await?;
+                Ok::<_, reqwest::Error>(
+                    discovery["jwks_uri"]
+                        .as_str()
+                        .unwrap_or_default()
+                        .to_string(),
+                )
+            })
+            .await?;
+
+        let jwks = self.fetch_json(jwks_url).await?;
+        let jwks: Vec<Jwk> = serde_json::from_value(jwks["keys"].clone()).unwrap_or_default();
+        Ok(jwks
+            .iter()
+            .filter_map(|jwk| {
+                Some((
+                    jwk.kid.clone().unwrap_or_default(),
+                    VerifyingKey::from_jwk(jwk)?,
+                ))
+            })
+            .collect())
+    }
+
+    async fn key(&self, kid: &str) -> Option<VerifyingKey> {
+        if let Some(key) = self.keys.lock().unwrap().get(kid) {
+            return Some(key.clone());
+        }
+
+        let mut fetched_at = self.fetched_at.lock().await;
+        // another request might've fetched them while this one waited
+        if let Some(key) = self.keys.lock().unwrap().get(kid) {
+            return Some(key.clone());
+        }
+        if fetched_at.is_some_and(|at| at.elapsed() < KEYS_REFETCH_INTERVAL) {
+            return None;
+        }
+
+        *fetched_at = Some(Instant::now());
+        match self.fetch_keys().await {
+            Ok(keys) => {
+                let mut current = self.keys.lock().unwrap();
+                *current = keys;
+                current.get(kid).cloned()
+            }
+            Err(err) => {
+                log::warn!(
+                    "Failed to fetch the keys of '{}': {err}",
+                    self.config.issuer
+                );
+                None
+            }
+        }
+    }
+
+    /// The claims of `token`, if it's signed by the provider and meant for this server.
+    async fn verify(&self, token: &str) -> Option<Map<String, Value>> {
+        let mut parts = token.split('.');
+        let (Some(header), Some(claims), Some(signature), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return None;
+        };
+
+        let decode = |part: &str| BASE64_URL_SAFE_NO_PAD.decode(part).ok();
+        let header: JwtHeader = serde_json::from_slice(&decode(header)?).ok()?;
+        let key = self.key(header.kid.as_deref().unwrap_or_default()).await?;
+        let signed = &token[..token.len() - signature.len() - 1];
+        if !key.verify(&header.alg, signed.as_bytes(), &decode(signature)?) {
+            return None;
+        }
+
+        let claims: Map<String, Value> = serde_json::from_slice(&decode(claims)?).ok()?;
+        let now = Utc::now().timestamp();
+        let expires_at = claims.get("exp").and_then(Value::as_i64)?;
+        let not_before = claims
+            .get("nbf")
+            .and_then(Value::as_i64)
+            .unwrap_or(i64::MIN);
+        if now > expires_at + LEEWAY_SECS || now + LEEWAY_SECS < not_before {
+            return None;
+        }
+        if claims.get("iss").and_then(Value::as_str) != Some(self.config.issuer.as_str()) {
+            return None;
+        }
+        if let Some(audience) = &self.config.audience {
+            let is_for_us = match claims.get("aud") {
+                Some(Value::String(aud)) => aud == audience,
+                Some(Value::Array(auds)) => auds.iter().any(|aud| aud == audience),
+                _ => false,
+            };
+            if !is_for_us {
+                return None;
+            }
+        }
+
+        Some(claims)
+    }
+
+    fn payload(&self, claims: &Map<String, Value>) -> AuthPayload {
+        let string_claim =
+            |name: &str| claims.get(name).and_then(Value::as_str).map(str::to_string);
+
+        AuthPayload {
+            permissions: match claims.get(&self.config.permissions_claim) {
+                Some(Value::Array(permissions)) => permissions
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .map(str::to_string)
+                    .collect(),
+                Some(Value::String(permissions)) => {
+                    permissions.split_whitespace().map(str::to_string).collect()
+                }
+                _ => Vec::new(),
+            },
+            quarantine: claims
+                .get("quarantine")
+                .and_then(Value::as_bool)
+                .unwrap_or(false),
+            class: string_claim("class"),
+            client: string_claim("client"),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Authenticator for OidcAuthenticator {
+    async fn authenticate(&self, req: &HttpRequest) -> Authentication {
+        let Some(token) = bearer_token(req.headers()) else {
+            return Authentication::Missing;
+        };
+
+        match self.verify(token).await {
+            Some(claims) => Authentication::Valid {
+                id: claims
+                    .get("sub")
+                    .and_then(Value::as_str)
+                    .map_or_else(|| hashed_id(token), str::to_string),
+                payload: self.payload(&claims),
+            },
+            None => Authentication::Invalid,
+        }
+    }
+
+    fn challenge(&self) -> Option<&'static str> {
+        Some("Bearer")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use ring::{
+        rand::SystemRandom,
+        signature::{ECDSA_P256_SHA256_FIXED_SIGNING, EcdsaKeyPair, KeyPair},
+    };
+    use serde_json::json;
+
+    use super::*;
+
+    fn sign(key_pair: &EcdsaKeyPair, kid: &str, claims: Value) -> String {
+        let encode = |value: Value| BASE64_URL_SAFE_NO_PAD.encode(value.to_string());
+        let signed = format!(
+            "{}.{}",
+            encode(json!({ "alg": "ES256", "kid": kid })),
+            encode(claims)
+        );
+        let signature = key_pair
+            .sign(&SystemRandom::new(), signed.as_bytes())
+            .unwrap();
+        format!("{signed}.{}", BASE64_URL_SAFE_NO_PAD.encode(signature))
+    }
+
+    #[test]
+    fn tokens_need_a_known_key_and_matching_claims() {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        let key_pair =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng)
+                .unwrap();
+
+        let authenticator = OidcAuthenticator::new(&OidcConfig {
+            issuer: "https://id.example.com".into(),
+            audience: Some("cdn".into()),
+            jwks_url: Some("http://127.0.0.1:9/keys".into()),
+            permissions_claim: "scope".into(),
+        })
+        .unwrap();
+        authenticator.keys.lock().unwrap().insert(
+            "key-1".into(),
+            VerifyingKey::EcP256 {
+                point: key_pair.public_key().as_ref().to_vec(),
+            },
+        );
+        // as if just fetched, so unknown keys don't go looking
+        *block_on(authenticator.fetched_at.lock()) = Some(Instant::now());
+
+        let now = Utc::now().timestamp();
+        let claims = |changes: Value| {
+            let mut claims = json!({
+                "iss": "https://id.example.com",
+                "aud": ["other", "cdn"],
+                "sub": "alice",
+                "exp": now + 300,
+                "scope": "list stat",
+            });
+            claims
+                .as_object_mut()
+                .unwrap()
+                .extend(changes.as_object().unwrap().clone());
+            claims
+        };
+
+        block_on(async {
+            let token = sign(&key_pair, "key-1", claims(json!({})));
+            let verified = authenticator.verify(&token).await.unwrap();
+            assert_eq!(
+                authenticator.payload(&verified).permissions,
+                ["list", "stat"]
+            );
+
+            let expired = sign(&key_pair, "key-1", claims(json!({ "exp": now - 3600 })));
+            assert!(authenticator.verify(&expired).await.is_none());
+            let wrong_audience = sign(&key_pair, "key-1", claims(json!({ "aud": "other" })));
+            assert!(authenticator.verify(&wrong_audience).await.is_none());
+            let wrong_issuer = sign(&key_pair, "key-1", claims(json!({ "iss": "https://evil" })));
+            assert!(authenticator.verify(&wrong_issuer).await.is_none());
+            let unknown_key = sign(&key_pair, "key-2", claims(json!({})));
+            assert!(authenticator.verify(&unknown_key).await.is_none());
+
+            // claims swapped out from under the signature
+            let (signed, signature) = token.rsplit_once('.').unwrap();
+            let (header, _) = signed.split_once('.').unwrap();
+            let forged_claims =
+                BASE64_URL_SAFE_NO_PAD.encode(claims(json!({ "scope": "*" })).to_string());
+            let forged = format!("{header}.{forged_claims}.{signature}");
+            assert!(authenticator.verify(&forged).await.is_none());
+        });
+    }
+}
@@ -0,0 +1,213 @@
+use std::{
+    cmp::Reverse,
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use actix_web::{HttpRequest, http::Method};
+use async_trait::async_trait;
+
+use crate::{
+    authorized::{Authentication, Authenticator, authenticator},
+    config::server::FileSource,
+    routes::file_path::normalize_path,
+};
+
+// API routes about the one file named by the rest of their path, e.g. `GET /api/info/{file}`,
+// with the methods they answer. Any other one goes to the upload and delete routes instead,
+// which are about the file at the whole path. A test checks every such route is listed
+const FILE_ROUTES: [(&str, &[Method]); 9] = [
+    ("info", &[Method::GET]),
+    ("verify", &[Method::GET]),
+    ("list", &[Method::GET]),
+    ("archive", &[Method::GET]),
+    ("versions", &[Method::GET, Method::POST]),
+    ("undelete", &[Method::POST]),
+    ("restore", &[Method::POST]),
+    ("admin/approve", &[Method::POST]),
+    ("admin/reject", &[Method::POST]),
+];
+
+/// Checks the requests about files under a mount with an `auth` of its own against that,
+/// and everything else against the server's `auth`. Requests spanning the whole store, such
+/// as searches and bulk deletes, always count as the server's.
+pub struct MountRealms {
+    default: Arc<dyn Authenticator>,
+    // longest prefix first, so the first one a path is under is the realm it's in
+    realms: Vec<(PathBuf, Arc<dyn Authenticator>)>,
+}
+
+impl MountRealms {
+    /// Wraps `default` with the realms of the mounts in `source`, unless none has one.
+    pub fn wrap(
+        default: Arc<dyn Authenticator>,
+        source: &FileSource,
+    ) -> io::Result<Arc<dyn Authenticator>> {
+        let FileSource::Mounted { mounts } = source else {
+            return Ok(default);
+        };
+
+        let mut realms = Vec::new();
+        for mount in mounts {
+            if let Some(auth) = &mount.auth {
+                let prefix = normalize_path(&mount.prefix).ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("invalid mount prefix '{}'", mount.prefix),
+                    )
+                })?;
+                realms.push((prefix, authenticator(auth)?));
+            }
+        }
+        if realms.is_empty() {
+            return Ok(default);
+        }
+
+        realms.sort_by_key(|(prefix, _)| Reverse(prefix.components().count()));
+        Ok(Arc::new(MountRealms { default, realms }))
+    }
+
+    fn realm(&self, path: &Path) -> Option<&(PathBuf, Arc<dyn Authenticator>)> {
+        self.realms
+            .iter()
+            .find(|(prefix, _)| path.starts_with(prefix))
+    }
+
+    fn authenticator_for(&self, path: &Path) -> &dyn Authenticator {
+        self.realm(path)
+            .map_or(&*self.default, |(_, realm)| &**realm)
+    }
+}
+
+/// The file a request is about as far as its URL tells, which handlers resolving another one
+/// check again with `payload_for`.
+pub fn file_path_of(req: &HttpRequest) -> Option<PathBuf> {
+    let path = req.uri().path().trim_start_matches('/');
+    let path = match path.strip_prefix("api/") {
+        Some(api_path) => FILE_ROUTES
+            .iter()
+            .filter(|(_, methods)| methods.contains(req.method()))
+            .find_map(|(name, _)| api_path.strip_prefix(name)?.strip_prefix('/'))
+            .unwrap_or(api_path),
+        None => path,
+    };
+    normalize_path(path).filter(|path| path != Path::new(""))
+}
+
+#[async_trait(?Send)]
+impl Authenticator for MountRealms {
+    async fn authenticate(&self, req: &HttpRequest) -> Authentication {
+        let path = file_path_of(req).unwrap_or_default();
+        self.authenticator_for(&path).authenticate(req).await
+    }
+
+    fn challenge(&self) -> Option<&'static str> {
+        self.default.challenge()
+    }
+
+    fn challenge_for(&self, req: &HttpRequest) -> Option<&'static str> {
+        let path = file_path_of(req).unwrap_or_default();
+        self.authenticator_for(&path).challenge_for(req)
+    }
+
+    fn realm_of(&self, path: &Path) -> Option<&Path> {
+        self.realm(path).map(|(prefix, _)| prefix.as_path())
+    }
+
+    async fn authenticate_for(&self, req: &HttpRequest, path: &Path) -> Authentication {
+        self.authenticator_for(path).authenticate(req).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, fs};
+
+    use actix_web::{http::header, test::TestRequest};
+    use sha2::{Digest, Sha256};
+
+    use super::*;
+    use crate::config::server::{AuthConfig, Mount};
+
+    fn api_keys(key: &str) -> Option<AuthConfig> {
+        let payload = serde_json::from_value(serde_json::json!({ "permissions": [] })).unwrap();
+        Some(AuthConfig::ApiKey {
+            header: "X-API-Key".to_string(),
+            keys: BTreeMap::from([(format!("{:x}", Sha256::digest(key)), payload)]),
+        })
+    }
+
+    #[actix_web::test]
+    async fn mounts_are_checked_against_their_own_realm() {
+        let mount = |prefix: &str, auth| Mount {
+            prefix: prefix.to_string(),
+            source: FileSource::Memory,
+            auth,
+        };
+        let source = FileSource::Mounted {
+            mounts: vec![
+                mount("team", api_keys("team-key")),
+                mount("team/ci", api_keys("ci-key")),
+                mount("public", None),
+            ],
+        };
+        let default = authenticator(&api_keys("admin-key").unwrap()).unwrap();
+        let realms = MountRealms::wrap(default, &source).unwrap();
+
+        let is_valid = async |method: Method, path: &str, key: &str| {
+            let req = TestRequest::default()
+                .method(method)
+                .uri(path)
+                .insert_header(("X-API-Key", key))
+                .insert_header((header::USER_AGENT, "test"))
+                .to_http_request();
+            matches!(
+                realms.authenticate(&req).await,
+                Authentication::Valid { .. }
+            )
+        };
+
+        assert!(is_valid(Method::POST, "/team/a.txt", "team-key").await);
+        assert!(!is_valid(Method::POST, "/team/a.txt", "admin-key").await);
+        assert!(is_valid(Method::POST, "/api/team/a.txt", "team-key").await);
+        assert!(is_valid(Method::GET, "/api/info/team/a.txt", "team-key").await);
+        assert!(is_valid(Method::POST, "/team/ci/build.zip", "ci-key").await);
+        assert!(!is_valid(Method::POST, "/team/ci/build.zip", "team-key").await);
+        // no way around the realm by spelling the path differently
+        assert!(!is_valid(Method::POST, "/public/../team/a.txt", "admin-key").await);
+        assert!(!is_valid(Method::POST, "/%74eam/a.txt", "admin-key").await);
+        assert!(is_valid(Method::POST, "/public/a.txt", "admin-key").await);
+        assert!(is_valid(Method::POST, "/api/jobs", "admin-key").await);
+        // uploads to a path starting like a route are about the file at the whole path
+        assert!(!is_valid(Method::POST, "/api/info/team/a.txt", "team-key").await);
+        assert!(!is_valid(Method::DELETE, "/api/info/team/a.txt", "team-key").await);
+        assert!(is_valid(Method::DELETE, "/api/info/team/a.txt", "admin-key").await);
+        assert!(is_valid(Method::POST, "/api/admin/approve/team/a.txt", "team-key").await);
+    }
+
+    #[test]
+    fn every_api_route_about_a_file_is_listed() {
+        let routes_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/routes");
+        let mut found = 0;
+        for entry in fs::read_dir(routes_dir).unwrap() {
+            let source = fs::read_to_string(entry.unwrap().path()).unwrap();
+            // e.g. `#[get("/info/{path:.*}")]`, leaving out the ones about the whole path
+            let routes = source.lines().filter_map(|line| {
+                let (method, rest) = line.trim().strip_prefix("#[")?.split_once("(\"/")?;
+                let name = rest.strip_suffix("/{path:.*}\")]")?;
+                Some((method.to_uppercase(), name))
+            });
+            // `.well-known` is outside of /api
+            for (method, name) in routes.filter(|(_, name)| *name != ".well-known") {
+                assert!(
+                    FILE_ROUTES.iter().any(|(listed, methods)| *listed == name
+                        && methods.iter().any(|listed| listed.as_str() == method)),
+                    "{method} /api/{name}/{{path}} is missing from FILE_ROUTES"
+                );
+                found += 1;
+            }
+        }
+        assert!(found >= FILE_ROUTES.len());
+    }
+}
@@ -0,0 +1,141 @@
+use std::{fs, io, path::Path};
+
+use actix_web::{
+    HttpRequest, HttpResponse,
+    http::{StatusCode, header},
+    web::Bytes,
+};
+use base64::{Engine, prelude::BASE64_STANDARD};
+
+use crate::config::server::{BrandingConfig, SERVER_CONFIG_NAME};
+
+const DEFAULT_FAVICON: &[u8] = include_bytes!("../assets/favicon.ico");
+const DEFAULT_LOGO: &[u8] = include_bytes!("../assets/logo.svg");
+
+// looked for next to the server config, in order of preference
+const LOGO_OVERRIDES: [(&str, &str); 2] =
+    [("logo.svg", "image/svg+xml"), ("logo.png", "image/png")];
+const FAVICON_OVERRIDE: &str = "favicon.ico";
+
+/// Title and images for the HTML pages the server renders itself.
+pub struct Branding {
+    title: String,
+    favicon: Bytes,
+    logo_data_uri: String,
+}
+
+impl Branding {
+    pub fn load(config: &BrandingConfig) -> io::Result<Self> {
+        let config_dir = Path::new(SERVER_CONFIG_NAME)
+            .parent()
+            .unwrap_or(Path::new(""));
+
+        let favicon = match fs::read(config_dir.join(FAVICON_OVERRIDE)) {
+            Ok(favicon) => Bytes::from(favicon),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                Bytes::from_static(DEFAULT_FAVICON)
+            }
+            Err(err) => return Err(err),
+        };
+
+        let mut logo = (DEFAULT_LOGO.to_vec(), "image/svg+xml");
+        for (name, mime) in LOGO_OVERRIDES {
+            match fs::read(config_dir.join(name)) {
+                Ok(bytes) => {
+                    logo = (bytes, mime);
+                    break;
+                }
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(Branding {
+            title: config.title.clone(),
+            favicon,
+            // inlined so pages don't need a route of their own for it, which would shadow a file
+            logo_data_uri: format!("data:{};base64,{}", logo.1, BASE64_STANDARD.encode(&logo.0)),
+        })
+    }
+
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    pub fn favicon(&self) -> Bytes {
+        self.favicon.clone()
+    }
+
+    /// Wraps `body`, which must already be escaped, in the branded page layout.
+    pub fn page(&self, heading: &str, body: &str) -> String {
+        let title = escape_html(&self.title);
+        let heading = escape_html(heading);
+        let logo = &self.logo_data_uri;
+
+        format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{heading} - {title}</title>
+<link rel="icon" href="/favicon.ico">
+<style>
+body {{ margin: 0; font-family: system-ui, sans-serif; color: #222; background: #f6f7f9; }}
+header {{ display: flex; align-items: center; gap: .75rem; padding: .75rem 1.5rem; background: #fff; border-bottom: 1px solid #e3e5e8; font-weight: 600; }}
+main {{ max-width: 60rem; margin: 2rem auto; padding: 0 1.5rem; }}
+</style>
+</head>
+<body>
+<header><img src="{logo}" alt="" width="32" height="32">{title}</header>
+<main>
+<h1>{heading}</h1>
+{body}
+</main>
+</body>
+</html>
+"#
+        )
+    }
+
+    /// An error response that's a branded page for browsers, and plain text for everything else.
+    pub fn error_response(
+        &self,
+        req: &HttpRequest,
+        status: StatusCode,
+        message: &str,
+    ) -> HttpResponse {
+        if !accepts_html(req) {
+            return HttpResponse::build(status).body(message.to_string());
+        }
+
+        let heading = status.canonical_reason().unwrap_or("Error");
+        let body = format!("<p>{}</p>", escape_html(message));
+
+        HttpResponse::build(status)
+            .content_type(header::ContentType::html())
+            .body(self.page(heading, &body))
+    }
+}
+
+pub fn accepts_html(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"))
+}
+
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -73,6 +73,29 @@ impl<K: Hash + Eq + Clone, V> CacheMap<K, V> {
         self.inner.insert(key, entry);
     }
 
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        self.inner.remove(key).map(|entry| entry.inner)
+    }
+
+    pub fn clear(&mut self) {
+        self.inner.clear();
+    }
+
+    /// Drops every expired entry, returning how many were removed.
+    pub fn remove_expired(&mut self) -> usize {
+        let now = Instant::now();
+        let before = self.inner.len();
+        self.inner.retain(|_, entry| now < entry.expires_at);
+        before - self.inner.len()
+    }
+
+    /// Drops every entry whose key `keep` turns down, returning how many were removed.
+    pub fn retain(&mut self, mut keep: impl FnMut(&K) -> bool) -> usize {
+        let before = self.inner.len();
+        self.inner.retain(|key, _| keep(key));
+        before - self.inner.len()
+    }
+
     pub fn evict_lru(&mut self) {
         if let Some(key) = self
             .inner
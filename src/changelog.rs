@@ -0,0 +1,276 @@
+use std::{
+    fs, io,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::{Deserialize, Serialize};
+
+use crate::config::server::ChangelogConfig;
+
+const CHANGELOG_FILE_NAME: &str = "changelog.db";
+
+pub type SharedChangelog = Arc<Changelog>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeOp {
+    Upload,
+    Delete,
+}
+
+impl ChangeOp {
+    fn as_str(self) -> &'static str {
+        match self {
+            ChangeOp::Upload => "upload",
+            ChangeOp::Delete => "delete",
+        }
+    }
+
+    fn from_str(op: &str) -> Option<Self> {
+        match op {
+            "upload" => Some(ChangeOp::Upload),
+            "delete" => Some(ChangeOp::Delete),
+            _ => None,
+        }
+    }
+}
+
+/// One upload or delete, numbered by `seq` in the order they happened.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub seq: i64,
+    pub path: String,
+    pub op: ChangeOp,
+    /// What was uploaded, deletes have none
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    pub at: DateTime<Utc>,
+}
+
+/// What changed after some change, as reported by `GET /api/changes?since=`.
+#[derive(Serialize, Debug)]
+pub struct Changes {
+    pub changes: Vec<Change>,
+    /// The last change made, a client that got this far is caught up
+    pub latest: i64,
+}
+
+/// Every upload and delete, numbered in the order they happened, so that a client that was
+/// away can catch up with what changed since the last one it saw, rather than listing every
+/// file again. The numbers never go back, even once older changes are pruned.
+pub struct Changelog {
+    // nothing is recorded, or even opened, while turned off
+    db: Option<Mutex<Connection>>,
+    retention: Duration,
+}
+
+impl Changelog {
+    pub fn open(data_dir: impl AsRef<Path>, config: &ChangelogConfig) -> io::Result<Self> {
+        let db = if config.enabled {
+            fs::create_dir_all(&data_dir)?;
+            let db = Connection::open(data_dir.as_ref().join(CHANGELOG_FILE_NAME))
+                .map_err(io::Error::other)?;
+            db.execute_batch(
+                "CREATE TABLE IF NOT EXISTS changes (
+                    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                    path TEXT NOT NULL,
+                    op TEXT NOT NULL,
+                    hash TEXT,
+                    at INTEGER NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS pruned (
+                    id INTEGER PRIMARY KEY CHECK (id = 0),
+                    through INTEGER NOT NULL
+                );",
+            )
+            .map_err(io::Error::other)?;
+            Some(Mutex::new(db))
+        } else {
+            None
+        };
+
+        Ok(Changelog {
+            db,
+            retention: Duration::from_secs(config.retention_secs),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.db.is_some()
+    }
+
+    /// Records a change to `path`, returning its number.
+    pub fn append(&self, path: &Path, op: ChangeOp, hash: Option<&str>) -> io::Result<i64> {
+        let Some(db) = &self.db else {
+            return Ok(0);
+        };
+
+        let db = db.lock().unwrap();
+        db.execute(
+            "INSERT INTO changes (path, op, hash, at) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                path.to_string_lossy(),
+                op.as_str(),
+                hash,
+                Utc::now().timestamp_millis()
+            ],
+        )
+        .map_err(io::Error::other)?;
+        Ok(db.last_insert_rowid())
+    }
+
+    /// Up to `limit` of the changes made after change `since`, oldest first. `None` when some
+    /// of them were already pruned, so they can't all be told anymore.
+    pub fn since(&self, since: i64, limit: usize) -> io::Result<Option<Changes>> {
+        let Some(db) = &self.db else {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "changes aren't being recorded",
+            ));
+        };
+
+        let db = db.lock().unwrap();
+        let pruned_through: i64 = db
+            .query_row("SELECT through FROM pruned WHERE id = 0", [], |row| {
+                row.get(0)
+            })
+            .optional()
+            .map_err(io::Error::other)?
+            .unwrap_or_default();
+        if since < pruned_through {
+            return Ok(None);
+        }
+
+        // kept by sqlite for the numbers to never be handed out twice, even with nothing left
+        let latest: i64 = db
+            .query_row(
+                "SELECT seq FROM sqlite_sequence WHERE name = 'changes'",
+                [],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(io::Error::other)?
+            .unwrap_or_default();
+
+        let mut statement = db
+            .prepare(
+                "SELECT seq, path, op, hash, at FROM changes WHERE seq > ?1
+                    ORDER BY seq LIMIT ?2",
+            )
+            .map_err(io::Error::other)?;
+        let changes = statement
+            .query_map(params![since, limit as i64], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                ))
+            })
+            .map_err(io::Error::other)?
+            .filter_map(|row| {
+                let (seq, path, op, hash, at) = match row {
+                    Ok(row) => row,
+                    Err(err) => return Some(Err(io::Error::other(err))),
+                };
+                Some(Ok(Change {
+                    seq,
+                    path,
+                    op: ChangeOp::from_str(&op)?,
+                    hash,
+                    at: DateTime::from_timestamp_millis(at)?,
+                }))
+            })
+            .collect::<io::Result<_>>()?;
+
+        Ok(Some(Changes { changes, latest }))
+    }
+
+    /// Removes the changes older than the retention, returning how many there were.
+    pub fn prune(&self) -> io::Result<usize> {
+        let Some(db) = &self.db else {
+            return Ok(0);
+        };
+        let Some(cutoff) = TimeDelta::from_std(self.retention)
+            .ok()
+            .and_then(|retention| Utc::now().checked_sub_signed(retention))
+        else {
+            return Ok(0);
+        };
+
+        let mut db = db.lock().unwrap();
+        let tx = db.transaction().map_err(io::Error::other)?;
+        let through: Option<i64> = tx
+            .query_row(
+                "SELECT MAX(seq) FROM changes WHERE at < ?1",
+                [cutoff.timestamp_millis()],
+                |row| row.get(0),
+            )
+            .map_err(io::Error::other)?;
+        let Some(through) = through else {
+            return Ok(0);
+        };
+
+        let pruned = tx
+            .execute("DELETE FROM changes WHERE seq <= ?1", [through])
+            .map_err(io::Error::other)?;
+        tx.execute(
+            "INSERT INTO pruned (id, through) VALUES (0, ?1)
+                ON CONFLICT (id) DO UPDATE SET through = MAX(through, ?1)",
+            [through],
+        )
+        .map_err(io::Error::other)?;
+        tx.commit().map_err(io::Error::other)?;
+        Ok(pruned)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::temp_dir;
+
+    #[test]
+    fn clients_catch_up_until_changes_are_pruned() {
+        let dir = temp_dir();
+        let config = ChangelogConfig {
+            enabled: true,
+            retention_secs: 0,
+        };
+        let changelog = Changelog::open(&dir, &config).unwrap();
+
+        let first = changelog
+            .append(Path::new("a.txt"), ChangeOp::Upload, Some("abc"))
+            .unwrap();
+        let second = changelog
+            .append(Path::new("a.txt"), ChangeOp::Delete, None)
+            .unwrap();
+        assert!(second > first);
+
+        let caught_up = changelog.since(first, 100).unwrap().unwrap();
+        assert_eq!(caught_up.latest, second);
+        assert_eq!(caught_up.changes.len(), 1);
+        assert_eq!(caught_up.changes[0].op, ChangeOp::Delete);
+        assert_eq!(
+            changelog.since(0, 1).unwrap().unwrap().changes[0].seq,
+            first
+        );
+
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(changelog.prune().unwrap(), 2);
+        // too far behind to be told what changed, but numbering carries on where it was
+        assert!(changelog.since(first, 100).unwrap().is_none());
+        let caught_up = changelog.since(second, 100).unwrap().unwrap();
+        assert!(caught_up.changes.is_empty());
+        assert_eq!(caught_up.latest, second);
+        let third = changelog
+            .append(Path::new("b.txt"), ChangeOp::Upload, Some("def"))
+            .unwrap();
+        assert!(third > second);
+    }
+}
@@ -0,0 +1,55 @@
+#[cfg(unix)]
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Super simple CDN / file server")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Detach from the terminal and run in the background, logging to syslog
+    #[cfg(unix)]
+    #[arg(long)]
+    pub daemon: bool,
+
+    /// Where to write the process id when running with --daemon
+    #[cfg(unix)]
+    #[arg(long, default_value = "cdn.pid", requires = "daemon")]
+    pub pidfile: PathBuf,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Validate the config and print it as the server would use it, with secrets redacted
+    CheckConfig,
+    /// Print the JSON Schema of the config, for editor completion
+    ConfigSchema,
+    /// Start a server over a temporary store and upload, download and delete a file through
+    /// it, exiting with an error if any of it fails
+    Selftest,
+    /// Read a password from stdin and print its argon2 hash, for a `basic` auth user's
+    /// `password_hash`
+    HashPassword,
+    /// Manage the Windows service for this server
+    #[cfg(windows)]
+    Service {
+        #[command(subcommand)]
+        action: ServiceAction,
+    },
+}
+
+#[cfg(windows)]
+#[derive(Subcommand, Debug)]
+pub enum ServiceAction {
+    /// Register the service to start automatically with Windows
+    Install,
+    /// Stop and remove the service
+    Uninstall,
+    Start,
+    Stop,
+    /// Entry point used by the service control manager, not meant to be run by hand
+    #[command(hide = true)]
+    Run,
+}
@@ -0,0 +1,133 @@
+use std::{io, str::FromStr};
+
+use cron::Schedule;
+use schemars::schema_for;
+use serde_json::Value;
+
+use crate::{
+    authorized,
+    branding::Branding,
+    config::server::{FileSource, ServerConfig},
+    cors::Cors,
+    derive::Derivatives,
+    file_store::{EncryptedFileStore, RemoteFileStore},
+    geoip::GeoIp,
+    header_rules::HeaderRules,
+    home_page::HomePage,
+    hotlink::Hotlinks,
+    mime_types::MimeTypes,
+};
+
+/// Fields left out when printing the config, wherever they appear in it.
+const SECRET_FIELDS: &[&str] = &[
+    "connection_string",
+    "key",
+    "password",
+    "secret_access_key",
+    "service_account_key",
+    "token",
+];
+
+/// Parses and validates the config without starting the server or rewriting the file, then
+/// prints it as the server would use it. Returns whether the server could start with it.
+pub fn check_config() -> io::Result<bool> {
+    let config_file = ServerConfig::new_file();
+    let path = config_file.path().display();
+
+    let config = match config_file.peek() {
+        Ok(Some(config)) => config,
+        Ok(None) => {
+            eprintln!("There's no '{path}' yet, the defaults would be used");
+            ServerConfig::default()
+        }
+        Err(err) => {
+            eprintln!("error: failed to parse '{path}': {err}");
+            return Ok(false);
+        }
+    };
+
+    let mut effective = serde_json::to_value(&config)?;
+    redact(&mut effective);
+    println!("{}", serde_json::to_string_pretty(&effective)?);
+
+    let problems = problems(&config);
+    for problem in &problems {
+        eprintln!("error: {problem}");
+    }
+    if problems.is_empty() {
+        eprintln!("'{path}' is valid");
+    }
+    Ok(problems.is_empty())
+}
+
+/// Prints the JSON Schema of the config, for editors to complete and check it with.
+pub fn print_schema() -> io::Result<()> {
+    let schema = schema_for!(ServerConfig);
+    println!("{}", serde_json::to_string_pretty(&schema)?);
+    Ok(())
+}
+
+/// What would stop the server from starting, as far as can be told without starting it.
+fn problems(config: &ServerConfig) -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut check = |result: io::Result<()>| {
+        if let Err(err) = result {
+            problems.push(err.to_string());
+        }
+    };
+
+    check(authorized::server_authenticator(config).map(|_| ()));
+    check(Derivatives::new(&config.data_dir, &config.derivatives).map(|_| ()));
+    check(HeaderRules::new(&config.headers, &config.render).map(|_| ()));
+    check(MimeTypes::new(&config.mime_overrides).map(|_| ()));
+    check(Cors::new(&config.cors).map(|_| ()));
+    check(Hotlinks::new(&config.hotlinks).map(|_| ()));
+    check(Branding::load(&config.branding).map(|_| ()));
+    check(HomePage::load(&config.home_page).map(|_| ()));
+    check(GeoIp::load(&config.geoip).map(|_| ()));
+    if let Some(key) = &config.encryption.key {
+        check(EncryptedFileStore::check_key(key));
+    }
+    check(check_source(&config.files_source));
+    if let Some(mirror) = &config.mirror {
+        check(check_source(&mirror.upstream));
+    }
+
+    for (name, task) in &config.scheduler.tasks {
+        if let Some(schedule) = &task.schedule
+            && let Err(err) = Schedule::from_str(schedule)
+        {
+            problems.push(format!("invalid schedule for task '{name}': {err}"));
+        }
+    }
+
+    problems
+}
+
+// only what can be checked without reaching out to the source
+fn check_source(source: &FileSource) -> io::Result<()> {
+    match source {
+        FileSource::Remote(remote) => RemoteFileStore::new(remote).map(|_| ()),
+        FileSource::Tiered(tiered) => tiered.tiers.iter().try_for_each(check_source),
+        FileSource::Mounted { mounts } => mounts
+            .iter()
+            .try_for_each(|mount| check_source(&mount.source)),
+        _ => Ok(()),
+    }
+}
+
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            for (name, field) in fields {
+                if SECRET_FIELDS.contains(&name.as_str()) && !field.is_null() {
+                    *field = Value::String("<redacted>".to_string());
+                } else {
+                    redact(field);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
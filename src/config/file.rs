@@ -1,7 +1,7 @@
 use std::{
     fs::{self, File},
     io,
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
 use serde::{Serialize, de::DeserializeOwned};
@@ -50,6 +50,20 @@ impl<T: DeserializeOwned + Serialize + Default> ConfigFile<T> {
         Ok(self.data.as_ref().unwrap())
     }
 
+    /// Reads the file as it is, without creating or rewriting it. None if there's no file yet.
+    pub fn peek(&self) -> io::Result<Option<T>> {
+        if !self.file_path.is_file() {
+            return Ok(None);
+        }
+
+        let file = File::open(&self.file_path)?;
+        Ok(Some(serde_json::from_reader(&file)?))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.file_path
+    }
+
     pub fn read_and_save(&mut self) -> io::Result<()> {
         self.read()?;
         // re-save to ensure formatting and any new default fields
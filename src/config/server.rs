@@ -1,31 +1,279 @@
-use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use schemars::{JsonSchema, Schema, SchemaGenerator, json_schema};
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de::Error};
 use serde_default::DefaultFromSerde;
 
-use crate::config::file::ConfigFile;
+use crate::{authorized::AuthPayload, config::file::ConfigFile};
 
 pub const SERVER_CONFIG_NAME: &str = "config/server.json";
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum FileSource {
-    Local { base_dir: String },
+    Local {
+        base_dir: String,
+        /// Replaces `memory_cache` for this source alone, so a busy one can't push the files of
+        /// another out of the cache
+        #[serde(default)]
+        memory_cache: Option<MemoryCache>,
+        /// SQLite database to keep the metadata of the files in, instead of a `.metadata.json`
+        /// next to each. Sidecars already there are moved into it on startup
+        #[serde(default)]
+        metadata_db: Option<String>,
+        /// Picks up changes made to `base_dir` other than through the server, e.g. by a deploy
+        #[serde(default = "default_enabled")]
+        watch: bool,
+        /// Keeps what files replaced or deleted through the server were, so they can be
+        /// restored through `/api/versions/{file}`
+        #[serde(default)]
+        versions: Option<VersionsConfig>,
+        /// Which symlinks under `base_dir` files are served through
+        #[serde(default)]
+        follow_symlinks: SymlinkPolicy,
+        /// Files at least this large are served out of a memory map rather than read a chunk
+        /// at a time, which saves on syscalls for multi-GB files. Off by default: a file
+        /// truncated in place while mapped kills the whole server with SIGBUS, and pages not
+        /// read ahead yet are faulted in on the worker, holding up its other requests. Only for
+        /// fast disks under directories nothing but this server writes to
+        #[serde(default)]
+        mmap_min_bytes: Option<u64>,
+    },
+    S3(S3Config),
+    AzureBlob(AzureBlobConfig),
+    Gcs(GcsConfig),
+    #[serde(rename = "webdav")]
+    WebDav(WebDavConfig),
+    /// Another instance of this server, read-only
+    Remote(RemoteConfig),
+    /// Like `local`, but identical files are only stored once, at the cost of the files not
+    /// being laid out under their paths in `base_dir`
+    ContentAddressed {
+        base_dir: String,
+    },
+    /// Nothing is kept once the server stops, for tests and throwaway deployments
+    Memory,
+    /// Several sources tried in order, e.g. a local directory in front of a bucket
+    Tiered(TieredConfig),
+    /// Several sources each under their own path, usually given as just the list of mounts
+    Mounted {
+        mounts: Vec<Mount>,
+    },
+}
+
+/// A source appearing under `prefix`, e.g. `{"prefix": "builds", "source": {...}}`. The
+/// longest matching prefix wins.
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+pub struct Mount {
+    pub prefix: String,
+    pub source: FileSource,
+    /// Checks the requests about files under `prefix` instead of the server's `auth`, e.g.
+    /// `{"type": "none", "permissions": []}` to let anyone upload there
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth: Option<AuthConfig>,
+}
+
+/// `files_source` is either a single source or the list of mounts.
+fn deserialize_files_source<'de, D: Deserializer<'de>>(d: D) -> Result<FileSource, D::Error> {
+    // buffered, so either way gets the errors of what it was actually meant as
+    let value = serde_json::Value::deserialize(d)?;
+    if value.is_array() {
+        let mounts = Vec::<Mount>::deserialize(value).map_err(D::Error::custom)?;
+        Ok(FileSource::Mounted { mounts })
+    } else {
+        FileSource::deserialize(value).map_err(D::Error::custom)
+    }
+}
+
+fn serialize_files_source<S: Serializer>(source: &FileSource, s: S) -> Result<S::Ok, S::Error> {
+    match source {
+        FileSource::Mounted { mounts } => mounts.serialize(s),
+        source => source.serialize(s),
+    }
+}
+
+fn files_source_schema(generator: &mut SchemaGenerator) -> Schema {
+    json_schema!({
+        "anyOf": [
+            generator.subschema_for::<FileSource>(),
+            generator.subschema_for::<Vec<Mount>>(),
+        ]
+    })
+}
+
+/// Sources ordered from the fastest to the slowest, where the last one holds every file and
+/// receives the uploads. The ones in front of it only ever hold copies.
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+pub struct TieredConfig {
+    pub tiers: Vec<FileSource>,
+    #[serde(default)]
+    pub promotion: PromotionPolicy,
+}
+
+/// When files found further down are copied into the first tier.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(tag = "policy", rename_all = "snake_case")]
+pub enum PromotionPolicy {
+    /// The first tier is only filled by other means, e.g. syncing it ahead of time
+    #[default]
+    Never,
+    /// In the background, the first time a file is read while not in the first tier
+    OnAccess {
+        /// Larger files are left where they are
+        #[serde(default)]
+        max_size_bytes: Option<u64>,
+    },
+}
+
+/// A container in Azure Blob Storage, or the Azurite emulator.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct AzureBlobConfig {
+    pub container: String,
+    /// As shown for the storage account in the portal, e.g.
+    /// `DefaultEndpointsProtocol=https;AccountName=...;AccountKey=...;EndpointSuffix=core.windows.net`
+    pub connection_string: String,
+    /// Blob name prefix the files are kept under, for sharing a container
+    #[serde(default)]
+    pub prefix: String,
+    /// Replaces `memory_cache` for this container
+    #[serde(default)]
+    pub memory_cache: Option<MemoryCache>,
+}
+
+/// A Google Cloud Storage bucket.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct GcsConfig {
+    pub bucket: String,
+    /// Path to a service account's JSON key file, falls back to
+    /// `GOOGLE_APPLICATION_CREDENTIALS` and the like from the environment when unset
+    #[serde(default)]
+    pub service_account_path: Option<String>,
+    /// The contents of the JSON key file, for when it can't be kept on disk
+    #[serde(default)]
+    pub service_account_key: Option<String>,
+    /// Object name prefix the files are kept under, for sharing a bucket
+    #[serde(default)]
+    pub prefix: String,
+    /// Replaces `memory_cache` for this bucket
+    #[serde(default)]
+    pub memory_cache: Option<MemoryCache>,
+}
+
+/// An S3 bucket, or anything speaking the same API such as MinIO.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct S3Config {
+    pub bucket: String,
+    /// Falls back to `AWS_REGION` and the like from the environment when unset
+    #[serde(default)]
+    pub region: Option<String>,
+    /// For S3-compatible services, e.g. `http://localhost:9000`
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    /// Falls back to `AWS_ACCESS_KEY_ID` and the like from the environment when unset
+    #[serde(default)]
+    pub access_key_id: Option<String>,
+    #[serde(default)]
+    pub secret_access_key: Option<String>,
+    /// Key prefix the files are kept under, for sharing a bucket
+    #[serde(default)]
+    pub prefix: String,
+    /// Replaces `memory_cache` for this bucket
+    #[serde(default)]
+    pub memory_cache: Option<MemoryCache>,
+}
+
+/// A share on a WebDAV server, e.g. Nextcloud or Apache's `mod_dav`.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct WebDavConfig {
+    /// Of the directory the files are kept in, e.g. `https://dav.example.com/files/`
+    pub url: String,
+    /// For basic authentication, no credentials are sent when unset
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    /// Replaces `memory_cache` for this share
+    #[serde(default)]
+    pub memory_cache: Option<MemoryCache>,
+}
+
+/// Earlier versions of a local source's files, kept as `<file>@v<n>` in a directory of their own.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct VersionsConfig {
+    /// Outside of the source's `base_dir`, where they'd be served like any other file
+    pub dir: String,
+    /// The oldest versions of a file are dropped once it has more
+    #[serde(default = "default_max_versions")]
+    pub max_versions: u32,
+}
+
+const fn default_max_versions() -> u32 {
+    10
+}
+
+/// Another instance of this server, read through its API.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct RemoteConfig {
+    /// Where it's reachable, e.g. `https://cdn.example.com/`
+    pub url: String,
+    /// Sent as the bearer token, needs the `stat` and `list` permissions of that instance
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+/// Keeps `files_source` a read-only copy of `upstream`, synced by the `mirror_sync` task.
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+pub struct MirrorConfig {
+    pub upstream: FileSource,
 }
 
 impl Default for FileSource {
     fn default() -> Self {
         FileSource::Local {
             base_dir: "files".into(),
+            memory_cache: None,
+            metadata_db: None,
+            watch: true,
+            versions: None,
+            follow_symlinks: SymlinkPolicy::default(),
+            mmap_min_bytes: None,
         }
     }
 }
 
-#[derive(DefaultFromSerde, Serialize, Deserialize, Debug)]
+/// Which symlinks a local source follows, for files and for the directories they're in.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SymlinkPolicy {
+    /// Anything behind a symlink is treated as missing
+    Never,
+    /// Only the ones leading somewhere else in `base_dir`, so nothing outside it is served
+    #[default]
+    WithinBase,
+    /// Wherever they lead, e.g. to a directory shared with another deployment
+    Always,
+}
+
+/// What to do with requests for non-canonical file paths, e.g. `a//b/`, `a/./b` or `a%2Fb`.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PathPolicy {
+    /// Treat the request as if it was made for the canonical path
+    #[default]
+    Normalize,
+    /// Answer reads with a permanent redirect to the canonical path
+    Redirect,
+}
+
+/// Looked up files kept around, which holds on to their handles rather than contents.
+#[derive(DefaultFromSerde, Serialize, Deserialize, JsonSchema, Debug, Clone)]
 #[serde(default)]
 pub struct MemoryCache {
     #[serde(default = "default_enabled")]
     pub enabled: bool,
     #[serde(default = "default_cache_time_secs")]
     pub cache_time_secs: u64,
+    /// Larger files are looked up again every time
     #[serde(default = "default_max_size_bytes")]
     pub max_size_bytes: u64,
     #[serde(default = "default_max_files_cached")]
@@ -48,16 +296,674 @@ const fn default_max_files_cached() -> usize {
     100 // 100 files * ~10MB each = ~1GB max of cached files
 }
 
-#[derive(DefaultFromSerde, Serialize, Deserialize, Debug)]
+#[derive(DefaultFromSerde, Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(default)]
+pub struct StoreTracingConfig {
+    /// Store operations taking longer are logged, and counted as slow in `GET /api/store/stats`
+    #[serde(default = "default_slow_threshold_ms")]
+    pub slow_threshold_ms: u64,
+}
+
+const fn default_slow_threshold_ms() -> u64 {
+    1000
+}
+
+#[derive(DefaultFromSerde, Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(default)]
+pub struct SchedulerConfig {
+    /// Overrides for individual background tasks, keyed by task name
+    pub tasks: BTreeMap<String, TaskConfig>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct TaskConfig {
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Cron expression including seconds, e.g. `0 */5 * * * *`, uses the task's default if unset
+    #[serde(default)]
+    pub schedule: Option<String>,
+}
+
+#[derive(DefaultFromSerde, Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(default)]
+pub struct WebhookConfig {
+    /// Every file event is POSTed as JSON to each of these
+    pub urls: Vec<String>,
+    /// Webhooks only sent the events they're subscribed to
+    pub subscriptions: Vec<WebhookSubscription>,
+    /// Sent as a bearer token with every delivery, e.g. one allowed to `replicate` when a
+    /// standby mirror is among the `urls`
+    pub token: Option<String>,
+    /// Failed deliveries are retried with backoff, and parked as dead letters after this many tries
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    #[serde(default = "default_request_timeout_secs")]
+    pub request_timeout_secs: u64,
+}
+
+/// What file events, out of every one there is, a webhook is sent.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct WebhookSubscription {
+    pub url: String,
+    /// Only events for files under one of these, e.g. `releases/`, or under any path if empty
+    #[serde(default)]
+    pub prefixes: Vec<String>,
+    /// Only events of these types, or of any type if empty
+    #[serde(default)]
+    pub events: Vec<WebhookEventType>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEventType {
+    Upload,
+    Delete,
+}
+
+/// What happens when a hook fails.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HookErrorPolicy {
+    /// Turns away the upload or download, or skips the later hooks once it's already happened
+    #[default]
+    Abort,
+    /// Logs the failure and runs the later hooks as if it had succeeded
+    Continue,
+}
+
+#[derive(DefaultFromSerde, Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(default)]
+pub struct HookConfig {
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Hooks run lowest first, ties going by name
+    pub order: i32,
+    pub on_error: HookErrorPolicy,
+}
+
+/// What happens to uploads larger than the scanners are fed.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ScanOverLimit {
+    /// Scans the start of the upload, which is where file signatures are
+    #[default]
+    Sample,
+    /// Leaves the upload unscanned
+    Skip,
+}
+
+#[derive(DefaultFromSerde, Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(default)]
+pub struct ScanConfig {
+    /// The most of an upload the scanners are fed, 0 for all of it
+    pub max_scan_bytes: u64,
+    pub over_limit: ScanOverLimit,
+}
+
+const fn default_max_attempts() -> u32 {
+    10
+}
+
+const fn default_request_timeout_secs() -> u64 {
+    10
+}
+
+/// A single step of turning a file into a derivative of it.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TransformConfig {
+    Gzip {
+        #[serde(default = "default_gzip_level")]
+        level: u32,
+    },
+    Zstd {
+        #[serde(default = "default_zstd_level")]
+        level: i32,
+    },
+    /// Scales an image down to fit within `width` and `height`, keeping its aspect ratio and
+    /// never making it larger, and converts it to `format` if given
+    Resize {
+        width: Option<u32>,
+        height: Option<u32>,
+        format: Option<ImageFormat>,
+    },
+}
+
+/// Formats images can be converted to, by a `resize` transform or `?format=`.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+    Png,
+    #[serde(alias = "jpg")]
+    Jpeg,
+    Webp,
+    Gif,
+}
+
+const fn default_gzip_level() -> u32 {
+    9
+}
+
+const fn default_zstd_level() -> i32 {
+    19
+}
+
+#[derive(DefaultFromSerde, Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(default)]
+pub struct DerivativesConfig {
+    /// Named chains of transforms, a file's derivative is requested with `?derive=<name>`
+    pub presets: BTreeMap<String, Vec<TransformConfig>>,
+    /// Cached derivatives that haven't been requested for this long are removed
+    #[serde(default = "default_derivative_max_age_secs")]
+    pub max_age_secs: u64,
+    /// Largest width or height images can be resized to with `?w=` and `?h=`, 0 turns resizing
+    /// by query parameters off, leaving it to the presets
+    #[serde(default = "default_max_image_dimension")]
+    pub max_image_dimension: u32,
+}
+
+const fn default_max_image_dimension() -> u32 {
+    4096
+}
+
+const fn default_derivative_max_age_secs() -> u64 {
+    7 * 24 * 60 * 60 // 1 week
+}
+
+/// Used by the server's own HTML pages. The favicon and logo are built in, but can be
+/// replaced by putting a `favicon.ico` and `logo.svg` or `logo.png` in the config directory.
+#[derive(DefaultFromSerde, Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(default)]
+pub struct BrandingConfig {
+    #[serde(default = "default_title")]
+    pub title: String,
+}
+
+fn default_title() -> String {
+    "Super Simple CDN".to_string()
+}
+
+/// A status page for requests for `/` that nothing else answers, rather than a 404, with the
+/// branding title, how many files there are, an upload form for requests with credentials and
+/// `links`. A `home.html` next to the config replaces the page, with `{{title}}`, `{{files}}`,
+/// `{{total_bytes}}`, `{{upload_form}}` and `{{links}}` filled in.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Default)]
+#[serde(default)]
+pub struct HomePageConfig {
+    pub enabled: bool,
+    pub links: Vec<HomePageLink>,
+}
+
+/// A link on the home page, e.g. to a web UI or documentation.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct HomePageLink {
+    pub label: String,
+    pub url: String,
+}
+
+/// Generates `/robots.txt`, which otherwise is looked up like any other file.
+#[derive(DefaultFromSerde, Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(default)]
+pub struct RobotsConfig {
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    #[serde(default = "default_robots_disallow")]
+    pub disallow: Vec<String>,
+    pub sitemap: Option<String>,
+}
+
+fn default_robots_disallow() -> Vec<String> {
+    vec!["/api/".to_string()]
+}
+
+/// Whether a file is served to anyone asking for it, or only to requests with a valid token.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Visibility {
+    #[default]
+    Public,
+    Private,
+}
+
+/// Settings for a common kind of upload, picked with `?profile=<name>` instead of being
+/// repeated on every request.
+#[derive(DefaultFromSerde, Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(default)]
+pub struct UploadProfile {
+    /// Directory the upload's path is taken relative to, e.g. `screenshots`
+    pub prefix: String,
+    /// Replaces the file name with a random one, keeping its extension
+    pub random_name: bool,
+    /// Uploads are deleted again once they're this old
+    pub expires_after_secs: Option<u64>,
+    pub tags: Vec<String>,
+    pub visibility: Visibility,
+}
+
+/// Files are encrypted before they're stored when a key is set. Turning this on doesn't
+/// encrypt files that are already stored, which then can't be read anymore.
+#[derive(DefaultFromSerde, Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(default)]
+pub struct EncryptionConfig {
+    /// 32 bytes encoded as base64, e.g. from `openssl rand -base64 32`
+    pub key: Option<String>,
+}
+
+/// Country based access rules for served files, using a MaxMind GeoIP2 or GeoLite2 database.
+/// Countries are ISO 3166-1 alpha-2 codes, e.g. `DE`.
+#[derive(DefaultFromSerde, Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(default)]
+pub struct GeoIpConfig {
+    /// Path to the `.mmdb` country (or city) database, lookups are off when unset
+    pub database: Option<String>,
+    /// When not empty, only these countries are served, and neither are unknown ones
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+/// Serves the `fallback` file for paths under `prefix` that don't exist, for single page
+/// apps doing their own routing, e.g. `{"prefix": "app", "fallback": "index.html"}`.
+/// The fallback is served as HTML, unlike other `.html` files.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct FallbackRule {
+    #[serde(default)]
+    pub prefix: String,
+    /// Relative to `prefix`
+    pub fallback: String,
+    /// Serves the fallback as the page saying nothing's there, with `404 Not Found`, and only
+    /// once no directory listing or status page answers instead, e.g. for a `404.html`
+    #[serde(default)]
+    pub not_found: bool,
+}
+
+/// Answers requests for a directory under `prefix` on the file route with a listing of it,
+/// e.g. `{"prefix": "downloads"}`, which `"enabled": false` turns back off for a directory
+/// further down.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct DirectoryListingRule {
+    #[serde(default)]
+    pub prefix: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+/// The digest files are hashed with for their metadata and ETags. Files keep the hash they
+/// were stored with when it's changed, new uploads get the new one.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    /// Much faster for large files, though clients can only send `blake3` checksums along
+    Blake3,
+}
+
+impl HashAlgorithm {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Blake3 => "blake3",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sha256" => Some(HashAlgorithm::Sha256),
+            "blake3" => Some(HashAlgorithm::Blake3),
+            _ => None,
+        }
+    }
+}
+
+/// Limits on what the files take up altogether, counted when first needed and kept up to date
+/// as they're uploaded and deleted. Uploads that would go over are turned away with a `507`.
+#[derive(DefaultFromSerde, Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(default)]
+pub struct QuotaConfig {
+    pub max_total_bytes: Option<u64>,
+    pub max_files: Option<u64>,
+}
+
+impl QuotaConfig {
+    pub fn is_limited(&self) -> bool {
+        self.max_total_bytes.is_some() || self.max_files.is_some()
+    }
+}
+
+/// Deleted files are moved into the trash in the data directory while enabled, where they can
+/// be restored from until they've been there for `retention_secs`.
+#[derive(DefaultFromSerde, Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(default)]
+pub struct TrashConfig {
+    pub enabled: bool,
+    #[serde(default = "default_trash_retention_secs")]
+    pub retention_secs: u64,
+}
+
+const fn default_trash_retention_secs() -> u64 {
+    30 * 24 * 60 * 60 // 30 days
+}
+
+/// When each file was last downloaded, kept only when `enabled`. Nothing about who
+/// downloaded it is kept, and the times are rounded down to `resolution_secs`.
+#[derive(DefaultFromSerde, Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(default)]
+pub struct AccessTimesConfig {
+    pub enabled: bool,
+    #[serde(default = "default_access_resolution_secs")]
+    pub resolution_secs: u64,
+}
+
+const fn default_access_resolution_secs() -> u64 {
+    60 * 60 // an hour
+}
+
+/// Every upload and delete is numbered in a changelog, for `GET /api/changes?since=`. Changes
+/// older than `retention_secs` are removed by the `changelog_prune` task, after which clients
+/// that last caught up before them have to list the files again.
+#[derive(DefaultFromSerde, Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(default)]
+pub struct ChangelogConfig {
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    #[serde(default = "default_changelog_retention_secs")]
+    pub retention_secs: u64,
+}
+
+const fn default_changelog_retention_secs() -> u64 {
+    30 * 24 * 60 * 60 // 30 days
+}
+
+/// Uploads are written to a temp file and moved into place once complete. Those left behind
+/// by uploads that never finished are removed once not written to for `idle_timeout_secs`.
+#[derive(DefaultFromSerde, Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(default)]
+pub struct PartialUploadsConfig {
+    #[serde(default = "default_partial_upload_idle_timeout_secs")]
+    pub idle_timeout_secs: u64,
+}
+
+const fn default_partial_upload_idle_timeout_secs() -> u64 {
+    60 * 60 // an hour
+}
+
+/// Local files are held open while they're downloaded and while cached. Downloads are refused
+/// with `503 Service Unavailable` once `max_open` are, rather than running out of file
+/// descriptors for everything else.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Default)]
+#[serde(default)]
+pub struct FileHandlesConfig {
+    /// 0 for three quarters of the process's open file limit, leaving the rest for
+    /// connections and databases
+    pub max_open: u64,
+}
+
+/// How fast uploads are taken in, for the tokens of one class.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Default)]
+pub struct UploadRateLimit {
+    /// Each upload on its own
+    #[serde(default)]
+    pub per_connection_bytes_per_sec: Option<u64>,
+    /// Every upload of the class together
+    #[serde(default)]
+    pub global_bytes_per_sec: Option<u64>,
+}
+
+/// How fast downloads are sent, so a few large ones can't take up the whole uplink.
+#[derive(DefaultFromSerde, Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(default)]
+pub struct DownloadRateLimit {
+    /// Each download on its own
+    pub per_connection_bytes_per_sec: Option<u64>,
+    /// Every download to one client address together
+    pub per_ip_bytes_per_sec: Option<u64>,
+}
+
+/// How API requests prove who they're from, which also decides who sees private files.
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AuthConfig {
+    /// Bearer tokens signed with HS256 using the secret in the `secret_env` environment
+    /// variable, `JWT_SESSION_SECRET` by default, carrying their permissions as claims
+    Jwt {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        secret_env: Option<String>,
+    },
+    /// A key sent in a header
+    ApiKey {
+        #[serde(default = "default_api_key_header")]
+        header: String,
+        /// Keyed by the SHA-256 of each key in hex, so the keys themselves aren't written down
+        keys: BTreeMap<String, AuthPayload>,
+    },
+    /// A username and password, keyed by the username
+    Basic { users: BTreeMap<String, BasicUser> },
+    /// Bearer tokens issued by an OpenID Connect provider
+    Oidc(OidcConfig),
+    /// Every request gets these permissions, e.g. behind a proxy that already checked it
+    None(AuthPayload),
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig::Jwt { secret_env: None }
+    }
+}
+
+fn default_api_key_header() -> String {
+    "X-API-Key".into()
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct BasicUser {
+    /// An argon2 (or other PHC) string, e.g. from `cdn hash-password`
+    pub password_hash: String,
+    #[serde(flatten)]
+    pub grants: AuthPayload,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct OidcConfig {
+    /// Has to match the `iss` claim exactly, e.g. `https://accounts.example.com`
+    pub issuer: String,
+    /// Checked against the `aud` claim when set
+    #[serde(default)]
+    pub audience: Option<String>,
+    /// Found through the issuer's discovery document when unset
+    #[serde(default)]
+    pub jwks_url: Option<String>,
+    /// Either a list or, like `scope`, a string separated by spaces
+    #[serde(default = "default_permissions_claim")]
+    pub permissions_claim: String,
+}
+
+fn default_permissions_claim() -> String {
+    "permissions".into()
+}
+
+/// What a registered client sends requests from, e.g. `{"user_agents": ["restic/*"]}`.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Default)]
+pub struct ClientRule {
+    /// Globs the `User-Agent` header has to match one of
+    pub user_agents: Vec<String>,
+}
+
+/// Extra headers for served files whose path matches `pattern`. Patterns without a `/`
+/// match the file name anywhere, e.g. `*.woff2`, others match from the root, e.g. `downloads/**`.
+/// `Content-Type` and `ETag` can't be overridden this way.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct HeaderRule {
+    pub pattern: String,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Extra headers for the private files among them, on top of `Cache-Control: private,
+    /// no-store` and `Vary: Authorization`, which these can replace
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub private_headers: BTreeMap<String, String>,
+}
+
+/// Lets the HTML and SVG files whose path matches `pattern` be served as what they are rather
+/// than as plain text, e.g. `docs/**` for hosting documentation. Patterns work the same as for
+/// header rules.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct RenderRule {
+    pub pattern: String,
+    /// Sent along with the files that render, e.g. `script-src 'none'`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_security_policy: Option<String>,
+}
+
+/// Which other sites' scripts may fetch files and call the API, answered with CORS headers
+/// on both and to the preflight requests browsers send first.
+#[derive(DefaultFromSerde, Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(default)]
+pub struct CorsConfig {
+    /// e.g. `https://app.example.com`, `*` allowing any, none sending no CORS headers at all
+    #[serde(default = "default_cors_origins")]
+    pub allowed_origins: Vec<String>,
+    /// Methods scripts may use beyond `GET`, `HEAD` and `POST`
+    #[serde(default = "default_cors_methods")]
+    pub allowed_methods: Vec<String>,
+    /// Request headers scripts may set beyond the ones always allowed
+    #[serde(default = "default_cors_headers")]
+    pub allowed_headers: Vec<String>,
+    /// Response headers scripts may read beyond the ones they always can, e.g. `ETag`
+    pub exposed_headers: Vec<String>,
+    /// Lets scripts send cookies and `Authorization` along, which `*` can't be used with
+    pub allow_credentials: bool,
+    /// How long browsers may go without asking again, 0 leaving it up to them
+    #[serde(default = "default_cors_max_age_secs")]
+    pub max_age_secs: u64,
+}
+
+/// Keeps other sites from embedding or linking straight to files, by the page a request
+/// says it came from.
+#[derive(DefaultFromSerde, Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(default)]
+pub struct HotlinkConfig {
+    /// Hosts pages may embed files from besides the server's own, e.g. `example.com` or
+    /// `*.example.com` for its subdomains, none turning the protection off
+    pub allowed_hosts: Vec<String>,
+    /// Lets requests naming no page through, as for typing the URL in or browsers keeping
+    /// where they've been to themselves
+    #[serde(default = "default_enabled")]
+    pub allow_no_referer: bool,
+    /// File sent instead of the ones asked for, e.g. an image saying where to find them,
+    /// rather than `403 Forbidden`. Read once at startup
+    pub placeholder: Option<String>,
+}
+
+fn default_cors_origins() -> Vec<String> {
+    vec!["*".to_string()]
+}
+
+fn default_cors_methods() -> Vec<String> {
+    ["GET", "HEAD", "POST", "PUT", "DELETE"]
+        .map(String::from)
+        .to_vec()
+}
+
+fn default_cors_headers() -> Vec<String> {
+    ["Authorization", "Content-Type", "Range"]
+        .map(String::from)
+        .to_vec()
+}
+
+const fn default_cors_max_age_secs() -> u64 {
+    600
+}
+
+#[derive(DefaultFromSerde, Serialize, Deserialize, JsonSchema, Debug)]
 #[serde(default)]
 pub struct ServerConfig {
+    /// Where editors find the schema from `cdn config-schema`, kept when the file is rewritten
+    #[serde(rename = "$schema", skip_serializing_if = "Option::is_none")]
+    pub schema: Option<String>,
     #[serde(default = "default_host")]
     pub host: String,
     #[serde(default = "default_port")]
     pub port: u16,
-    #[serde(default = "FileSource::default")]
+    /// Take client addresses from `Forwarded`/`X-Forwarded-For`, for the GeoIP rules and the
+    /// download limits per address. Only safe behind a proxy setting them
+    pub trust_forwarded_for: bool,
+    #[serde(
+        default = "FileSource::default",
+        deserialize_with = "deserialize_files_source",
+        serialize_with = "serialize_files_source"
+    )]
+    #[schemars(schema_with = "files_source_schema")]
     pub files_source: FileSource,
+    pub encryption: EncryptionConfig,
+    pub quota: QuotaConfig,
+    /// Content-addressed sources always use `sha256`, which their layout is keyed by
+    pub hash_algorithm: HashAlgorithm,
     pub memory_cache: MemoryCache,
+    pub store_tracing: StoreTracingConfig,
+    pub path_policy: PathPolicy,
+    /// How long in-flight requests get to finish when stopping or handing over to a new process
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64,
+    pub scheduler: SchedulerConfig,
+    /// Where the server keeps its own state, such as the status of background jobs
+    #[serde(default = "default_data_dir")]
+    pub data_dir: String,
+    pub webhooks: WebhookConfig,
+    /// Keyed by hook name, `webhooks` being the one queueing webhook events
+    pub hooks: BTreeMap<String, HookConfig>,
+    /// How much of each upload the scanners of hooks get to look at
+    pub scanning: ScanConfig,
+    pub derivatives: DerivativesConfig,
+    /// Applied in order, so later rules override headers set by earlier ones
+    pub headers: Vec<HeaderRule>,
+    pub cors: CorsConfig,
+    pub hotlinks: HotlinkConfig,
+    /// The longest matching prefix wins
+    pub fallbacks: Vec<FallbackRule>,
+    /// No directory is listed unless a rule says so, the longest matching prefix wins
+    pub directory_listings: Vec<DirectoryListingRule>,
+    /// HTML and SVG files are served as plain text, so they can't run scripts on the server's
+    /// origin, unless one of these lets them render. The last matching rule wins
+    pub render: Vec<RenderRule>,
+    /// Markdown files are rendered as HTML for browsers, as they are for `?render=1` anyway,
+    /// unless asked for with `?render=0`
+    pub render_markdown: bool,
+    /// Types to serve files with these extensions as, over the ones guessed otherwise, e.g.
+    /// `{"gcode": "text/x-gcode"}`
+    pub mime_overrides: BTreeMap<String, String>,
+    /// Served as HTML for requests for a directory, the first of these it has, e.g.
+    /// `["index.html", "index.htm"]` for hosting static sites
+    pub index_files: Vec<String>,
+    /// Keyed by the name uploads select them with
+    pub upload_profiles: BTreeMap<String, UploadProfile>,
+    /// When above 0, `DELETE` only marks a file to be deleted this long after, and it's still
+    /// served until then unless taken back with `POST /api/undelete/{file}`
+    pub delete_grace_secs: u64,
+    /// Checks files against their hash as they're served, cutting the response short when
+    /// they don't match rather than passing on silent corruption. `/api/verify/{file}` checks
+    /// one on demand either way
+    pub verify_reads: bool,
+    pub trash: TrashConfig,
+    pub access_times: AccessTimesConfig,
+    pub partial_uploads: PartialUploadsConfig,
+    pub file_handles: FileHandlesConfig,
+    pub changelog: ChangelogConfig,
+    /// Keyed by the `class` claim of tokens, with `default` covering tokens without one or
+    /// of a class not listed. Downloads are never held back by these
+    pub upload_rate_limits: BTreeMap<String, UploadRateLimit>,
+    pub download_rate_limit: DownloadRateLimit,
+    /// Keyed by the `client` claim of tokens. API requests made with a token of a listed
+    /// client are turned away unless they come from one of its user agents
+    pub clients: BTreeMap<String, ClientRule>,
+    pub auth: AuthConfig,
+    /// While set, nothing can be uploaded or deleted and the files only change through syncs
+    pub mirror: Option<MirrorConfig>,
+    pub robots: RobotsConfig,
+    pub branding: BrandingConfig,
+    pub home_page: HomePageConfig,
+    pub geoip: GeoIpConfig,
+    /// Directory `/.well-known/` is served from, e.g. for ACME challenges or `security.txt`
+    #[serde(default = "default_well_known_dir")]
+    pub well_known_dir: String,
 }
 
 impl ServerConfig {
@@ -73,3 +979,15 @@ fn default_host() -> String {
 const fn default_port() -> u16 {
     3000
 }
+
+const fn default_shutdown_timeout_secs() -> u64 {
+    30
+}
+
+fn default_data_dir() -> String {
+    "data".to_string()
+}
+
+fn default_well_known_dir() -> String {
+    "well-known".to_string()
+}
@@ -0,0 +1,347 @@
+use std::io;
+
+use actix_web::{
+    HttpRequest, HttpResponse, Result,
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::{
+        Method,
+        header::{self, HeaderMap, HeaderName, HeaderValue},
+    },
+    middleware::Next,
+    web::Data,
+};
+
+use crate::config::server::CorsConfig;
+
+enum AllowedOrigins {
+    None,
+    Any,
+    // lowercase, without a trailing slash
+    Listed(Vec<String>),
+}
+
+/// The configured CORS policy, validated and ready to answer requests from other origins with.
+pub struct Cors {
+    origins: AllowedOrigins,
+    methods: Vec<Method>,
+    headers: Vec<HeaderName>,
+    // the lists above, as they're sent in answer to preflight requests
+    allow_methods: HeaderValue,
+    allow_headers: HeaderValue,
+    expose_headers: Option<HeaderValue>,
+    allow_credentials: bool,
+    max_age: Option<HeaderValue>,
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+fn joined<T: AsRef<str>>(items: &[T]) -> io::Result<HeaderValue> {
+    let joined = items
+        .iter()
+        .map(AsRef::as_ref)
+        .collect::<Vec<_>>()
+        .join(", ");
+    HeaderValue::try_from(joined.as_str())
+        .map_err(|_| invalid(format!("invalid CORS header list '{joined}'")))
+}
+
+fn normalize_origin(origin: &str) -> String {
+    origin.trim_end_matches('/').to_ascii_lowercase()
+}
+
+impl Cors {
+    pub fn new(config: &CorsConfig) -> io::Result<Self> {
+        let origins = if config.allowed_origins.is_empty() {
+            AllowedOrigins::None
+        } else if config.allowed_origins.iter().any(|origin| origin == "*") {
+            AllowedOrigins::Any
+        } else {
+            AllowedOrigins::Listed(
+                config
+                    .allowed_origins
+                    .iter()
+                    .map(|origin| normalize_origin(origin))
+                    .collect(),
+            )
+        };
+        // browsers refuse credentialed responses allowed for any origin
+        if matches!(origins, AllowedOrigins::Any) && config.allow_credentials {
+            return Err(invalid(
+                "CORS can't allow credentials from any origin, list the allowed origins instead"
+                    .to_string(),
+            ));
+        }
+
+        let methods = config
+            .allowed_methods
+            .iter()
+            .map(|method| {
+                Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                    .map_err(|_| invalid(format!("invalid CORS method '{method}'")))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        let headers = config
+            .allowed_headers
+            .iter()
+            .map(|name| {
+                HeaderName::try_from(name.as_str())
+                    .map_err(|_| invalid(format!("invalid CORS header name '{name}'")))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        Ok(Cors {
+            origins,
+            allow_methods: joined(&methods)?,
+            allow_headers: joined(&headers)?,
+            methods,
+            headers,
+            expose_headers: match config.exposed_headers.is_empty() {
+                true => None,
+                false => Some(joined(&config.exposed_headers)?),
+            },
+            allow_credentials: config.allow_credentials,
+            max_age: (config.max_age_secs > 0).then(|| HeaderValue::from(config.max_age_secs)),
+        })
+    }
+
+    fn is_enabled(&self) -> bool {
+        !matches!(self.origins, AllowedOrigins::None)
+    }
+
+    /// What to send as `Access-Control-Allow-Origin` to a request from `origin`, if it's
+    /// allowed at all.
+    fn allowed_origin(&self, origin: Option<&HeaderValue>) -> Option<HeaderValue> {
+        match &self.origins {
+            AllowedOrigins::None => None,
+            AllowedOrigins::Any => Some(HeaderValue::from_static("*")),
+            AllowedOrigins::Listed(origins) => {
+                let origin = origin?;
+                let normalized = normalize_origin(origin.to_str().ok()?);
+                origins.contains(&normalized).then(|| origin.clone())
+            }
+        }
+    }
+
+    fn insert_origin_headers(&self, allowed: HeaderValue, headers: &mut HeaderMap) {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, allowed);
+        if self.allow_credentials {
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+        // answers differ by origin, so caches have to keep them apart
+        if matches!(self.origins, AllowedOrigins::Listed(_)) {
+            headers.append(header::VARY, HeaderValue::from_static("Origin"));
+        }
+    }
+
+    /// Lets the script that sent a request from `origin` read the response to it, if allowed.
+    fn apply(&self, origin: Option<&HeaderValue>, headers: &mut HeaderMap) {
+        match self.allowed_origin(origin) {
+            Some(allowed) => {
+                self.insert_origin_headers(allowed, headers);
+                if let Some(exposed) = &self.expose_headers {
+                    headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, exposed.clone());
+                }
+            }
+            None if matches!(self.origins, AllowedOrigins::Listed(_)) => {
+                headers.append(header::VARY, HeaderValue::from_static("Origin"));
+            }
+            None => {}
+        }
+    }
+
+    /// Answers the request a browser sends before one a script can't send without asking.
+    fn preflight(&self, req: &HttpRequest) -> HttpResponse {
+        let Some(allowed) = self.allowed_origin(req.headers().get(header::ORIGIN)) else {
+            return HttpResponse::Forbidden().body("Origin not allowed");
+        };
+
+        let method = req
+            .headers()
+            .get(header::ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|method| Method::from_bytes(method.as_bytes()).ok());
+        // these can always be sent, along with the headers that go with them
+        let is_simple =
+            |method: &Method| [Method::GET, Method::HEAD, Method::POST].contains(method);
+        if !method.is_some_and(|method| is_simple(&method) || self.methods.contains(&method)) {
+            return HttpResponse::Forbidden().body("Method not allowed");
+        }
+
+        let requested_headers = req
+            .headers()
+            .get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+            .and_then(|headers| headers.to_str().ok())
+            .unwrap_or_default();
+        let all_allowed = requested_headers
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .all(|name| {
+                self.headers
+                    .iter()
+                    .any(|allowed| allowed.as_str().eq_ignore_ascii_case(name))
+            });
+        if !all_allowed {
+            return HttpResponse::Forbidden().body("Headers not allowed");
+        }
+
+        let mut response = HttpResponse::NoContent().finish();
+        let headers = response.headers_mut();
+        self.insert_origin_headers(allowed, headers);
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_METHODS,
+            self.allow_methods.clone(),
+        );
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_HEADERS,
+            self.allow_headers.clone(),
+        );
+        if let Some(max_age) = &self.max_age {
+            headers.insert(header::ACCESS_CONTROL_MAX_AGE, max_age.clone());
+        }
+        response
+    }
+}
+
+/// Answers preflight requests, before they'd be turned away for having no credentials, and
+/// adds the CORS headers to everything else.
+pub async fn cors(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>> {
+    let cors = req
+        .app_data::<Data<Cors>>()
+        .expect("Cors is registered as app data")
+        .clone();
+    if !cors.is_enabled() {
+        return Ok(next.call(req).await?.map_into_left_body());
+    }
+
+    if req.method() == Method::OPTIONS
+        && req
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+    {
+        let response = cors.preflight(req.request());
+        return Ok(req.into_response(response.map_into_right_body()));
+    }
+
+    let origin = req.headers().get(header::ORIGIN).cloned();
+    let mut res = next.call(req).await?;
+    cors.apply(origin.as_ref(), res.headers_mut());
+    Ok(res.map_into_left_body())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        App,
+        http::StatusCode,
+        middleware, route,
+        test::{TestRequest, call_service, init_service},
+    };
+
+    use super::*;
+
+    #[route("/{path:.*}", method = "GET", method = "PUT")]
+    async fn ok() -> HttpResponse {
+        HttpResponse::Ok()
+            .insert_header((header::VARY, "Accept"))
+            .finish()
+    }
+
+    #[actix_web::test]
+    async fn only_listed_origins_are_let_in() {
+        let config = CorsConfig {
+            allowed_origins: vec!["https://app.example.com/".to_string()],
+            exposed_headers: vec!["ETag".to_string()],
+            allow_credentials: true,
+            ..Default::default()
+        };
+        let app = init_service(
+            App::new()
+                .app_data(Data::new(Cors::new(&config).unwrap()))
+                .service(ok)
+                .wrap(middleware::from_fn(cors)),
+        )
+        .await;
+        fn header<B>(res: &ServiceResponse<B>, name: HeaderName) -> String {
+            res.headers()
+                .get_all(name)
+                .map(|value| value.to_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        }
+
+        let req = TestRequest::get()
+            .uri("/a.txt")
+            .insert_header((header::ORIGIN, "https://APP.example.com"))
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(
+            header(&res, header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            "https://APP.example.com"
+        );
+        assert_eq!(
+            header(&res, header::ACCESS_CONTROL_ALLOW_CREDENTIALS),
+            "true"
+        );
+        assert_eq!(header(&res, header::ACCESS_CONTROL_EXPOSE_HEADERS), "ETag");
+        assert_eq!(header(&res, header::VARY), "Accept, Origin");
+
+        let req = TestRequest::get()
+            .uri("/a.txt")
+            .insert_header((header::ORIGIN, "https://evil.example.com"))
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(
+            !res.headers()
+                .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+        );
+
+        let preflight = |origin, method, headers| {
+            TestRequest::default()
+                .method(Method::OPTIONS)
+                .uri("/api/a.txt")
+                .insert_header((header::ORIGIN, origin))
+                .insert_header((header::ACCESS_CONTROL_REQUEST_METHOD, method))
+                .insert_header((header::ACCESS_CONTROL_REQUEST_HEADERS, headers))
+                .to_request()
+        };
+        let res = call_service(
+            &app,
+            preflight("https://app.example.com", "PUT", "authorization, range"),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            header(&res, header::ACCESS_CONTROL_ALLOW_METHODS),
+            "GET, HEAD, POST, PUT, DELETE"
+        );
+        assert_eq!(header(&res, header::ACCESS_CONTROL_MAX_AGE), "600");
+
+        for (origin, method, headers) in [
+            ("https://evil.example.com", "PUT", ""),
+            ("https://app.example.com", "PATCH", ""),
+            ("https://app.example.com", "PUT", "x-secret"),
+        ] {
+            let res = call_service(&app, preflight(origin, method, headers)).await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN, "for {origin} {method}");
+        }
+
+        // any origin can't be combined with credentials
+        assert!(
+            Cors::new(&CorsConfig {
+                allow_credentials: true,
+                ..Default::default()
+            })
+            .is_err()
+        );
+    }
+}
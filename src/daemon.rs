@@ -0,0 +1,26 @@
+use std::{env, fs, io, path::Path, process};
+
+use daemonize::Daemonize;
+use syslog::Formatter3164;
+
+use crate::handover;
+
+/// Forks into the background, this must happen before any runtime threads are spawned.
+pub fn daemonize(pid_file: &Path) -> io::Result<()> {
+    // a process taking over from a previous daemon is already detached, and the previous
+    // process still holds the lock on the pid file, so only the pid needs to be updated
+    if handover::is_successor() {
+        return fs::write(pid_file, process::id().to_string());
+    }
+
+    // make sure syslog is reachable while there's still a terminal to report the error to,
+    // since stdout and stderr are discarded once detached
+    syslog::unix(Formatter3164::default()).map_err(io::Error::other)?;
+
+    // keep the current directory, since config and files are resolved relative to it
+    Daemonize::new()
+        .pid_file(pid_file)
+        .working_directory(env::current_dir()?)
+        .start()
+        .map_err(io::Error::other)
+}
@@ -0,0 +1,437 @@
+use std::{
+    fs::{self, File},
+    io::{self, Cursor, Read, Write},
+    iter,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use actix_web::{mime::Mime, web};
+use flate2::{Compression, write::GzEncoder};
+use futures::{StreamExt, future};
+use image::{DynamicImage, ImageError, imageops::FilterType};
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::{
+    config::server::{DerivativesConfig, ImageFormat, TransformConfig},
+    file_store::{FsFile, StoredFile, StoredFileCore},
+};
+
+const DERIVATIVES_DIR_NAME: &str = "derivatives";
+
+pub type SharedDerivatives = Arc<Derivatives>;
+
+/// A derivative ready to be served from the cache.
+pub struct Derived {
+    pub file: FsFile,
+    pub etag: String,
+    /// Set when the transforms produce something other than the source's type
+    pub content_type: Option<Mime>,
+}
+
+/// Produces files derived from stored ones by running them through a preset chain of
+/// transforms, caching the results on disk by the source's hash.
+pub struct Derivatives {
+    cache_dir: PathBuf,
+    config: DerivativesConfig,
+}
+
+impl Derivatives {
+    pub fn new(data_dir: impl AsRef<Path>, config: &DerivativesConfig) -> io::Result<Self> {
+        for (name, chain) in &config.presets {
+            let resizes_to_nothing = chain.iter().any(|transform| {
+                matches!(transform, TransformConfig::Resize { width, height, .. }
+                    if *width == Some(0) || *height == Some(0))
+            });
+            if resizes_to_nothing {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("derivative preset '{name}' resizes images to a width or height of 0"),
+                ));
+            }
+        }
+
+        Ok(Derivatives {
+            cache_dir: data_dir.as_ref().join(DERIVATIVES_DIR_NAME),
+            config: config.clone(),
+        })
+    }
+
+    /// Gets the `preset` derivative of `source`, building it first if it isn't cached.
+    pub async fn get(&self, preset: &str, source: &StoredFile) -> io::Result<Derived> {
+        let chain = self.config.presets.get(preset).ok_or(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("no derivative preset named '{preset}'"),
+        ))?;
+        self.derive(chain, source).await
+    }
+
+    /// Gets `source` resized as asked for with `?w=`, `?h=` and `?format=`, building it first
+    /// if it isn't cached.
+    pub async fn resize(
+        &self,
+        width: Option<u32>,
+        height: Option<u32>,
+        format: Option<ImageFormat>,
+        source: &StoredFile,
+    ) -> io::Result<Derived> {
+        let max = self.config.max_image_dimension;
+        if max == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "resizing images is turned off",
+            ));
+        }
+        if [width, height]
+            .into_iter()
+            .flatten()
+            .any(|dimension| dimension == 0 || dimension > max)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("width and height have to be between 1 and {max}"),
+            ));
+        }
+        self.derive(
+            &[TransformConfig::Resize {
+                width,
+                height,
+                format,
+            }],
+            source,
+        )
+        .await
+    }
+
+    async fn derive(&self, chain: &[TransformConfig], source: &StoredFile) -> io::Result<Derived> {
+        let source_hash = &source.metadata().hash;
+        if source_hash.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "file has no recorded hash to key its derivatives by",
+            ));
+        }
+
+        // keyed by the chain itself rather than the preset name, so editing a preset
+        // doesn't keep serving what the old one produced
+        let mut digest = Sha256::new();
+        digest.update(source_hash.as_bytes());
+        digest.update(serde_json::to_vec(chain)?);
+        let key = format!("{:x}", digest.finalize());
+
+        let path = self.cache_dir.join(&key[..2]).join(&key);
+        if path.is_file() {
+            // refreshing the timestamp is what keeps a used derivative from being purged
+            let _ = File::options()
+                .write(true)
+                .open(&path)
+                .and_then(|f| f.set_modified(SystemTime::now()));
+        } else {
+            // the transforms are blocking, so the source is handed over to them chunk by chunk
+            let (sender, mut receiver) = mpsc::channel(4);
+            let input = IterReader::new(iter::from_fn(move || receiver.blocking_recv()));
+            let chain = chain.to_vec();
+            let dest = path.clone();
+
+            let mut source_stream = source.bytes_stream();
+            let feed = async move {
+                while let Some(chunk) = source_stream.next().await {
+                    // the build stopped early, it has its own error to report
+                    if sender.send(chunk.map(Vec::from)).await.is_err() {
+                        break;
+                    }
+                }
+            };
+
+            let (built, _) =
+                future::join(web::block(move || build(&chain, input, &dest)), feed).await;
+            built.map_err(io::Error::other)??;
+        }
+
+        Ok(Derived {
+            file: FsFile::open(&path).await?,
+            etag: key,
+            content_type: chain.iter().rev().find_map(output_type),
+        })
+    }
+
+    /// Removes cached derivatives that haven't been used within the configured max age.
+    pub fn purge_stale(&self) -> io::Result<usize> {
+        let max_age = Duration::from_secs(self.config.max_age_secs);
+        let now = SystemTime::now();
+        let mut purged = 0;
+
+        let Ok(shards) = fs::read_dir(&self.cache_dir) else {
+            return Ok(0);
+        };
+
+        for shard in shards {
+            for entry in fs::read_dir(shard?.path())? {
+                let entry = entry?;
+                let modified = entry.metadata()?.modified()?;
+
+                if now.duration_since(modified).unwrap_or_default() > max_age {
+                    fs::remove_file(entry.path())?;
+                    purged += 1;
+                }
+            }
+        }
+
+        Ok(purged)
+    }
+}
+
+/// Runs `input` through every transform in `chain`, leaving the result at `dest`.
+fn build(chain: &[TransformConfig], input: impl Read, dest: &Path) -> io::Result<()> {
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    // every step writes to a temp file the next one reads from, the last one is renamed
+    // into place so concurrent requests never see a partial derivative
+    let temp_paths: Vec<_> = chain
+        .iter()
+        .map(|_| dest.with_extension(format!("{}.tmp", Uuid::new_v4().simple())))
+        .collect();
+
+    let result = (|| {
+        let mut input: Box<dyn Read> = Box::new(input);
+        for (transform, temp_path) in chain.iter().zip(&temp_paths) {
+            let mut output = File::create(temp_path)?;
+            apply(transform, &mut input, &mut output)?;
+            output.sync_all()?;
+            input = Box::new(File::open(temp_path)?);
+        }
+
+        match temp_paths.last() {
+            Some(last) => fs::rename(last, dest),
+            // an empty chain derives the file itself
+            None => io::copy(&mut input, &mut File::create(dest)?).map(|_| ()),
+        }
+    })();
+
+    for temp_path in &temp_paths {
+        let _ = fs::remove_file(temp_path);
+    }
+
+    result
+}
+
+fn apply(
+    transform: &TransformConfig,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+) -> io::Result<()> {
+    match transform {
+        TransformConfig::Gzip { level } => {
+            let mut encoder = GzEncoder::new(output, Compression::new(*level));
+            io::copy(input, &mut encoder)?;
+            encoder.finish().map(|_| ())
+        }
+        TransformConfig::Zstd { level } => zstd::stream::copy_encode(input, output, *level),
+        TransformConfig::Resize {
+            width,
+            height,
+            format,
+        } => resize(input, output, *width, *height, *format),
+    }
+}
+
+fn resize(
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+    width: Option<u32>,
+    height: Option<u32>,
+    format: Option<ImageFormat>,
+) -> io::Result<()> {
+    // anything that isn't an image it can read is the request's fault, not the server's
+    let invalid = |err: ImageError| io::Error::new(io::ErrorKind::InvalidInput, err);
+
+    let mut bytes = Vec::new();
+    input.read_to_end(&mut bytes)?;
+    let source_format = image::guess_format(&bytes).map_err(invalid)?;
+    let mut image = image::load_from_memory_with_format(&bytes, source_format).map_err(invalid)?;
+
+    // only ever scaled down, the bounds left out being the image's own
+    let (max_width, max_height) = (
+        width.unwrap_or(u32::MAX).min(image.width()),
+        height.unwrap_or(u32::MAX).min(image.height()),
+    );
+    if max_width < image.width() || max_height < image.height() {
+        image = image.resize(max_width, max_height, FilterType::Triangle);
+    }
+
+    let format = format.map_or(source_format, image_format);
+    // JPEGs have no alpha channel to keep
+    if format == image::ImageFormat::Jpeg {
+        image = DynamicImage::ImageRgb8(image.to_rgb8());
+    }
+    let mut encoded = Cursor::new(Vec::new());
+    image.write_to(&mut encoded, format).map_err(invalid)?;
+    output.write_all(encoded.get_ref())
+}
+
+fn image_format(format: ImageFormat) -> image::ImageFormat {
+    match format {
+        ImageFormat::Png => image::ImageFormat::Png,
+        ImageFormat::Jpeg => image::ImageFormat::Jpeg,
+        ImageFormat::Webp => image::ImageFormat::WebP,
+        ImageFormat::Gif => image::ImageFormat::Gif,
+    }
+}
+
+fn output_type(transform: &TransformConfig) -> Option<Mime> {
+    let mime = match transform {
+        TransformConfig::Gzip { .. } => "application/gzip",
+        TransformConfig::Zstd { .. } => "application/zstd",
+        TransformConfig::Resize {
+            format: Some(format),
+            ..
+        } => image_format(*format).to_mime_type(),
+        // still the type the image was
+        TransformConfig::Resize { format: None, .. } => return None,
+    };
+
+    mime.parse().ok()
+}
+
+/// Adapts a stored file's chunks into a reader.
+struct IterReader<I> {
+    chunks: I,
+    current: Vec<u8>,
+    offset: usize,
+}
+
+impl<I> IterReader<I> {
+    fn new(chunks: I) -> Self {
+        IterReader {
+            chunks,
+            current: Vec::new(),
+            offset: 0,
+        }
+    }
+}
+
+impl<I: Iterator<Item = io::Result<Vec<u8>>>> Read for IterReader<I> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.offset >= self.current.len() {
+            match self.chunks.next() {
+                Some(chunk) => {
+                    self.current = chunk?;
+                    self.offset = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+
+        let len = buf.len().min(self.current.len() - self.offset);
+        buf[..len].copy_from_slice(&self.current[self.offset..self.offset + len]);
+        self.offset += len;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageReader, Rgba, RgbaImage};
+
+    use super::*;
+    use crate::fixtures::temp_dir;
+
+    #[test]
+    fn images_are_scaled_down_and_converted() {
+        let dir = temp_dir();
+        let mut png = Cursor::new(Vec::new());
+        RgbaImage::from_pixel(40, 20, Rgba([200, 100, 50, 128]))
+            .write_to(&mut png, image::ImageFormat::Png)
+            .unwrap();
+
+        let resized = |width, height, format| {
+            let dest = dir.join(Uuid::new_v4().simple().to_string());
+            let chain = [TransformConfig::Resize {
+                width,
+                height,
+                format,
+            }];
+            build(&chain, png.get_ref().as_slice(), &dest).unwrap();
+            let reader = ImageReader::open(&dest)
+                .unwrap()
+                .with_guessed_format()
+                .unwrap();
+            let format = reader.format().unwrap();
+            let image = reader.decode().unwrap();
+            (image.width(), image.height(), format)
+        };
+
+        assert_eq!(
+            resized(Some(10), None, Some(ImageFormat::Webp)),
+            (10, 5, image::ImageFormat::WebP)
+        );
+        assert_eq!(
+            resized(Some(30), Some(6), Some(ImageFormat::Jpeg)),
+            (12, 6, image::ImageFormat::Jpeg)
+        );
+        // never scaled up
+        assert_eq!(
+            resized(Some(400), None, None),
+            (40, 20, image::ImageFormat::Png)
+        );
+
+        let err = build(
+            &[TransformConfig::Resize {
+                width: Some(10),
+                height: None,
+                format: None,
+            }],
+            b"not an image".as_slice(),
+            &dir.join("text"),
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn presets_chain_resizing_and_compression() {
+        let dir = temp_dir();
+        let mut png = Cursor::new(Vec::new());
+        RgbaImage::from_pixel(40, 20, Rgba([200, 100, 50, 255]))
+            .write_to(&mut png, image::ImageFormat::Png)
+            .unwrap();
+
+        let chain: Vec<TransformConfig> = serde_json::from_value(serde_json::json!([
+            { "type": "resize", "width": 10, "format": "webp" },
+            { "type": "gzip" },
+        ]))
+        .unwrap();
+        let dest = dir.join("thumb");
+        build(&chain, png.get_ref().as_slice(), &dest).unwrap();
+        assert_eq!(
+            chain.iter().rev().find_map(output_type).unwrap(),
+            "application/gzip"
+        );
+
+        let mut webp = Vec::new();
+        flate2::read::GzDecoder::new(File::open(&dest).unwrap())
+            .read_to_end(&mut webp)
+            .unwrap();
+        let image = image::load_from_memory_with_format(&webp, image::ImageFormat::WebP).unwrap();
+        assert_eq!((image.width(), image.height()), (10, 5));
+
+        let config = DerivativesConfig {
+            presets: [(
+                "broken".to_string(),
+                vec![TransformConfig::Resize {
+                    width: Some(0),
+                    height: None,
+                    format: None,
+                }],
+            )]
+            .into(),
+            ..Default::default()
+        };
+        assert!(Derivatives::new(&dir, &config).is_err());
+    }
+}
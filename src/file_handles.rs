@@ -0,0 +1,168 @@
+use std::{
+    fs::File,
+    ops::Deref,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+};
+
+use serde::Serialize;
+
+use crate::config::server::FileHandlesConfig;
+
+/// The local files held open by downloads and the file caches, counted so that a spike in
+/// downloads is turned away before the process runs out of file descriptors, rather than
+/// failing everything from accepting connections to writing uploads once it has.
+pub struct FileHandles {
+    open: Arc<AtomicUsize>,
+    limit: usize,
+    process_limit: Option<u64>,
+    refused: AtomicU64,
+}
+
+/// How many files are held open against how many may be, for `GET /api/store/file_handles`.
+#[derive(Serialize, Debug)]
+pub struct FileHandlesUsage {
+    pub open: usize,
+    pub limit: usize,
+    /// The most files the process may have open, connections and databases included
+    pub process_limit: Option<u64>,
+    /// Downloads refused for being at the limit since the server started
+    pub refused: u64,
+}
+
+impl FileHandles {
+    pub fn new(config: &FileHandlesConfig) -> Self {
+        let process_limit = process_limit();
+        let limit = match (config.max_open, process_limit) {
+            (0, Some(process_limit)) => (process_limit / 4 * 3).try_into().unwrap_or(usize::MAX),
+            (0, None) => usize::MAX,
+            (max_open, _) => max_open.try_into().unwrap_or(usize::MAX),
+        };
+
+        FileHandles {
+            open: Arc::default(),
+            limit,
+            process_limit,
+            refused: AtomicU64::new(0),
+        }
+    }
+
+    /// Counts `file` as open for as long as it's around.
+    pub fn track(&self, file: File) -> OpenFile {
+        self.open.fetch_add(1, Ordering::Relaxed);
+        OpenFile {
+            file,
+            open: Some(Arc::clone(&self.open)),
+        }
+    }
+
+    /// Whether a new download would go over the limit, which counts it as refused if so.
+    pub fn refuses_more(&self) -> bool {
+        let refuses = self.open.load(Ordering::Relaxed) >= self.limit;
+        if refuses {
+            self.refused.fetch_add(1, Ordering::Relaxed);
+        }
+        refuses
+    }
+
+    pub fn usage(&self) -> FileHandlesUsage {
+        FileHandlesUsage {
+            open: self.open.load(Ordering::Relaxed),
+            limit: self.limit,
+            process_limit: self.process_limit,
+            refused: self.refused.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Default for FileHandles {
+    /// Counts the files without ever refusing any, for stores opened outside of a server.
+    fn default() -> Self {
+        FileHandles {
+            open: Arc::default(),
+            limit: usize::MAX,
+            process_limit: None,
+            refused: AtomicU64::new(0),
+        }
+    }
+}
+
+/// An open file, counted against the limit until it's closed.
+pub struct OpenFile {
+    file: File,
+    // files opened outside of any store aren't counted
+    open: Option<Arc<AtomicUsize>>,
+}
+
+impl From<File> for OpenFile {
+    fn from(file: File) -> Self {
+        OpenFile { file, open: None }
+    }
+}
+
+impl Deref for OpenFile {
+    type Target = File;
+
+    fn deref(&self) -> &File {
+        &self.file
+    }
+}
+
+impl Drop for OpenFile {
+    fn drop(&mut self) {
+        if let Some(open) = &self.open {
+            open.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// The soft limit on open files, which is the one that's enforced.
+#[cfg(unix)]
+fn process_limit() -> Option<u64> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: getrlimit only writes to the struct it's handed
+    let ret = unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) };
+    (ret == 0 && limit.rlim_cur != libc::RLIM_INFINITY).then_some(limit.rlim_cur)
+}
+
+#[cfg(windows)]
+fn process_limit() -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::temp_dir;
+
+    #[test]
+    fn files_count_until_closed() {
+        let handles = FileHandles::new(&FileHandlesConfig { max_open: 2 });
+        let dir = temp_dir();
+        let path = dir.join("file");
+        std::fs::write(&path, b"contents").unwrap();
+
+        let first = handles.track(File::open(&path).unwrap());
+        assert!(!handles.refuses_more());
+        let second = Arc::new(handles.track(File::open(&path).unwrap()));
+        assert!(handles.refuses_more());
+
+        drop(first);
+        assert!(!handles.refuses_more());
+        // held on to by a stream that's still going
+        let still_reading = Arc::clone(&second);
+        let third = handles.track(File::open(&path).unwrap());
+        drop(second);
+        assert!(handles.refuses_more());
+        drop(still_reading);
+        drop(third);
+
+        let usage = handles.usage();
+        assert_eq!((usage.open, usage.limit, usage.refused), (0, 2, 2));
+    }
+}
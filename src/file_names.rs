@@ -0,0 +1,91 @@
+use std::{
+    borrow::Cow,
+    path::{Component, Path, PathBuf},
+};
+
+use unicode_normalization::{UnicodeNormalization, is_nfc};
+
+/// A file or directory name as it's stored and looked up, so that the same name finds the
+/// same file whichever client sent it. Names are composed (NFC), since macOS hands out
+/// decomposed ones where everything else composes them, e.g. `ü` as `u` and a combining `¨`.
+pub fn normalize_name(name: &str) -> Cow<'_, str> {
+    if is_nfc(name) {
+        Cow::Borrowed(name)
+    } else {
+        Cow::Owned(name.nfc().collect())
+    }
+}
+
+/// `path` with every name in it normalized by [`normalize_name`].
+pub fn normalize_names(path: &Path) -> PathBuf {
+    path.components()
+        .map(|component| match component {
+            Component::Normal(name) => match name.to_str() {
+                Some(name) => normalize_name(name).into_owned().into(),
+                None => name.to_os_string(),
+            },
+            component => component.as_os_str().to_os_string(),
+        })
+        .collect()
+}
+
+/// Whether Windows can't keep a file under `name` as given: device names like `CON` or
+/// `nul.txt`, characters it doesn't allow, and trailing dots and spaces, which it drops,
+/// leaving a file under a different name than the one asked for.
+pub fn is_reserved_on_windows(name: &str) -> bool {
+    const DEVICES: [&str; 4] = ["CON", "PRN", "AUX", "NUL"];
+
+    let stem = name.split('.').next().unwrap_or_default().trim_end();
+    let is_device = DEVICES
+        .iter()
+        .any(|device| stem.eq_ignore_ascii_case(device))
+        || ["COM", "LPT"].iter().any(|prefix| {
+            stem.len() == 4
+                && stem[..3].eq_ignore_ascii_case(prefix)
+                && stem.as_bytes()[3].is_ascii_digit()
+        });
+    is_device
+        || name.ends_with(['.', ' '])
+        || name.contains(['<', '>', ':', '"', '|', '?', '*'])
+        || name.chars().any(|c| c.is_ascii_control())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn composes_decomposed_names() {
+        assert_eq!(
+            normalize_names(Path::new("/base/u\u{308}ber/a.txt")),
+            Path::new("/base/über/a.txt")
+        );
+    }
+
+    #[test]
+    fn knows_names_windows_cant_keep() {
+        for name in [
+            "CON",
+            "nul.txt",
+            "Com1.log",
+            "lpt9",
+            "aux .md",
+            "a.",
+            "a ",
+            "a:b",
+            "what?.txt",
+        ] {
+            assert!(is_reserved_on_windows(name), "{name}");
+        }
+        for name in [
+            "CONSOLE",
+            "com10",
+            "a.txt",
+            "nul-hypothesis.md",
+            ".hidden",
+            "ünï",
+        ] {
+            assert!(!is_reserved_on_windows(name), "{name}");
+        }
+    }
+}
@@ -1,81 +1,596 @@
+mod cas;
+mod dedup;
+mod encrypted;
+mod file_cache;
+mod memory;
+mod metadata_db;
+mod mounted;
+mod object;
+mod quota;
+mod remote;
+mod spellings;
+mod tiered;
+mod traced;
+mod versions;
+mod watch;
+mod webdav;
+
 use std::{
+    collections::HashMap,
     fs::{self, File},
-    io::{self, BufReader, Read, Write},
-    iter,
-    path::{Path, PathBuf},
+    io,
+    ops::AddAssign,
+    path::{Component, Path, PathBuf},
     sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
 };
 
+use actix_web::{
+    rt::task,
+    web::{Bytes, BytesMut},
+};
+use async_stream::try_stream;
+use chrono::{DateTime, Utc};
+use futures::{StreamExt, stream::LocalBoxStream};
 use path_clean::PathClean;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{
+    config::server::{FileSource, HashAlgorithm, MemoryCache, SymlinkPolicy, VersionsConfig},
+    file_handles::{FileHandles, OpenFile},
+    file_names::{is_reserved_on_windows, normalize_names},
+    file_store::{
+        dedup::{Claim, InFlightUploads, Leader, Sink},
+        file_cache::FileCache,
+        metadata_db::{MetadataDb, key_of, sidecar_target},
+        spellings::{Spellings, read_spellings},
+        versions::Versions,
+        watch::{OwnWrites, WatchedStore},
+    },
+    hooks::ScanRecord,
+};
 
-use crate::{cache_map::CacheMap, config::server::FileSource};
+pub use cas::{CasFileStore, CasStagedUpload};
+pub use encrypted::{EncryptedFile, EncryptedFileStore};
+pub use memory::{MemoryFile, MemoryFileStore, MemoryStagedUpload};
+pub use mounted::{MountedFileStore, MountedStagedUpload};
+pub use object::{ObjectFile, ObjectFileStore, ObjectStagedUpload};
+pub use quota::{QuotaFileStore, QuotaStagedUpload, Usage};
+pub use remote::{RemoteFile, RemoteFileStore};
+pub use tiered::{TieredFileStore, TieredStagedUpload};
+pub use traced::{StoreTracer, TracedFileStore};
+pub use versions::Version;
 
+/// Chunks of file contents, as they arrive from or are sent to a client.
+pub type ByteStream<'a> = LocalBoxStream<'a, io::Result<Bytes>>;
+
+/// Stores are consistent for readers within the server: once `commit_upload` or `remove`
+/// returns, every lookup sees the change, and a file that was looked up keeps streaming the
+/// contents its metadata describes, even if it's replaced while being read.
 pub trait FileStorageCore {
-    fn exists(&self, path: &Path) -> bool;
-    fn get_file(&self, path: &Path) -> Option<Arc<StoredFile>>;
-    fn upload(&self, path: &Path, reader: BufReader<File>) -> io::Result<()>;
-    fn remove(&self, path: &Path) -> io::Result<()>;
+    async fn exists(&self, path: &Path) -> bool;
+    async fn get_file(&self, path: &Path) -> Option<Arc<StoredFile>>;
+    /// Looks up whatever is at `path`, which is the root directory when empty.
+    async fn stat(&self, path: &Path) -> Option<Entry>;
+    /// Receives an upload without making it visible yet, dropping the returned
+    /// [`StagedUpload`] discards it again. `size_hint` is how large the client said it is,
+    /// which is only taken as a guess, if it said at all.
+    async fn stage_upload(
+        &self,
+        path: &Path,
+        stream: ByteStream<'_>,
+        size_hint: Option<u64>,
+    ) -> io::Result<StagedUpload<'_>>;
+    /// Makes a staged upload visible at its path, replacing whatever was there.
+    async fn commit_upload(&self, staged: StagedUpload<'_>) -> io::Result<FileMetadata>;
+    async fn remove(&self, path: &Path) -> io::Result<()>;
+    /// Lists the direct children of `dir`, which is the root when empty.
+    async fn list(&self, dir: &Path) -> io::Result<Vec<DirEntry>>;
 }
 
 pub enum FileStore {
     Filesystem(FsFileStore),
+    Object(ObjectFileStore),
+    Memory(MemoryFileStore),
+    ContentAddressed(CasFileStore),
+    Encrypted(EncryptedFileStore),
+    Tiered(TieredFileStore),
+    Mounted(MountedFileStore),
+    Remote(RemoteFileStore),
+    Traced(TracedFileStore),
+    Quota(QuotaFileStore),
 }
 
 impl FileStorageCore for FileStore {
-    fn exists(&self, path: &Path) -> bool {
+    async fn exists(&self, path: &Path) -> bool {
+        match self {
+            FileStore::Filesystem(fs_store) => fs_store.exists(path).await,
+            FileStore::Object(object_store) => object_store.exists(path).await,
+            FileStore::Memory(memory_store) => memory_store.exists(path).await,
+            FileStore::ContentAddressed(cas_store) => cas_store.exists(path).await,
+            FileStore::Encrypted(encrypted_store) => encrypted_store.exists(path).await,
+            FileStore::Tiered(tiered_store) => tiered_store.exists(path).await,
+            FileStore::Mounted(mounted_store) => mounted_store.exists(path).await,
+            FileStore::Remote(remote_store) => remote_store.exists(path).await,
+            FileStore::Traced(traced_store) => traced_store.exists(path).await,
+            FileStore::Quota(quota_store) => quota_store.exists(path).await,
+        }
+    }
+
+    async fn get_file(&self, path: &Path) -> Option<Arc<StoredFile>> {
+        match self {
+            FileStore::Filesystem(fs_store) => fs_store.get_file(path).await,
+            FileStore::Object(object_store) => object_store.get_file(path).await,
+            FileStore::Memory(memory_store) => memory_store.get_file(path).await,
+            FileStore::ContentAddressed(cas_store) => cas_store.get_file(path).await,
+            FileStore::Encrypted(encrypted_store) => encrypted_store.get_file(path).await,
+            FileStore::Tiered(tiered_store) => tiered_store.get_file(path).await,
+            FileStore::Mounted(mounted_store) => mounted_store.get_file(path).await,
+            FileStore::Remote(remote_store) => remote_store.get_file(path).await,
+            FileStore::Traced(traced_store) => traced_store.get_file(path).await,
+            FileStore::Quota(quota_store) => quota_store.get_file(path).await,
+        }
+    }
+
+    async fn stat(&self, path: &Path) -> Option<Entry> {
+        match self {
+            FileStore::Filesystem(fs_store) => fs_store.stat(path).await,
+            FileStore::Object(object_store) => object_store.stat(path).await,
+            FileStore::Memory(memory_store) => memory_store.stat(path).await,
+            FileStore::ContentAddressed(cas_store) => cas_store.stat(path).await,
+            FileStore::Encrypted(encrypted_store) => encrypted_store.stat(path).await,
+            FileStore::Tiered(tiered_store) => tiered_store.stat(path).await,
+            FileStore::Mounted(mounted_store) => mounted_store.stat(path).await,
+            FileStore::Remote(remote_store) => remote_store.stat(path).await,
+            FileStore::Traced(traced_store) => traced_store.stat(path).await,
+            FileStore::Quota(quota_store) => quota_store.stat(path).await,
+        }
+    }
+
+    async fn stage_upload(
+        &self,
+        path: &Path,
+        stream: ByteStream<'_>,
+        size_hint: Option<u64>,
+    ) -> io::Result<StagedUpload<'_>> {
         match self {
-            FileStore::Filesystem(fs_store) => fs_store.exists(path),
+            FileStore::Filesystem(fs_store) => fs_store.stage_upload(path, stream, size_hint).await,
+            FileStore::Object(object_store) => {
+                object_store.stage_upload(path, stream, size_hint).await
+            }
+            FileStore::Memory(memory_store) => {
+                memory_store.stage_upload(path, stream, size_hint).await
+            }
+            FileStore::ContentAddressed(cas_store) => {
+                cas_store.stage_upload(path, stream, size_hint).await
+            }
+            FileStore::Encrypted(encrypted_store) => {
+                encrypted_store.stage_upload(path, stream, size_hint).await
+            }
+            FileStore::Tiered(tiered_store) => {
+                tiered_store.stage_upload(path, stream, size_hint).await
+            }
+            FileStore::Mounted(mounted_store) => {
+                mounted_store.stage_upload(path, stream, size_hint).await
+            }
+            FileStore::Remote(remote_store) => {
+                remote_store.stage_upload(path, stream, size_hint).await
+            }
+            FileStore::Traced(traced_store) => {
+                traced_store.stage_upload(path, stream, size_hint).await
+            }
+            FileStore::Quota(quota_store) => {
+                quota_store.stage_upload(path, stream, size_hint).await
+            }
         }
     }
 
-    fn get_file(&self, path: &Path) -> Option<Arc<StoredFile>> {
+    async fn commit_upload(&self, staged: StagedUpload<'_>) -> io::Result<FileMetadata> {
         match self {
-            FileStore::Filesystem(fs_store) => fs_store.get_file(path),
+            FileStore::Filesystem(fs_store) => fs_store.commit_upload(staged).await,
+            FileStore::Object(object_store) => object_store.commit_upload(staged).await,
+            FileStore::Memory(memory_store) => memory_store.commit_upload(staged).await,
+            FileStore::ContentAddressed(cas_store) => cas_store.commit_upload(staged).await,
+            FileStore::Encrypted(encrypted_store) => encrypted_store.commit_upload(staged).await,
+            FileStore::Tiered(tiered_store) => tiered_store.commit_upload(staged).await,
+            FileStore::Mounted(mounted_store) => mounted_store.commit_upload(staged).await,
+            FileStore::Remote(remote_store) => remote_store.commit_upload(staged).await,
+            FileStore::Traced(traced_store) => traced_store.commit_upload(staged).await,
+            FileStore::Quota(quota_store) => quota_store.commit_upload(staged).await,
         }
     }
 
-    fn upload(&self, path: &Path, reader: BufReader<File>) -> io::Result<()> {
+    async fn remove(&self, path: &Path) -> io::Result<()> {
         match self {
-            FileStore::Filesystem(fs_store) => fs_store.upload(path, reader),
+            FileStore::Filesystem(fs_store) => fs_store.remove(path).await,
+            FileStore::Object(object_store) => object_store.remove(path).await,
+            FileStore::Memory(memory_store) => memory_store.remove(path).await,
+            FileStore::ContentAddressed(cas_store) => cas_store.remove(path).await,
+            FileStore::Encrypted(encrypted_store) => encrypted_store.remove(path).await,
+            FileStore::Tiered(tiered_store) => tiered_store.remove(path).await,
+            FileStore::Mounted(mounted_store) => mounted_store.remove(path).await,
+            FileStore::Remote(remote_store) => remote_store.remove(path).await,
+            FileStore::Traced(traced_store) => traced_store.remove(path).await,
+            FileStore::Quota(quota_store) => quota_store.remove(path).await,
         }
     }
 
-    fn remove(&self, path: &Path) -> io::Result<()> {
+    async fn list(&self, dir: &Path) -> io::Result<Vec<DirEntry>> {
         match self {
-            FileStore::Filesystem(fs_store) => fs_store.remove(path),
+            FileStore::Filesystem(fs_store) => fs_store.list(dir).await,
+            FileStore::Object(object_store) => object_store.list(dir).await,
+            FileStore::Memory(memory_store) => memory_store.list(dir).await,
+            FileStore::ContentAddressed(cas_store) => cas_store.list(dir).await,
+            FileStore::Encrypted(encrypted_store) => encrypted_store.list(dir).await,
+            FileStore::Tiered(tiered_store) => tiered_store.list(dir).await,
+            FileStore::Mounted(mounted_store) => mounted_store.list(dir).await,
+            FileStore::Remote(remote_store) => remote_store.list(dir).await,
+            FileStore::Traced(traced_store) => traced_store.list(dir).await,
+            FileStore::Quota(quota_store) => quota_store.list(dir).await,
         }
     }
 }
 
-impl From<&FileSource> for FileStore {
-    fn from(value: &FileSource) -> Self {
-        match value {
-            FileSource::Local { base_dir } => FileStore::Filesystem(FsFileStore::new(base_dir)),
+impl FileStore {
+    /// Frees cached entries that have expired but haven't been looked up since.
+    pub fn purge_expired_cache(&self) -> usize {
+        match self {
+            FileStore::Filesystem(fs_store) => fs_store.cache.remove_expired(),
+            FileStore::Object(object_store) => object_store.purge_expired_cache(),
+            FileStore::Memory(_) | FileStore::ContentAddressed(_) | FileStore::Remote(_) => 0,
+            FileStore::Encrypted(encrypted_store) => encrypted_store.purge_expired_cache(),
+            FileStore::Tiered(tiered_store) => tiered_store.purge_expired_cache(),
+            FileStore::Mounted(mounted_store) => mounted_store.purge_expired_cache(),
+            FileStore::Traced(traced_store) => traced_store.purge_expired_cache(),
+            FileStore::Quota(quota_store) => quota_store.purge_expired_cache(),
+        }
+    }
+
+    /// Retries writing the metadata of local files that were committed without it, returning
+    /// how many are still waiting on it.
+    pub async fn retry_pending_metadata(&self) -> usize {
+        match self {
+            FileStore::Filesystem(fs_store) => fs_store.retry_pending_metadata().await,
+            FileStore::Encrypted(encrypted_store) => encrypted_store.retry_pending_metadata().await,
+            FileStore::Tiered(tiered_store) => tiered_store.retry_pending_metadata().await,
+            FileStore::Mounted(mounted_store) => mounted_store.retry_pending_metadata().await,
+            FileStore::Traced(traced_store) => traced_store.retry_pending_metadata().await,
+            FileStore::Quota(quota_store) => quota_store.retry_pending_metadata().await,
+            FileStore::Object(_)
+            | FileStore::Memory(_)
+            | FileStore::ContentAddressed(_)
+            | FileStore::Remote(_) => 0,
+        }
+    }
+
+    /// Removes the metadata and cached lookups of local files deleted other than through the
+    /// server. There's nothing to collect for other sources.
+    pub async fn collect_garbage(&self) -> io::Result<GcReport> {
+        match self {
+            FileStore::Filesystem(fs_store) => fs_store.collect_garbage().await,
+            FileStore::Encrypted(encrypted_store) => encrypted_store.collect_garbage().await,
+            FileStore::Tiered(tiered_store) => tiered_store.collect_garbage().await,
+            FileStore::Mounted(mounted_store) => mounted_store.collect_garbage().await,
+            FileStore::Traced(traced_store) => traced_store.collect_garbage().await,
+            FileStore::Quota(quota_store) => quota_store.collect_garbage().await,
+            FileStore::Object(_)
+            | FileStore::Memory(_)
+            | FileStore::ContentAddressed(_)
+            | FileStore::Remote(_) => Ok(GcReport::default()),
+        }
+    }
+
+    /// Counts the uploads still being written to local files, removing those not written to
+    /// for `idle_timeout`, which were left behind by uploads that never finished. Other sources
+    /// don't keep partial uploads around.
+    pub async fn partial_uploads(
+        &self,
+        idle_timeout: Option<Duration>,
+    ) -> io::Result<PartialUploads> {
+        match self {
+            FileStore::Filesystem(fs_store) => fs_store.partial_uploads(idle_timeout).await,
+            FileStore::ContentAddressed(cas_store) => cas_store.partial_uploads(idle_timeout).await,
+            FileStore::Encrypted(encrypted_store) => {
+                encrypted_store.partial_uploads(idle_timeout).await
+            }
+            FileStore::Tiered(tiered_store) => tiered_store.partial_uploads(idle_timeout).await,
+            FileStore::Mounted(mounted_store) => mounted_store.partial_uploads(idle_timeout).await,
+            FileStore::Traced(traced_store) => traced_store.partial_uploads(idle_timeout).await,
+            FileStore::Quota(quota_store) => quota_store.partial_uploads(idle_timeout).await,
+            FileStore::Object(_) | FileStore::Memory(_) | FileStore::Remote(_) => {
+                Ok(PartialUploads::default())
+            }
+        }
+    }
+
+    /// How much is stored against the quota, unless there's none.
+    pub async fn usage(&self) -> Option<io::Result<Usage>> {
+        match self {
+            FileStore::Quota(quota_store) => Some(quota_store.usage().await),
+            _ => None,
+        }
+    }
+
+    /// The earlier versions of the file at `path`, oldest first. Only local sources keep any.
+    pub async fn versions(&self, path: &Path) -> io::Result<Vec<Version>> {
+        match self {
+            FileStore::Filesystem(fs_store) => fs_store.versions(path).await,
+            FileStore::Encrypted(encrypted_store) => encrypted_store.versions(path).await,
+            FileStore::Mounted(mounted_store) => mounted_store.versions(path).await,
+            FileStore::Traced(traced_store) => traced_store.versions(path).await,
+            FileStore::Quota(quota_store) => quota_store.versions(path).await,
+            _ => Err(versions_unsupported()),
+        }
+    }
+
+    /// Version `version` of the file at `path`, if it's still kept.
+    pub async fn get_version(
+        &self,
+        path: &Path,
+        version: u32,
+    ) -> io::Result<Option<Arc<StoredFile>>> {
+        match self {
+            FileStore::Filesystem(fs_store) => Ok(fs_store
+                .get_version(path, version)
+                .await?
+                .map(|file| Arc::new(StoredFile::from(file)))),
+            FileStore::Encrypted(encrypted_store) => {
+                encrypted_store.get_version(path, version).await
+            }
+            FileStore::Mounted(mounted_store) => mounted_store.get_version(path, version).await,
+            FileStore::Traced(traced_store) => traced_store.get_version(path, version).await,
+            FileStore::Quota(quota_store) => quota_store.get_version(path, version).await,
+            _ => Err(versions_unsupported()),
         }
     }
 }
 
+fn versions_unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "versions aren't kept for this source",
+    )
+}
+
+impl FileStore {
+    /// Opens the store for `source`, caching files as `cache` says unless the source brings
+    /// its own settings. The stores actually holding files are timed by `tracer`, and the
+    /// local files they open counted by `handles`.
+    pub fn open(
+        source: &FileSource,
+        cache: &MemoryCache,
+        hash_algorithm: HashAlgorithm,
+        tracer: &Arc<StoreTracer>,
+        handles: &Arc<FileHandles>,
+    ) -> io::Result<Self> {
+        let (store, backend) = match source {
+            FileSource::Local {
+                base_dir,
+                memory_cache,
+                metadata_db,
+                watch,
+                versions,
+                follow_symlinks,
+                mmap_min_bytes,
+            } => {
+                // made up front, so there's a base directory for the symlink policy to resolve
+                fs::create_dir_all(base_dir)?;
+                let mut store = FsFileStore::new(base_dir)
+                    .with_cache(memory_cache.as_ref().unwrap_or(cache))
+                    .with_hash_algorithm(hash_algorithm)
+                    .with_symlink_policy(*follow_symlinks)
+                    .with_mmap_min_bytes(*mmap_min_bytes)
+                    .with_file_handles(handles);
+                if let Some(min_bytes) = mmap_min_bytes {
+                    log::warn!(
+                        "Serving files of at least {min_bytes} bytes out of memory maps, a file \
+                        truncated in place while being served crashes the server"
+                    );
+                }
+                if let Some(metadata_db) = metadata_db {
+                    store = store.with_metadata_db(metadata_db)?;
+                }
+                if let Some(versions) = versions {
+                    store = store.with_versions(versions)?;
+                }
+                if *watch {
+                    store = store.watched()?;
+                }
+                (FileStore::Filesystem(store), format!("local:{base_dir}"))
+            }
+            FileSource::S3(config) => (
+                FileStore::Object(
+                    ObjectFileStore::s3(config)?
+                        .with_cache(config.memory_cache.as_ref().unwrap_or(cache))
+                        .with_hash_algorithm(hash_algorithm),
+                ),
+                format!("s3:{}", config.bucket),
+            ),
+            FileSource::AzureBlob(config) => (
+                FileStore::Object(
+                    ObjectFileStore::azure(config)?
+                        .with_cache(config.memory_cache.as_ref().unwrap_or(cache))
+                        .with_hash_algorithm(hash_algorithm),
+                ),
+                format!("azure_blob:{}", config.container),
+            ),
+            FileSource::Gcs(config) => (
+                FileStore::Object(
+                    ObjectFileStore::gcs(config)?
+                        .with_cache(config.memory_cache.as_ref().unwrap_or(cache))
+                        .with_hash_algorithm(hash_algorithm),
+                ),
+                format!("gcs:{}", config.bucket),
+            ),
+            FileSource::WebDav(config) => (
+                FileStore::Object(
+                    ObjectFileStore::webdav(config)?
+                        .with_cache(config.memory_cache.as_ref().unwrap_or(cache))
+                        .with_hash_algorithm(hash_algorithm),
+                ),
+                format!("webdav:{}", config.url),
+            ),
+            FileSource::Remote(config) => (
+                FileStore::Remote(RemoteFileStore::new(config)?),
+                format!("remote:{}", config.url),
+            ),
+            FileSource::Memory => (
+                FileStore::Memory(MemoryFileStore::default().with_hash_algorithm(hash_algorithm)),
+                "memory".to_string(),
+            ),
+            FileSource::ContentAddressed { base_dir } => (
+                FileStore::ContentAddressed(
+                    CasFileStore::open(base_dir)?.with_file_handles(handles),
+                ),
+                format!("content_addressed:{base_dir}"),
+            ),
+            // made up of other stores, which are traced on their own
+            FileSource::Tiered(config) => {
+                return Ok(FileStore::Tiered(TieredFileStore::new(
+                    config
+                        .tiers
+                        .iter()
+                        .map(|tier| FileStore::open(tier, cache, hash_algorithm, tracer, handles))
+                        .collect::<io::Result<_>>()?,
+                    config.promotion,
+                )?));
+            }
+            FileSource::Mounted { mounts } => {
+                return Ok(FileStore::Mounted(MountedFileStore::new(
+                    mounts
+                        .iter()
+                        .map(|mount| {
+                            let store = FileStore::open(
+                                &mount.source,
+                                cache,
+                                hash_algorithm,
+                                tracer,
+                                handles,
+                            )?;
+                            Ok((mount.prefix.clone(), store))
+                        })
+                        .collect::<io::Result<_>>()?,
+                )?));
+            }
+        };
+
+        Ok(FileStore::Traced(TracedFileStore::new(
+            store, backend, tracer,
+        )))
+    }
+}
+
 pub trait StoredFileCore {
     fn metadata(&self) -> &FileMetadata;
-    fn bytes_iter(&self) -> Box<dyn Iterator<Item = io::Result<Vec<u8>>> + 'static>;
+    /// Streams the contents, nothing is read until the stream is first polled.
+    fn bytes_stream(&self) -> ByteStream<'static>;
+    /// Streams `len` bytes of the contents from `start` on. Files that can't be read from part
+    /// way through read and drop everything before `start` instead.
+    fn range_stream(&self, start: u64, len: u64) -> ByteStream<'static> {
+        sliced(self.bytes_stream(), start, len)
+    }
+}
+
+/// The `len` bytes of `stream` from `start` on.
+fn sliced(mut stream: ByteStream<'static>, start: u64, len: u64) -> ByteStream<'static> {
+    try_stream! {
+        let (mut offset, end) = (0, start + len);
+        while offset < end
+            && let Some(chunk) = stream.next().await
+        {
+            let chunk: Bytes = chunk?;
+            let chunk_end = offset + chunk.len() as u64;
+            if chunk_end > start {
+                let from = start.saturating_sub(offset) as usize;
+                let to = (end.min(chunk_end) - offset) as usize;
+                yield chunk.slice(from..to);
+            }
+            offset = chunk_end;
+        }
+    }
+    .boxed_local()
 }
 
 pub enum StoredFile {
     Filesystem(FsFile),
+    Object(ObjectFile),
+    Memory(MemoryFile),
+    Encrypted(EncryptedFile),
+    Remote(RemoteFile),
 }
 
 impl StoredFileCore for StoredFile {
     fn metadata(&self) -> &FileMetadata {
         match self {
             StoredFile::Filesystem(fs_file) => fs_file.metadata(),
+            StoredFile::Object(object_file) => object_file.metadata(),
+            StoredFile::Memory(memory_file) => memory_file.metadata(),
+            StoredFile::Encrypted(encrypted_file) => encrypted_file.metadata(),
+            StoredFile::Remote(remote_file) => remote_file.metadata(),
+        }
+    }
+
+    fn bytes_stream(&self) -> ByteStream<'static> {
+        match self {
+            StoredFile::Filesystem(fs_file) => fs_file.bytes_stream(),
+            StoredFile::Object(object_file) => object_file.bytes_stream(),
+            StoredFile::Memory(memory_file) => memory_file.bytes_stream(),
+            StoredFile::Encrypted(encrypted_file) => encrypted_file.bytes_stream(),
+            StoredFile::Remote(remote_file) => remote_file.bytes_stream(),
+        }
+    }
+
+    fn range_stream(&self, start: u64, len: u64) -> ByteStream<'static> {
+        match self {
+            StoredFile::Filesystem(fs_file) => fs_file.range_stream(start, len),
+            StoredFile::Object(object_file) => object_file.range_stream(start, len),
+            StoredFile::Memory(memory_file) => memory_file.range_stream(start, len),
+            StoredFile::Encrypted(encrypted_file) => encrypted_file.range_stream(start, len),
+            StoredFile::Remote(remote_file) => remote_file.range_stream(start, len),
+        }
+    }
+}
+
+/// An upload that has been fully received but isn't visible yet.
+pub enum StagedUpload<'a> {
+    Filesystem(FsStagedUpload<'a>),
+    Object(ObjectStagedUpload),
+    Memory(MemoryStagedUpload),
+    ContentAddressed(CasStagedUpload),
+    Tiered(TieredStagedUpload<'a>),
+    Mounted(MountedStagedUpload<'a>),
+    Quota(QuotaStagedUpload<'a>),
+}
+
+impl StagedUpload<'_> {
+    pub fn metadata(&self) -> &FileMetadata {
+        match self {
+            StagedUpload::Filesystem(staged) => &staged.metadata,
+            StagedUpload::Object(staged) => &staged.metadata,
+            StagedUpload::Memory(staged) => &staged.metadata,
+            StagedUpload::ContentAddressed(staged) => &staged.metadata,
+            StagedUpload::Tiered(staged) => staged.inner.metadata(),
+            StagedUpload::Mounted(staged) => staged.inner.metadata(),
+            StagedUpload::Quota(staged) => staged.inner.metadata(),
         }
     }
 
-    fn bytes_iter(&self) -> Box<dyn Iterator<Item = io::Result<Vec<u8>>> + 'static> {
+    /// Keeps how the upload was scanned along with its metadata.
+    pub fn record_scan(&mut self, scan: Option<ScanRecord>) {
+        self.metadata_mut().scan = scan;
+    }
+
+    fn metadata_mut(&mut self) -> &mut FileMetadata {
         match self {
-            StoredFile::Filesystem(fs_file) => fs_file.bytes_iter(),
+            StagedUpload::Filesystem(staged) => &mut staged.metadata,
+            StagedUpload::Object(staged) => &mut staged.metadata,
+            StagedUpload::Memory(staged) => &mut staged.metadata,
+            StagedUpload::ContentAddressed(staged) => &mut staged.metadata,
+            StagedUpload::Tiered(staged) => staged.inner.metadata_mut(),
+            StagedUpload::Mounted(staged) => staged.inner.metadata_mut(),
+            StagedUpload::Quota(staged) => staged.inner.metadata_mut(),
         }
     }
 }
@@ -84,251 +599,2206 @@ impl StoredFileCore for StoredFile {
 pub struct FileMetadata {
     pub hash: String,
     pub size_bytes: u64,
+    /// What `hash` was worked out with, metadata from before it was recorded being `sha256`
+    #[serde(default)]
+    pub algorithm: HashAlgorithm,
+    /// When the file was first uploaded, kept when it's replaced, unknown for metadata from
+    /// before it was recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<Utc>>,
+    /// When what's there now was uploaded, which local files without it read from the file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified_at: Option<DateTime<Utc>>,
+    /// How much of the upload was scanned, unknown for ones no hook scanned
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scan: Option<ScanRecord>,
+    /// Only ever set on what a commit returns, when the file was committed but its metadata
+    /// couldn't be written yet and is kept in memory until the retries get it written
+    #[serde(skip)]
+    pub pending: bool,
+}
+
+pub enum Entry {
+    File(Arc<StoredFile>),
+    Dir,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryKind {
+    File,
+    Dir,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DirEntry {
+    pub name: String,
+    pub kind: EntryKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size_bytes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified_at: Option<DateTime<Utc>>,
 }
 
 impl FileMetadata {
     pub fn hash_to_hex(digest: Sha256) -> String {
         format!("{:x}", digest.finalize())
     }
-}
 
-// ------------------------
+    /// Keeps when the file was first uploaded from the metadata of the one it replaces.
+    pub fn replacing(&mut self, previous: Option<&FileMetadata>) {
+        if let Some(created_at) = previous.and_then(|previous| previous.created_at) {
+            self.created_at = Some(created_at);
+        }
+    }
+}
 
-pub struct FsFileStore {
-    base_path: PathBuf,
-    cache: Mutex<CacheMap<PathBuf, Arc<StoredFile>>>,
+/// Hashes a file as it's streamed through, with whichever algorithm it's set up for.
+pub enum Hasher {
+    Sha256(Sha256),
+    // the state of a blake3 hasher is large enough to be worth keeping off the stack
+    Blake3(Box<blake3::Hasher>),
 }
 
-impl FsFileStore {
-    pub fn new(base_path: impl AsRef<Path>) -> Self {
-        FsFileStore {
-            base_path: base_path.as_ref().to_path_buf(),
-            cache: Mutex::new(CacheMap::new()),
+impl Hasher {
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::default()),
         }
     }
 
-    fn full_path(&self, path: impl AsRef<Path>) -> Option<PathBuf> {
-        // makes use of path_clean crate to clean up any .. or . segments
-        // to prevent directory traversal attacks
-        let combined = self.base_path.join(path).clean();
-
-        // ensure the final cleaned path is still within base directory
-        if combined.starts_with(&self.base_path) && combined.file_name().is_some() {
-            Some(combined)
-        } else {
-            None
+    pub fn update(&mut self, bytes: &[u8]) {
+        match self {
+            Hasher::Sha256(digest) => digest.update(bytes),
+            Hasher::Blake3(hasher) => {
+                hasher.update(bytes);
+            }
         }
     }
 
-    fn is_valid_path(&self, path: impl AsRef<Path>) -> bool {
-        let path = path.as_ref();
-
-        let name = match path.file_name().and_then(|p| p.to_str()) {
-            Some(name) => name.to_ascii_lowercase(),
-            _ => return false,
+    /// The metadata of the `size_bytes` hashed, as if they were uploaded just now.
+    pub fn finish(self, size_bytes: u64) -> FileMetadata {
+        let (hash, algorithm) = match self {
+            Hasher::Sha256(digest) => (FileMetadata::hash_to_hex(digest), HashAlgorithm::Sha256),
+            Hasher::Blake3(hasher) => (
+                hasher.finalize().to_hex().to_string(),
+                HashAlgorithm::Blake3,
+            ),
         };
-
-        // this relies on the assumption that METADATA_FILE_EXT is all lowercase
-        if name.ends_with(METADATA_FILE_EXT) {
-            return false;
-        }
-
-        // get where the /api path would be, resulting in path conflicts
-        let api_path = self.full_path("api").unwrap();
-        if path.starts_with(api_path) {
-            return false;
+        let now = Utc::now();
+        FileMetadata {
+            hash,
+            size_bytes,
+            algorithm,
+            created_at: Some(now),
+            modified_at: Some(now),
+            scan: None,
+            pending: false,
         }
+    }
+}
 
-        true
+/// Hashes everything `stream` has left the same way `metadata` was, for comparing against it.
+pub async fn rehash(
+    mut stream: ByteStream<'_>,
+    metadata: &FileMetadata,
+) -> io::Result<FileMetadata> {
+    let mut hasher = BackgroundHasher::spawn(metadata.algorithm);
+    while let Some(chunk) = stream.next().await {
+        hasher.update(chunk?).await?;
     }
+    hasher.finish().await
 }
 
-impl FileStorageCore for FsFileStore {
-    fn exists(&self, path: &Path) -> bool {
-        self.full_path(path).is_some_and(|p| p.is_file())
+/// Passes `stream` through, failing it at the end if it didn't hash to what `metadata` says.
+/// By then everything else has been sent, but cutting the response short still keeps it from
+/// being taken as intact, or cached as such.
+pub fn verified<'a>(
+    mut stream: ByteStream<'a>,
+    path: &Path,
+    metadata: &FileMetadata,
+) -> ByteStream<'a> {
+    // not every source knows the hashes of its files
+    if metadata.hash.is_empty() {
+        return stream;
     }
 
-    fn get_file(&self, path: &Path) -> Option<Arc<StoredFile>> {
-        if !self.exists(path) {
-            return None;
+    let path = path.to_path_buf();
+    let expected = metadata.clone();
+    try_stream! {
+        // hashed off the worker thread, which only has to hand each chunk over
+        let mut hasher = BackgroundHasher::spawn(expected.algorithm);
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            hasher.update(chunk.clone()).await?;
+            yield chunk;
         }
 
-        let file_path = self.full_path(path)?;
-        let mut cache = self.cache.lock().unwrap();
-        if let Some(file) = cache.get(&file_path) {
-            return Some(file.clone());
+        let actual = hasher.finish().await?;
+        if actual.hash != expected.hash || actual.size_bytes != expected.size_bytes {
+            log::error!(
+                "'{}' is corrupted, it hashes to {} rather than {}",
+                path.display(),
+                actual.hash,
+                expected.hash
+            );
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("contents hash to {} rather than {}", actual.hash, expected.hash),
+            ))?;
         }
+    }
+    .boxed_local()
+}
 
-        let file = Arc::new(FsFile::new_existing(&file_path).into());
-        cache.insert(file_path.clone(), Arc::clone(&file));
-
-        Some(file)
+/// Reserves `size_bytes` on disk for a large upload into `file`, so it's laid out in one piece
+/// and one that won't fit fails before it's streamed rather than part way through. Failing
+/// for any other reason, e.g. the filesystem not supporting it, is left for the writes to find
+/// out about.
+#[cfg(target_os = "linux")]
+fn preallocate(file: &impl std::os::fd::AsRawFd, size_bytes: u64) -> io::Result<()> {
+    if size_bytes < PREALLOCATE_MIN_BYTES {
+        return Ok(());
     }
 
-    fn upload(&self, path: &Path, mut reader: BufReader<File>) -> io::Result<()> {
-        let path = self.full_path(path).ok_or(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "provided file path is in an invalid place",
-        ))?;
+    // the length is kept, so identical uploads following this one still see how far it got,
+    // and a client that declared more than it sent doesn't leave the file padded out
+    let len = libc::off_t::try_from(size_bytes).map_err(io::Error::other)?;
+    // SAFETY: the fd is borrowed from an open file for the length of the call
+    let ret = unsafe { libc::fallocate(file.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, 0, len) };
+    if ret != 0 && io::Error::last_os_error().kind() == io::ErrorKind::StorageFull {
+        return Err(io::Error::new(
+            io::ErrorKind::StorageFull,
+            format!("there isn't room for the {size_bytes} bytes of the upload"),
+        ));
+    }
+    Ok(())
+}
 
-        if !self.is_valid_path(&path) {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "cannot upload due to invalid file name or path",
-            ));
-        }
+#[cfg(not(target_os = "linux"))]
+fn preallocate<F>(_file: &F, _size_bytes: u64) -> io::Result<()> {
+    Ok(())
+}
 
-        // ensure parent directories exist, if any
-        if let Some(parent) = path.parent() {
-            fs::create_dir_all(parent)?;
-        }
+/// Hashes chunks in batches on the blocking pool, so hashing one batch overlaps with writing
+/// the next rather than taking turns with it. A thread is only borrowed for each batch, so a
+/// slow upload doesn't hold one for as long as it takes to arrive.
+struct BackgroundHasher {
+    hashing: Option<Hashing>,
+    batch: Vec<Bytes>,
+    batch_bytes: usize,
+    size_bytes: u64,
+}
 
-        let mut target_file = File::create(&path)?;
+enum Hashing {
+    Idle(Hasher),
+    Busy(task::JoinHandle<Hasher>),
+}
 
-        let mut digest = Sha256::new();
-        let mut buffer = [0u8; 8192];
-        let mut written_bytes: u64 = 0;
+impl BackgroundHasher {
+    fn spawn(algorithm: HashAlgorithm) -> Self {
+        BackgroundHasher {
+            hashing: Some(Hashing::Idle(Hasher::new(algorithm))),
+            batch: Vec::new(),
+            batch_bytes: 0,
+            size_bytes: 0,
+        }
+    }
 
-        loop {
-            let n = match reader.read(&mut buffer) {
-                Ok(0) => break,
-                Ok(n) => n,
-                Err(err) => {
-                    // attempt to clean up partial file on error
-                    let _ = fs::remove_file(&path);
-                    return Err(err);
-                }
-            };
+    /// Queues `chunk` to be hashed, waiting for the last batch if a new one is full before
+    /// that's been hashed.
+    async fn update(&mut self, chunk: Bytes) -> io::Result<()> {
+        self.size_bytes += chunk.len() as u64;
+        self.batch_bytes += chunk.len();
+        self.batch.push(chunk);
+        if self.batch_bytes >= HASH_BATCH_BYTES {
+            self.hash_batch().await?;
+        }
+        Ok(())
+    }
 
-            written_bytes += n as u64;
-            let bytes = &buffer[..n];
+    async fn finish(mut self) -> io::Result<FileMetadata> {
+        if !self.batch.is_empty() {
+            self.hash_batch().await?;
+        }
+        let hasher = self.hasher().await?;
+        Ok(hasher.finish(self.size_bytes))
+    }
 
-            if let Err(err) = target_file.write(bytes) {
-                let _ = fs::remove_file(&path);
-                return Err(err);
+    async fn hash_batch(&mut self) -> io::Result<()> {
+        let batch = std::mem::take(&mut self.batch);
+        self.batch_bytes = 0;
+        let mut hasher = self.hasher().await?;
+        self.hashing = Some(Hashing::Busy(task::spawn_blocking(move || {
+            for chunk in &batch {
+                hasher.update(chunk);
             }
+            hasher
+        })));
+        Ok(())
+    }
 
-            digest.update(bytes);
+    /// The hasher, once it's done with the batch it was given last.
+    async fn hasher(&mut self) -> io::Result<Hasher> {
+        match self.hashing.take() {
+            Some(Hashing::Idle(hasher)) => Ok(hasher),
+            Some(Hashing::Busy(hashing)) => hashing.await.map_err(io::Error::other),
+            None => Err(io::Error::other("hashing stopped before the upload ended")),
         }
+    }
+}
 
-        let hash = FileMetadata::hash_to_hex(digest);
-        let metadata = FileMetadata {
-            hash,
-            size_bytes: written_bytes,
-        };
-
-        let metadata_path = metadata_path(&path);
-        let metadata_file = File::create(&metadata_path)?;
-        serde_json::to_writer(metadata_file, &metadata)?;
+impl io::Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
 
+    fn flush(&mut self) -> io::Result<()> {
         Ok(())
     }
+}
 
-    fn remove(&self, path: &Path) -> io::Result<()> {
-        let path = self.full_path(path).ok_or(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "provided file path is in an invalid place",
-        ))?;
+/// Whether `name` belongs to a file the stores keep for themselves, which is never served.
+fn is_internal_name(name: &str) -> bool {
+    // this relies on the assumption that both extensions are all lowercase
+    let name = name.to_ascii_lowercase();
+    name.ends_with(METADATA_FILE_EXT) || name.ends_with(UPLOAD_FILE_EXT)
+}
 
-        if !self.is_valid_path(&path) {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "cannot remove due to invalid file name or path",
-            ));
-        }
+/// Whether `path` is kept out of reach, being either internal or shadowed by the API.
+fn is_hidden(path: &Path) -> bool {
+    let is_internal = path
+        .file_name()
+        .is_some_and(|name| name.to_str().is_none_or(is_internal_name));
 
-        if !self.exists(&path) {
-            return Ok(());
-        }
+    is_internal || path.components().next() == Some(Component::Normal("api".as_ref()))
+}
 
-        fs::remove_file(&path)?;
-        let metadata_path = metadata_path(&path);
-        if metadata_path.is_file() {
-            fs::remove_file(metadata_path)?;
-        }
+/// Turns a relative path into the key its file is kept under, which is empty for the root.
+/// For stores that don't map paths onto a directory of their own.
+fn relative_key(path: &Path) -> io::Result<PathBuf> {
+    path.components()
+        .filter(|component| *component != Component::CurDir)
+        .map(|component| match component {
+            Component::Normal(part) => Ok(part),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "provided file path is in an invalid place",
+            )),
+        })
+        .collect()
+}
 
-        Ok(())
+fn file_key(path: &Path) -> io::Result<PathBuf> {
+    let key = relative_key(path)?;
+    if key.file_name().is_none() || is_hidden(&key) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid file name or path",
+        ));
     }
-}
 
-pub const METADATA_FILE_EXT: &str = ".metadata.json";
+    Ok(key)
+}
 
-fn metadata_path(path: &PathBuf) -> PathBuf {
-    let mut os_str = path
-        .file_name()
-        .map(|s| s.to_os_string())
-        .unwrap_or_default();
+// ------------------------
 
-    os_str.push(METADATA_FILE_EXT);
-    path.with_file_name(os_str)
+pub struct FsFileStore {
+    base_path: PathBuf,
+    cache: Arc<FileCache<PathBuf>>,
+    hash_algorithm: HashAlgorithm,
+    // a commit swaps a file and its metadata one after the other, lookups wait it out
+    commit_lock: tokio::sync::RwLock<()>,
+    in_flight: InFlightUploads,
+    // sidecar files next to each file when unset
+    metadata_db: Option<Arc<MetadataDb>>,
+    own_writes: Arc<OwnWrites>,
+    // replaced and deleted files are gone for good when unset
+    versions: Option<Versions>,
+    // metadata of committed files that couldn't be written yet, looked up from here meanwhile
+    pending_metadata: Mutex<HashMap<PathBuf, FileMetadata>>,
+    // files without metadata being hashed, so lookups of the same one wait for it rather
+    // than hashing it again
+    generating: Mutex<HashMap<PathBuf, Arc<tokio::sync::Mutex<()>>>>,
+    // stops watching once dropped
+    watcher: Option<notify::RecommendedWatcher>,
+    // only kept while watched, directories are read for every missing name otherwise
+    spellings: Option<Arc<Spellings>>,
+    symlink_policy: SymlinkPolicy,
+    // the base directory with its own symlinks resolved, for the policy to compare against;
+    // unset when it couldn't be resolved
+    canonical_base: Option<PathBuf>,
+    // files are always read a chunk at a time when unset
+    mmap_min_bytes: Option<u64>,
+    handles: Arc<FileHandles>,
 }
 
-pub struct FsFile {
-    path: PathBuf,
-    metadata_path: PathBuf,
-    metadata: FileMetadata,
-}
+impl FsFileStore {
+    pub fn new(base_path: impl AsRef<Path>) -> Self {
+        FsFileStore {
+            base_path: base_path.as_ref().to_path_buf(),
+            cache: Arc::new(FileCache::new(&MemoryCache::default())),
+            hash_algorithm: HashAlgorithm::default(),
+            commit_lock: tokio::sync::RwLock::new(()),
+            in_flight: InFlightUploads::default(),
+            metadata_db: None,
+            own_writes: Arc::default(),
+            versions: None,
+            pending_metadata: Mutex::default(),
+            generating: Mutex::default(),
+            watcher: None,
+            spellings: None,
+            symlink_policy: SymlinkPolicy::default(),
+            canonical_base: None,
+            mmap_min_bytes: None,
+            handles: Arc::default(),
+        }
+        .with_symlink_policy(SymlinkPolicy::default())
+    }
 
-impl FsFile {
-    pub fn new_existing(file_path: impl AsRef<Path>) -> Self {
-        let path = file_path.as_ref().to_path_buf();
-        let metadata_path = metadata_path(&path);
+    pub fn with_cache(mut self, config: &MemoryCache) -> Self {
+        self.cache = Arc::new(FileCache::new(config));
+        self
+    }
 
-        let mut file = FsFile {
-            path,
-            metadata_path,
-            metadata: FileMetadata::default(),
-        };
+    pub fn with_hash_algorithm(mut self, algorithm: HashAlgorithm) -> Self {
+        self.hash_algorithm = algorithm;
+        self
+    }
 
-        file.metadata = file.read_metadata().unwrap_or_default();
-        file
+    /// Follows symlinks as `policy` says. The base directory is resolved here once, and if it
+    /// can't be, nothing in it can be got to while only symlinks within it are followed.
+    pub fn with_symlink_policy(mut self, policy: SymlinkPolicy) -> Self {
+        self.symlink_policy = policy;
+        self.canonical_base = self.base_path.canonicalize().ok();
+        if policy == SymlinkPolicy::WithinBase && self.canonical_base.is_none() {
+            log::warn!(
+                "'{}' can't be resolved, so none of the files in it will be served",
+                self.base_path.display()
+            );
+        }
+        self
     }
 
-    fn read_metadata(&self) -> Result<FileMetadata, io::Error> {
-        let metadata_file = File::open(&self.metadata_path)?;
-        let metadata = serde_json::from_reader(metadata_file)?;
-        Ok(metadata)
+    pub fn with_mmap_min_bytes(mut self, min_bytes: Option<u64>) -> Self {
+        self.mmap_min_bytes = min_bytes;
+        self
     }
-}
 
-impl From<FsFile> for StoredFile {
-    fn from(value: FsFile) -> Self {
-        StoredFile::Filesystem(value)
+    pub fn with_file_handles(mut self, handles: &Arc<FileHandles>) -> Self {
+        self.handles = Arc::clone(handles);
+        self
     }
-}
 
-impl StoredFileCore for FsFile {
-    fn metadata(&self) -> &FileMetadata {
-        &self.metadata
+    /// Keeps the metadata in the database at `path`, moving any sidecars already there into it.
+    pub fn with_metadata_db(mut self, path: impl AsRef<Path>) -> io::Result<Self> {
+        let db = MetadataDb::open(path)?;
+        let imported = db.import_sidecars(&self.base_path)?;
+        if imported > 0 {
+            log::info!(
+                "Moved the metadata of {imported} files in '{}' into the database",
+                self.base_path.display()
+            );
+        }
+
+        self.metadata_db = Some(Arc::new(db));
+        Ok(self)
     }
 
-    fn bytes_iter(&self) -> Box<dyn Iterator<Item = io::Result<Vec<u8>>> + 'static> {
-        let file = File::open(&self.path).unwrap();
+    /// Keeps what files were before they're replaced or deleted, as described by `config`.
+    pub fn with_versions(mut self, config: &VersionsConfig) -> io::Result<Self> {
+        self.versions = Some(Versions::open(config)?);
+        Ok(self)
+    }
 
-        let mut reader = BufReader::new(file);
-        let mut buffer = [0; 8192];
-        let mut is_failed = false;
+    /// Watches the directory for changes made to it other than through the store, so files
+    /// edited or replaced by hand aren't served from the cache or with stale metadata.
+    pub fn watched(mut self) -> io::Result<Self> {
+        let spellings = Arc::new(Spellings::default());
+        let watched = WatchedStore {
+            base_path: self.base_path.clone(),
+            cache: Arc::clone(&self.cache),
+            metadata_db: self.metadata_db.clone(),
+            own_writes: Arc::clone(&self.own_writes),
+            spellings: Arc::clone(&spellings),
+        };
+        self.watcher = Some(watched.watch()?);
+        self.spellings = Some(spellings);
+        Ok(self)
+    }
 
-        Box::new(iter::from_fn(move || {
-            // stop iteration on failure, so we don't keep trying to read a broken stream
-            if is_failed {
-                return None;
-            }
+    /// Where `path` is in the base directory going by its name alone, unless that's outside it.
+    fn in_base(&self, path: impl AsRef<Path>) -> Option<PathBuf> {
+        // the same name is the same file however it was spelled, which on Windows only holds
+        // for names it keeps as given
+        let path = normalize_names(path.as_ref());
+        if cfg!(windows)
+            && path
+                .iter()
+                .any(|name| is_reserved_on_windows(&name.to_string_lossy()))
+        {
+            return None;
+        }
 
-            let bytes_read = match reader.read(&mut buffer) {
-                Ok(0) => return None, // EOF
-                Ok(n) => n,
-                Err(_) => {
-                    // mark as failed, so we don't keep trying but so we can return an error once
-                    is_failed = true;
-                    return Some(Err(io::Error::new(
-                        io::ErrorKind::Other,
-                        "Failed to read file",
-                    )));
-                }
-            };
+        // makes use of path_clean crate to clean up any .. or . segments
+        // to prevent directory traversal attacks
+        let combined = self.base_path.join(path).clean();
+
+        // ensure the final cleaned path is still within base directory
+        if combined.starts_with(&self.base_path) && combined.file_name().is_some() {
+            Some(combined)
+        } else {
+            None
+        }
+    }
+
+    /// Like `in_base`, spelled the way it's kept on disk, as long as no symlink on the way
+    /// takes it somewhere it shouldn't go.
+    async fn full_path(&self, path: impl AsRef<Path>) -> Option<PathBuf> {
+        let combined = self.in_base(path)?;
+        let combined = self.spelled_on_disk(combined).await;
+        self.follows_symlinks(&combined).await.then_some(combined)
+    }
+
+    /// `path`, normalized and within the base directory, with the names of anything missing
+    /// under them swapped for one on disk that normalizes to the same, so files kept under
+    /// decomposed names from before names were composed can still be found and replaced.
+    async fn spelled_on_disk(&self, path: PathBuf) -> PathBuf {
+        let Ok(relative) = path.strip_prefix(&self.base_path) else {
+            return path;
+        };
+        // only names beyond ASCII can be spelled more than one way
+        if relative.to_str().is_some_and(str::is_ascii) {
+            return path;
+        }
+
+        let base_path = self.base_path.clone();
+        let relative = relative.to_path_buf();
+        let spellings = self.spellings.clone();
+        task::spawn_blocking(move || {
+            let mut spelled = base_path;
+            for name in relative.iter() {
+                let as_given = spelled.join(name);
+                let on_disk = match name.to_str() {
+                    Some(name) if !name.is_ascii() && fs::symlink_metadata(&as_given).is_err() => {
+                        match &spellings {
+                            Some(spellings) => spellings.find(&spelled, name),
+                            None => read_spellings(&spelled).remove(name),
+                        }
+                    }
+                    _ => None,
+                };
+                match on_disk {
+                    Some(on_disk) => spelled.push(on_disk),
+                    None => spelled = as_given,
+                }
+            }
+            spelled
+        })
+        .await
+        .unwrap_or(path)
+    }
+
+    /// Whether the symlink policy allows getting to `path`, which is already cleaned and
+    /// within the base directory. Parts of it that don't exist yet, e.g. for an upload, are
+    /// judged by the part that does, and anything that can't be looked at isn't allowed.
+    async fn follows_symlinks(&self, path: &Path) -> bool {
+        let policy = self.symlink_policy;
+        if policy == SymlinkPolicy::Always {
+            return true;
+        }
+
+        let base_path = self.base_path.clone();
+        let canonical_base = self.canonical_base.clone();
+        let path = path.to_path_buf();
+        // every directory on the way is looked at on disk
+        task::spawn_blocking(move || match policy {
+            SymlinkPolicy::Always => true,
+            SymlinkPolicy::Never => path
+                .ancestors()
+                .take_while(|ancestor| *ancestor != base_path)
+                .all(|ancestor| match fs::symlink_metadata(ancestor) {
+                    Ok(metadata) => !metadata.is_symlink(),
+                    Err(err) => err.kind() == io::ErrorKind::NotFound,
+                }),
+            SymlinkPolicy::WithinBase => {
+                let Some(canonical_base) = canonical_base else {
+                    return false;
+                };
+                // a dangling symlink doesn't resolve either, but renaming over it to upload
+                // replaces the link rather than writing to where it points
+                for ancestor in path.ancestors() {
+                    match ancestor.canonicalize() {
+                        Ok(resolved) => return resolved.starts_with(canonical_base),
+                        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                        Err(_) => return false,
+                    }
+                }
+                false
+            }
+        })
+        .await
+        .unwrap_or(false)
+    }
+
+    fn is_valid_path(&self, path: impl AsRef<Path>) -> bool {
+        let path = path.as_ref();
+
+        match path.file_name().and_then(|p| p.to_str()) {
+            Some(name) if !is_internal_name(name) => {}
+            _ => return false,
+        }
+
+        // get where the /api path would be, resulting in path conflicts
+        let api_path = self.in_base("api").unwrap();
+        if path.starts_with(api_path) {
+            return false;
+        }
+
+        true
+    }
+
+    /// Like `full_path`, except the base directory itself is fine to resolve to.
+    async fn resolve_dir(&self, path: &Path) -> io::Result<PathBuf> {
+        let resolved = self.base_path.join(path).clean();
+        if resolved.starts_with(&self.base_path) && self.follows_symlinks(&resolved).await {
+            Ok(resolved)
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "provided directory path is in an invalid place",
+            ))
+        }
+    }
+
+    /// The metadata of the file at `file_path`, unless it has none or it no longer matches
+    /// the file.
+    async fn read_metadata(&self, file_path: &Path) -> Option<FileMetadata> {
+        let pending = self
+            .pending_metadata
+            .lock()
+            .unwrap()
+            .get(file_path)
+            .cloned();
+        let metadata = match (pending, &self.metadata_db) {
+            (Some(pending), _) => pending,
+            (None, Some(db)) => db.get(&key_of(&self.base_path, file_path)).ok()??,
+            (None, None) => read_metadata(&metadata_path(file_path)).await.ok()?,
+        };
+
+        let times = tokio::fs::metadata(file_path).await.ok()?;
+        let mut metadata = (metadata.size_bytes == times.len() && !metadata.hash.is_empty())
+            .then_some(metadata)?;
+        // from before it was recorded, when the file itself is the best there is to go by
+        if metadata.modified_at.is_none() {
+            metadata.modified_at = times.modified().ok().map(DateTime::from);
+        }
+        Some(metadata)
+    }
+
+    /// Hashes the file at `file_path` and keeps the result, as if it had been uploaded, unless
+    /// a lookup of it is already doing so, in which case that one's result is waited for.
+    async fn generate_metadata_once(&self, file_path: &Path) -> io::Result<FileMetadata> {
+        let generating = Arc::clone(
+            self.generating
+                .lock()
+                .unwrap()
+                .entry(file_path.to_path_buf())
+                .or_default(),
+        );
+
+        let result = {
+            let _generating = generating.lock().await;
+            // whoever had it before may have just kept what it's looked up by
+            let kept = {
+                let _guard = self.commit_lock.read().await;
+                self.read_metadata(file_path).await
+            };
+            match kept {
+                Some(metadata) => Ok(metadata),
+                None => self.generate_metadata(file_path).await,
+            }
+        };
+
+        // the last lookup waiting on it cleans up, the map and this one hold the only others
+        let mut all_generating = self.generating.lock().unwrap();
+        if Arc::strong_count(&generating) == 2 {
+            all_generating.remove(file_path);
+        }
+        result
+    }
+
+    /// Hashes the file at `file_path` and keeps the result, as if it had been uploaded. Large
+    /// files take a while, so commits aren't held up by it, and the result is only kept if
+    /// the file is still the one that was hashed once it's done.
+    async fn generate_metadata(&self, file_path: &Path) -> io::Result<FileMetadata> {
+        let (metadata, hashed) = hash_file(file_path, self.hash_algorithm).await?;
+
+        let _guard = self.commit_lock.read().await;
+        let now = tokio::fs::metadata(file_path).await?;
+        if (now.len(), now.modified().ok()) != hashed {
+            return Err(io::Error::other("file changed while it was being hashed"));
+        }
+        self.keep_generated_metadata(file_path, &metadata).await?;
+
+        Ok(metadata)
+    }
+
+    async fn keep_generated_metadata(
+        &self,
+        file_path: &Path,
+        metadata: &FileMetadata,
+    ) -> io::Result<()> {
+        match &self.metadata_db {
+            Some(db) => db.set(&key_of(&self.base_path, file_path), metadata)?,
+            None => {
+                let contents = serde_json::to_vec(metadata)?;
+                tokio::fs::write(metadata_path(file_path), contents).await?;
+            }
+        }
+
+        log::info!("Generated metadata for '{}'", file_path.display());
+        Ok(())
+    }
+
+    /// Puts the metadata of a committed upload where it's looked up, which for sidecars means
+    /// moving the one written ahead of the commit into place.
+    async fn write_metadata(&self, staged: &FsStagedUpload<'_>) -> io::Result<()> {
+        match &self.metadata_db {
+            Some(db) => db.set(&key_of(&self.base_path, &staged.path), &staged.metadata),
+            None => {
+                let sidecar = metadata_path(&staged.temp_path);
+                tokio::fs::rename(&sidecar, metadata_path(&staged.path)).await
+            }
+        }
+    }
+
+    /// Writes the metadata of files committed while it couldn't be written, returning how many
+    /// are still waiting on it.
+    pub async fn retry_pending_metadata(&self) -> usize {
+        let pending: Vec<_> = self
+            .pending_metadata
+            .lock()
+            .unwrap()
+            .clone()
+            .into_iter()
+            .collect();
+
+        for (file_path, metadata) in pending {
+            let _guard = self.commit_lock.write().await;
+            // replaced or removed since
+            let is_pending = |pending: &HashMap<PathBuf, FileMetadata>| {
+                pending
+                    .get(&file_path)
+                    .is_some_and(|p| p.hash == metadata.hash)
+            };
+            if !is_pending(&self.pending_metadata.lock().unwrap()) {
+                continue;
+            }
+
+            let written = match &self.metadata_db {
+                Some(db) => {
+                    let (db, key) = (Arc::clone(db), key_of(&self.base_path, &file_path));
+                    let metadata = metadata.clone();
+                    task::spawn_blocking(move || db.set(&key, &metadata))
+                        .await
+                        .unwrap_or_else(|err| Err(io::Error::other(err)))
+                }
+                None => match serde_json::to_vec(&metadata) {
+                    Ok(contents) => tokio::fs::write(metadata_path(&file_path), contents).await,
+                    Err(err) => Err(err.into()),
+                },
+            };
+            match written {
+                Ok(()) => {
+                    // nothing else changes it while the commit lock is held
+                    self.pending_metadata.lock().unwrap().remove(&file_path);
+                    log::info!("Wrote the pending metadata of '{}'", file_path.display());
+                }
+                Err(err) => log::debug!(
+                    "Still can't write the metadata of '{}': {err}",
+                    file_path.display()
+                ),
+            }
+        }
+
+        self.pending_metadata.lock().unwrap().len()
+    }
+
+    /// Removes the metadata left behind by files that are gone, and drops the cached lookups
+    /// of them. Only files deleted other than through the server leave any, which watching
+    /// the directory catches most of as they happen.
+    pub async fn collect_garbage(&self) -> io::Result<GcReport> {
+        // found without holding the commit lock, each is checked again under it
+        let base_path = self.base_path.clone();
+        let metadata_db = self.metadata_db.clone();
+        let (sidecars, keys) = task::spawn_blocking(move || {
+            let keys = match &metadata_db {
+                Some(db) => db.keys()?,
+                None => Vec::new(),
+            };
+            Ok::<_, io::Error>((find_sidecars(&base_path)?, keys))
+        })
+        .await
+        .map_err(io::Error::other)??;
+
+        let mut report = GcReport::default();
+        for sidecar in sidecars {
+            let Some(file_path) = sidecar_target(&sidecar) else {
+                continue;
+            };
+            let _guard = self.commit_lock.write().await;
+            // an upload's own sidecar is only without its file while the commit holds the lock
+            if is_file(&file_path).await {
+                continue;
+            }
+            match tokio::fs::remove_file(&sidecar).await {
+                Ok(()) => report.metadata_removed += 1,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        if let Some(db) = &self.metadata_db {
+            for key in keys {
+                let _guard = self.commit_lock.write().await;
+                if !is_file(&self.base_path.join(&key)).await {
+                    db.remove(&key)?;
+                    report.metadata_removed += 1;
+                }
+            }
+        }
+
+        // retrying these would only write new sidecars for files that aren't there
+        let pending: Vec<_> = self
+            .pending_metadata
+            .lock()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect();
+        for file_path in pending {
+            let _guard = self.commit_lock.write().await;
+            if !is_file(&file_path).await {
+                self.pending_metadata.lock().unwrap().remove(&file_path);
+                report.metadata_removed += 1;
+            }
+        }
+
+        let cache = Arc::clone(&self.cache);
+        report.cache_entries_removed =
+            task::spawn_blocking(move || cache.retain(|file_path| file_path.is_file()))
+                .await
+                .map_err(io::Error::other)?;
+
+        Ok(report)
+    }
+
+    pub async fn partial_uploads(
+        &self,
+        idle_timeout: Option<Duration>,
+    ) -> io::Result<PartialUploads> {
+        let base_path = self.base_path.clone();
+        task::spawn_blocking(move || sweep_partial_uploads(&base_path, true, idle_timeout))
+            .await
+            .map_err(io::Error::other)?
+    }
+
+    /// Moves the file at `file_path` out of the way as its next version, if versions are kept
+    /// and there's a file there. Must be called while holding the commit lock.
+    async fn keep_version(&self, file_path: &Path) -> io::Result<()> {
+        let Some(versions) = &self.versions else {
+            return Ok(());
+        };
+        if !is_file(file_path).await {
+            return Ok(());
+        }
+
+        let metadata = match self.read_metadata(file_path).await {
+            Some(metadata) => metadata,
+            // the commit lock is already held, nothing can change the file meanwhile
+            None => {
+                let (metadata, _) = hash_file(file_path, self.hash_algorithm).await?;
+                self.keep_generated_metadata(file_path, &metadata).await?;
+                metadata
+            }
+        };
+        let key = key_of(&self.base_path, file_path);
+        let version = versions.keep(&key, file_path, metadata).await?;
+        log::debug!("Kept '{key}' as version {}", version.version);
+        Ok(())
+    }
+
+    /// The earlier versions of the file at `path`, oldest first.
+    pub async fn versions(&self, path: &Path) -> io::Result<Vec<Version>> {
+        let (versions, key) = self.versions_of(path).await?;
+        versions.list(&key).await
+    }
+
+    /// Version `version` of the file at `path`, if it's still kept.
+    pub async fn get_version(&self, path: &Path, version: u32) -> io::Result<Option<FsFile>> {
+        let (versions, key) = self.versions_of(path).await?;
+        versions.get(&key, version).await
+    }
+
+    async fn versions_of(&self, path: &Path) -> io::Result<(&Versions, String)> {
+        let versions = self.versions.as_ref().ok_or_else(versions_unsupported)?;
+        let full_path = self
+            .full_path(path)
+            .await
+            .filter(|full_path| self.is_valid_path(full_path))
+            .ok_or(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "provided file path is in an invalid place",
+            ))?;
+        Ok((versions, key_of(&self.base_path, &full_path)))
+    }
+
+    async fn write_upload(
+        &self,
+        temp_path: &Path,
+        stream: ByteStream<'_>,
+        size_hint: Option<u64>,
+    ) -> io::Result<(FileMetadata, Option<Leader<'_>>)> {
+        // the prefix might already have reached the end, which some streams don't like polled twice
+        let mut stream = stream.fuse();
+
+        // hold back the start of the upload, it's what identical uploads in flight are matched by
+        let mut prefix = Vec::new();
+        while prefix.len() < dedup::PREFIX_LEN {
+            match stream.next().await {
+                Some(chunk) => prefix.extend_from_slice(&chunk?),
+                None => break,
+            }
+        }
+
+        let claim = if prefix.len() >= dedup::PREFIX_LEN {
+            self.in_flight.claim(&prefix, temp_path)
+        } else {
+            Claim::Alone
+        };
+
+        let (leader, mut sink) = match claim {
+            Claim::Leader(leader) => (
+                Some(leader),
+                Sink::File(tokio::fs::File::create(temp_path).await?),
+            ),
+            Claim::Follower(follower) => (
+                None,
+                Sink::Shadow {
+                    follower,
+                    matched: 0,
+                },
+            ),
+            Claim::Alone => (None, Sink::File(tokio::fs::File::create(temp_path).await?)),
+        };
+        if let (Sink::File(file), Some(size_bytes)) = (&sink, size_hint) {
+            preallocate(file, size_bytes)?;
+        }
+
+        let mut hasher = BackgroundHasher::spawn(self.hash_algorithm);
+        let mut next_chunk = Some(Bytes::from(prefix));
+
+        loop {
+            let bytes = match next_chunk.take() {
+                Some(bytes) => bytes,
+                None => match stream.next().await {
+                    Some(chunk) => chunk?,
+                    None => break,
+                },
+            };
+
+            // handed off first, so it's hashed while it's being written
+            hasher.update(bytes.clone()).await?;
+            sink.write(&bytes, temp_path).await?;
+        }
+
+        let metadata = hasher.finish().await?;
+
+        sink.finish(&metadata, temp_path).await?;
+        Ok((metadata, leader))
+    }
+}
+
+impl FileStorageCore for FsFileStore {
+    async fn exists(&self, path: &Path) -> bool {
+        match self.full_path(path).await {
+            Some(path) => is_file(&path).await,
+            None => false,
+        }
+    }
+
+    async fn get_file(&self, path: &Path) -> Option<Arc<StoredFile>> {
+        let file_path = self.full_path(path).await?;
+        if !is_file(&file_path).await {
+            return None;
+        }
+
+        if let Some(file) = self.cache.get(&file_path) {
+            return Some(file);
+        }
+
+        let version = self.cache.version();
+        let mut generated = false;
+        let file = loop {
+            {
+                let _guard = self.commit_lock.read().await;
+                if let Some(metadata) = self.read_metadata(&file_path).await {
+                    break FsFile::open_with(&file_path, Some(metadata), Some(&self.handles))
+                        .await
+                        .ok()?
+                        .mapped_from(self.mmap_min_bytes);
+                }
+            }
+            // changed again right after it was hashed, the next lookup can try again
+            if generated {
+                return None;
+            }
+
+            // put there by hand, or changed since. It's looked up again once it's kept, as
+            // the file could have been replaced in the meantime
+            if let Err(err) = self.generate_metadata_once(&file_path).await {
+                log::error!(
+                    "Error generating metadata for '{}': {err}",
+                    file_path.display()
+                );
+                return None;
+            }
+            generated = true;
+        };
+
+        let file = Arc::new(StoredFile::from(file));
+        self.cache.insert(file_path, Arc::clone(&file), version);
+
+        Some(file)
+    }
+
+    async fn stage_upload(
+        &self,
+        path: &Path,
+        stream: ByteStream<'_>,
+        size_hint: Option<u64>,
+    ) -> io::Result<StagedUpload<'_>> {
+        let path = self.full_path(path).await.ok_or(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "provided file path is in an invalid place",
+        ))?;
+
+        if !self.is_valid_path(&path) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cannot upload due to invalid file name or path",
+            ));
+        }
+
+        // ensure parent directories exist, if any
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        // written next to the target and renamed over it when committed, so readers never
+        // see a partial file and files are never modified in place
+        let temp_path = upload_temp_path(&path);
+        let mut staged = FsStagedUpload {
+            path,
+            temp_path,
+            metadata: FileMetadata::default(),
+            leader: None,
+        };
+
+        (staged.metadata, staged.leader) = self
+            .write_upload(&staged.temp_path, stream, size_hint)
+            .await?;
+        Ok(StagedUpload::Filesystem(staged))
+    }
+
+    async fn commit_upload(&self, staged: StagedUpload<'_>) -> io::Result<FileMetadata> {
+        let StagedUpload::Filesystem(mut staged) = staged else {
+            return Err(io::Error::other("upload was staged by a different store"));
+        };
+        let previous = self.read_metadata(&staged.path).await;
+        staged.metadata.replacing(previous.as_ref());
+
+        // a sidecar is written before the file is visible, failing to write it fails the
+        // upload, which leaves everything as it was once the staged upload is dropped
+        if self.metadata_db.is_none() {
+            let metadata = serde_json::to_vec(&staged.metadata)?;
+            let sidecar = metadata_path(&staged.temp_path);
+            if let Err(err) = tokio::fs::write(&sidecar, metadata).await {
+                return Err(io::Error::new(
+                    err.kind(),
+                    format!("upload was rolled back, its metadata couldn't be written: {err}"),
+                ));
+            }
+        }
+
+        let mut pending = false;
+        {
+            let _guard = self.commit_lock.write().await;
+            self.own_writes.note(&staged.path);
+            self.keep_version(&staged.path).await?;
+            tokio::fs::rename(&staged.temp_path, &staged.path).await?;
+            self.cache.invalidate(&staged.path);
+            self.pending_metadata.lock().unwrap().remove(&staged.path);
+
+            // the file is visible by now, so its metadata is rather kept until it can be written
+            if let Err(err) = self.write_metadata(&staged).await {
+                log::warn!(
+                    "Committed '{}' without writing its metadata, retrying later: {err}",
+                    staged.path.display()
+                );
+                self.pending_metadata
+                    .lock()
+                    .unwrap()
+                    .insert(staged.path.clone(), staged.metadata.clone());
+                pending = true;
+            }
+        }
+
+        if let Some(leader) = staged.leader.take() {
+            leader.finish(&staged.metadata, &staged.path);
+        }
+
+        Ok(FileMetadata {
+            pending,
+            ..staged.metadata.clone()
+        })
+    }
+
+    async fn remove(&self, path: &Path) -> io::Result<()> {
+        let path = self.full_path(path).await.ok_or(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "provided file path is in an invalid place",
+        ))?;
+
+        if !self.is_valid_path(&path) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cannot remove due to invalid file name or path",
+            ));
+        }
+
+        // `path` is already resolved here, going through `exists` would join the base twice
+        if !is_file(&path).await {
+            return Ok(());
+        }
+
+        let _guard = self.commit_lock.write().await;
+        self.own_writes.note(&path);
+        self.pending_metadata.lock().unwrap().remove(&path);
+        if self.versions.is_some() {
+            self.keep_version(&path).await?;
+        } else {
+            tokio::fs::remove_file(&path).await?;
+        }
+        self.cache.invalidate(&path);
+        // the watcher leaves the store's own writes to it
+        if let (Some(spellings), Some(dir)) = (&self.spellings, path.parent()) {
+            spellings.invalidate(dir);
+        }
+        if let Some(db) = &self.metadata_db {
+            db.remove(&key_of(&self.base_path, &path))?;
+        } else {
+            match tokio::fs::remove_file(metadata_path(&path)).await {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn stat(&self, path: &Path) -> Option<Entry> {
+        let full_path = self.resolve_dir(path).await.ok()?;
+        if full_path == self.base_path {
+            return Some(Entry::Dir);
+        }
+
+        if !self.is_valid_path(&full_path) {
+            return None;
+        }
+
+        let is_dir = tokio::fs::metadata(&full_path)
+            .await
+            .is_ok_and(|metadata| metadata.is_dir());
+        if is_dir {
+            Some(Entry::Dir)
+        } else {
+            self.get_file(path).await.map(Entry::File)
+        }
+    }
+
+    async fn list(&self, dir: &Path) -> io::Result<Vec<DirEntry>> {
+        let dir_path = self.resolve_dir(dir).await?;
+
+        let mut entries = Vec::new();
+        let mut dir_entries = tokio::fs::read_dir(&dir_path).await?;
+        while let Some(entry) = dir_entries.next_entry().await? {
+            let path = entry.path();
+            let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+
+            let file_type = entry.file_type().await?;
+            if file_type.is_dir() {
+                if path != self.base_path.join("api") {
+                    entries.push(DirEntry {
+                        name,
+                        kind: EntryKind::Dir,
+                        size_bytes: None,
+                        modified_at: None,
+                    });
+                }
+            } else if file_type.is_file() && self.is_valid_path(&path) {
+                let file_metadata = entry.metadata().await?;
+                // files without metadata yet were changed whenever the file says they were
+                let modified_at = match self.read_metadata(&path).await {
+                    Some(metadata) => metadata.modified_at,
+                    None => file_metadata.modified().ok().map(DateTime::from),
+                };
+                entries.push(DirEntry {
+                    name,
+                    kind: EntryKind::File,
+                    size_bytes: Some(file_metadata.len()),
+                    modified_at,
+                });
+            }
+        }
+
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    }
+}
+
+/// What a garbage collection pass removed, as reported by `POST /api/store/gc`.
+#[derive(Serialize, Debug, Clone, Copy, Default)]
+pub struct GcReport {
+    /// Sidecars and database rows of files that are gone
+    pub metadata_removed: usize,
+    pub cache_entries_removed: usize,
+}
+
+impl AddAssign for GcReport {
+    fn add_assign(&mut self, other: Self) {
+        self.metadata_removed += other.metadata_removed;
+        self.cache_entries_removed += other.cache_entries_removed;
+    }
+}
+
+/// Uploads being written, or left behind by ones that never finished, as reported by
+/// `GET /api/store/partial_uploads`.
+#[derive(Serialize, Debug, Clone, Copy, Default)]
+pub struct PartialUploads {
+    pub files: usize,
+    pub total_bytes: u64,
+    /// Not written to for longer than the idle timeout, and removed rather than counted
+    pub abandoned_removed: usize,
+}
+
+impl AddAssign for PartialUploads {
+    fn add_assign(&mut self, other: Self) {
+        self.files += other.files;
+        self.total_bytes += other.total_bytes;
+        self.abandoned_removed += other.abandoned_removed;
+    }
+}
+
+/// Counts the partial uploads in `dir`, and in every directory under it if `recursive`,
+/// removing those not written to for `idle_timeout` along with their sidecars.
+fn sweep_partial_uploads(
+    dir: &Path,
+    recursive: bool,
+    idle_timeout: Option<Duration>,
+) -> io::Result<PartialUploads> {
+    let mut report = PartialUploads::default();
+    let mut dirs = vec![dir.to_path_buf()];
+
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if entry.file_type()?.is_dir() {
+                if recursive {
+                    dirs.push(path);
+                }
+                continue;
+            }
+            if !entry
+                .file_name()
+                .to_string_lossy()
+                .ends_with(UPLOAD_FILE_EXT)
+            {
+                continue;
+            }
+
+            // finished in the meantime
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            // an upload in progress writes to it with every chunk it receives
+            let idle_for = metadata
+                .modified()
+                .ok()
+                .and_then(|at| SystemTime::now().duration_since(at).ok())
+                .unwrap_or_default();
+            if idle_timeout.is_some_and(|timeout| idle_for > timeout) {
+                for abandoned in [metadata_path(&path), path] {
+                    match fs::remove_file(&abandoned) {
+                        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                        _ => {}
+                    }
+                }
+                report.abandoned_removed += 1;
+            } else {
+                report.files += 1;
+                report.total_bytes += metadata.len();
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Every sidecar under `dir`, however deep.
+fn find_sidecars(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut sidecars = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if entry.file_type()?.is_dir() {
+                dirs.push(path);
+            } else if sidecar_target(&path).is_some() {
+                sidecars.push(path);
+            }
+        }
+    }
+
+    Ok(sidecars)
+}
+
+pub const METADATA_FILE_EXT: &str = ".metadata.json";
+pub const UPLOAD_FILE_EXT: &str = ".uploading";
+// each chunk is read on a blocking thread, so not too small to be worth the trip
+const READ_CHUNK_LEN: usize = 64 * 1024;
+// chunks of a memory map are sent without being copied, so they can be larger than read ones
+const MMAP_CHUNK_LEN: usize = 1024 * 1024;
+// smaller uploads are written quickly enough not to be worth reserving room for
+const PREALLOCATE_MIN_BYTES: u64 = 8 * 1024 * 1024;
+// how much of an upload is hashed at a time, and so can be written ahead of the hashing before
+// it holds up the writes
+const HASH_BATCH_BYTES: usize = 1024 * 1024;
+
+fn metadata_path(path: &Path) -> PathBuf {
+    let mut os_str = path
+        .file_name()
+        .map(|s| s.to_os_string())
+        .unwrap_or_default();
+
+    os_str.push(METADATA_FILE_EXT);
+    path.with_file_name(os_str)
+}
+
+pub struct FsStagedUpload<'a> {
+    path: PathBuf,
+    temp_path: PathBuf,
+    metadata: FileMetadata,
+    leader: Option<Leader<'a>>,
+}
+
+impl Drop for FsStagedUpload<'_> {
+    fn drop(&mut self) {
+        // already gone if the upload was committed
+        let _ = fs::remove_file(&self.temp_path);
+        let _ = fs::remove_file(metadata_path(&self.temp_path));
+    }
+}
+
+fn upload_temp_path(path: &Path) -> PathBuf {
+    let mut os_str = path
+        .file_name()
+        .map(|s| s.to_os_string())
+        .unwrap_or_default();
+
+    os_str.push(format!(".{}{UPLOAD_FILE_EXT}", Uuid::new_v4().simple()));
+    path.with_file_name(os_str)
+}
+
+pub struct FsFile {
+    // opened when looked up, so a file replaced afterwards is still read as it was then
+    file: Arc<OpenFile>,
+    metadata: FileMetadata,
+    mmap_min_bytes: Option<u64>,
+}
+
+impl FsFile {
+    pub async fn open(file_path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = file_path.as_ref();
+        let metadata = read_metadata(&metadata_path(path)).await.ok();
+        Self::open_with(path, metadata, None).await
+    }
+
+    /// Opens the file with metadata looked up from somewhere other than its sidecar, counted
+    /// by `handles` while it's open if given.
+    async fn open_with(
+        path: &Path,
+        metadata: Option<FileMetadata>,
+        handles: Option<&FileHandles>,
+    ) -> io::Result<Self> {
+        let file = tokio::fs::File::open(path).await?;
+
+        // without metadata the size still has to be right, since it's used for Content-Length
+        let metadata = match metadata {
+            Some(metadata) => metadata,
+            None => FileMetadata {
+                size_bytes: file.metadata().await?.len(),
+                ..Default::default()
+            },
+        };
+
+        let file = file.into_std().await;
+        Ok(FsFile {
+            file: Arc::new(match handles {
+                Some(handles) => handles.track(file),
+                None => file.into(),
+            }),
+            metadata,
+            mmap_min_bytes: None,
+        })
+    }
+
+    /// Serves the file out of a memory map if it's at least `min_bytes` large.
+    fn mapped_from(mut self, min_bytes: Option<u64>) -> Self {
+        self.mmap_min_bytes = min_bytes;
+        self
+    }
+}
+
+impl FsFile {
+    /// For files whose metadata is kept somewhere other than next to them.
+    fn with_metadata(
+        file_path: impl AsRef<Path>,
+        metadata: FileMetadata,
+        handles: &FileHandles,
+    ) -> io::Result<Self> {
+        Ok(FsFile {
+            file: Arc::new(handles.track(File::open(file_path)?)),
+            metadata,
+            mmap_min_bytes: None,
+        })
+    }
+}
+
+/// Hashes the file at `file_path` as it is on disk, along with the size and modification
+/// time it had before, to tell whether it changed while it was being hashed.
+async fn hash_file(
+    file_path: &Path,
+    algorithm: HashAlgorithm,
+) -> io::Result<(FileMetadata, (u64, Option<SystemTime>))> {
+    let path = file_path.to_path_buf();
+    task::spawn_blocking(move || {
+        let mut file = File::open(path)?;
+        let times = file.metadata()?;
+        let mut hasher = Hasher::new(algorithm);
+        let size_bytes = io::copy(&mut file, &mut hasher)?;
+
+        // put there by hand, so it was last changed whenever the file says it was
+        let modified_at = times.modified().ok().map(DateTime::<Utc>::from);
+        let created_at = times.created().ok().map(DateTime::<Utc>::from);
+        let metadata = FileMetadata {
+            created_at: created_at.or(modified_at),
+            modified_at,
+            ..hasher.finish(size_bytes)
+        };
+        Ok((metadata, (times.len(), times.modified().ok())))
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+async fn read_metadata(metadata_path: &Path) -> io::Result<FileMetadata> {
+    let metadata = tokio::fs::read(metadata_path).await?;
+    Ok(serde_json::from_slice(&metadata)?)
+}
+
+async fn is_file(path: &Path) -> bool {
+    tokio::fs::metadata(path)
+        .await
+        .is_ok_and(|metadata| metadata.is_file())
+}
+
+impl From<FsFile> for StoredFile {
+    fn from(value: FsFile) -> Self {
+        StoredFile::Filesystem(value)
+    }
+}
+
+impl StoredFileCore for FsFile {
+    fn metadata(&self) -> &FileMetadata {
+        &self.metadata
+    }
+
+    fn bytes_stream(&self) -> ByteStream<'static> {
+        self.stream_from(0, None)
+    }
+
+    fn range_stream(&self, start: u64, len: u64) -> ByteStream<'static> {
+        self.stream_from(start, Some(start + len))
+    }
+}
+
+impl FsFile {
+    /// Streams the contents from `start` up to `end`, or for as long as there are any.
+    fn stream_from(&self, start: u64, end: Option<u64>) -> ByteStream<'static> {
+        let file = Arc::clone(&self.file);
+        // an empty file can't be mapped
+        let size_bytes = self.metadata.size_bytes;
+        if size_bytes > 0 && self.mmap_min_bytes.is_some_and(|min| size_bytes >= min) {
+            return mapped_stream(file, start, end);
+        }
+
+        try_stream! {
+            let mut offset = start;
+            // every chunk is split off the same buffer, whose room is taken back for the next
+            // one once the chunks before it were sent and dropped, rather than allocating anew
+            let mut buffer = BytesMut::new();
+            while end.is_none_or(|end| offset < end) {
+                let chunk_len = end.map_or(READ_CHUNK_LEN, |end| {
+                    (end - offset).min(READ_CHUNK_LEN as u64) as usize
+                });
+                buffer.reserve(chunk_len);
+                buffer.resize(chunk_len, 0);
+
+                // reading at an offset rather than from the file's cursor, which every reader
+                // of the same file would share, and which tokio::fs has no async version of
+                let file = Arc::clone(&file);
+                let bytes_read;
+                (buffer, bytes_read) = task::spawn_blocking(move || {
+                    let bytes_read = read_at(&file, &mut buffer, offset)?;
+                    Ok::<_, io::Error>((buffer, bytes_read))
+                })
+                .await
+                .map_err(io::Error::other)??;
+
+                buffer.truncate(bytes_read);
+                if bytes_read == 0 {
+                    break;
+                }
+                offset += bytes_read as u64;
+                yield buffer.split().freeze();
+            }
+        }
+        .boxed_local()
+    }
+}
+
+/// Streams `file` from `start` up to `end` out of a memory map, in chunks sharing the map
+/// rather than copied out of it. Reading the chunks faults their pages in on whichever thread
+/// sends them, the worker's own unless read ahead already.
+fn mapped_stream(file: Arc<OpenFile>, start: u64, end: Option<u64>) -> ByteStream<'static> {
+    try_stream! {
+        // SAFETY: this server only ever replaces files by renaming over them, never writing
+        // in place, so what's mapped doesn't change underneath. Something else truncating the
+        // file while it's mapped does, and the next read of a page past the end is a SIGBUS,
+        // which is why `mmap_min_bytes` is off unless configured
+        let map = unsafe { memmap2::Mmap::map(&**file)? };
+        // read ahead of being sent, so that sending it rarely has to wait on the disk
+        #[cfg(unix)]
+        let _ = map.advise(memmap2::Advice::Sequential);
+
+        let contents = Bytes::from_owner(map);
+        let end = end.map_or(contents.len(), |end| (end as usize).min(contents.len()));
+        let mut offset = start as usize;
+        while offset < end {
+            let chunk_end = (offset + MMAP_CHUNK_LEN).min(end);
+            yield contents.slice(offset..chunk_end);
+            offset = chunk_end;
+        }
+    }
+    .boxed_local()
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, thread};
+
+    use actix_web::rt::System;
+    use futures::{TryStreamExt, future, stream};
+
+    use super::*;
+    use crate::fixtures::{TempDir, temp_dir};
+
+    /// Runs `future` on a runtime of its own, which the async file IO needs.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        System::new().block_on(future)
+    }
+
+    /// A store in its own directory, which is removed again when dropped.
+    struct TempStore {
+        store: FsFileStore,
+        dir: TempDir,
+    }
+
+    impl TempStore {
+        fn new() -> Self {
+            let dir = temp_dir();
+            TempStore {
+                store: FsFileStore::new(&dir),
+                dir,
+            }
+        }
+    }
+
+    // kept under the dedup prefix length, so every upload is written on its own
+    fn contents(version: usize) -> Vec<u8> {
+        format!("version {version}\n")
+            .repeat(version % 100 + 1)
+            .into_bytes()
+    }
+
+    async fn upload(store: &FsFileStore, path: &str, contents: Vec<u8>) -> FileMetadata {
+        let stream = stream::iter([Ok(Bytes::from(contents))]).boxed_local();
+        let staged = store
+            .stage_upload(Path::new(path), stream, None)
+            .await
+            .unwrap();
+        store.commit_upload(staged).await.unwrap()
+    }
+
+    async fn read(file: &StoredFile) -> Vec<u8> {
+        file.bytes_stream()
+            .try_fold(Vec::new(), |mut all, chunk| async move {
+                all.extend_from_slice(&chunk);
+                Ok(all)
+            })
+            .await
+            .unwrap()
+    }
+
+    fn assert_consistent(file: &StoredFile, contents: &[u8]) {
+        let hash = FileMetadata::hash_to_hex(Sha256::new_with_prefix(contents));
+        assert_eq!(file.metadata().hash, hash);
+        assert_eq!(file.metadata().size_bytes, contents.len() as u64);
+    }
+
+    #[test]
+    fn upload_replaces_cached_file_immediately() {
+        let temp = TempStore::new();
+        block_on(async {
+            upload(&temp.store, "a.txt", contents(1)).await;
+            let old = temp.store.get_file(Path::new("a.txt")).await.unwrap();
+
+            let metadata = upload(&temp.store, "a.txt", contents(2)).await;
+            let new = temp.store.get_file(Path::new("a.txt")).await.unwrap();
+
+            assert!(!Arc::ptr_eq(&old, &new));
+            assert_eq!(new.metadata().hash, metadata.hash);
+            assert_eq!(read(&new).await, contents(2));
+        });
+    }
+
+    #[test]
+    fn names_find_the_same_file_however_theyre_composed() {
+        let temp = TempStore::new();
+        block_on(async {
+            upload(&temp.store, "u\u{308}ber.txt", contents(1)).await;
+
+            let file = temp.store.get_file(Path::new("über.txt")).await.unwrap();
+            assert_eq!(read(&file).await, contents(1));
+            assert!(temp.dir.join("über.txt").is_file());
+        });
+    }
+
+    #[test]
+    fn looked_up_file_keeps_its_contents_when_replaced() {
+        let temp = TempStore::new();
+        block_on(async {
+            upload(&temp.store, "a.txt", contents(1)).await;
+            let old = temp.store.get_file(Path::new("a.txt")).await.unwrap();
+            upload(&temp.store, "a.txt", contents(2)).await;
+
+            let read_old = read(&old).await;
+            assert_eq!(read_old, contents(1));
+            assert_consistent(&old, &read_old);
+        });
+    }
+
+    #[test]
+    fn removal_is_visible_immediately() {
+        let temp = TempStore::new();
+        block_on(async {
+            upload(&temp.store, "a.txt", contents(1)).await;
+            assert!(temp.store.get_file(Path::new("a.txt")).await.is_some());
+
+            temp.store.remove(Path::new("a.txt")).await.unwrap();
+            assert!(temp.store.get_file(Path::new("a.txt")).await.is_none());
+        });
+    }
+
+    #[test]
+    fn files_added_by_hand_get_metadata() {
+        let temp = TempStore::new();
+        fs::write(temp.dir.join("a.txt"), contents(1)).unwrap();
+        block_on(async {
+            let file = temp.store.get_file(Path::new("a.txt")).await.unwrap();
+            assert_consistent(&file, &contents(1));
+            assert!(temp.dir.join("a.txt.metadata.json").exists());
+
+            // replaced by hand as well, leaving the old metadata behind
+            fs::write(temp.dir.join("a.txt"), contents(2)).unwrap();
+            temp.store.cache.invalidate(&temp.dir.join("a.txt"));
+            let file = temp.store.get_file(Path::new("a.txt")).await.unwrap();
+            assert_consistent(&file, &contents(2));
+        });
+    }
+
+    #[test]
+    fn concurrent_lookups_of_a_file_added_by_hand_hash_it_once() {
+        let temp = TempStore::new();
+        let big = contents(7).repeat(10_000);
+        fs::write(temp.dir.join("big.bin"), &big).unwrap();
+        block_on(async {
+            let (files, uploaded) = future::join(
+                future::join_all((0..8).map(|_| temp.store.get_file(Path::new("big.bin")))),
+                // commits don't wait for the hashing
+                upload(&temp.store, "other.txt", contents(1)),
+            )
+            .await;
+            for file in files {
+                assert_consistent(&file.unwrap(), &big);
+            }
+            assert_eq!(uploaded.size_bytes, contents(1).len() as u64);
+            assert!(temp.store.generating.lock().unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn hashing_an_upload_only_borrows_a_thread_for_each_batch() {
+        // with a single blocking thread, anything else needing one would wait for the upload
+        // to end if the hashing kept hold of it in between chunks
+        let system = System::with_tokio_rt(|| {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .max_blocking_threads(1)
+                .build()
+                .unwrap()
+        });
+        let chunks = [contents(1).repeat(HASH_BATCH_BYTES), contents(2)];
+        system.block_on(async {
+            let mut hasher = BackgroundHasher::spawn(HashAlgorithm::Sha256);
+            for chunk in &chunks {
+                hasher.update(Bytes::from(chunk.clone())).await.unwrap();
+                let other = task::spawn_blocking(|| ());
+                tokio::time::timeout(Duration::from_secs(5), other)
+                    .await
+                    .expect("the hashing held onto the blocking thread")
+                    .unwrap();
+            }
+
+            let mut expected = Hasher::new(HashAlgorithm::Sha256);
+            for chunk in &chunks {
+                expected.update(chunk);
+            }
+            let size_bytes = chunks.iter().map(|chunk| chunk.len() as u64).sum();
+            let metadata = hasher.finish().await.unwrap();
+            assert_eq!(metadata.hash, expected.finish(size_bytes).hash);
+            assert_eq!(metadata.size_bytes, size_bytes);
+        });
+    }
+
+    #[test]
+    fn files_kept_under_decomposed_names_are_still_found() {
+        // with the spellings on disk read every time, and kept while watched
+        for watched in [false, true] {
+            let temp = TempStore::new();
+            let watched_store;
+            let store = if watched {
+                watched_store = FsFileStore::new(&temp.dir).watched().unwrap();
+                &watched_store
+            } else {
+                &temp.store
+            };
+            // "über" with the ü as a u and a combining diaeresis, as it was kept before
+            fs::create_dir(temp.dir.join("u\u{308}ber")).unwrap();
+            fs::write(temp.dir.join("u\u{308}ber/a\u{308}.txt"), contents(1)).unwrap();
+            block_on(async {
+                for path in ["über/ä.txt", "u\u{308}ber/a\u{308}.txt"] {
+                    let file = store.get_file(Path::new(path)).await;
+                    assert_consistent(&file.expect(path), &contents(1));
+                }
+
+                // replacing it doesn't leave the old spelling behind
+                upload(store, "über/ä.txt", contents(2)).await;
+                let names: Vec<_> = fs::read_dir(temp.dir.join("u\u{308}ber"))
+                    .unwrap()
+                    .map(|entry| entry.unwrap().file_name())
+                    .filter(|name| !is_internal_name(&name.to_string_lossy()))
+                    .collect();
+                assert_eq!(names, ["a\u{308}.txt"]);
+
+                store.remove(Path::new("über/ä.txt")).await.unwrap();
+                assert!(!store.exists(Path::new("über/ä.txt")).await);
+                assert!(!temp.dir.join("u\u{308}ber/a\u{308}.txt").exists());
+            });
+        }
+    }
+
+    #[test]
+    fn sidecars_move_into_the_metadata_db() {
+        let temp = TempStore::new();
+        block_on(async {
+            let metadata = upload(&temp.store, "a/b.txt", contents(1)).await;
+
+            let store = FsFileStore::new(&temp.dir)
+                .with_metadata_db(temp.dir.join("metadata.db"))
+                .unwrap();
+            assert!(!temp.dir.join("a/b.txt.metadata.json").exists());
+            let file = store.get_file(Path::new("a/b.txt")).await.unwrap();
+            assert_eq!(file.metadata().hash, metadata.hash);
+
+            upload(&store, "c.txt", contents(2)).await;
+            assert!(!temp.dir.join("c.txt.metadata.json").exists());
+            let file = store.get_file(Path::new("c.txt")).await.unwrap();
+            assert_consistent(&file, &contents(2));
+        });
+    }
+
+    #[test]
+    fn replaced_and_deleted_files_are_kept_as_versions() {
+        let temp = TempStore::new();
+        let config = VersionsConfig {
+            dir: temp.dir.join(".versions").to_string_lossy().into_owned(),
+            max_versions: 2,
+        };
+        let store = FsFileStore::new(&temp.dir).with_versions(&config).unwrap();
+        block_on(async {
+            for version in 1..=3 {
+                upload(&store, "a/b.txt", contents(version)).await;
+            }
+            store.remove(Path::new("a/b.txt")).await.unwrap();
+
+            // the first upload was dropped to stay within the limit
+            let versions = store.versions(Path::new("a/b.txt")).await.unwrap();
+            let numbers: Vec<_> = versions.iter().map(|v| v.version).collect();
+            assert_eq!(numbers, [2, 3]);
+            assert!(temp.dir.join(".versions/a/b.txt@v3").exists());
+
+            let file = StoredFile::from(
+                store
+                    .get_version(Path::new("a/b.txt"), 3)
+                    .await
+                    .unwrap()
+                    .unwrap(),
+            );
+            assert_eq!(read(&file).await, contents(3));
+            assert_consistent(&file, &contents(3));
+            assert!(
+                store
+                    .get_version(Path::new("a/b.txt"), 1)
+                    .await
+                    .unwrap()
+                    .is_none()
+            );
+        });
+    }
+
+    #[test]
+    fn upload_is_rolled_back_when_its_metadata_cant_be_written() {
+        let temp = TempStore::new();
+        block_on(async {
+            upload(&temp.store, "a.txt", contents(1)).await;
+
+            let stream = stream::iter([Ok(Bytes::from(contents(2)))]).boxed_local();
+            let staged = temp
+                .store
+                .stage_upload(Path::new("a.txt"), stream, None)
+                .await
+                .unwrap();
+            let StagedUpload::Filesystem(fs_staged) = &staged else {
+                unreachable!()
+            };
+            // nothing can be written where a directory is
+            fs::create_dir(metadata_path(&fs_staged.temp_path)).unwrap();
+
+            assert!(temp.store.commit_upload(staged).await.is_err());
+            let file = temp.store.get_file(Path::new("a.txt")).await.unwrap();
+            assert_eq!(read(&file).await, contents(1));
+            assert_consistent(&file, &contents(1));
+        });
+    }
+
+    #[test]
+    fn metadata_that_cant_be_put_in_place_is_retried() {
+        let temp = TempStore::new();
+        let sidecar = temp.dir.join("a.txt.metadata.json");
+        fs::create_dir_all(sidecar.join("blocked")).unwrap();
+        block_on(async {
+            let committed = upload(&temp.store, "a.txt", contents(1)).await;
+            assert!(committed.pending);
+            let file = temp.store.get_file(Path::new("a.txt")).await.unwrap();
+            assert_consistent(&file, &contents(1));
+            assert_eq!(temp.store.retry_pending_metadata().await, 1);
+
+            fs::remove_dir_all(&sidecar).unwrap();
+            assert_eq!(temp.store.retry_pending_metadata().await, 0);
+            assert!(!upload(&temp.store, "a.txt", contents(2)).await.pending);
+            let file = temp.store.get_file(Path::new("a.txt")).await.unwrap();
+            let metadata = read_metadata(&sidecar).await.unwrap();
+            assert_eq!(metadata.hash, file.metadata().hash);
+        });
+    }
+
+    #[test]
+    fn metadata_of_files_deleted_by_hand_is_collected() {
+        let temp = TempStore::new();
+        block_on(async {
+            upload(&temp.store, "a/b.txt", contents(1)).await;
+            upload(&temp.store, "c.txt", contents(2)).await;
+            temp.store.get_file(Path::new("a/b.txt")).await.unwrap();
+            fs::remove_file(temp.dir.join("a/b.txt")).unwrap();
+
+            let report = temp.store.collect_garbage().await.unwrap();
+            assert_eq!(report.metadata_removed, 1);
+            assert_eq!(report.cache_entries_removed, 1);
+            assert!(!temp.dir.join("a/b.txt.metadata.json").exists());
+            assert!(temp.dir.join("c.txt.metadata.json").exists());
+
+            let store = FsFileStore::new(&temp.dir)
+                .with_metadata_db(temp.dir.join("metadata.db"))
+                .unwrap();
+            fs::remove_file(temp.dir.join("c.txt")).unwrap();
+            let report = store.collect_garbage().await.unwrap();
+            assert_eq!(report.metadata_removed, 1);
+            assert!(
+                store
+                    .metadata_db
+                    .as_ref()
+                    .unwrap()
+                    .keys()
+                    .unwrap()
+                    .is_empty()
+            );
+        });
+    }
+
+    #[test]
+    fn uploads_are_hashed_with_the_configured_algorithm() {
+        let temp = TempStore::new();
+        let store = FsFileStore::new(&temp.dir).with_hash_algorithm(HashAlgorithm::Blake3);
+        block_on(async {
+            let metadata = upload(&store, "a.txt", contents(1)).await;
+            assert_eq!(metadata.hash, blake3::hash(&contents(1)).to_hex().as_str());
+            assert_eq!(metadata.algorithm, HashAlgorithm::Blake3);
+
+            let sidecar = read_metadata(&temp.dir.join("a.txt.metadata.json")).await;
+            assert_eq!(sidecar.unwrap().algorithm, HashAlgorithm::Blake3);
+        });
+
+        // sidecars from before the algorithm was recorded
+        let legacy: FileMetadata =
+            serde_json::from_str(r#"{"hash": "abc", "size_bytes": 3}"#).unwrap();
+        assert_eq!(legacy.algorithm, HashAlgorithm::Sha256);
+    }
+
+    #[test]
+    fn abandoned_partial_uploads_are_removed_once_idle() {
+        let temp = TempStore::new();
+        fs::create_dir_all(temp.dir.join("docs")).unwrap();
+        let active = temp.dir.join(format!("a.txt.1{UPLOAD_FILE_EXT}"));
+        let abandoned = temp.dir.join(format!("docs/b.txt.2{UPLOAD_FILE_EXT}"));
+        fs::write(&active, "abc").unwrap();
+        fs::write(&abandoned, "abcdef").unwrap();
+        File::options()
+            .write(true)
+            .open(&abandoned)
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(2 * 60 * 60))
+            .unwrap();
+
+        block_on(async {
+            let report = temp.store.partial_uploads(None).await.unwrap();
+            assert_eq!((report.files, report.total_bytes), (2, 9));
+
+            let idle_timeout = Some(Duration::from_secs(60 * 60));
+            let report = temp.store.partial_uploads(idle_timeout).await.unwrap();
+            assert_eq!((report.files, report.total_bytes), (1, 3));
+            assert_eq!(report.abandoned_removed, 1);
+        });
+        assert!(active.exists());
+        assert!(!abandoned.exists());
+    }
+
+    #[test]
+    fn files_without_a_recorded_modification_time_use_their_own() {
+        let temp = TempStore::new();
+        fs::write(temp.dir.join("a.txt"), "abc").unwrap();
+        fs::write(
+            temp.dir.join("a.txt.metadata.json"),
+            r#"{"hash": "abc", "size_bytes": 3}"#,
+        )
+        .unwrap();
+        let modified = fs::metadata(temp.dir.join("a.txt"))
+            .unwrap()
+            .modified()
+            .unwrap();
+
+        block_on(async {
+            let file = temp.store.get_file(Path::new("a.txt")).await.unwrap();
+            assert_eq!(file.metadata().hash, "abc");
+            assert_eq!(file.metadata().modified_at, Some(DateTime::from(modified)));
+        });
+    }
+
+    #[test]
+    fn replaced_files_keep_when_they_were_created() {
+        let temp = TempStore::new();
+        fs::create_dir(temp.dir.join("db")).unwrap();
+        let with_db = FsFileStore::new(temp.dir.join("db"))
+            .with_metadata_db(temp.dir.join("metadata.db"))
+            .unwrap();
+        block_on(async {
+            for store in [&temp.store, &with_db] {
+                let first = upload(store, "a.txt", contents(1)).await;
+                let second = upload(store, "a.txt", contents(2)).await;
+                assert!(first.created_at.is_some());
+                assert!(second.modified_at > first.modified_at);
+
+                let file = store.get_file(Path::new("a.txt")).await.unwrap();
+                let created_at = first.created_at.map(|at| at.timestamp_millis());
+                assert_eq!(
+                    file.metadata().created_at.map(|at| at.timestamp_millis()),
+                    created_at
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn chunks_reuse_the_buffer_once_dropped() {
+        let temp = TempStore::new();
+        let store = FsFileStore::new(&temp.dir);
+        let large = contents(0).repeat(READ_CHUNK_LEN / 10 * 3 + 1);
+        block_on(async {
+            upload(&store, "large.bin", large.clone()).await;
+
+            let file = store.get_file(Path::new("large.bin")).await.unwrap();
+            let mut stream = file.bytes_stream();
+            let mut read = Vec::new();
+            let mut buffers = HashSet::new();
+            while let Some(chunk) = stream.try_next().await.unwrap() {
+                read.extend_from_slice(&chunk);
+                buffers.insert(chunk.as_ptr());
+            }
+            assert_eq!(read, large);
+            assert_eq!(buffers.len(), 1);
+        });
+    }
+
+    #[test]
+    fn large_files_are_served_from_a_memory_map() {
+        let temp = TempStore::new();
+        let store = FsFileStore::new(&temp.dir).with_mmap_min_bytes(Some(MMAP_CHUNK_LEN as u64));
+        let large = contents(7).repeat(MMAP_CHUNK_LEN / 10);
+        block_on(async {
+            upload(&store, "large.bin", large.clone()).await;
+            upload(&store, "small.txt", contents(1)).await;
+            upload(&store, "empty.txt", Vec::new()).await;
+
+            let file = store.get_file(Path::new("large.bin")).await.unwrap();
+            assert_consistent(&file, &large);
+            // in chunks far larger than read ones
+            let chunks: Vec<_> = file.bytes_stream().try_collect().await.unwrap();
+            assert!(chunks.len() < large.len() / READ_CHUNK_LEN);
+            let range = MMAP_CHUNK_LEN as u64 - 5..MMAP_CHUNK_LEN as u64 + 5;
+            let part: Vec<_> = file
+                .range_stream(range.start, 10)
+                .try_collect()
+                .await
+                .unwrap();
+            assert_eq!(
+                part.concat(),
+                &large[range.start as usize..range.end as usize]
+            );
+
+            let file = store.get_file(Path::new("small.txt")).await.unwrap();
+            assert_consistent(&file, &contents(1));
+            let file = store.get_file(Path::new("empty.txt")).await.unwrap();
+            assert_consistent(&file, &[]);
+        });
+    }
+
+    #[test]
+    fn uploads_declared_larger_than_sent_keep_their_size() {
+        let temp = TempStore::new();
+        block_on(async {
+            let stream = stream::iter([Ok(Bytes::from(contents(1)))]).boxed_local();
+            let size_hint = Some(PREALLOCATE_MIN_BYTES * 2);
+            let staged = temp
+                .store
+                .stage_upload(Path::new("a.txt"), stream, size_hint);
+            temp.store
+                .commit_upload(staged.await.unwrap())
+                .await
+                .unwrap();
+
+            let file = temp.store.get_file(Path::new("a.txt")).await.unwrap();
+            assert_consistent(&file, &contents(1));
+            let len = fs::metadata(temp.dir.join("a.txt")).unwrap().len();
+            assert_eq!(len, contents(1).len() as u64);
+        });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_are_followed_as_configured() {
+        let temp = TempStore::new();
+        let outside = TempStore::new();
+        fs::write(outside.dir.join("secret.txt"), contents(1)).unwrap();
+        fs::write(temp.dir.join("a.txt"), contents(2)).unwrap();
+        std::os::unix::fs::symlink(&outside.dir, temp.dir.join("escape")).unwrap();
+        std::os::unix::fs::symlink(temp.dir.join("a.txt"), temp.dir.join("b.txt")).unwrap();
+
+        // (policy, escape/secret.txt served, b.txt served)
+        let policies = [
+            (SymlinkPolicy::Never, false, false),
+            (SymlinkPolicy::WithinBase, false, true),
+            (SymlinkPolicy::Always, true, true),
+        ];
+        block_on(async {
+            for (policy, escapes, within) in policies {
+                let store = FsFileStore::new(&temp.dir).with_symlink_policy(policy);
+                let escape = Path::new("escape/secret.txt");
+                assert_eq!(store.exists(escape).await, escapes, "for {policy:?}");
+                assert_eq!(store.list(Path::new("escape")).await.is_ok(), escapes);
+                assert_eq!(
+                    store.exists(Path::new("b.txt")).await,
+                    within,
+                    "for {policy:?}"
+                );
+                assert!(store.exists(Path::new("a.txt")).await);
+            }
+
+            // nor can uploads be written through one
+            let store = FsFileStore::new(&temp.dir);
+            let stream = stream::iter([Ok(Bytes::from(contents(3)))]).boxed_local();
+            let staged = store
+                .stage_upload(Path::new("escape/new.txt"), stream, None)
+                .await;
+            assert_eq!(staged.err().unwrap().kind(), io::ErrorKind::InvalidInput);
+            assert!(!outside.dir.join("new.txt").exists());
+
+            // without a base to compare against, nothing is taken to be within it
+            let later = temp.dir.join("later");
+            let store = FsFileStore::new(&later);
+            fs::create_dir(&later).unwrap();
+            fs::write(later.join("a.txt"), contents(1)).unwrap();
+            assert!(!store.exists(Path::new("a.txt")).await);
+        });
+    }
+
+    #[test]
+    fn corrupted_files_fail_verification() {
+        let temp = TempStore::new();
+        block_on(async {
+            upload(&temp.store, "a.txt", contents(1)).await;
+            let file = temp.store.get_file(Path::new("a.txt")).await.unwrap();
+            let stream = verified(file.bytes_stream(), Path::new("a.txt"), file.metadata());
+            assert_eq!(
+                stream
+                    .map_ok(|chunk| chunk.to_vec())
+                    .try_concat()
+                    .await
+                    .unwrap(),
+                contents(1)
+            );
+
+            // flipped in place, so the size still matches
+            let mut corrupted = contents(1);
+            corrupted[0] ^= 1;
+            fs::write(temp.dir.join("a.txt"), &corrupted).unwrap();
+
+            let actual = rehash(file.bytes_stream(), file.metadata()).await.unwrap();
+            assert_ne!(actual.hash, file.metadata().hash);
+            let stream = verified(file.bytes_stream(), Path::new("a.txt"), file.metadata());
+            let err = stream
+                .map_ok(|chunk| chunk.to_vec())
+                .try_concat()
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        });
+    }
+
+    #[test]
+    fn lookup_racing_a_write_is_not_cached() {
+        let temp = TempStore::new();
+        let path = temp.dir.join("a.txt");
+        block_on(upload(&temp.store, "a.txt", contents(1)));
+
+        let cache = FileCache::new(&MemoryCache::default());
+        let version = cache.version();
+        let stale = Arc::new(StoredFile::from(block_on(FsFile::open(&path)).unwrap()));
+
+        cache.invalidate(&path);
+        cache.insert(path.clone(), stale, version);
+        assert!(cache.get(&path).is_none());
+
+        let fresh = Arc::new(StoredFile::from(block_on(FsFile::open(&path)).unwrap()));
+        cache.insert(path.clone(), fresh, cache.version());
+        assert!(cache.get(&path).is_some());
+    }
+
+    #[test]
+    fn concurrent_uploads_and_reads_stay_consistent() {
+        const WRITERS: usize = 4;
+        const VERSIONS_PER_WRITER: usize = 50;
+
+        let temp = TempStore::new();
+        let store = &temp.store;
+        block_on(upload(store, "a.txt", contents(0)));
+
+        thread::scope(|scope| {
+            for writer in 0..WRITERS {
+                scope.spawn(move || {
+                    for i in 0..VERSIONS_PER_WRITER {
+                        let version = writer * VERSIONS_PER_WRITER + i;
+                        block_on(upload(store, "a.txt", contents(version)));
+                    }
+                });
+            }
+
+            for _ in 0..WRITERS {
+                scope.spawn(|| {
+                    for _ in 0..VERSIONS_PER_WRITER * 2 {
+                        block_on(async {
+                            let file = store.get_file(Path::new("a.txt")).await.unwrap();
+                            assert_consistent(&file, &read(&file).await);
+                        });
+                    }
+                });
+            }
+        });
 
-            return Some(Ok(Vec::from(&buffer[..bytes_read])));
-        }))
+        // whichever upload was committed last, the lookup has to agree with the disk
+        block_on(async {
+            let file = store.get_file(Path::new("a.txt")).await.unwrap();
+            let on_disk = fs::read(temp.dir.join("a.txt")).unwrap();
+            assert_eq!(read(&file).await, on_disk);
+            assert_consistent(&file, &on_disk);
+        });
     }
 }
@@ -0,0 +1,481 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use rusqlite::{Connection, OptionalExtension, params};
+use tokio::{io::AsyncWriteExt, task};
+use uuid::Uuid;
+
+use crate::{
+    config::server::HashAlgorithm,
+    file_handles::FileHandles,
+    file_store::{
+        BackgroundHasher, ByteStream, DirEntry, Entry, EntryKind, FileMetadata, FileStorageCore,
+        FsFile, PartialUploads, StagedUpload, StoredFile, UPLOAD_FILE_EXT, file_key, preallocate,
+        relative_key, sweep_partial_uploads,
+    },
+};
+
+const BLOBS_DIR_NAME: &str = "blobs";
+const INDEX_FILE_NAME: &str = "index.sqlite";
+
+/// Keeps each distinct content once, as a blob named by its hash, with the paths pointing
+/// at them kept in an index. A blob is deleted once no path points at it anymore.
+pub struct CasFileStore {
+    blobs_dir: PathBuf,
+    // every change to which blobs are referenced happens under this lock, so a blob is
+    // never deleted between being looked up and opened
+    index: Arc<Mutex<Connection>>,
+    handles: Arc<FileHandles>,
+}
+
+impl CasFileStore {
+    pub fn open(base_dir: impl AsRef<Path>) -> io::Result<Self> {
+        let base_dir = base_dir.as_ref();
+        let blobs_dir = base_dir.join(BLOBS_DIR_NAME);
+        fs::create_dir_all(&blobs_dir)?;
+
+        let index = Connection::open(base_dir.join(INDEX_FILE_NAME)).map_err(io::Error::other)?;
+        index
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS files (
+                    path TEXT PRIMARY KEY,
+                    hash TEXT NOT NULL,
+                    size_bytes INTEGER NOT NULL,
+                    created_at INTEGER,
+                    modified_at INTEGER,
+                    scan TEXT
+                );
+                CREATE INDEX IF NOT EXISTS files_hash ON files (hash);",
+            )
+            .map_err(io::Error::other)?;
+
+        // indexes from before the timestamps were recorded don't know them
+        if index.prepare("SELECT created_at FROM files").is_err() {
+            index
+                .execute_batch(
+                    "ALTER TABLE files ADD COLUMN created_at INTEGER;
+                    ALTER TABLE files ADD COLUMN modified_at INTEGER;",
+                )
+                .map_err(io::Error::other)?;
+        }
+        // nor the scans
+        if index.prepare("SELECT scan FROM files").is_err() {
+            index
+                .execute_batch("ALTER TABLE files ADD COLUMN scan TEXT;")
+                .map_err(io::Error::other)?;
+        }
+
+        Ok(CasFileStore {
+            blobs_dir,
+            index: Arc::new(Mutex::new(index)),
+            handles: Arc::default(),
+        })
+    }
+
+    pub fn with_file_handles(mut self, handles: &Arc<FileHandles>) -> Self {
+        self.handles = Arc::clone(handles);
+        self
+    }
+
+    /// Uploads are written next to the blobs, and nowhere under them.
+    pub async fn partial_uploads(
+        &self,
+        idle_timeout: Option<Duration>,
+    ) -> io::Result<PartialUploads> {
+        let blobs_dir = self.blobs_dir.clone();
+        task::spawn_blocking(move || sweep_partial_uploads(&blobs_dir, false, idle_timeout))
+            .await
+            .map_err(io::Error::other)?
+    }
+
+    #[cfg(test)]
+    fn blob_path(&self, hash: &str) -> PathBuf {
+        blob_path(&self.blobs_dir, hash)
+    }
+
+    /// Runs `query` with the index locked on the blocking pool, since both the index and the
+    /// blobs it may open or delete are on disk.
+    async fn with_index<T: Send + 'static>(
+        &self,
+        query: impl FnOnce(&mut Connection, &Path) -> io::Result<T> + Send + 'static,
+    ) -> io::Result<T> {
+        let (index, blobs_dir) = (Arc::clone(&self.index), self.blobs_dir.clone());
+        task::spawn_blocking(move || query(&mut index.lock().unwrap(), &blobs_dir))
+            .await
+            .unwrap_or_else(|err| Err(io::Error::other(err)))
+    }
+}
+
+fn blob_path(blobs_dir: &Path, hash: &str) -> PathBuf {
+    // spread out over subdirectories, so none of them grows too large
+    blobs_dir.join(&hash[..2]).join(hash)
+}
+
+/// Deletes the blob for `hash` unless some path still points at it.
+fn release(index: &Connection, blobs_dir: &Path, hash: &str) -> io::Result<()> {
+    let references: i64 = index
+        .query_row(
+            "SELECT COUNT(*) FROM files WHERE hash = ?1",
+            [hash],
+            |row| row.get(0),
+        )
+        .map_err(io::Error::other)?;
+
+    if references == 0 {
+        match fs::remove_file(blob_path(blobs_dir, hash)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// The path as it's kept in the index, with `/` between the segments.
+fn index_key(path: &Path) -> Option<String> {
+    let parts = path
+        .iter()
+        .map(|s| s.to_str())
+        .collect::<Option<Vec<_>>>()?;
+    Some(parts.join("/"))
+}
+
+/// Bounds of the range in the index holding everything under `dir`, since `0` comes right
+/// after `/`.
+fn dir_range(dir: &str) -> (String, String) {
+    if dir.is_empty() {
+        (String::new(), char::MAX.to_string())
+    } else {
+        (format!("{dir}/"), format!("{dir}0"))
+    }
+}
+
+fn invalid_path() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "invalid file name or path")
+}
+
+impl FileStorageCore for CasFileStore {
+    async fn exists(&self, path: &Path) -> bool {
+        self.get_file(path).await.is_some()
+    }
+
+    async fn get_file(&self, path: &Path) -> Option<Arc<StoredFile>> {
+        let key = index_key(&file_key(path).ok()?)?;
+
+        let handles = Arc::clone(&self.handles);
+        let file = self.with_index(move |index, blobs_dir| {
+            let metadata = index
+                .query_row(
+                    "SELECT hash, size_bytes, created_at, modified_at, scan FROM files
+                        WHERE path = ?1",
+                    [&key],
+                    |row| {
+                        Ok(FileMetadata {
+                            hash: row.get(0)?,
+                            size_bytes: row.get::<_, i64>(1)? as u64,
+                            algorithm: HashAlgorithm::Sha256,
+                            created_at: row
+                                .get::<_, Option<i64>>(2)?
+                                .and_then(DateTime::from_timestamp_millis),
+                            modified_at: row
+                                .get::<_, Option<i64>>(3)?
+                                .and_then(DateTime::from_timestamp_millis),
+                            scan: row
+                                .get::<_, Option<String>>(4)?
+                                .and_then(|scan| serde_json::from_str(&scan).ok()),
+                            pending: false,
+                        })
+                    },
+                )
+                .optional()
+                .inspect_err(|err| log::error!("Error looking up '{key}' in the index: {err}"))
+                .ok()
+                .flatten();
+            let Some(metadata) = metadata else {
+                return Ok(None);
+            };
+
+            let blob_path = blob_path(blobs_dir, &metadata.hash);
+            match FsFile::with_metadata(&blob_path, metadata, &handles) {
+                Ok(file) => Ok(Some(file)),
+                Err(err) => {
+                    log::error!("Blob for '{key}' is missing: {err}");
+                    Ok(None)
+                }
+            }
+        });
+        let file = file.await.ok()??;
+        Some(Arc::new(StoredFile::Filesystem(file)))
+    }
+
+    async fn stat(&self, path: &Path) -> Option<Entry> {
+        let key = index_key(&relative_key(path).ok()?)?;
+        if key.is_empty() {
+            return Some(Entry::Dir);
+        }
+
+        if let Some(file) = self.get_file(path).await {
+            return Some(Entry::File(file));
+        }
+
+        // directories only exist as the parents of the files in them
+        let (start, end) = dir_range(&key);
+        let is_dir = self.with_index(|index, _| {
+            Ok(index
+                .query_row(
+                    "SELECT EXISTS (SELECT 1 FROM files WHERE path >= ?1 AND path < ?2)",
+                    [start, end],
+                    |row| row.get(0),
+                )
+                .unwrap_or(false))
+        });
+        is_dir.await.unwrap_or(false).then_some(Entry::Dir)
+    }
+
+    async fn stage_upload(
+        &self,
+        path: &Path,
+        mut stream: ByteStream<'_>,
+        size_hint: Option<u64>,
+    ) -> io::Result<StagedUpload<'_>> {
+        let key = index_key(&file_key(path)?).ok_or_else(invalid_path)?;
+
+        let temp_path = self
+            .blobs_dir
+            .join(format!("{}{UPLOAD_FILE_EXT}", Uuid::new_v4().simple()));
+        let mut staged = CasStagedUpload {
+            key,
+            temp_path,
+            metadata: FileMetadata::default(),
+        };
+
+        let mut file = tokio::fs::File::create(&staged.temp_path).await?;
+        if let Some(size_bytes) = size_hint {
+            preallocate(&file, size_bytes)?;
+        }
+        // files are laid out by their sha256, whatever the config says
+        let mut hasher = BackgroundHasher::spawn(HashAlgorithm::Sha256);
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            // handed off first, so it's hashed while it's being written
+            hasher.update(chunk.clone()).await?;
+            file.write_all(&chunk).await?;
+        }
+        // writes may still be in flight until flushed, the blob is renamed once committed
+        file.flush().await?;
+
+        let now = Utc::now();
+        staged.metadata = FileMetadata {
+            created_at: Some(now),
+            modified_at: Some(now),
+            ..hasher.finish().await?
+        };
+        Ok(StagedUpload::ContentAddressed(staged))
+    }
+
+    async fn commit_upload(&self, staged: StagedUpload<'_>) -> io::Result<FileMetadata> {
+        let StagedUpload::ContentAddressed(staged) = staged else {
+            return Err(io::Error::other("upload was staged by a different store"));
+        };
+
+        let (key, temp_path) = (staged.key.clone(), staged.temp_path.clone());
+        let mut metadata = staged.metadata.clone();
+        self.with_index(move |index, blobs_dir| {
+            // the same contents might already be stored, the upload is thrown away then
+            let blob_path = blob_path(blobs_dir, &metadata.hash);
+            if !blob_path.is_file() {
+                fs::create_dir_all(blob_path.parent().unwrap())?;
+                fs::rename(&temp_path, &blob_path)?;
+            }
+
+            let tx = index.transaction().map_err(io::Error::other)?;
+            let replaced: Option<(String, Option<i64>)> = tx
+                .query_row(
+                    "SELECT hash, created_at FROM files WHERE path = ?1",
+                    [&key],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .optional()
+                .map_err(io::Error::other)?;
+            if let Some(created_at) = replaced
+                .as_ref()
+                .and_then(|(_, at)| at.and_then(DateTime::from_timestamp_millis))
+            {
+                metadata.created_at = Some(created_at);
+            }
+            tx.execute(
+                "INSERT INTO files (path, hash, size_bytes, created_at, modified_at, scan)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                    ON CONFLICT (path) DO UPDATE SET
+                        hash = ?2, size_bytes = ?3, created_at = ?4, modified_at = ?5, scan = ?6",
+                params![
+                    key,
+                    metadata.hash,
+                    metadata.size_bytes as i64,
+                    metadata.created_at.map(|at| at.timestamp_millis()),
+                    metadata.modified_at.map(|at| at.timestamp_millis()),
+                    metadata
+                        .scan
+                        .map(|scan| serde_json::to_string(&scan))
+                        .transpose()?,
+                ],
+            )
+            .map_err(io::Error::other)?;
+            tx.commit().map_err(io::Error::other)?;
+
+            if let Some((replaced, _)) = replaced
+                && replaced != metadata.hash
+            {
+                release(index, blobs_dir, &replaced)?;
+            }
+
+            Ok(metadata)
+        })
+        .await
+    }
+
+    async fn remove(&self, path: &Path) -> io::Result<()> {
+        let key = index_key(&file_key(path)?).ok_or_else(invalid_path)?;
+
+        self.with_index(move |index, blobs_dir| {
+            let removed: Option<String> = index
+                .query_row(
+                    "DELETE FROM files WHERE path = ?1 RETURNING hash",
+                    [&key],
+                    |row| row.get(0),
+                )
+                .optional()
+                .map_err(io::Error::other)?;
+
+            if let Some(hash) = removed {
+                release(index, blobs_dir, &hash)?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list(&self, dir: &Path) -> io::Result<Vec<DirEntry>> {
+        let dir = index_key(&relative_key(dir)?).ok_or_else(invalid_path)?;
+        let (start, end) = dir_range(&dir);
+
+        let range = (start.clone(), end);
+        let rows = self.with_index(move |index, _| {
+            let mut statement = index
+                .prepare(
+                    "SELECT path, size_bytes, modified_at FROM files
+                        WHERE path >= ?1 AND path < ?2 ORDER BY path",
+                )
+                .map_err(io::Error::other)?;
+            let rows = statement
+                .query_map([&range.0, &range.1], |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, i64>(1)? as u64,
+                        row.get::<_, Option<i64>>(2)?,
+                    ))
+                })
+                .map_err(io::Error::other)?;
+            rows.collect::<Result<Vec<_>, _>>()
+                .map_err(io::Error::other)
+        });
+
+        let mut entries: Vec<DirEntry> = Vec::new();
+        for (path, size_bytes, modified_at) in rows.await? {
+            let rest = &path[start.len()..];
+
+            let entry = match rest.split_once('/') {
+                Some((name, _)) => DirEntry {
+                    name: name.to_string(),
+                    kind: EntryKind::Dir,
+                    size_bytes: None,
+                    modified_at: None,
+                },
+                None => DirEntry {
+                    name: rest.to_string(),
+                    kind: EntryKind::File,
+                    size_bytes: Some(size_bytes),
+                    modified_at: modified_at.and_then(DateTime::from_timestamp_millis),
+                },
+            };
+
+            // every file in a subdirectory comes up, it only needs listing once
+            if entries.last().is_none_or(|last| last.name != entry.name) {
+                entries.push(entry);
+            }
+        }
+
+        if entries.is_empty() && !dir.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "directory does not exist",
+            ));
+        }
+
+        Ok(entries)
+    }
+}
+
+/// An upload written to a temporary blob, which becomes the real one or is thrown away
+/// when committed.
+pub struct CasStagedUpload {
+    key: String,
+    temp_path: PathBuf,
+    pub(super) metadata: FileMetadata,
+}
+
+impl Drop for CasStagedUpload {
+    fn drop(&mut self) {
+        // already gone if the upload was committed as a new blob
+        let _ = fs::remove_file(&self.temp_path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::web::Bytes;
+    use futures::stream;
+
+    use super::*;
+    use crate::fixtures::temp_dir;
+
+    async fn upload(store: &CasFileStore, path: &str, contents: &'static [u8]) -> FileMetadata {
+        let stream = stream::iter([Ok(Bytes::from_static(contents))]).boxed_local();
+        let staged = store
+            .stage_upload(Path::new(path), stream, None)
+            .await
+            .unwrap();
+        store.commit_upload(staged).await.unwrap()
+    }
+
+    #[actix_web::test]
+    async fn blobs_are_shared_and_released_with_their_last_path() {
+        let dir = temp_dir();
+        let store = CasFileStore::open(&dir).unwrap();
+
+        let same = upload(&store, "a.txt", b"same").await;
+        upload(&store, "b/c.txt", b"same").await;
+        let blob = store.blob_path(&same.hash);
+        assert!(blob.is_file());
+
+        store.remove(Path::new("a.txt")).await.unwrap();
+        assert!(blob.is_file());
+        assert!(store.get_file(Path::new("b/c.txt")).await.is_some());
+
+        // replacing the contents releases the old blob as well
+        let other = upload(&store, "b/c.txt", b"other").await;
+        assert!(!blob.is_file());
+        assert!(store.blob_path(&other.hash).is_file());
+
+        store.remove(Path::new("b/c.txt")).await.unwrap();
+        assert!(!store.blob_path(&other.hash).is_file());
+        assert!(store.list(Path::new("")).await.unwrap().is_empty());
+
+        drop(store);
+    }
+}
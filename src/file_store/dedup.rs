@@ -0,0 +1,275 @@
+use std::{
+    collections::HashMap,
+    io::{self, SeekFrom},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::{self, File},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    sync::watch,
+};
+
+use crate::file_store::FileMetadata;
+
+/// How much of an upload is held back to match it against the ones already in flight,
+/// anything shorter isn't worth deduplicating.
+pub const PREFIX_LEN: usize = 64 * 1024;
+
+// a follower that received everything waits this long for its leader to finish, before
+// giving up and writing its own copy
+const LEADER_WAIT: Duration = Duration::from_secs(30);
+
+#[derive(Clone)]
+enum Outcome {
+    Pending,
+    Failed,
+    Finished {
+        metadata: FileMetadata,
+        path: PathBuf,
+    },
+}
+
+struct InFlight {
+    temp_path: PathBuf,
+    outcome: watch::Receiver<Outcome>,
+}
+
+/// Uploads currently being written, keyed by a hash of their first [`PREFIX_LEN`] bytes.
+#[derive(Default)]
+pub struct InFlightUploads {
+    uploads: Mutex<HashMap<String, InFlight>>,
+}
+
+impl InFlightUploads {
+    /// Decides how an upload starting with `prefix` gets written. The first upload with a
+    /// given prefix leads and writes to `temp_path`, later ones follow it for as long as
+    /// their bytes keep matching.
+    pub fn claim(&self, prefix: &[u8], temp_path: &Path) -> Claim<'_> {
+        let key = format!("{:x}", Sha256::digest(&prefix[..PREFIX_LEN]));
+        let mut uploads = self.uploads.lock().unwrap();
+
+        if let Some(leader) = uploads.get(&key) {
+            // the leader might have just finished and moved its file away, opened right away
+            // while holding the lock so it can't in between
+            return match std::fs::File::open(&leader.temp_path) {
+                Ok(leader_file) => Claim::Follower(Follower {
+                    leader_file: File::from_std(leader_file),
+                    outcome: leader.outcome.clone(),
+                }),
+                Err(_) => Claim::Alone,
+            };
+        }
+
+        let (sender, outcome) = watch::channel(Outcome::Pending);
+        uploads.insert(
+            key.clone(),
+            InFlight {
+                temp_path: temp_path.to_path_buf(),
+                outcome,
+            },
+        );
+
+        Claim::Leader(Leader {
+            uploads: self,
+            key,
+            sender,
+        })
+    }
+}
+
+pub enum Claim<'a> {
+    Leader(Leader<'a>),
+    Follower(Follower),
+    Alone,
+}
+
+/// Held by the upload others may follow, letting them know how it went once dropped.
+pub struct Leader<'a> {
+    uploads: &'a InFlightUploads,
+    key: String,
+    sender: watch::Sender<Outcome>,
+}
+
+impl Leader<'_> {
+    /// Marks the upload as committed to `path`, to be linked to by any identical followers.
+    pub fn finish(self, metadata: &FileMetadata, path: &Path) {
+        self.sender.send_replace(Outcome::Finished {
+            metadata: metadata.clone(),
+            path: path.to_path_buf(),
+        });
+    }
+}
+
+impl Drop for Leader<'_> {
+    fn drop(&mut self) {
+        self.uploads.uploads.lock().unwrap().remove(&self.key);
+        self.sender.send_if_modified(|outcome| {
+            let pending = matches!(outcome, Outcome::Pending);
+            if pending {
+                *outcome = Outcome::Failed;
+            }
+            pending
+        });
+    }
+}
+
+pub struct Follower {
+    leader_file: File,
+    outcome: watch::Receiver<Outcome>,
+}
+
+/// Where the bytes of an upload go, either to its own file or nowhere while they're
+/// identical to what a leader has already written.
+pub enum Sink {
+    File(File),
+    Shadow { follower: Follower, matched: u64 },
+}
+
+impl Sink {
+    pub async fn write(&mut self, bytes: &[u8], temp_path: &Path) -> io::Result<()> {
+        match self {
+            Sink::File(file) => file.write_all(bytes).await,
+            Sink::Shadow { follower, matched } => {
+                let mut leader_bytes = vec![0; bytes.len()];
+                // the leader not having written this far yet counts as a mismatch too
+                let same = follower
+                    .leader_file
+                    .read_exact(&mut leader_bytes)
+                    .await
+                    .is_ok()
+                    && leader_bytes == bytes;
+
+                if same {
+                    *matched += bytes.len() as u64;
+                    return Ok(());
+                }
+
+                let mut file = follower.copy_matched(*matched, temp_path).await?;
+                file.write_all(bytes).await?;
+                *self = Sink::File(file);
+                Ok(())
+            }
+        }
+    }
+
+    /// Makes sure `temp_path` holds the complete upload, linking to the leader's file when
+    /// it turned out identical.
+    pub async fn finish(self, metadata: &FileMetadata, temp_path: &Path) -> io::Result<()> {
+        let (mut follower, matched) = match self {
+            // writes may still be in flight until flushed, the file is renamed right after
+            Sink::File(mut file) => return file.flush().await,
+            Sink::Shadow { follower, matched } => (follower, matched),
+        };
+
+        if let Some(leader_path) = follower.identical_leader(metadata).await {
+            // files are only ever replaced by renaming over them, never written in place,
+            // so sharing the same data between both paths is safe. Another upload may have
+            // been renamed over the leader's path since, so the link only stands if it's
+            // still the file whose bytes were compared.
+            let leader_file = follower.leader_file.metadata().await?;
+            if fs::hard_link(&leader_path, temp_path).await.is_ok()
+                && fs::metadata(temp_path)
+                    .await
+                    .is_ok_and(|linked| same_file(&linked, &leader_file))
+            {
+                return Ok(());
+            }
+
+            let _ = fs::remove_file(temp_path).await;
+        }
+
+        follower
+            .copy_matched(matched, temp_path)
+            .await?
+            .flush()
+            .await
+    }
+}
+
+impl Follower {
+    async fn identical_leader(&mut self, metadata: &FileMetadata) -> Option<PathBuf> {
+        let wait = self.outcome.wait_for(|o| !matches!(o, Outcome::Pending));
+        let outcome = tokio::time::timeout(LEADER_WAIT, wait).await.ok()?.ok()?;
+
+        match &*outcome {
+            Outcome::Finished {
+                metadata: leader,
+                path,
+            } if leader.hash == metadata.hash && leader.size_bytes == metadata.size_bytes => {
+                Some(path.clone())
+            }
+            _ => None,
+        }
+    }
+
+    /// Writes the first `matched` bytes of the leader's file into a file of our own.
+    async fn copy_matched(&mut self, matched: u64, temp_path: &Path) -> io::Result<File> {
+        let mut file = File::create(temp_path).await?;
+        self.leader_file.seek(SeekFrom::Start(0)).await?;
+
+        let copied = tokio::io::copy(&mut (&mut self.leader_file).take(matched), &mut file).await?;
+        if copied != matched {
+            return Err(io::Error::other("leader upload was truncated"));
+        }
+
+        Ok(file)
+    }
+}
+
+#[cfg(unix)]
+fn same_file(a: &std::fs::Metadata, b: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    (a.dev(), a.ino()) == (b.dev(), b.ino())
+}
+
+// which file is behind a path can't be told from its metadata here, so followers always
+// write their own copy
+#[cfg(windows)]
+fn same_file(_: &std::fs::Metadata, _: &std::fs::Metadata) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::server::HashAlgorithm, file_store::Hasher, fixtures::temp_dir};
+
+    #[actix_web::test]
+    async fn followers_only_link_the_file_they_matched() {
+        let dir = temp_dir();
+        let contents = [vec![b'a'; PREFIX_LEN], b"the rest".to_vec()].concat();
+        let mut hasher = Hasher::new(HashAlgorithm::Sha256);
+        hasher.update(&contents);
+        let metadata = hasher.finish(contents.len() as u64);
+
+        let uploads = InFlightUploads::default();
+        let (leader_temp, follower_temp) = (dir.join("leader"), dir.join("follower"));
+        let Claim::Leader(leader) = uploads.claim(&contents, &leader_temp) else {
+            panic!("the first upload leads");
+        };
+        fs::write(&leader_temp, &contents).await.unwrap();
+        let Claim::Follower(follower) = uploads.claim(&contents, &follower_temp) else {
+            panic!("an identical upload follows");
+        };
+        let mut sink = Sink::Shadow {
+            follower,
+            matched: 0,
+        };
+        sink.write(&contents, &follower_temp).await.unwrap();
+
+        let path = dir.join("file");
+        fs::rename(&leader_temp, &path).await.unwrap();
+        leader.finish(&metadata, &path);
+        // replaced by one just as long before the follower gets to it
+        let other = [vec![b'a'; PREFIX_LEN], b"the best".to_vec()].concat();
+        fs::write(dir.join("other"), other).await.unwrap();
+        fs::rename(dir.join("other"), &path).await.unwrap();
+
+        sink.finish(&metadata, &follower_temp).await.unwrap();
+        assert_eq!(fs::read(&follower_temp).await.unwrap(), contents);
+    }
+}
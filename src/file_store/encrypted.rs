@@ -0,0 +1,369 @@
+use std::{cell::RefCell, io, path::Path, rc::Rc, sync::Arc, time::Duration};
+
+use crate::{
+    config::server::HashAlgorithm,
+    file_store::{
+        ByteStream, DirEntry, Entry, EntryKind, FileMetadata, FileStorageCore, FileStore, GcReport,
+        Hasher, PartialUploads, StagedUpload, StoredFile, StoredFileCore, Version,
+    },
+};
+use actix_web::web::Bytes;
+use aes_gcm::{
+    Aes256Gcm, KeyInit,
+    aead::{
+        OsRng,
+        rand_core::RngCore,
+        stream::{DecryptorBE32, EncryptorBE32},
+    },
+};
+use async_stream::try_stream;
+use base64::{Engine, prelude::BASE64_STANDARD};
+use futures::StreamExt;
+
+/// Random per file and written ahead of the contents, the rest of each segment's nonce is
+/// its counter and whether it's the last one.
+const NONCE_PREFIX_LEN: usize = 7;
+const CHUNK_LEN: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+const SEGMENT_LEN: usize = CHUNK_LEN + TAG_LEN;
+
+fn cipher(key: &str) -> io::Result<Aes256Gcm> {
+    let invalid_key = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "encryption key must be 32 bytes encoded as base64",
+        )
+    };
+
+    let key = BASE64_STANDARD
+        .decode(key.trim())
+        .map_err(|_| invalid_key())?;
+    Aes256Gcm::new_from_slice(&key).map_err(|_| invalid_key())
+}
+
+/// Encrypts contents with AES-256-GCM before they reach the wrapped store, and decrypts them
+/// on the way out. Files are split into segments that are authenticated on their own, so
+/// they can be streamed, while still detecting segments being reordered or cut off.
+///
+/// The metadata kept next to each file describes the plaintext, so hashes and sizes are the
+/// same as they'd be without encryption.
+pub struct EncryptedFileStore {
+    inner: Box<FileStore>,
+    // holds the expanded key, which is rather large to be copying around
+    cipher: Arc<Aes256Gcm>,
+    hash_algorithm: HashAlgorithm,
+}
+
+impl EncryptedFileStore {
+    /// `key` is 32 bytes encoded as base64, e.g. from `openssl rand -base64 32`.
+    pub fn new(inner: FileStore, key: &str) -> io::Result<Self> {
+        Ok(EncryptedFileStore {
+            inner: Box::new(inner),
+            cipher: Arc::new(cipher(key)?),
+            hash_algorithm: HashAlgorithm::default(),
+        })
+    }
+
+    /// What the plaintext is hashed with, since the wrapped store only sees the ciphertext.
+    pub fn with_hash_algorithm(mut self, algorithm: HashAlgorithm) -> Self {
+        self.hash_algorithm = algorithm;
+        self
+    }
+
+    /// Whether `key` would be accepted, without needing a store to wrap.
+    pub fn check_key(key: &str) -> io::Result<()> {
+        cipher(key).map(|_| ())
+    }
+
+    pub fn purge_expired_cache(&self) -> usize {
+        self.inner.purge_expired_cache()
+    }
+
+    pub async fn collect_garbage(&self) -> io::Result<GcReport> {
+        Box::pin(self.inner.collect_garbage()).await
+    }
+
+    pub async fn partial_uploads(
+        &self,
+        idle_timeout: Option<Duration>,
+    ) -> io::Result<PartialUploads> {
+        Box::pin(self.inner.partial_uploads(idle_timeout)).await
+    }
+
+    pub async fn retry_pending_metadata(&self) -> usize {
+        Box::pin(self.inner.retry_pending_metadata()).await
+    }
+
+    /// Versions are kept as they were stored, along with the metadata of their plaintext.
+    pub async fn versions(&self, path: &Path) -> io::Result<Vec<Version>> {
+        Box::pin(self.inner.versions(path)).await
+    }
+
+    pub async fn get_version(
+        &self,
+        path: &Path,
+        version: u32,
+    ) -> io::Result<Option<Arc<StoredFile>>> {
+        let file = Box::pin(self.inner.get_version(path, version)).await?;
+        Ok(file.map(|file| self.wrap(file)))
+    }
+
+    fn wrap(&self, file: Arc<StoredFile>) -> Arc<StoredFile> {
+        Arc::new(StoredFile::Encrypted(EncryptedFile {
+            inner: file,
+            cipher: Arc::clone(&self.cipher),
+        }))
+    }
+}
+
+impl FileStorageCore for EncryptedFileStore {
+    async fn exists(&self, path: &Path) -> bool {
+        Box::pin(self.inner.exists(path)).await
+    }
+
+    async fn get_file(&self, path: &Path) -> Option<Arc<StoredFile>> {
+        let file = Box::pin(self.inner.get_file(path)).await?;
+        Some(self.wrap(file))
+    }
+
+    async fn stat(&self, path: &Path) -> Option<Entry> {
+        match Box::pin(self.inner.stat(path)).await? {
+            Entry::File(file) => Some(Entry::File(self.wrap(file))),
+            Entry::Dir => Some(Entry::Dir),
+        }
+    }
+
+    async fn stage_upload(
+        &self,
+        path: &Path,
+        stream: ByteStream<'_>,
+        _size_hint: Option<u64>,
+    ) -> io::Result<StagedUpload<'_>> {
+        let plaintext = Rc::new(RefCell::new((Hasher::new(self.hash_algorithm), 0)));
+        let encrypted = encrypt_stream(
+            Aes256Gcm::clone(&self.cipher),
+            stream,
+            Rc::clone(&plaintext),
+        );
+
+        // what's stored is larger than what the client declared, by however many chunks it is
+        let mut staged = Box::pin(self.inner.stage_upload(path, encrypted, None)).await?;
+
+        // the wrapped store only saw the ciphertext, what it measured isn't what gets served
+        let (hasher, size_bytes) = plaintext.replace((Hasher::new(self.hash_algorithm), 0));
+        *staged.metadata_mut() = hasher.finish(size_bytes);
+        Ok(staged)
+    }
+
+    async fn commit_upload(&self, staged: StagedUpload<'_>) -> io::Result<FileMetadata> {
+        Box::pin(self.inner.commit_upload(staged)).await
+    }
+
+    async fn remove(&self, path: &Path) -> io::Result<()> {
+        Box::pin(self.inner.remove(path)).await
+    }
+
+    async fn list(&self, dir: &Path) -> io::Result<Vec<DirEntry>> {
+        let mut entries = Box::pin(self.inner.list(dir)).await?;
+
+        // some stores list the size of what they hold, which is the ciphertext
+        for entry in &mut entries {
+            if entry.kind == EntryKind::File {
+                let file = Box::pin(self.inner.get_file(&dir.join(&entry.name))).await;
+                entry.size_bytes = file.map(|file| file.metadata().size_bytes);
+            }
+        }
+
+        Ok(entries)
+    }
+}
+
+pub struct EncryptedFile {
+    inner: Arc<StoredFile>,
+    cipher: Arc<Aes256Gcm>,
+}
+
+impl StoredFileCore for EncryptedFile {
+    fn metadata(&self) -> &FileMetadata {
+        self.inner.metadata()
+    }
+
+    fn bytes_stream(&self) -> ByteStream<'static> {
+        decrypt_stream(Aes256Gcm::clone(&self.cipher), self.inner.bytes_stream())
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn encrypt_stream<'a>(
+    cipher: Aes256Gcm,
+    mut source: ByteStream<'a>,
+    plaintext: Rc<RefCell<(Hasher, u64)>>,
+) -> ByteStream<'a> {
+    try_stream! {
+        let mut nonce_prefix = [0; NONCE_PREFIX_LEN];
+        OsRng.fill_bytes(&mut nonce_prefix);
+        let mut encryptor = EncryptorBE32::from_aead(cipher, (&nonce_prefix).into());
+        yield Bytes::copy_from_slice(&nonce_prefix);
+
+        let mut buffer = Vec::new();
+        while let Some(chunk) = source.next().await {
+            let chunk = chunk?;
+            {
+                let (hasher, size_bytes) = &mut *plaintext.borrow_mut();
+                hasher.update(&chunk);
+                *size_bytes += chunk.len() as u64;
+            }
+            buffer.extend_from_slice(&chunk);
+
+            // a full chunk is held back until more follows it, the last one is sealed differently
+            while buffer.len() > CHUNK_LEN {
+                let segment = encryptor
+                    .encrypt_next(&buffer[..CHUNK_LEN])
+                    .map_err(|_| io::Error::other("failed to encrypt upload"))?;
+                buffer.drain(..CHUNK_LEN);
+                yield Bytes::from(segment);
+            }
+        }
+
+        let segment = encryptor
+            .encrypt_last(&buffer[..])
+            .map_err(|_| io::Error::other("failed to encrypt upload"))?;
+        yield Bytes::from(segment);
+    }
+    .boxed_local()
+}
+
+fn decrypt_stream(cipher: Aes256Gcm, mut source: ByteStream<'static>) -> ByteStream<'static> {
+    try_stream! {
+        let mut buffer = Vec::new();
+        let mut is_done = false;
+
+        while buffer.len() < NONCE_PREFIX_LEN {
+            match source.next().await {
+                Some(chunk) => buffer.extend_from_slice(&chunk?),
+                None => Err(invalid_data("encrypted file is truncated"))?,
+            }
+        }
+        let nonce_prefix: [u8; NONCE_PREFIX_LEN] = buffer[..NONCE_PREFIX_LEN].try_into().unwrap();
+        let mut decryptor = DecryptorBE32::from_aead(cipher, (&nonce_prefix).into());
+        buffer.drain(..NONCE_PREFIX_LEN);
+
+        loop {
+            while !is_done && buffer.len() <= SEGMENT_LEN {
+                match source.next().await {
+                    Some(chunk) => buffer.extend_from_slice(&chunk?),
+                    None => is_done = true,
+                }
+            }
+
+            if is_done && buffer.len() <= SEGMENT_LEN {
+                let chunk = decryptor
+                    .decrypt_last(&buffer[..])
+                    .map_err(|_| invalid_data("failed to decrypt file, is the key right?"))?;
+                yield Bytes::from(chunk);
+                break;
+            }
+
+            let chunk = decryptor
+                .decrypt_next(&buffer[..SEGMENT_LEN])
+                .map_err(|_| invalid_data("failed to decrypt file, is the key right?"))?;
+            buffer.drain(..SEGMENT_LEN);
+            yield Bytes::from(chunk);
+        }
+    }
+    .boxed_local()
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::rt::System;
+    use futures::{TryStreamExt, stream};
+    use sha2::{Digest, Sha256};
+
+    use super::*;
+    use crate::file_store::MemoryFileStore;
+
+    const KEY: &str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+
+    async fn read(file: &StoredFile) -> io::Result<Vec<u8>> {
+        let chunks: Vec<Bytes> = file.bytes_stream().try_collect().await?;
+        Ok(chunks.concat())
+    }
+
+    #[test]
+    fn contents_round_trip_across_segments() {
+        let store =
+            EncryptedFileStore::new(FileStore::Memory(MemoryFileStore::default()), KEY).unwrap();
+
+        System::new().block_on(async {
+            for len in [0, 1, CHUNK_LEN, CHUNK_LEN + 1, 3 * CHUNK_LEN + 7] {
+                let contents: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+                // uneven chunks, so segments don't line up with what's received
+                let chunks = contents
+                    .chunks(1000)
+                    .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+                    .collect::<Vec<_>>();
+
+                let staged = store
+                    .stage_upload(
+                        Path::new("file.bin"),
+                        stream::iter(chunks).boxed_local(),
+                        None,
+                    )
+                    .await
+                    .unwrap();
+                let metadata = store.commit_upload(staged).await.unwrap();
+                assert_eq!(metadata.size_bytes, len as u64);
+                assert_eq!(
+                    metadata.hash,
+                    FileMetadata::hash_to_hex(Sha256::new_with_prefix(&contents))
+                );
+
+                let stored = store.inner.get_file(Path::new("file.bin")).await.unwrap();
+                let ciphertext = read(&stored).await.unwrap();
+                assert_ne!(ciphertext, contents);
+
+                let file = store.get_file(Path::new("file.bin")).await.unwrap();
+                assert_eq!(read(&file).await.unwrap(), contents);
+            }
+        });
+    }
+
+    #[test]
+    fn wrong_key_or_truncation_fails_to_decrypt() {
+        let other_key = "HxwdHhobGBkaFhQVExIREA8ODQwLCgkIBwYFBAMCAQA=";
+        let store =
+            EncryptedFileStore::new(FileStore::Memory(MemoryFileStore::default()), KEY).unwrap();
+
+        System::new().block_on(async {
+            let contents = vec![7; 2 * CHUNK_LEN + 5];
+            let chunks = stream::iter([Ok(Bytes::from(contents))]).boxed_local();
+            let staged = store
+                .stage_upload(Path::new("file.bin"), chunks, None)
+                .await
+                .unwrap();
+            store.commit_upload(staged).await.unwrap();
+
+            let stored = store.inner.get_file(Path::new("file.bin")).await.unwrap();
+            let ciphertext = read(&stored).await.unwrap();
+
+            let cipher = Aes256Gcm::new_from_slice(&BASE64_STANDARD.decode(other_key).unwrap());
+            let wrong_key = decrypt_stream(
+                cipher.unwrap(),
+                stream::iter([Ok(Bytes::from(ciphertext.clone()))]).boxed_local(),
+            );
+            assert!(wrong_key.try_collect::<Vec<_>>().await.is_err());
+
+            // dropping the last segment leaves a full one that wasn't sealed as the last
+            let truncated =
+                Bytes::copy_from_slice(&ciphertext[..NONCE_PREFIX_LEN + 2 * SEGMENT_LEN]);
+            let cipher = Aes256Gcm::new_from_slice(&BASE64_STANDARD.decode(KEY).unwrap());
+            let truncated =
+                decrypt_stream(cipher.unwrap(), stream::iter([Ok(truncated)]).boxed_local());
+            assert!(truncated.try_collect::<Vec<_>>().await.is_err());
+        });
+    }
+}
@@ -0,0 +1,89 @@
+use std::{
+    hash::Hash,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use crate::{
+    cache_map::CacheMap,
+    config::server::MemoryCache,
+    file_store::{StoredFile, StoredFileCore},
+};
+
+/// Files that were looked up, which never holds on to one a write has made stale.
+///
+/// Every write bumps the version, and a lookup only gets cached if no write happened since
+/// it started, otherwise a lookup racing a write could cache what it read before the write.
+pub struct FileCache<K: Hash + Eq + Clone> {
+    entries: Mutex<CacheMap<K, Arc<StoredFile>>>,
+    version: AtomicU64,
+    is_enabled: bool,
+    max_size_bytes: u64,
+}
+
+impl<K: Hash + Eq + Clone> FileCache<K> {
+    pub fn new(config: &MemoryCache) -> Self {
+        let entries = CacheMap::new()
+            .with_ttl(Duration::from_secs(config.cache_time_secs))
+            .with_max_size(config.max_files_cached);
+
+        FileCache {
+            entries: Mutex::new(entries),
+            version: AtomicU64::new(0),
+            is_enabled: config.enabled && config.max_files_cached > 0,
+            max_size_bytes: config.max_size_bytes,
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<Arc<StoredFile>> {
+        if !self.is_enabled {
+            return None;
+        }
+        self.entries.lock().unwrap().get(key).cloned()
+    }
+
+    /// The version to hand to [`FileCache::insert`], taken before looking the file up.
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::Acquire)
+    }
+
+    /// Caches `file` unless something was written since `version` was taken.
+    pub fn insert(&self, key: K, file: Arc<StoredFile>, version: u64) {
+        if !self.is_enabled || file.metadata().size_bytes > self.max_size_bytes {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        // checked while holding the lock, so a write can't land between the check and insert
+        if self.version() == version {
+            entries.insert(key, file);
+        }
+    }
+
+    /// Drops whatever is cached for `key`, to be called once it has been written to.
+    pub fn invalidate(&self, key: &K) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.remove(key);
+        self.version.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Drops everything cached, for when there's no telling which entries went stale.
+    pub fn clear(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.clear();
+        self.version.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Drops the entries whose key `keep` turns down. Only meant for entries that couldn't be
+    /// looked up anymore anyway, so lookups racing it aren't held to the version.
+    pub fn retain(&self, keep: impl FnMut(&K) -> bool) -> usize {
+        self.entries.lock().unwrap().retain(keep)
+    }
+
+    pub fn remove_expired(&self) -> usize {
+        self.entries.lock().unwrap().remove_expired()
+    }
+}
@@ -0,0 +1,256 @@
+use std::{
+    collections::BTreeMap,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use crate::{
+    config::server::HashAlgorithm,
+    file_store::{
+        BackgroundHasher, ByteStream, DirEntry, Entry, EntryKind, FileMetadata, FileStorageCore,
+        StagedUpload, StoredFile, StoredFileCore, file_key, is_hidden, relative_key,
+    },
+};
+use actix_web::web::Bytes;
+use futures::{StreamExt, stream};
+
+/// Keeps every file in memory, so nothing survives a restart.
+#[derive(Default)]
+pub struct MemoryFileStore {
+    files: RwLock<BTreeMap<PathBuf, Arc<StoredFile>>>,
+    hash_algorithm: HashAlgorithm,
+}
+
+impl MemoryFileStore {
+    pub fn with_hash_algorithm(mut self, algorithm: HashAlgorithm) -> Self {
+        self.hash_algorithm = algorithm;
+        self
+    }
+}
+
+impl FileStorageCore for MemoryFileStore {
+    async fn exists(&self, path: &Path) -> bool {
+        self.get_file(path).await.is_some()
+    }
+
+    async fn get_file(&self, path: &Path) -> Option<Arc<StoredFile>> {
+        let key = file_key(path).ok()?;
+        self.files.read().unwrap().get(&key).cloned()
+    }
+
+    async fn stat(&self, path: &Path) -> Option<Entry> {
+        let key = relative_key(path).ok()?;
+        if key.as_os_str().is_empty() {
+            return Some(Entry::Dir);
+        }
+        if is_hidden(&key) {
+            return None;
+        }
+
+        let files = self.files.read().unwrap();
+        if let Some(file) = files.get(&key) {
+            return Some(Entry::File(Arc::clone(file)));
+        }
+
+        // directories only exist as the parents of the files in them
+        let is_dir = files
+            .range(key.clone()..)
+            .next()
+            .is_some_and(|(path, _)| path.starts_with(&key));
+        is_dir.then_some(Entry::Dir)
+    }
+
+    async fn stage_upload(
+        &self,
+        path: &Path,
+        mut stream: ByteStream<'_>,
+        _size_hint: Option<u64>,
+    ) -> io::Result<StagedUpload<'_>> {
+        let key = file_key(path)?;
+
+        let mut contents = Vec::new();
+        // hashed on the blocking pool like uploads to disk, a large one would hold up the
+        // worker's other requests otherwise
+        let mut hasher = BackgroundHasher::spawn(self.hash_algorithm);
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            contents.extend_from_slice(&chunk);
+            hasher.update(chunk).await?;
+        }
+
+        let metadata = hasher.finish().await?;
+
+        Ok(StagedUpload::Memory(MemoryStagedUpload {
+            key,
+            contents: Bytes::from(contents),
+            metadata,
+        }))
+    }
+
+    async fn commit_upload(&self, staged: StagedUpload<'_>) -> io::Result<FileMetadata> {
+        let StagedUpload::Memory(mut staged) = staged else {
+            return Err(io::Error::other("upload was staged by a different store"));
+        };
+
+        let mut files = self.files.write().unwrap();
+        staged
+            .metadata
+            .replacing(files.get(&staged.key).map(|file| file.metadata()));
+        let file = MemoryFile {
+            contents: staged.contents,
+            metadata: staged.metadata.clone(),
+        };
+        files.insert(staged.key, Arc::new(StoredFile::Memory(file)));
+
+        Ok(staged.metadata)
+    }
+
+    async fn remove(&self, path: &Path) -> io::Result<()> {
+        let key = file_key(path)?;
+        self.files.write().unwrap().remove(&key);
+        Ok(())
+    }
+
+    async fn list(&self, dir: &Path) -> io::Result<Vec<DirEntry>> {
+        let dir = relative_key(dir)?;
+        let files = self.files.read().unwrap();
+
+        let mut entries: Vec<DirEntry> = Vec::new();
+        for (path, file) in files.range(dir.clone()..) {
+            let Ok(rest) = path.strip_prefix(&dir) else {
+                break;
+            };
+
+            let mut components = rest.iter();
+            let Some(name) = components.next().and_then(|name| name.to_str()) else {
+                continue;
+            };
+
+            let entry = match components.next() {
+                Some(_) => DirEntry {
+                    name: name.to_string(),
+                    kind: EntryKind::Dir,
+                    size_bytes: None,
+                    modified_at: None,
+                },
+                None => DirEntry {
+                    name: name.to_string(),
+                    kind: EntryKind::File,
+                    size_bytes: Some(file.metadata().size_bytes),
+                    modified_at: file.metadata().modified_at,
+                },
+            };
+
+            // every file in a subdirectory comes up, it only needs listing once
+            if entries.last().is_none_or(|last| last.name != entry.name) {
+                entries.push(entry);
+            }
+        }
+
+        if entries.is_empty() && !dir.as_os_str().is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "directory does not exist",
+            ));
+        }
+
+        Ok(entries)
+    }
+}
+
+/// An upload that has been fully buffered but isn't visible yet.
+pub struct MemoryStagedUpload {
+    key: PathBuf,
+    contents: Bytes,
+    pub(super) metadata: FileMetadata,
+}
+
+pub struct MemoryFile {
+    contents: Bytes,
+    metadata: FileMetadata,
+}
+
+impl StoredFileCore for MemoryFile {
+    fn metadata(&self) -> &FileMetadata {
+        &self.metadata
+    }
+
+    fn bytes_stream(&self) -> ByteStream<'static> {
+        stream::iter([Ok(self.contents.clone())]).boxed_local()
+    }
+
+    fn range_stream(&self, start: u64, len: u64) -> ByteStream<'static> {
+        let start = (start as usize).min(self.contents.len());
+        let end = (start + len as usize).min(self.contents.len());
+        stream::iter([Ok(self.contents.slice(start..end))]).boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::rt::System;
+
+    use super::*;
+
+    async fn upload(store: &MemoryFileStore, path: &str) {
+        let stream = stream::iter([Ok(Bytes::from_static(b"contents"))]).boxed_local();
+        let staged = store
+            .stage_upload(Path::new(path), stream, None)
+            .await
+            .unwrap();
+        store.commit_upload(staged).await.unwrap();
+    }
+
+    #[test]
+    fn directories_exist_through_their_files() {
+        let store = MemoryFileStore::default();
+        System::new().block_on(async {
+            upload(&store, "a/b/c.txt").await;
+            upload(&store, "a/d.txt").await;
+            upload(&store, "ab.txt").await;
+
+            assert!(matches!(store.stat(Path::new("a")).await, Some(Entry::Dir)));
+            assert!(matches!(
+                store.stat(Path::new("a/b")).await,
+                Some(Entry::Dir)
+            ));
+            assert!(matches!(
+                store.stat(Path::new("a/d.txt")).await,
+                Some(Entry::File(_))
+            ));
+            assert!(store.stat(Path::new("a/x")).await.is_none());
+
+            let names = |entries: Vec<DirEntry>| -> Vec<_> {
+                entries.into_iter().map(|e| (e.name, e.kind)).collect()
+            };
+            assert_eq!(
+                names(store.list(Path::new("")).await.unwrap()),
+                [
+                    ("a".into(), EntryKind::Dir),
+                    ("ab.txt".into(), EntryKind::File)
+                ]
+            );
+            assert_eq!(
+                names(store.list(Path::new("a")).await.unwrap()),
+                [
+                    ("b".into(), EntryKind::Dir),
+                    ("d.txt".into(), EntryKind::File)
+                ]
+            );
+
+            store.remove(Path::new("a/b/c.txt")).await.unwrap();
+            assert!(store.stat(Path::new("a/b")).await.is_none());
+            assert!(store.list(Path::new("a/b")).await.is_err());
+        });
+    }
+
+    #[test]
+    fn rejects_paths_outside_the_store() {
+        let store = MemoryFileStore::default();
+        let stream = stream::iter([Ok(Bytes::new())]).boxed_local();
+        let result =
+            System::new().block_on(store.stage_upload(Path::new("../a.txt"), stream, None));
+        assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidInput);
+    }
+}
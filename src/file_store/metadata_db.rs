@@ -0,0 +1,192 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use chrono::{DateTime, Utc};
+use rusqlite::{Connection, OptionalExtension, params};
+
+use crate::{
+    config::server::HashAlgorithm,
+    file_store::{FileMetadata, METADATA_FILE_EXT},
+};
+
+/// The metadata of a local directory's files kept in one database, rather than in a
+/// `.metadata.json` next to each of them.
+pub struct MetadataDb {
+    db: Mutex<Connection>,
+}
+
+impl MetadataDb {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        if let Some(parent) = path.as_ref().parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let db = Connection::open(path).map_err(io::Error::other)?;
+        db.execute_batch(
+            "CREATE TABLE IF NOT EXISTS metadata (
+                path TEXT PRIMARY KEY,
+                hash TEXT NOT NULL,
+                size_bytes INTEGER NOT NULL,
+                uploaded_at INTEGER NOT NULL,
+                algorithm TEXT NOT NULL DEFAULT 'sha256',
+                created_at INTEGER,
+                modified_at INTEGER
+            );",
+        )
+        .map_err(io::Error::other)?;
+
+        // databases from before the algorithm was recorded only ever held sha256 hashes, and
+        // ones from before the timestamps or scans were don't know them
+        let added_columns = [
+            ("algorithm", "TEXT NOT NULL DEFAULT 'sha256'"),
+            ("created_at", "INTEGER"),
+            ("modified_at", "INTEGER"),
+            ("scan", "TEXT"),
+        ];
+        for (column, definition) in added_columns {
+            if db
+                .prepare(&format!("SELECT {column} FROM metadata"))
+                .is_err()
+            {
+                db.execute_batch(&format!(
+                    "ALTER TABLE metadata ADD COLUMN {column} {definition};"
+                ))
+                .map_err(io::Error::other)?;
+            }
+        }
+
+        Ok(MetadataDb { db: Mutex::new(db) })
+    }
+
+    pub fn get(&self, key: &str) -> io::Result<Option<FileMetadata>> {
+        let db = self.db.lock().unwrap();
+        db.query_row(
+            "SELECT hash, size_bytes, algorithm, created_at, modified_at, scan FROM metadata
+                WHERE path = ?1",
+            [key],
+            |row| {
+                Ok(FileMetadata {
+                    hash: row.get(0)?,
+                    size_bytes: row.get::<_, i64>(1)? as u64,
+                    algorithm: HashAlgorithm::from_name(&row.get::<_, String>(2)?)
+                        .unwrap_or_default(),
+                    created_at: row
+                        .get::<_, Option<i64>>(3)?
+                        .and_then(DateTime::from_timestamp_millis),
+                    modified_at: row
+                        .get::<_, Option<i64>>(4)?
+                        .and_then(DateTime::from_timestamp_millis),
+                    scan: row
+                        .get::<_, Option<String>>(5)?
+                        .and_then(|scan| serde_json::from_str(&scan).ok()),
+                    pending: false,
+                })
+            },
+        )
+        .optional()
+        .map_err(io::Error::other)
+    }
+
+    pub fn set(&self, key: &str, metadata: &FileMetadata) -> io::Result<()> {
+        let db = self.db.lock().unwrap();
+        db.execute(
+            "INSERT OR REPLACE INTO metadata
+                (path, hash, size_bytes, uploaded_at, algorithm, created_at, modified_at, scan)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                key,
+                metadata.hash,
+                metadata.size_bytes as i64,
+                Utc::now().timestamp(),
+                metadata.algorithm.as_str(),
+                metadata.created_at.map(|at| at.timestamp_millis()),
+                metadata.modified_at.map(|at| at.timestamp_millis()),
+                metadata
+                    .scan
+                    .map(|scan| serde_json::to_string(&scan))
+                    .transpose()?,
+            ],
+        )
+        .map_err(io::Error::other)?;
+        Ok(())
+    }
+
+    pub fn remove(&self, key: &str) -> io::Result<()> {
+        let db = self.db.lock().unwrap();
+        db.execute("DELETE FROM metadata WHERE path = ?1", [key])
+            .map_err(io::Error::other)?;
+        Ok(())
+    }
+
+    /// The key of every file there's metadata for.
+    pub fn keys(&self) -> io::Result<Vec<String>> {
+        let db = self.db.lock().unwrap();
+        let mut statement = db
+            .prepare("SELECT path FROM metadata")
+            .map_err(io::Error::other)?;
+        statement
+            .query_map([], |row| row.get(0))
+            .map_err(io::Error::other)?
+            .collect::<Result<_, _>>()
+            .map_err(io::Error::other)
+    }
+
+    /// Moves the sidecars found under `base_dir` into the database, taking their word over
+    /// whatever it had for the same files. Returns how many there were.
+    pub fn import_sidecars(&self, base_dir: &Path) -> io::Result<usize> {
+        let mut imported = 0;
+        let mut dirs = vec![base_dir.to_path_buf()];
+
+        while let Some(dir) = dirs.pop() {
+            let entries = match fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+
+            for entry in entries {
+                let path = entry?.path();
+                if path.is_dir() {
+                    dirs.push(path);
+                    continue;
+                }
+
+                let Some(file_path) = sidecar_target(&path) else {
+                    continue;
+                };
+                // the file's gone, the sidecar has nothing left to describe
+                if !file_path.is_file() {
+                    fs::remove_file(&path)?;
+                    continue;
+                }
+
+                let metadata: FileMetadata = serde_json::from_slice(&fs::read(&path)?)?;
+                self.set(&key_of(base_dir, &file_path), &metadata)?;
+                fs::remove_file(&path)?;
+                imported += 1;
+            }
+        }
+
+        Ok(imported)
+    }
+}
+
+/// The file a sidecar at `path` belongs to, if it is one.
+pub fn sidecar_target(path: &Path) -> Option<PathBuf> {
+    let name = path.file_name()?.to_str()?;
+    let file_name = name.strip_suffix(METADATA_FILE_EXT)?;
+    Some(path.with_file_name(file_name))
+}
+
+/// The key of the file at `path`, relative to `base_dir` and always separated by `/`.
+pub fn key_of(base_dir: &Path, path: &Path) -> String {
+    path.strip_prefix(base_dir)
+        .unwrap_or(path)
+        .iter()
+        .map(|part| part.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
@@ -0,0 +1,292 @@
+use std::{
+    cmp::Reverse,
+    io,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::file_store::{
+    ByteStream, DirEntry, Entry, EntryKind, FileMetadata, FileStorageCore, FileStore, GcReport,
+    PartialUploads, StagedUpload, StoredFile, Version, relative_key,
+};
+
+/// Several stores each appearing under their own prefix, with the directories leading up to
+/// a prefix existing even when no store has them.
+pub struct MountedFileStore {
+    // longest prefix first, so the first one a path is under is the one it belongs to
+    mounts: Vec<(PathBuf, FileStore)>,
+}
+
+impl MountedFileStore {
+    pub fn new(mounts: Vec<(String, FileStore)>) -> io::Result<Self> {
+        let invalid_prefix = |prefix: &str, reason: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("cannot mount at '{prefix}', {reason}"),
+            )
+        };
+
+        let mut resolved: Vec<(PathBuf, FileStore)> = Vec::new();
+        for (prefix, store) in mounts {
+            let key = relative_key(Path::new(prefix.trim_matches('/')))?;
+            if key.components().next() == Some(Component::Normal("api".as_ref())) {
+                return Err(invalid_prefix(&prefix, "it's taken by the API"));
+            }
+            if resolved.iter().any(|(existing, _)| *existing == key) {
+                return Err(invalid_prefix(
+                    &prefix,
+                    "something is already mounted there",
+                ));
+            }
+            resolved.push((key, store));
+        }
+
+        resolved.sort_by_key(|(prefix, _)| Reverse(prefix.components().count()));
+        Ok(MountedFileStore { mounts: resolved })
+    }
+
+    pub fn purge_expired_cache(&self) -> usize {
+        self.mounts
+            .iter()
+            .map(|(_, store)| store.purge_expired_cache())
+            .sum()
+    }
+
+    pub async fn collect_garbage(&self) -> io::Result<GcReport> {
+        let mut report = GcReport::default();
+        for (_, store) in &self.mounts {
+            report += Box::pin(store.collect_garbage()).await?;
+        }
+        Ok(report)
+    }
+
+    pub async fn partial_uploads(
+        &self,
+        idle_timeout: Option<Duration>,
+    ) -> io::Result<PartialUploads> {
+        let mut report = PartialUploads::default();
+        for (_, store) in &self.mounts {
+            report += Box::pin(store.partial_uploads(idle_timeout)).await?;
+        }
+        Ok(report)
+    }
+
+    pub async fn retry_pending_metadata(&self) -> usize {
+        let mut pending = 0;
+        for (_, store) in &self.mounts {
+            pending += Box::pin(store.retry_pending_metadata()).await;
+        }
+        pending
+    }
+
+    /// The index of the mount `path` is under, along with the rest of the path within it.
+    fn resolve(&self, path: &Path) -> io::Result<Option<(usize, PathBuf)>> {
+        let key = relative_key(path)?;
+        Ok(self.mounts.iter().enumerate().find_map(|(i, (prefix, _))| {
+            let rest = key.strip_prefix(prefix).ok()?;
+            Some((i, rest.to_path_buf()))
+        }))
+    }
+
+    pub async fn versions(&self, path: &Path) -> io::Result<Vec<Version>> {
+        let (i, rest) = self.resolve(path)?.ok_or_else(Self::not_mounted)?;
+        Box::pin(self.mounts[i].1.versions(&rest)).await
+    }
+
+    pub async fn get_version(
+        &self,
+        path: &Path,
+        version: u32,
+    ) -> io::Result<Option<Arc<StoredFile>>> {
+        let (i, rest) = self.resolve(path)?.ok_or_else(Self::not_mounted)?;
+        Box::pin(self.mounts[i].1.get_version(&rest, version)).await
+    }
+
+    /// Names of the mount points directly inside `dir`, or leading to one further down.
+    fn mount_points_in(&self, dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .mounts
+            .iter()
+            .filter_map(|(prefix, _)| {
+                let rest = prefix.strip_prefix(dir).ok()?;
+                let name = rest.components().next()?;
+                Some(name.as_os_str().to_string_lossy().into_owned())
+            })
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    fn not_mounted() -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "provided file path isn't under any mount",
+        )
+    }
+}
+
+impl FileStorageCore for MountedFileStore {
+    async fn exists(&self, path: &Path) -> bool {
+        match self.resolve(path) {
+            Ok(Some((i, rest))) => Box::pin(self.mounts[i].1.exists(&rest)).await,
+            _ => false,
+        }
+    }
+
+    async fn get_file(&self, path: &Path) -> Option<Arc<StoredFile>> {
+        let (i, rest) = self.resolve(path).ok()??;
+        Box::pin(self.mounts[i].1.get_file(&rest)).await
+    }
+
+    async fn stat(&self, path: &Path) -> Option<Entry> {
+        let key = relative_key(path).ok()?;
+        if !self.mount_points_in(&key).is_empty() {
+            return Some(Entry::Dir);
+        }
+
+        let (i, rest) = self.resolve(&key).ok()??;
+        Box::pin(self.mounts[i].1.stat(&rest)).await
+    }
+
+    async fn stage_upload(
+        &self,
+        path: &Path,
+        stream: ByteStream<'_>,
+        size_hint: Option<u64>,
+    ) -> io::Result<StagedUpload<'_>> {
+        let (mount, rest) = self.resolve(path)?.ok_or_else(Self::not_mounted)?;
+        let inner = Box::pin(self.mounts[mount].1.stage_upload(&rest, stream, size_hint)).await?;
+        Ok(StagedUpload::Mounted(MountedStagedUpload {
+            mount,
+            inner: Box::new(inner),
+        }))
+    }
+
+    async fn commit_upload(&self, staged: StagedUpload<'_>) -> io::Result<FileMetadata> {
+        let StagedUpload::Mounted(staged) = staged else {
+            return Err(io::Error::other("upload was staged by a different store"));
+        };
+        Box::pin(self.mounts[staged.mount].1.commit_upload(*staged.inner)).await
+    }
+
+    async fn remove(&self, path: &Path) -> io::Result<()> {
+        let (i, rest) = self.resolve(path)?.ok_or_else(Self::not_mounted)?;
+        Box::pin(self.mounts[i].1.remove(&rest)).await
+    }
+
+    async fn list(&self, dir: &Path) -> io::Result<Vec<DirEntry>> {
+        let key = relative_key(dir)?;
+        let mount_points = self.mount_points_in(&key);
+
+        let mut entries = match self.resolve(&key)? {
+            Some((i, rest)) => match Box::pin(self.mounts[i].1.list(&rest)).await {
+                Ok(entries) => entries,
+                // the directory only exists for leading up to a mount point
+                Err(err) if err.kind() == io::ErrorKind::NotFound && !mount_points.is_empty() => {
+                    Vec::new()
+                }
+                Err(err) => return Err(err),
+            },
+            None if !mount_points.is_empty() => Vec::new(),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "directory does not exist",
+                ));
+            }
+        };
+
+        // a mount point hides whatever the store it's in has under the same name
+        entries.retain(|entry| !mount_points.contains(&entry.name));
+        entries.extend(mount_points.into_iter().map(|name| DirEntry {
+            name,
+            kind: EntryKind::Dir,
+            size_bytes: None,
+            modified_at: None,
+        }));
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    }
+}
+
+/// An upload staged in one of the mounted stores, along with which one.
+pub struct MountedStagedUpload<'a> {
+    mount: usize,
+    pub(super) inner: Box<StagedUpload<'a>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::rt::System;
+    use actix_web::web::Bytes;
+    use futures::{StreamExt, stream};
+
+    use super::*;
+    use crate::file_store::MemoryFileStore;
+
+    async fn upload(store: &MountedFileStore, path: &str) -> io::Result<FileMetadata> {
+        let stream = stream::iter([Ok(Bytes::from_static(b"contents"))]).boxed_local();
+        let staged = store.stage_upload(Path::new(path), stream, None).await?;
+        store.commit_upload(staged).await
+    }
+
+    fn names(entries: Vec<DirEntry>) -> Vec<String> {
+        entries.into_iter().map(|entry| entry.name).collect()
+    }
+
+    #[test]
+    fn paths_go_to_the_longest_matching_mount() {
+        let store = MountedFileStore::new(vec![
+            (
+                "public".into(),
+                FileStore::Memory(MemoryFileStore::default()),
+            ),
+            (
+                "/ci/builds/".into(),
+                FileStore::Memory(MemoryFileStore::default()),
+            ),
+            (
+                "public/media".into(),
+                FileStore::Memory(MemoryFileStore::default()),
+            ),
+        ])
+        .unwrap();
+
+        System::new().block_on(async {
+            upload(&store, "public/a.txt").await.unwrap();
+            upload(&store, "public/media/b.png").await.unwrap();
+            upload(&store, "ci/builds/1/app.zip").await.unwrap();
+            assert!(upload(&store, "elsewhere.txt").await.is_err());
+
+            let (_, public) = store
+                .mounts
+                .iter()
+                .find(|(prefix, _)| prefix == Path::new("public"))
+                .unwrap();
+            assert!(public.exists(Path::new("a.txt")).await);
+            assert!(!public.exists(Path::new("media/b.png")).await);
+            assert!(store.exists(Path::new("public/media/b.png")).await);
+
+            // directories leading up to mount points exist without any store having them
+            assert_eq!(
+                names(store.list(Path::new("")).await.unwrap()),
+                ["ci", "public"]
+            );
+            assert_eq!(
+                names(store.list(Path::new("ci")).await.unwrap()),
+                ["builds"]
+            );
+            assert_eq!(
+                names(store.list(Path::new("public")).await.unwrap()),
+                ["a.txt", "media"]
+            );
+            assert!(matches!(
+                store.stat(Path::new("ci")).await,
+                Some(Entry::Dir)
+            ));
+            assert!(store.list(Path::new("elsewhere")).await.is_err());
+        });
+    }
+}
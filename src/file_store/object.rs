@@ -0,0 +1,477 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::{Component, Path},
+    sync::Arc,
+};
+
+use actix_web::{rt, web::Bytes};
+use futures::{
+    StreamExt, TryStreamExt,
+    stream::{self, FuturesUnordered},
+};
+use object_store::{
+    GetOptions, MultipartUpload, ObjectStore, ObjectStoreExt, PutPayload,
+    aws::AmazonS3Builder,
+    azure::MicrosoftAzureBuilder,
+    gcp::GoogleCloudStorageBuilder,
+    path::{Path as ObjectPath, PathPart},
+};
+use percent_encoding::percent_decode_str;
+use tokio::sync::RwLock;
+
+use crate::{
+    config::server::{
+        AzureBlobConfig, GcsConfig, HashAlgorithm, MemoryCache, S3Config, WebDavConfig,
+    },
+    file_store::{
+        ByteStream, DirEntry, Entry, EntryKind, FileMetadata, FileStorageCore, Hasher,
+        METADATA_FILE_EXT, StagedUpload, StoredFile, StoredFileCore, file_cache::FileCache,
+        is_hidden, is_internal_name, webdav::WebDavStore,
+    },
+};
+
+// S3 and GCS want every part but the last to be at least 5MiB, Azure is fine with anything
+const PART_SIZE: usize = 8 * 1024 * 1024;
+const MAX_PARTS_IN_FLIGHT: usize = 4;
+
+/// Keeps files in a cloud object store, with directories being the key prefixes.
+pub struct ObjectFileStore {
+    store: Arc<dyn ObjectStore>,
+    prefix: ObjectPath,
+    cache: FileCache<ObjectPath>,
+    hash_algorithm: HashAlgorithm,
+    // a commit completes an upload and then writes its metadata, lookups wait it out
+    commit_lock: RwLock<()>,
+}
+
+impl ObjectFileStore {
+    fn new(store: impl ObjectStore, prefix: &str) -> Self {
+        ObjectFileStore {
+            store: Arc::new(store),
+            prefix: ObjectPath::from(prefix),
+            cache: FileCache::new(&MemoryCache::default()),
+            hash_algorithm: HashAlgorithm::default(),
+            commit_lock: RwLock::new(()),
+        }
+    }
+
+    pub fn with_cache(mut self, config: &MemoryCache) -> Self {
+        self.cache = FileCache::new(config);
+        self
+    }
+
+    pub fn with_hash_algorithm(mut self, algorithm: HashAlgorithm) -> Self {
+        self.hash_algorithm = algorithm;
+        self
+    }
+
+    pub fn s3(config: &S3Config) -> io::Result<Self> {
+        let mut builder = AmazonS3Builder::from_env().with_bucket_name(&config.bucket);
+
+        if let Some(region) = &config.region {
+            builder = builder.with_region(region);
+        }
+        if let Some(endpoint) = &config.endpoint {
+            builder = builder
+                .with_endpoint(endpoint)
+                .with_allow_http(endpoint.starts_with("http://"));
+        }
+        if let Some(access_key_id) = &config.access_key_id {
+            builder = builder.with_access_key_id(access_key_id);
+        }
+        if let Some(secret_access_key) = &config.secret_access_key {
+            builder = builder.with_secret_access_key(secret_access_key);
+        }
+
+        let store = builder.build().map_err(invalid_config)?;
+        Ok(Self::new(store, &config.prefix))
+    }
+
+    pub fn gcs(config: &GcsConfig) -> io::Result<Self> {
+        let mut builder = GoogleCloudStorageBuilder::from_env().with_bucket_name(&config.bucket);
+
+        if let Some(path) = &config.service_account_path {
+            builder = builder.with_service_account_path(path);
+        }
+        if let Some(key) = &config.service_account_key {
+            builder = builder.with_service_account_key(key);
+        }
+
+        let store = builder.build().map_err(invalid_config)?;
+        Ok(Self::new(store, &config.prefix))
+    }
+
+    pub fn azure(config: &AzureBlobConfig) -> io::Result<Self> {
+        let mut builder = MicrosoftAzureBuilder::new().with_container_name(&config.container);
+
+        // `Key=value` pairs separated by `;`, as copied from the portal
+        let settings: HashMap<_, _> = config
+            .connection_string
+            .split(';')
+            .filter_map(|setting| setting.trim().split_once('='))
+            .collect();
+
+        if let Some(account) = settings.get("AccountName") {
+            builder = builder.with_account(*account);
+        }
+        if let Some(key) = settings.get("AccountKey") {
+            builder = builder.with_access_key(*key);
+        }
+        if let Some(token) = settings.get("SharedAccessSignature") {
+            builder = builder.with_sas_authorization(sas_query_pairs(token));
+        }
+        if settings.get("UseDevelopmentStorage") == Some(&"true") {
+            builder = builder.with_use_emulator(true);
+        }
+
+        // only needed outside the public cloud, or for the emulator on another host
+        let endpoint = match (settings.get("BlobEndpoint"), settings.get("EndpointSuffix")) {
+            (Some(endpoint), _) => Some(endpoint.to_string()),
+            (None, Some(suffix)) if *suffix != "core.windows.net" => {
+                let protocol = settings.get("DefaultEndpointsProtocol").unwrap_or(&"https");
+                let account = settings.get("AccountName").unwrap_or(&"");
+                Some(format!("{protocol}://{account}.blob.{suffix}"))
+            }
+            _ => None,
+        };
+        if let Some(endpoint) = endpoint {
+            builder = builder
+                .with_allow_http(endpoint.starts_with("http://"))
+                .with_endpoint(endpoint);
+        }
+
+        let store = builder.build().map_err(invalid_config)?;
+        Ok(Self::new(store, &config.prefix))
+    }
+
+    pub fn webdav(config: &WebDavConfig) -> io::Result<Self> {
+        let store = WebDavStore::new(config)?;
+        let prefix = store.prefix().to_string();
+        Ok(Self::new(store, &prefix))
+    }
+
+    pub fn purge_expired_cache(&self) -> usize {
+        self.cache.remove_expired()
+    }
+
+    /// Turns a relative file path into the key of its object, which is the prefix when empty.
+    fn object_key(&self, path: &Path) -> io::Result<ObjectPath> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "provided file path is in an invalid place",
+            )
+        };
+
+        let mut parts: Vec<_> = self.prefix.parts().collect();
+        for component in path.components() {
+            match component {
+                Component::Normal(part) => {
+                    parts.push(PathPart::from(part.to_str().ok_or_else(invalid)?).to_owned());
+                }
+                Component::CurDir => {}
+                _ => return Err(invalid()),
+            }
+        }
+
+        Ok(ObjectPath::from_iter(parts))
+    }
+
+    /// Like `object_key`, except only for paths files can be uploaded to and served from.
+    fn file_key(&self, path: &Path) -> io::Result<ObjectPath> {
+        if path.file_name().is_none() || is_hidden(path) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid file name or path",
+            ));
+        }
+
+        self.object_key(path)
+    }
+
+    async fn read_metadata(&self, key: &ObjectPath) -> io::Result<FileMetadata> {
+        let bytes = self.store.get(&metadata_key(key)).await?.bytes().await?;
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    /// Sends the upload in parts as they fill up, it stays invisible until completed.
+    async fn write_upload(
+        upload: &mut dyn MultipartUpload,
+        mut stream: ByteStream<'_>,
+        algorithm: HashAlgorithm,
+    ) -> io::Result<FileMetadata> {
+        let mut in_flight = FuturesUnordered::new();
+        let mut buffer = Vec::with_capacity(PART_SIZE);
+        let mut hasher = Hasher::new(algorithm);
+        let mut written_bytes: u64 = 0;
+
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            hasher.update(&chunk);
+            written_bytes += chunk.len() as u64;
+            buffer.extend_from_slice(&chunk);
+
+            if buffer.len() >= PART_SIZE {
+                if in_flight.len() >= MAX_PARTS_IN_FLIGHT
+                    && let Some(result) = in_flight.next().await
+                {
+                    result?;
+                }
+
+                let part = std::mem::replace(&mut buffer, Vec::with_capacity(PART_SIZE));
+                in_flight.push(upload.put_part(PutPayload::from(part)));
+            }
+        }
+
+        // the stores take care of uploads without any parts themselves, as empty files
+        if !buffer.is_empty() {
+            in_flight.push(upload.put_part(PutPayload::from(buffer)));
+        }
+
+        while let Some(result) = in_flight.next().await {
+            result?;
+        }
+
+        Ok(hasher.finish(written_bytes))
+    }
+}
+
+impl FileStorageCore for ObjectFileStore {
+    async fn exists(&self, path: &Path) -> bool {
+        self.get_file(path).await.is_some()
+    }
+
+    async fn get_file(&self, path: &Path) -> Option<Arc<StoredFile>> {
+        let key = self.file_key(path).ok()?;
+        if let Some(file) = self.cache.get(&key) {
+            return Some(file);
+        }
+
+        let version = self.cache.version();
+        let _guard = self.commit_lock.read().await;
+
+        let object = match self.store.head(&key).await {
+            Ok(object) => object,
+            Err(object_store::Error::NotFound { .. }) => return None,
+            Err(err) => {
+                log::error!("Error looking up object '{key}': {err}");
+                return None;
+            }
+        };
+
+        // without metadata the size still has to be right, since it's used for Content-Length
+        let metadata = self
+            .read_metadata(&key)
+            .await
+            .unwrap_or_else(|_| FileMetadata {
+                size_bytes: object.size,
+                ..Default::default()
+            });
+
+        let file = Arc::new(StoredFile::Object(ObjectFile {
+            store: Arc::clone(&self.store),
+            key: key.clone(),
+            e_tag: object.e_tag,
+            metadata,
+        }));
+        self.cache.insert(key, Arc::clone(&file), version);
+
+        Some(file)
+    }
+
+    async fn stat(&self, path: &Path) -> Option<Entry> {
+        if let Some(file) = self.get_file(path).await {
+            return Some(Entry::File(file));
+        }
+
+        let key = self.object_key(path).ok()?;
+        if key == self.prefix {
+            return Some(Entry::Dir);
+        }
+        if is_hidden(path) {
+            return None;
+        }
+
+        // directories only exist as the common prefix of the objects in them
+        let listing = self.store.list_with_delimiter(Some(&key)).await.ok()?;
+        let is_empty = listing.objects.is_empty() && listing.common_prefixes.is_empty();
+        (!is_empty).then_some(Entry::Dir)
+    }
+
+    async fn stage_upload(
+        &self,
+        path: &Path,
+        stream: ByteStream<'_>,
+        _size_hint: Option<u64>,
+    ) -> io::Result<StagedUpload<'_>> {
+        let key = self.file_key(path)?;
+
+        let mut upload = self.store.put_multipart(&key).await?;
+        match Self::write_upload(&mut *upload, stream, self.hash_algorithm).await {
+            Ok(metadata) => Ok(StagedUpload::Object(ObjectStagedUpload {
+                key,
+                metadata,
+                upload: Some(upload),
+            })),
+            Err(err) => {
+                let _ = upload.abort().await;
+                Err(err)
+            }
+        }
+    }
+
+    async fn commit_upload(&self, staged: StagedUpload<'_>) -> io::Result<FileMetadata> {
+        let StagedUpload::Object(mut staged) = staged else {
+            return Err(io::Error::other("upload was staged by a different store"));
+        };
+
+        let _guard = self.commit_lock.write().await;
+        let previous = self.read_metadata(&staged.key).await.ok();
+        staged.metadata.replacing(previous.as_ref());
+        if let Some(mut upload) = staged.upload.take() {
+            upload.complete().await?;
+        }
+        self.cache.invalidate(&staged.key);
+
+        let metadata = serde_json::to_vec(&staged.metadata)?;
+        self.store
+            .put(&metadata_key(&staged.key), PutPayload::from(metadata))
+            .await?;
+
+        Ok(staged.metadata.clone())
+    }
+
+    async fn remove(&self, path: &Path) -> io::Result<()> {
+        let key = self.file_key(path)?;
+
+        // deleting a missing object isn't an error, so there's no need to check first
+        let _guard = self.commit_lock.write().await;
+        self.store.delete(&key).await?;
+        self.cache.invalidate(&key);
+        self.store.delete(&metadata_key(&key)).await?;
+
+        Ok(())
+    }
+
+    async fn list(&self, dir: &Path) -> io::Result<Vec<DirEntry>> {
+        let key = self.object_key(dir)?;
+        let is_root = key == self.prefix;
+        let prefix = (key.parts_count() > 0).then_some(&key);
+
+        let listing = self.store.list_with_delimiter(prefix).await?;
+        if !is_root && listing.objects.is_empty() && listing.common_prefixes.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "directory does not exist",
+            ));
+        }
+
+        let dirs = listing
+            .common_prefixes
+            .iter()
+            .filter_map(|prefix| prefix.filename())
+            .filter(|name| !(is_root && *name == "api"))
+            .map(|name| DirEntry {
+                name: name.to_string(),
+                kind: EntryKind::Dir,
+                size_bytes: None,
+                modified_at: None,
+            });
+
+        let files = listing
+            .objects
+            .iter()
+            .filter_map(|object| Some((object.location.filename()?, object)))
+            .filter(|(name, _)| !is_internal_name(name))
+            // the object is written as the upload completes, rather than looking up each
+            // file's metadata for when that was
+            .map(|(name, object)| DirEntry {
+                name: name.to_string(),
+                kind: EntryKind::File,
+                size_bytes: Some(object.size),
+                modified_at: Some(object.last_modified),
+            });
+
+        let mut entries: Vec<_> = dirs.chain(files).collect();
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    }
+}
+
+fn invalid_config(err: object_store::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, err)
+}
+
+/// Splits a SAS token, i.e. the query string of a presigned URL, into its parameters.
+fn sas_query_pairs(token: &str) -> Vec<(String, String)> {
+    token
+        .trim_start_matches('?')
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(key, value)| {
+            let decode = |s: &str| percent_decode_str(s).decode_utf8_lossy().into_owned();
+            (decode(key), decode(value))
+        })
+        .collect()
+}
+
+fn metadata_key(key: &ObjectPath) -> ObjectPath {
+    let name = format!("{}{METADATA_FILE_EXT}", key.filename().unwrap_or_default());
+    let parent = key.parts().take(key.parts_count().saturating_sub(1));
+    ObjectPath::from_iter(parent.chain([PathPart::from(name.as_str()).to_owned()]))
+}
+
+/// A multipart upload with every part sent, waiting to be completed.
+pub struct ObjectStagedUpload {
+    key: ObjectPath,
+    pub(super) metadata: FileMetadata,
+    upload: Option<Box<dyn MultipartUpload>>,
+}
+
+impl Drop for ObjectStagedUpload {
+    fn drop(&mut self) {
+        // already gone if the upload was committed, otherwise the parts are left behind
+        // and billed for until aborted
+        if let Some(mut upload) = self.upload.take() {
+            let key = self.key.clone();
+            rt::spawn(async move {
+                if let Err(err) = upload.abort().await {
+                    log::warn!("Failed to abort upload of '{key}': {err}");
+                }
+            });
+        }
+    }
+}
+
+pub struct ObjectFile {
+    store: Arc<dyn ObjectStore>,
+    key: ObjectPath,
+    /// Of the object when it was looked up, which the metadata describes
+    e_tag: Option<String>,
+    metadata: FileMetadata,
+}
+
+impl StoredFileCore for ObjectFile {
+    fn metadata(&self) -> &FileMetadata {
+        &self.metadata
+    }
+
+    fn bytes_stream(&self) -> ByteStream<'static> {
+        let store = Arc::clone(&self.store);
+        let key = self.key.clone();
+        // objects can't be pinned like open files, so one replaced since the lookup fails
+        // to read rather than mixing the new contents with the old metadata
+        let options = GetOptions {
+            if_match: self.e_tag.clone(),
+            ..Default::default()
+        };
+
+        stream::once(async move { store.get_opts(&key, options).await })
+            .map_ok(|result| result.into_stream())
+            .try_flatten()
+            .map_err(io::Error::from)
+            .map_ok(Bytes::from)
+            .boxed_local()
+    }
+}
@@ -0,0 +1,299 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use async_stream::try_stream;
+use futures::StreamExt;
+use serde::Serialize;
+use tokio::sync::{Mutex, MutexGuard, OnceCell};
+
+use crate::{
+    config::server::QuotaConfig,
+    file_store::{
+        ByteStream, DirEntry, Entry, EntryKind, FileMetadata, FileStorageCore, FileStore, GcReport,
+        PartialUploads, StagedUpload, StoredFile, StoredFileCore, Version,
+    },
+};
+
+/// How much is stored, as reported by `GET /api/store/usage`.
+#[derive(Serialize, Debug, Clone, Copy, Default)]
+pub struct Usage {
+    pub files: u64,
+    pub total_bytes: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_files: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_total_bytes: Option<u64>,
+}
+
+/// Turns away uploads that would take the wrapped store past its quota. What's stored is
+/// counted once when first needed, and kept up to date from then on, so it's only accurate as
+/// long as the files don't change other than through the server.
+pub struct QuotaFileStore {
+    inner: Box<FileStore>,
+    max_files: Option<u64>,
+    max_total_bytes: Option<u64>,
+    // held for the whole of a commit or removal, so the check and the change go together
+    usage: OnceCell<Mutex<Usage>>,
+}
+
+impl QuotaFileStore {
+    pub fn new(inner: FileStore, config: &QuotaConfig) -> Self {
+        QuotaFileStore {
+            inner: Box::new(inner),
+            max_files: config.max_files,
+            max_total_bytes: config.max_total_bytes,
+            usage: OnceCell::new(),
+        }
+    }
+
+    pub fn purge_expired_cache(&self) -> usize {
+        self.inner.purge_expired_cache()
+    }
+
+    pub async fn collect_garbage(&self) -> io::Result<GcReport> {
+        Box::pin(self.inner.collect_garbage()).await
+    }
+
+    pub async fn partial_uploads(
+        &self,
+        idle_timeout: Option<Duration>,
+    ) -> io::Result<PartialUploads> {
+        Box::pin(self.inner.partial_uploads(idle_timeout)).await
+    }
+
+    pub async fn retry_pending_metadata(&self) -> usize {
+        Box::pin(self.inner.retry_pending_metadata()).await
+    }
+
+    pub async fn versions(&self, path: &Path) -> io::Result<Vec<Version>> {
+        Box::pin(self.inner.versions(path)).await
+    }
+
+    pub async fn get_version(
+        &self,
+        path: &Path,
+        version: u32,
+    ) -> io::Result<Option<Arc<StoredFile>>> {
+        Box::pin(self.inner.get_version(path, version)).await
+    }
+
+    pub async fn usage(&self) -> io::Result<Usage> {
+        Ok(*self.lock_usage().await?)
+    }
+
+    async fn lock_usage(&self) -> io::Result<MutexGuard<'_, Usage>> {
+        let usage = self
+            .usage
+            .get_or_try_init(|| async {
+                let usage = Box::pin(self.count()).await?;
+                log::info!(
+                    "Counted {} files taking {} bytes towards the quota",
+                    usage.files,
+                    usage.total_bytes
+                );
+                Ok::<_, io::Error>(Mutex::new(usage))
+            })
+            .await?;
+        Ok(usage.lock().await)
+    }
+
+    /// Walks the whole store, adding up its files.
+    async fn count(&self) -> io::Result<Usage> {
+        let mut usage = Usage {
+            max_files: self.max_files,
+            max_total_bytes: self.max_total_bytes,
+            ..Default::default()
+        };
+
+        let mut dirs = vec![PathBuf::new()];
+        while let Some(dir) = dirs.pop() {
+            for entry in Box::pin(self.inner.list(&dir)).await? {
+                match entry.kind {
+                    EntryKind::Dir => dirs.push(dir.join(&entry.name)),
+                    EntryKind::File => {
+                        usage.files += 1;
+                        usage.total_bytes += entry.size_bytes.unwrap_or_default();
+                    }
+                }
+            }
+        }
+
+        Ok(usage)
+    }
+
+    /// The size of the file at `path`, if there is one.
+    async fn size_of(&self, path: &Path) -> Option<u64> {
+        match Box::pin(self.inner.stat(path)).await {
+            Some(Entry::File(file)) => Some(file.metadata().size_bytes),
+            _ => None,
+        }
+    }
+}
+
+fn quota_exceeded(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::StorageFull,
+        format!("upload would exceed the {what} quota"),
+    )
+}
+
+impl FileStorageCore for QuotaFileStore {
+    async fn exists(&self, path: &Path) -> bool {
+        Box::pin(self.inner.exists(path)).await
+    }
+
+    async fn get_file(&self, path: &Path) -> Option<Arc<StoredFile>> {
+        Box::pin(self.inner.get_file(path)).await
+    }
+
+    async fn stat(&self, path: &Path) -> Option<Entry> {
+        Box::pin(self.inner.stat(path)).await
+    }
+
+    async fn stage_upload(
+        &self,
+        path: &Path,
+        stream: ByteStream<'_>,
+        size_hint: Option<u64>,
+    ) -> io::Result<StagedUpload<'_>> {
+        // cut off as soon as it can't fit anymore, rather than only once it's all been received
+        let stream = match self.max_total_bytes {
+            Some(max_total_bytes) => {
+                let replaced = self.size_of(path).await.unwrap_or_default();
+                let used = self.lock_usage().await?.total_bytes;
+                let allowance = (max_total_bytes + replaced).saturating_sub(used);
+                // or before anything's received, when the client already said it won't fit
+                if size_hint.is_some_and(|size_bytes| size_bytes > allowance) {
+                    return Err(quota_exceeded("size"));
+                }
+                capped(stream, allowance)
+            }
+            None => stream,
+        };
+
+        let inner = Box::pin(self.inner.stage_upload(path, stream, size_hint)).await?;
+        Ok(StagedUpload::Quota(QuotaStagedUpload {
+            path: path.to_path_buf(),
+            inner: Box::new(inner),
+        }))
+    }
+
+    async fn commit_upload(&self, staged: StagedUpload<'_>) -> io::Result<FileMetadata> {
+        let StagedUpload::Quota(staged) = staged else {
+            return Err(io::Error::other("upload was staged by a different store"));
+        };
+
+        let mut usage = self.lock_usage().await?;
+        let replaced = self.size_of(&staged.path).await;
+        let files = usage.files + replaced.is_none() as u64;
+        let total_bytes = usage
+            .total_bytes
+            .saturating_sub(replaced.unwrap_or_default())
+            + staged.inner.metadata().size_bytes;
+
+        if self.max_files.is_some_and(|max| files > max) {
+            return Err(quota_exceeded("file count"));
+        }
+        if self.max_total_bytes.is_some_and(|max| total_bytes > max) {
+            return Err(quota_exceeded("size"));
+        }
+
+        let metadata = Box::pin(self.inner.commit_upload(*staged.inner)).await?;
+        usage.files = files;
+        usage.total_bytes = total_bytes;
+        Ok(metadata)
+    }
+
+    async fn remove(&self, path: &Path) -> io::Result<()> {
+        let mut usage = self.lock_usage().await?;
+        let removed = self.size_of(path).await;
+        Box::pin(self.inner.remove(path)).await?;
+
+        if let Some(size_bytes) = removed {
+            usage.files = usage.files.saturating_sub(1);
+            usage.total_bytes = usage.total_bytes.saturating_sub(size_bytes);
+        }
+        Ok(())
+    }
+
+    async fn list(&self, dir: &Path) -> io::Result<Vec<DirEntry>> {
+        Box::pin(self.inner.list(dir)).await
+    }
+}
+
+/// Fails `stream` once more than `max_bytes` came through it.
+fn capped(mut stream: ByteStream<'_>, max_bytes: u64) -> ByteStream<'_> {
+    try_stream! {
+        let mut received = 0;
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            received += chunk.len() as u64;
+            if received > max_bytes {
+                Err(quota_exceeded("size"))?;
+            }
+            yield chunk;
+        }
+    }
+    .boxed_local()
+}
+
+/// An upload staged in the wrapped store, along with where it's going.
+pub struct QuotaStagedUpload<'a> {
+    path: PathBuf,
+    pub(super) inner: Box<StagedUpload<'a>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::rt::System;
+    use actix_web::web::Bytes;
+    use futures::stream;
+
+    use super::*;
+    use crate::file_store::MemoryFileStore;
+
+    async fn upload(store: &QuotaFileStore, path: &str, len: usize) -> io::Result<FileMetadata> {
+        let stream = stream::iter([Ok(Bytes::from(vec![b'a'; len]))]).boxed_local();
+        let staged = store.stage_upload(Path::new(path), stream, None).await?;
+        store.commit_upload(staged).await
+    }
+
+    #[test]
+    fn uploads_past_the_quota_are_turned_away() {
+        let store = QuotaFileStore::new(
+            FileStore::Memory(MemoryFileStore::default()),
+            &QuotaConfig {
+                max_total_bytes: Some(100),
+                max_files: Some(2),
+            },
+        );
+
+        System::new().block_on(async {
+            upload(&store, "a.txt", 60).await.unwrap();
+            let err = upload(&store, "b.txt", 50).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+
+            // replacing a file only counts the difference
+            upload(&store, "a.txt", 90).await.unwrap();
+            upload(&store, "b.txt", 10).await.unwrap();
+            let err = upload(&store, "c.txt", 0).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+
+            store.remove(Path::new("a.txt")).await.unwrap();
+            upload(&store, "c.txt", 0).await.unwrap();
+            let usage = store.usage().await.unwrap();
+            assert_eq!((usage.files, usage.total_bytes), (2, 10));
+
+            // turned away up front when it's declared too large
+            let stream = stream::iter([Ok(Bytes::new())]).boxed_local();
+            let staged = store
+                .stage_upload(Path::new("d.txt"), stream, Some(91))
+                .await;
+            assert_eq!(staged.err().unwrap().kind(), io::ErrorKind::StorageFull);
+        });
+    }
+}
@@ -0,0 +1,229 @@
+use std::{
+    io,
+    path::{Component, Path},
+    sync::Arc,
+};
+
+use async_stream::try_stream;
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use reqwest::{
+    Client, Response, StatusCode, Url,
+    header::{ACCEPT, AUTHORIZATION, HeaderMap, HeaderValue},
+};
+use serde::Deserialize;
+
+use crate::{
+    config::server::{HashAlgorithm, RemoteConfig},
+    file_store::{
+        ByteStream, DirEntry, Entry, EntryKind, FileMetadata, FileStorageCore, StagedUpload,
+        StoredFile, StoredFileCore, relative_key,
+    },
+    hooks::ScanRecord,
+};
+
+/// Another instance of this server, read through its API. Nothing can be written to it.
+pub struct RemoteFileStore {
+    client: Client,
+    url: Url,
+}
+
+/// What `/api/info/{path}` answers with, as far as it's needed here.
+#[derive(Deserialize)]
+struct RemoteInfo {
+    kind: EntryKind,
+    #[serde(default)]
+    size_bytes: u64,
+    #[serde(default)]
+    hash: String,
+    #[serde(default)]
+    hash_algorithm: HashAlgorithm,
+    #[serde(default)]
+    created_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    modified_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    scan: Option<ScanRecord>,
+}
+
+fn read_only() -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, "remote sources are read-only")
+}
+
+impl RemoteFileStore {
+    pub fn new(config: &RemoteConfig) -> io::Result<Self> {
+        let invalid = |err: String| io::Error::new(io::ErrorKind::InvalidInput, err);
+
+        let url = Url::parse(&config.url).map_err(|err| invalid(err.to_string()))?;
+        if url.cannot_be_a_base() {
+            return Err(invalid(format!(
+                "'{}' can't have paths added to it",
+                config.url
+            )));
+        }
+
+        let mut headers = HeaderMap::new();
+        if let Some(token) = &config.token {
+            let mut value = HeaderValue::try_from(format!("Bearer {token}"))
+                .map_err(|err| invalid(err.to_string()))?;
+            value.set_sensitive(true);
+            headers.insert(AUTHORIZATION, value);
+        }
+        let client = Client::builder()
+            .default_headers(headers)
+            .build()
+            .map_err(io::Error::other)?;
+
+        Ok(RemoteFileStore { client, url })
+    }
+
+    /// The URL of `path` on the remote, under the route at `route`, e.g. `["api", "info"]`.
+    fn url_of(&self, route: &[&str], path: &Path) -> io::Result<Url> {
+        let key = relative_key(path)?;
+        let mut url = self.url.clone();
+        {
+            let mut segments = url.path_segments_mut().unwrap();
+            segments.pop_if_empty().extend(route);
+            for component in key.components() {
+                if let Component::Normal(part) = component {
+                    segments.push(&part.to_string_lossy());
+                }
+            }
+            // the routes take the root as an empty path, which still needs its slash
+            if key.as_os_str().is_empty() {
+                segments.push("");
+            }
+        }
+        Ok(url)
+    }
+
+    async fn info(&self, path: &Path) -> io::Result<Option<RemoteInfo>> {
+        let response = self
+            .client
+            .get(self.url_of(&["api", "info"], path)?)
+            .send()
+            .await
+            .map_err(io::Error::other)?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        let response = response.error_for_status().map_err(io::Error::other)?;
+        response.json().await.map(Some).map_err(io::Error::other)
+    }
+}
+
+impl FileStorageCore for RemoteFileStore {
+    async fn exists(&self, path: &Path) -> bool {
+        self.get_file(path).await.is_some()
+    }
+
+    async fn get_file(&self, path: &Path) -> Option<Arc<StoredFile>> {
+        match self.stat(path).await? {
+            Entry::File(file) => Some(file),
+            Entry::Dir => None,
+        }
+    }
+
+    async fn stat(&self, path: &Path) -> Option<Entry> {
+        let info = match self.info(path).await {
+            Ok(info) => info?,
+            Err(err) => {
+                log::error!(
+                    "Error looking up '{}' on {}: {err}",
+                    path.display(),
+                    self.url
+                );
+                return None;
+            }
+        };
+
+        match info.kind {
+            EntryKind::Dir => Some(Entry::Dir),
+            EntryKind::File => Some(Entry::File(Arc::new(StoredFile::Remote(RemoteFile {
+                client: self.client.clone(),
+                url: self.url_of(&[], path).ok()?,
+                metadata: FileMetadata {
+                    hash: info.hash,
+                    size_bytes: info.size_bytes,
+                    algorithm: info.hash_algorithm,
+                    created_at: info.created_at,
+                    modified_at: info.modified_at,
+                    scan: info.scan,
+                    pending: false,
+                },
+            })))),
+        }
+    }
+
+    async fn stage_upload(
+        &self,
+        _path: &Path,
+        _stream: ByteStream<'_>,
+        _size_hint: Option<u64>,
+    ) -> io::Result<StagedUpload<'_>> {
+        Err(read_only())
+    }
+
+    async fn commit_upload(&self, _staged: StagedUpload<'_>) -> io::Result<FileMetadata> {
+        Err(read_only())
+    }
+
+    async fn remove(&self, _path: &Path) -> io::Result<()> {
+        Err(read_only())
+    }
+
+    async fn list(&self, dir: &Path) -> io::Result<Vec<DirEntry>> {
+        let response = self
+            .client
+            .get(self.url_of(&["api", "list"], dir)?)
+            .header(ACCEPT, "application/json")
+            .send()
+            .await
+            .map_err(io::Error::other)?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "directory does not exist",
+            )),
+            StatusCode::CONFLICT => Err(io::Error::new(
+                io::ErrorKind::NotADirectory,
+                "path is a file",
+            )),
+            _ => response
+                .error_for_status()
+                .map_err(io::Error::other)?
+                .json()
+                .await
+                .map_err(io::Error::other),
+        }
+    }
+}
+
+pub struct RemoteFile {
+    client: Client,
+    url: Url,
+    metadata: FileMetadata,
+}
+
+impl StoredFileCore for RemoteFile {
+    fn metadata(&self) -> &FileMetadata {
+        &self.metadata
+    }
+
+    fn bytes_stream(&self) -> ByteStream<'static> {
+        let request = self.client.get(self.url.clone());
+        try_stream! {
+            let response = request
+                .send()
+                .await
+                .and_then(Response::error_for_status)
+                .map_err(io::Error::other)?;
+            let mut body = response.bytes_stream();
+            while let Some(chunk) = body.next().await {
+                yield chunk.map_err(io::Error::other)?;
+            }
+        }
+        .boxed_local()
+    }
+}
@@ -0,0 +1,106 @@
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use crate::file_names::normalize_name;
+
+/// The names kept on disk spelled other than normalized, e.g. decomposed ones from before
+/// names were composed, in each directory looked in so far. Kept by stores that are watched,
+/// whose watcher forgets a directory once anything in it changes, so a missing name doesn't
+/// read its whole directory every time it's asked for.
+///
+/// Like the file cache, a directory read while something in the store changed isn't kept,
+/// as it could be from before the change.
+#[derive(Default)]
+pub struct Spellings {
+    dirs: Mutex<HashMap<PathBuf, Arc<HashMap<String, OsString>>>>,
+    version: AtomicU64,
+}
+
+impl Spellings {
+    /// The name in `dir` that normalizes to `name` but is spelled otherwise on disk.
+    pub fn find(&self, dir: &Path, name: &str) -> Option<OsString> {
+        let cached = self.dirs.lock().unwrap().get(dir).cloned();
+        let names = match cached {
+            Some(names) => names,
+            None => {
+                let version = self.version.load(Ordering::Acquire);
+                let names = Arc::new(read_spellings(dir));
+                if self.version.load(Ordering::Acquire) == version {
+                    self.dirs
+                        .lock()
+                        .unwrap()
+                        .insert(dir.to_path_buf(), Arc::clone(&names));
+                }
+                names
+            }
+        };
+        names.get(name).cloned()
+    }
+
+    /// Forgets the names in `dir`, after something in it changed.
+    pub fn invalidate(&self, dir: &Path) {
+        self.version.fetch_add(1, Ordering::AcqRel);
+        self.dirs.lock().unwrap().remove(dir);
+    }
+
+    /// Forgets every directory, after changes that could be anywhere.
+    pub fn clear(&self) {
+        self.version.fetch_add(1, Ordering::AcqRel);
+        self.dirs.lock().unwrap().clear();
+    }
+}
+
+/// The names in `dir` spelled other than normalized, keyed by what they normalize to. Only
+/// names beyond ASCII can be, so for most directories there are none to keep.
+pub fn read_spellings(dir: &Path) -> HashMap<String, OsString> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return HashMap::new();
+    };
+    entries
+        .flatten()
+        .map(|entry| entry.file_name())
+        .filter_map(|on_disk| {
+            let name = on_disk.to_str().filter(|name| !name.is_ascii())?;
+            let normalized = normalize_name(name).into_owned();
+            let is_spelled_otherwise = normalized != name;
+            is_spelled_otherwise.then_some((normalized, on_disk))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::temp_dir;
+
+    #[test]
+    fn directories_are_read_again_once_they_change() {
+        let dir = temp_dir();
+        fs::write(dir.join("a\u{308}.txt"), "").unwrap();
+        fs::write(dir.join("ö.txt"), "").unwrap();
+        let spellings = Spellings::default();
+
+        assert_eq!(
+            spellings.find(&dir, "ä.txt"),
+            Some(OsString::from("a\u{308}.txt"))
+        );
+        // names already spelled normalized are found as given
+        assert_eq!(spellings.find(&dir, "ö.txt"), None);
+
+        fs::write(dir.join("u\u{308}.txt"), "").unwrap();
+        assert_eq!(spellings.find(&dir, "ü.txt"), None);
+        spellings.invalidate(&dir);
+        assert_eq!(
+            spellings.find(&dir, "ü.txt"),
+            Some(OsString::from("u\u{308}.txt"))
+        );
+    }
+}
@@ -0,0 +1,333 @@
+use std::{
+    collections::HashSet,
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use actix_web::rt;
+
+use crate::{
+    config::server::PromotionPolicy,
+    file_store::{
+        ByteStream, DirEntry, Entry, FileMetadata, FileStorageCore, FileStore, GcReport,
+        PartialUploads, StagedUpload, StoredFile, StoredFileCore,
+    },
+};
+
+/// Serves each file from the first of several stores that has it, with the last one holding
+/// every file and the ones in front of it only ever holding copies. Uploads go to the last
+/// one, after dropping the copies that would otherwise hide them.
+pub struct TieredFileStore {
+    tiers: Vec<Arc<FileStore>>,
+    promotion: PromotionPolicy,
+    // bumped by every write, a promotion only lands when nothing was written since it
+    // looked the file up, so an older copy never hides a newer upload
+    version: Arc<AtomicU64>,
+    writes: Arc<tokio::sync::Mutex<()>>,
+    promoting: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+impl TieredFileStore {
+    pub fn new(tiers: Vec<FileStore>, promotion: PromotionPolicy) -> io::Result<Self> {
+        if tiers.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a tiered store needs at least one tier",
+            ));
+        }
+
+        Ok(TieredFileStore {
+            tiers: tiers.into_iter().map(Arc::new).collect(),
+            promotion,
+            version: Arc::default(),
+            writes: Arc::default(),
+            promoting: Arc::default(),
+        })
+    }
+
+    pub fn purge_expired_cache(&self) -> usize {
+        self.tiers
+            .iter()
+            .map(|tier| tier.purge_expired_cache())
+            .sum()
+    }
+
+    pub async fn collect_garbage(&self) -> io::Result<GcReport> {
+        let mut report = GcReport::default();
+        for tier in &self.tiers {
+            report += Box::pin(tier.collect_garbage()).await?;
+        }
+        Ok(report)
+    }
+
+    pub async fn partial_uploads(
+        &self,
+        idle_timeout: Option<Duration>,
+    ) -> io::Result<PartialUploads> {
+        let mut report = PartialUploads::default();
+        for tier in &self.tiers {
+            report += Box::pin(tier.partial_uploads(idle_timeout)).await?;
+        }
+        Ok(report)
+    }
+
+    pub async fn retry_pending_metadata(&self) -> usize {
+        let mut pending = 0;
+        for tier in &self.tiers {
+            pending += Box::pin(tier.retry_pending_metadata()).await;
+        }
+        pending
+    }
+
+    fn last(&self) -> &FileStore {
+        self.tiers.last().unwrap()
+    }
+
+    fn copies(&self) -> &[Arc<FileStore>] {
+        &self.tiers[..self.tiers.len() - 1]
+    }
+
+    /// Copies `file`, found further down, into the first tier without holding up the request.
+    fn promote(&self, path: &Path, file: &Arc<StoredFile>, version: u64) {
+        let PromotionPolicy::OnAccess { max_size_bytes } = self.promotion else {
+            return;
+        };
+        if max_size_bytes.is_some_and(|max| file.metadata().size_bytes > max) {
+            return;
+        }
+        // a popular file is looked up many times before its first copy lands
+        if !self.promoting.lock().unwrap().insert(path.to_path_buf()) {
+            return;
+        }
+
+        let first = Arc::clone(&self.tiers[0]);
+        let file = Arc::clone(file);
+        let path = path.to_path_buf();
+        let current_version = Arc::clone(&self.version);
+        let writes = Arc::clone(&self.writes);
+        let promoting = Arc::clone(&self.promoting);
+
+        rt::spawn(async move {
+            let result = async {
+                let staged = Box::pin(first.stage_upload(
+                    &path,
+                    file.bytes_stream(),
+                    Some(file.metadata().size_bytes),
+                ))
+                .await?;
+                // local files put in place by hand have no hash to compare against
+                let (copied, source) = (staged.metadata(), file.metadata());
+                if copied.size_bytes != source.size_bytes
+                    || (!source.hash.is_empty() && copied.hash != source.hash)
+                {
+                    return Ok(false);
+                }
+
+                let _writes = writes.lock().await;
+                if current_version.load(Ordering::Acquire) != version {
+                    return Ok(false);
+                }
+                Box::pin(first.commit_upload(staged)).await?;
+                Ok::<_, io::Error>(true)
+            }
+            .await;
+            promoting.lock().unwrap().remove(&path);
+
+            match result {
+                Ok(true) => log::debug!("Promoted '{}' to the first tier", path.display()),
+                // changed while being copied, the next read tries again
+                Ok(false) => {}
+                Err(err) => log::warn!("Failed to promote '{}': {err}", path.display()),
+            }
+        });
+    }
+
+    /// Drops the copies of `path` in front of the last tier.
+    async fn remove_copies(&self, path: &Path) -> io::Result<()> {
+        for tier in self.copies() {
+            Box::pin(tier.remove(path)).await?;
+        }
+        Ok(())
+    }
+}
+
+impl FileStorageCore for TieredFileStore {
+    async fn exists(&self, path: &Path) -> bool {
+        for tier in &self.tiers {
+            if Box::pin(tier.exists(path)).await {
+                return true;
+            }
+        }
+        false
+    }
+
+    async fn get_file(&self, path: &Path) -> Option<Arc<StoredFile>> {
+        // taken before looking, so a write racing the lookup keeps it from being promoted
+        let version = self.version.load(Ordering::Acquire);
+        for (i, tier) in self.tiers.iter().enumerate() {
+            if let Some(file) = Box::pin(tier.get_file(path)).await {
+                if i > 0 {
+                    self.promote(path, &file, version);
+                }
+                return Some(file);
+            }
+        }
+        None
+    }
+
+    async fn stat(&self, path: &Path) -> Option<Entry> {
+        let version = self.version.load(Ordering::Acquire);
+        for (i, tier) in self.tiers.iter().enumerate() {
+            match Box::pin(tier.stat(path)).await {
+                Some(Entry::File(file)) => {
+                    if i > 0 {
+                        self.promote(path, &file, version);
+                    }
+                    return Some(Entry::File(file));
+                }
+                Some(Entry::Dir) => return Some(Entry::Dir),
+                None => {}
+            }
+        }
+        None
+    }
+
+    async fn stage_upload(
+        &self,
+        path: &Path,
+        stream: ByteStream<'_>,
+        size_hint: Option<u64>,
+    ) -> io::Result<StagedUpload<'_>> {
+        let inner = Box::pin(self.last().stage_upload(path, stream, size_hint)).await?;
+        Ok(StagedUpload::Tiered(TieredStagedUpload {
+            path: path.to_path_buf(),
+            inner: Box::new(inner),
+        }))
+    }
+
+    async fn commit_upload(&self, staged: StagedUpload<'_>) -> io::Result<FileMetadata> {
+        let StagedUpload::Tiered(staged) = staged else {
+            return Err(io::Error::other("upload was staged by a different store"));
+        };
+
+        let _writes = self.writes.lock().await;
+        self.version.fetch_add(1, Ordering::AcqRel);
+        // the copies go first, failing after that still leaves the old contents readable
+        self.remove_copies(&staged.path).await?;
+        Box::pin(self.last().commit_upload(*staged.inner)).await
+    }
+
+    async fn remove(&self, path: &Path) -> io::Result<()> {
+        let _writes = self.writes.lock().await;
+        self.version.fetch_add(1, Ordering::AcqRel);
+        self.remove_copies(path).await?;
+        Box::pin(self.last().remove(path)).await
+    }
+
+    async fn list(&self, dir: &Path) -> io::Result<Vec<DirEntry>> {
+        let mut entries: Vec<DirEntry> = Vec::new();
+        let mut is_found = false;
+
+        for tier in &self.tiers {
+            match Box::pin(tier.list(dir)).await {
+                Ok(listed) => {
+                    is_found = true;
+                    for entry in listed {
+                        if !entries.iter().any(|existing| existing.name == entry.name) {
+                            entries.push(entry);
+                        }
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        if !is_found {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "directory does not exist",
+            ));
+        }
+
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    }
+}
+
+/// An upload staged in the last tier, along with the path its copies are dropped from.
+pub struct TieredStagedUpload<'a> {
+    path: PathBuf,
+    pub(super) inner: Box<StagedUpload<'a>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::rt::System;
+    use actix_web::web::Bytes;
+    use futures::{StreamExt, TryStreamExt, stream};
+
+    use super::*;
+    use crate::file_store::MemoryFileStore;
+
+    async fn upload(store: &FileStore, path: &str, contents: &'static [u8]) {
+        let stream = stream::iter([Ok(Bytes::from_static(contents))]).boxed_local();
+        let staged = store
+            .stage_upload(Path::new(path), stream, None)
+            .await
+            .unwrap();
+        store.commit_upload(staged).await.unwrap();
+    }
+
+    async fn read(store: &impl FileStorageCore, path: &str) -> Option<Vec<u8>> {
+        let file = store.get_file(Path::new(path)).await?;
+        let chunks: Vec<Bytes> = file.bytes_stream().try_collect().await.unwrap();
+        Some(chunks.concat())
+    }
+
+    #[test]
+    fn uploads_replace_the_copies_in_front() {
+        let store = TieredFileStore::new(
+            vec![
+                FileStore::Memory(MemoryFileStore::default()),
+                FileStore::Memory(MemoryFileStore::default()),
+            ],
+            PromotionPolicy::Never,
+        )
+        .unwrap();
+
+        System::new().block_on(async {
+            upload(&store.tiers[0], "a.txt", b"copy").await;
+            upload(&store.tiers[1], "a.txt", b"old").await;
+            upload(&store.tiers[1], "dir/b.txt", b"cold").await;
+            assert_eq!(read(&store, "a.txt").await.unwrap(), b"copy");
+            assert_eq!(read(&store, "dir/b.txt").await.unwrap(), b"cold");
+
+            let stream = stream::iter([Ok(Bytes::from_static(b"new"))]).boxed_local();
+            let staged = store
+                .stage_upload(Path::new("a.txt"), stream, None)
+                .await
+                .unwrap();
+            store.commit_upload(staged).await.unwrap();
+            assert_eq!(read(&store, "a.txt").await.unwrap(), b"new");
+            assert!(read(&*store.tiers[0], "a.txt").await.is_none());
+
+            let names: Vec<_> = store
+                .list(Path::new(""))
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|entry| entry.name)
+                .collect();
+            assert_eq!(names, ["a.txt", "dir"]);
+
+            store.remove(Path::new("dir/b.txt")).await.unwrap();
+            assert!(!store.exists(Path::new("dir/b.txt")).await);
+        });
+    }
+}
@@ -0,0 +1,213 @@
+use std::{
+    cell::Cell,
+    collections::BTreeMap,
+    io,
+    path::Path,
+    rc::Rc,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures::{StreamExt, stream};
+use serde::Serialize;
+
+use crate::{
+    config::server::StoreTracingConfig,
+    file_store::{
+        ByteStream, DirEntry, Entry, FileMetadata, FileStorageCore, FileStore, GcReport,
+        PartialUploads, StagedUpload, StoredFile, Version,
+    },
+};
+
+/// How one kind of operation on one backend has been doing.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct OperationStats {
+    pub count: u64,
+    pub total_ms: f64,
+    pub max_ms: f64,
+    /// Of those operations, how many took longer than the threshold
+    pub slow: u64,
+}
+
+/// Times the operations of every traced store, logging the ones that are slow.
+pub struct StoreTracer {
+    slow_threshold: Duration,
+    // backend, then operation
+    stats: Mutex<BTreeMap<String, BTreeMap<&'static str, OperationStats>>>,
+}
+
+impl StoreTracer {
+    pub fn new(config: &StoreTracingConfig) -> Self {
+        StoreTracer {
+            slow_threshold: Duration::from_millis(config.slow_threshold_ms),
+            stats: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn record(
+        &self,
+        backend: &str,
+        operation: &'static str,
+        path: Option<&Path>,
+        elapsed: Duration,
+    ) {
+        let elapsed_ms = elapsed.as_secs_f64() * 1000.0;
+        let is_slow = elapsed > self.slow_threshold;
+        if is_slow {
+            match path {
+                Some(path) => log::warn!(
+                    "Slow {operation} of '{}' on {backend}: {elapsed_ms:.1}ms",
+                    path.display()
+                ),
+                None => log::warn!("Slow {operation} on {backend}: {elapsed_ms:.1}ms"),
+            }
+        }
+
+        let mut stats = self.stats.lock().unwrap();
+        let stats = stats
+            .entry(backend.to_string())
+            .or_default()
+            .entry(operation)
+            .or_default();
+        stats.count += 1;
+        stats.total_ms += elapsed_ms;
+        stats.max_ms = stats.max_ms.max(elapsed_ms);
+        stats.slow += is_slow as u64;
+    }
+
+    /// The stats of every operation so far, keyed by backend and then operation.
+    pub fn stats(&self) -> BTreeMap<String, BTreeMap<&'static str, OperationStats>> {
+        self.stats.lock().unwrap().clone()
+    }
+}
+
+/// Times every call into the wrapped store. Reading the contents of files it returns isn't
+/// counted, only looking them up.
+pub struct TracedFileStore {
+    inner: Box<FileStore>,
+    backend: String,
+    tracer: Arc<StoreTracer>,
+}
+
+impl TracedFileStore {
+    /// `backend` names the store in logs and stats, e.g. `local:files`.
+    pub fn new(inner: FileStore, backend: String, tracer: &Arc<StoreTracer>) -> Self {
+        TracedFileStore {
+            inner: Box::new(inner),
+            backend,
+            tracer: Arc::clone(tracer),
+        }
+    }
+
+    pub fn purge_expired_cache(&self) -> usize {
+        self.inner.purge_expired_cache()
+    }
+
+    pub async fn collect_garbage(&self) -> io::Result<GcReport> {
+        Box::pin(self.inner.collect_garbage()).await
+    }
+
+    pub async fn partial_uploads(
+        &self,
+        idle_timeout: Option<Duration>,
+    ) -> io::Result<PartialUploads> {
+        Box::pin(self.inner.partial_uploads(idle_timeout)).await
+    }
+
+    pub async fn retry_pending_metadata(&self) -> usize {
+        Box::pin(self.inner.retry_pending_metadata()).await
+    }
+
+    pub async fn versions(&self, path: &Path) -> io::Result<Vec<Version>> {
+        self.timed("versions", path, Box::pin(self.inner.versions(path)))
+            .await
+    }
+
+    pub async fn get_version(
+        &self,
+        path: &Path,
+        version: u32,
+    ) -> io::Result<Option<Arc<StoredFile>>> {
+        let lookup = Box::pin(self.inner.get_version(path, version));
+        self.timed("get_version", path, lookup).await
+    }
+
+    async fn timed<T>(
+        &self,
+        operation: &'static str,
+        path: &Path,
+        call: impl Future<Output = T>,
+    ) -> T {
+        let start = Instant::now();
+        let result = call.await;
+        self.tracer
+            .record(&self.backend, operation, Some(path), start.elapsed());
+        result
+    }
+}
+
+impl FileStorageCore for TracedFileStore {
+    async fn exists(&self, path: &Path) -> bool {
+        self.timed("exists", path, Box::pin(self.inner.exists(path)))
+            .await
+    }
+
+    async fn get_file(&self, path: &Path) -> Option<Arc<StoredFile>> {
+        self.timed("get_file", path, Box::pin(self.inner.get_file(path)))
+            .await
+    }
+
+    async fn stat(&self, path: &Path) -> Option<Entry> {
+        self.timed("stat", path, Box::pin(self.inner.stat(path)))
+            .await
+    }
+
+    async fn stage_upload(
+        &self,
+        path: &Path,
+        stream: ByteStream<'_>,
+        size_hint: Option<u64>,
+    ) -> io::Result<StagedUpload<'_>> {
+        // the upload arrives while it's being staged, the time spent waiting on the client
+        // isn't the store's doing
+        let waiting = Rc::new(Cell::new(Duration::ZERO));
+        let stream = waited_on(stream, Rc::clone(&waiting));
+
+        let start = Instant::now();
+        let staged = Box::pin(self.inner.stage_upload(path, stream, size_hint)).await;
+        let elapsed = start.elapsed().saturating_sub(waiting.get());
+        self.tracer
+            .record(&self.backend, "stage_upload", Some(path), elapsed);
+        staged
+    }
+
+    async fn commit_upload(&self, staged: StagedUpload<'_>) -> io::Result<FileMetadata> {
+        let start = Instant::now();
+        let metadata = Box::pin(self.inner.commit_upload(staged)).await;
+        // the staged upload doesn't say where it's going
+        self.tracer
+            .record(&self.backend, "commit_upload", None, start.elapsed());
+        metadata
+    }
+
+    async fn remove(&self, path: &Path) -> io::Result<()> {
+        self.timed("remove", path, Box::pin(self.inner.remove(path)))
+            .await
+    }
+
+    async fn list(&self, dir: &Path) -> io::Result<Vec<DirEntry>> {
+        self.timed("list", dir, Box::pin(self.inner.list(dir)))
+            .await
+    }
+}
+
+/// Adds the time spent waiting for each chunk of `stream` to `waiting`.
+fn waited_on<'a>(stream: ByteStream<'a>, waiting: Rc<Cell<Duration>>) -> ByteStream<'a> {
+    stream::unfold((stream, waiting), |(mut stream, waiting)| async move {
+        let start = Instant::now();
+        let chunk = stream.next().await;
+        waiting.set(waiting.get() + start.elapsed());
+        chunk.map(|chunk| (chunk, (stream, waiting)))
+    })
+    .boxed_local()
+}
@@ -0,0 +1,150 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::server::VersionsConfig,
+    file_store::{FileMetadata, FsFile, METADATA_FILE_EXT},
+};
+
+/// Separates a file's name from the number of one of its versions, e.g. `a.txt@v3`.
+const VERSION_SEPARATOR: &str = "@v";
+
+/// An earlier version of a file, as listed by `GET /api/versions/{file}`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Version {
+    pub version: u32,
+    #[serde(flatten)]
+    pub metadata: FileMetadata,
+    /// When it was replaced or deleted
+    pub archived_at: DateTime<Utc>,
+}
+
+/// The versions of a local directory's files that were replaced or deleted, kept as
+/// `<file>@v<n>` under a directory of their own along with their metadata.
+pub struct Versions {
+    dir: PathBuf,
+    max_versions: u32,
+}
+
+impl Versions {
+    pub fn open(config: &VersionsConfig) -> io::Result<Self> {
+        fs::create_dir_all(&config.dir)?;
+        Ok(Versions {
+            dir: PathBuf::from(&config.dir),
+            max_versions: config.max_versions,
+        })
+    }
+
+    /// Moves the file at `file_path`, known as `key`, out of the way as its next version.
+    /// The oldest versions are dropped once there are more than allowed.
+    pub async fn keep(
+        &self,
+        key: &str,
+        file_path: &Path,
+        metadata: FileMetadata,
+    ) -> io::Result<Version> {
+        let existing = self.list(key).await?;
+        let version = Version {
+            version: existing.last().map_or(1, |last| last.version + 1),
+            metadata,
+            archived_at: Utc::now(),
+        };
+
+        let version_path = self.version_path(key, version.version);
+        if let Some(parent) = version_path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        move_file(file_path, &version_path).await?;
+        tokio::fs::write(sidecar_path(&version_path), serde_json::to_vec(&version)?).await?;
+
+        // the one just kept always stays
+        let excess = (existing.len() + 1).saturating_sub(self.max_versions.max(1) as usize);
+        for old in &existing[..excess] {
+            let old_path = self.version_path(key, old.version);
+            tokio::fs::remove_file(sidecar_path(&old_path)).await?;
+            tokio::fs::remove_file(old_path).await?;
+        }
+
+        Ok(version)
+    }
+
+    /// The versions kept of the file known as `key`, oldest first.
+    pub async fn list(&self, key: &str) -> io::Result<Vec<Version>> {
+        let versions_of = self.dir.join(key);
+        let (Some(dir), Some(name)) = (versions_of.parent(), versions_of.file_name()) else {
+            return Ok(Vec::new());
+        };
+        let prefix = format!("{}{VERSION_SEPARATOR}", name.to_string_lossy());
+
+        let mut entries = match tokio::fs::read_dir(dir).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+
+        let mut versions = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let file_name = entry.file_name();
+            let Some(number) = file_name
+                .to_str()
+                .and_then(|name| name.strip_prefix(&prefix))
+                // sidecars don't parse as a number, and neither do versions of `<file>@v<n>`
+                .and_then(|number| number.parse::<u32>().ok())
+            else {
+                continue;
+            };
+
+            let sidecar = tokio::fs::read(sidecar_path(&entry.path())).await?;
+            let version: Version = serde_json::from_slice(&sidecar)?;
+            if version.version == number {
+                versions.push(version);
+            }
+        }
+
+        versions.sort_by_key(|version| version.version);
+        Ok(versions)
+    }
+
+    /// Opens version `version` of the file known as `key`, if it's still kept.
+    pub async fn get(&self, key: &str, version: u32) -> io::Result<Option<FsFile>> {
+        let version_path = self.version_path(key, version);
+        let sidecar = match tokio::fs::read(sidecar_path(&version_path)).await {
+            Ok(sidecar) => sidecar,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        let kept: Version = serde_json::from_slice(&sidecar)?;
+        FsFile::open_with(&version_path, Some(kept.metadata), None)
+            .await
+            .map(Some)
+    }
+
+    fn version_path(&self, key: &str, version: u32) -> PathBuf {
+        let mut path = self.dir.join(key).into_os_string();
+        path.push(format!("{VERSION_SEPARATOR}{version}"));
+        PathBuf::from(path)
+    }
+}
+
+fn sidecar_path(version_path: &Path) -> PathBuf {
+    let mut path = version_path.as_os_str().to_os_string();
+    path.push(METADATA_FILE_EXT);
+    PathBuf::from(path)
+}
+
+/// Renames `from` to `to`, copying it instead when they're on different filesystems.
+async fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    match tokio::fs::rename(from, to).await {
+        Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {
+            tokio::fs::copy(from, to).await?;
+            tokio::fs::remove_file(from).await
+        }
+        result => result,
+    }
+}
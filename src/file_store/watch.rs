@@ -0,0 +1,147 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use notify::{
+    Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher,
+    event::{ModifyKind, RemoveKind},
+};
+use path_clean::PathClean;
+
+use crate::file_store::{
+    file_cache::FileCache,
+    is_internal_name,
+    metadata_db::{MetadataDb, key_of},
+    metadata_path,
+    spellings::Spellings,
+};
+
+// the events of the store's own writes arrive shortly after them, and are no news to it
+const OWN_WRITE_GRACE: Duration = Duration::from_secs(2);
+
+/// Paths the store itself just wrote to or removed.
+#[derive(Default)]
+pub struct OwnWrites {
+    paths: Mutex<HashMap<PathBuf, Instant>>,
+}
+
+impl OwnWrites {
+    pub fn note(&self, path: &Path) {
+        let mut paths = self.paths.lock().unwrap();
+        paths.retain(|_, at| at.elapsed() < OWN_WRITE_GRACE);
+        paths.insert(path.to_path_buf(), Instant::now());
+    }
+
+    fn contains(&self, path: &Path) -> bool {
+        let paths = self.paths.lock().unwrap();
+        paths
+            .get(path)
+            .is_some_and(|at| at.elapsed() < OWN_WRITE_GRACE)
+    }
+}
+
+/// What a watcher needs of the store to keep it in line with changes made behind its back.
+pub struct WatchedStore {
+    pub base_path: PathBuf,
+    pub cache: Arc<FileCache<PathBuf>>,
+    pub metadata_db: Option<Arc<MetadataDb>>,
+    pub own_writes: Arc<OwnWrites>,
+    pub spellings: Arc<Spellings>,
+}
+
+impl WatchedStore {
+    /// Starts watching the directory, which goes on until the returned watcher is dropped.
+    pub fn watch(self) -> io::Result<RecommendedWatcher> {
+        fs::create_dir_all(&self.base_path)?;
+        // events may come with the resolved path rather than the one the store was given
+        let resolved = fs::canonicalize(&self.base_path)?;
+
+        let base_path = self.base_path.clone();
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<Event>| match event {
+                Ok(event) => self.handle(&resolved, event),
+                Err(err) => {
+                    log::warn!("Error watching '{}': {err}", self.base_path.display());
+                    // whatever was missed could have changed anything
+                    self.cache.clear();
+                    self.spellings.clear();
+                }
+            })
+            .map_err(io::Error::other)?;
+        watcher
+            .watch(&base_path, RecursiveMode::Recursive)
+            .map_err(io::Error::other)?;
+
+        Ok(watcher)
+    }
+
+    fn handle(&self, resolved: &Path, event: Event) {
+        match event.kind {
+            EventKind::Create(_)
+            | EventKind::Modify(ModifyKind::Data(_) | ModifyKind::Name(_) | ModifyKind::Any)
+            | EventKind::Remove(_) => {}
+            _ => return,
+        }
+        if event.need_rescan() {
+            self.cache.clear();
+            self.spellings.clear();
+            return;
+        }
+
+        for path in &event.paths {
+            let Ok(relative) = path
+                .strip_prefix(resolved)
+                .or_else(|_| path.strip_prefix(&self.base_path))
+            else {
+                continue;
+            };
+            // the same as the store's own keys
+            let path = self.base_path.join(relative).clean();
+
+            let is_internal = path
+                .file_name()
+                .is_none_or(|name| name.to_str().is_none_or(is_internal_name));
+            if is_internal || self.own_writes.contains(&path) {
+                continue;
+            }
+            if let Some(dir) = path.parent() {
+                self.spellings.invalidate(dir);
+            }
+
+            if matches!(event.kind, EventKind::Remove(RemoveKind::Folder)) || path.is_dir() {
+                // there's no telling which of the cached files were under it
+                self.cache.clear();
+                self.spellings.clear();
+                continue;
+            }
+
+            self.cache.invalidate(&path);
+            // looked up again with none, the file is hashed as if it was new
+            if let Err(err) = self.forget_metadata(&path) {
+                log::warn!(
+                    "Failed to drop the metadata of '{}' after it changed: {err}",
+                    path.display()
+                );
+            }
+            if !path.exists() {
+                // might have been a directory moved away, along with everything in it
+                self.cache.clear();
+                self.spellings.clear();
+            }
+        }
+    }
+
+    fn forget_metadata(&self, path: &Path) -> io::Result<()> {
+        match &self.metadata_db {
+            Some(db) => db.remove(&key_of(&self.base_path, path)),
+            None => match fs::remove_file(metadata_path(path)) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+                _ => Ok(()),
+            },
+        }
+    }
+}
@@ -0,0 +1,299 @@
+use std::{fmt, io};
+
+use actix_web::rt::{self, task::JoinHandle};
+use async_trait::async_trait;
+use base64::{Engine, prelude::BASE64_STANDARD};
+use futures::{
+    StreamExt,
+    channel::{mpsc, oneshot},
+    stream::{self, BoxStream},
+};
+use object_store::{
+    ClientOptions, CopyOptions, GetOptions, GetResult, ListResult, MultipartUpload, ObjectMeta,
+    ObjectStore, ObjectStoreExt, PutMultipartOptions, PutOptions, PutPayload, PutResult,
+    UploadPart,
+    http::{HttpBuilder, HttpStore},
+    path::{Path as ObjectPath, PathPart},
+};
+use percent_encoding::percent_decode_str;
+use reqwest::{
+    Body, Client, Method, Response, Url,
+    header::{AUTHORIZATION, HeaderMap, HeaderValue},
+};
+use uuid::Uuid;
+
+use crate::{config::server::WebDavConfig, file_store::UPLOAD_FILE_EXT};
+
+/// A WebDAV share, which is everything the object stores are except for multipart uploads.
+/// Those are streamed as a single request to a temporary file instead, which is moved into
+/// place once complete.
+#[derive(Debug)]
+pub struct WebDavStore {
+    http: HttpStore,
+    client: Client,
+    url: Url,
+    prefix: String,
+}
+
+impl WebDavStore {
+    pub fn new(config: &WebDavConfig) -> io::Result<Self> {
+        let invalid = |err: String| io::Error::new(io::ErrorKind::InvalidInput, err);
+
+        let mut url = Url::parse(&config.url).map_err(|err| invalid(err.to_string()))?;
+        // listings come back as paths from the root of the server, so that's where the store
+        // is, with the share's own path being the prefix of every key
+        let prefix = percent_decode_str(url.path())
+            .decode_utf8_lossy()
+            .trim_matches('/')
+            .to_string();
+        url.set_path("/");
+
+        let mut headers = HeaderMap::new();
+        if let Some(username) = &config.username {
+            let credentials = format!("{username}:{}", config.password.as_deref().unwrap_or(""));
+            let mut value =
+                HeaderValue::try_from(format!("Basic {}", BASE64_STANDARD.encode(credentials)))
+                    .map_err(|err| invalid(err.to_string()))?;
+            value.set_sensitive(true);
+            headers.insert(AUTHORIZATION, value);
+        }
+
+        let http = HttpBuilder::new()
+            .with_url(url.as_str())
+            .with_client_options(
+                ClientOptions::new()
+                    .with_allow_http(url.scheme() == "http")
+                    .with_default_headers(headers.clone()),
+            )
+            .build()
+            .map_err(|err| invalid(err.to_string()))?;
+        let client = Client::builder()
+            .default_headers(headers)
+            .build()
+            .map_err(io::Error::other)?;
+
+        Ok(WebDavStore {
+            http,
+            client,
+            url,
+            prefix,
+        })
+    }
+
+    /// The path of the share on the server, which the keys of its files start with.
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    fn url_of(&self, location: &ObjectPath) -> Url {
+        let mut url = self.url.clone();
+        url.path_segments_mut()
+            .unwrap()
+            .clear()
+            .extend(location.parts());
+        url
+    }
+}
+
+impl fmt::Display for WebDavStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "WebDAV({}{})", self.url, self.prefix)
+    }
+}
+
+#[async_trait]
+impl ObjectStore for WebDavStore {
+    async fn put_opts(
+        &self,
+        location: &ObjectPath,
+        payload: PutPayload,
+        opts: PutOptions,
+    ) -> object_store::Result<PutResult> {
+        self.http.put_opts(location, payload, opts).await
+    }
+
+    async fn put_multipart_opts(
+        &self,
+        location: &ObjectPath,
+        _opts: PutMultipartOptions,
+    ) -> object_store::Result<Box<dyn MultipartUpload>> {
+        let name = location.filename().unwrap_or_default();
+        let temp_name = format!(".{name}.{}{UPLOAD_FILE_EXT}", Uuid::new_v4().simple());
+        let parent = location
+            .parts()
+            .take(location.parts_count().saturating_sub(1));
+        let temp = ObjectPath::from_iter(parent.chain([PathPart::from(temp_name.as_str())]));
+
+        // an empty file first, which has the store create the directories leading up to it
+        self.http.put(&temp, PutPayload::new()).await?;
+
+        let (parts, body) = mpsc::unbounded::<(PutPayload, oneshot::Sender<()>)>();
+        let body = body.flat_map(|(payload, taken)| {
+            let _ = taken.send(());
+            stream::iter(payload.into_iter().map(Ok::<_, io::Error>))
+        });
+        let request = self
+            .client
+            .put(self.url_of(&temp))
+            .body(Body::wrap_stream(body))
+            .send();
+
+        Ok(Box::new(WebDavUpload {
+            client: self.client.clone(),
+            temp_url: self.url_of(&temp),
+            target_url: self.url_of(location),
+            parts: Some(parts),
+            request: Some(rt::spawn(request)),
+            is_done: false,
+        }))
+    }
+
+    async fn get_opts(
+        &self,
+        location: &ObjectPath,
+        options: GetOptions,
+    ) -> object_store::Result<GetResult> {
+        self.http.get_opts(location, options).await
+    }
+
+    fn delete_stream(
+        &self,
+        locations: BoxStream<'static, object_store::Result<ObjectPath>>,
+    ) -> BoxStream<'static, object_store::Result<ObjectPath>> {
+        self.http.delete_stream(locations)
+    }
+
+    fn list(
+        &self,
+        prefix: Option<&ObjectPath>,
+    ) -> BoxStream<'static, object_store::Result<ObjectMeta>> {
+        self.http.list(prefix)
+    }
+
+    async fn list_with_delimiter(
+        &self,
+        prefix: Option<&ObjectPath>,
+    ) -> object_store::Result<ListResult> {
+        self.http.list_with_delimiter(prefix).await
+    }
+
+    async fn copy_opts(
+        &self,
+        from: &ObjectPath,
+        to: &ObjectPath,
+        options: CopyOptions,
+    ) -> object_store::Result<()> {
+        self.http.copy_opts(from, to, options).await
+    }
+}
+
+/// A PUT of a temporary file with its body still being sent, each part going out in the
+/// order it was given.
+#[derive(Debug)]
+struct WebDavUpload {
+    client: Client,
+    temp_url: Url,
+    target_url: Url,
+    parts: Option<mpsc::UnboundedSender<(PutPayload, oneshot::Sender<()>)>>,
+    request: Option<JoinHandle<reqwest::Result<Response>>>,
+    // moved into place or deleted, either way there's nothing left to clean up
+    is_done: bool,
+}
+
+fn upload_error(err: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> object_store::Error {
+    object_store::Error::Generic {
+        store: "WebDAV",
+        source: err.into(),
+    }
+}
+
+#[async_trait]
+impl MultipartUpload for WebDavUpload {
+    fn put_part(&mut self, data: PutPayload) -> UploadPart {
+        // a part counts as sent once the request has taken it, so at most the parts in
+        // flight are ever held in memory
+        let (taken, is_taken) = oneshot::channel();
+        let is_queued = self
+            .parts
+            .as_ref()
+            .is_some_and(|parts| parts.unbounded_send((data, taken)).is_ok());
+
+        Box::pin(async move {
+            if !is_queued {
+                return Err(upload_error("upload is no longer being sent"));
+            }
+            is_taken
+                .await
+                .map_err(|_| upload_error("upload request ended early"))
+        })
+    }
+
+    async fn complete(&mut self) -> object_store::Result<PutResult> {
+        // ends the body, after which the server answers
+        self.parts.take();
+        let request = self
+            .request
+            .take()
+            .ok_or_else(|| upload_error("upload was already completed"))?;
+        request
+            .await
+            .map_err(upload_error)?
+            .and_then(Response::error_for_status)
+            .map_err(upload_error)?;
+
+        self.client
+            .request(Method::from_bytes(b"MOVE").unwrap(), self.temp_url.clone())
+            .header("Destination", self.target_url.as_str())
+            .header("Overwrite", "T")
+            .send()
+            .await
+            .and_then(Response::error_for_status)
+            .map_err(upload_error)?;
+        self.is_done = true;
+
+        Ok(PutResult {
+            e_tag: None,
+            version: None,
+            extensions: Default::default(),
+        })
+    }
+
+    async fn abort(&mut self) -> object_store::Result<()> {
+        self.cancel();
+        self.client
+            .delete(self.temp_url.clone())
+            .send()
+            .await
+            .map_err(upload_error)?;
+        self.is_done = true;
+        Ok(())
+    }
+}
+
+impl WebDavUpload {
+    fn cancel(&mut self) {
+        // before the parts go, since that ends the body as if it was complete and the
+        // server would keep the file as is
+        if let Some(request) = self.request.take() {
+            request.abort();
+        }
+        self.parts.take();
+    }
+}
+
+impl Drop for WebDavUpload {
+    fn drop(&mut self) {
+        if self.is_done {
+            return;
+        }
+
+        self.cancel();
+        let client = self.client.clone();
+        let temp_url = self.temp_url.clone();
+        rt::spawn(async move {
+            if let Err(err) = client.delete(temp_url.clone()).send().await {
+                log::warn!("Failed to delete abandoned upload '{temp_url}': {err}");
+            }
+        });
+    }
+}
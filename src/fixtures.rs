@@ -0,0 +1,275 @@
+//! A store seeded with the same files every time, along with everything the routes serving
+//! them need, for tests asserting exact responses.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use actix_http::Request;
+use actix_web::{
+    App, Error,
+    body::MessageBody,
+    dev::{Service, ServiceResponse},
+    test::init_service,
+    web::{Bytes, Data, ServiceConfig},
+};
+use futures::{StreamExt, stream};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{
+    SharedFileStore,
+    access_times::{AccessTimes, SharedAccessTimes},
+    attributes::{Attributes, SharedAttributes},
+    authorized::{self, Authenticator},
+    branding::Branding,
+    config::server::{AuthConfig, FileSource, Mount, ServerConfig},
+    cors::Cors,
+    derive::{Derivatives, SharedDerivatives},
+    file_handles::FileHandles,
+    file_store::{FileStorageCore, FileStore, FsFileStore},
+    geoip::GeoIp,
+    header_rules::HeaderRules,
+    highlight::Highlighter,
+    home_page::HomePage,
+    hooks::{Hooks, SharedHooks},
+    hotlink::Hotlinks,
+    mime_types::MimeTypes,
+    mirror::{SharedStandby, Standby},
+    quarantine::Quarantine,
+    routes::{
+        ScopeCreator, api::ApiRoute, serve_files::FileServeRoute, well_known::WellKnownRoute,
+    },
+    throttle::{DownloadThrottle, UploadThrottle},
+    token_clients::TokenClients,
+    transfers::Transfers,
+    trash::{SharedTrash, Trash},
+};
+
+/// A file the fixture store is seeded with.
+pub struct FixtureFile {
+    pub path: &'static str,
+    pub contents: &'static [u8],
+    /// Hex SHA-256 of `contents`, worked out by hand so a change in hashing shows up too
+    pub hash: &'static str,
+}
+
+pub const HELLO_TXT: FixtureFile = FixtureFile {
+    path: "hello.txt",
+    contents: b"Hello, world!\n",
+    hash: "d9014c4624844aa5bac314773d6b689ad467fa4e1d1a50a1b8a99d5a95f72ff5",
+};
+
+pub const PAGE_HTML: FixtureFile = FixtureFile {
+    path: "site/page.html",
+    contents: b"<!doctype html>\n<title>Fixture</title>\n",
+    hash: "0b83a1f1e093e87f1a2c649da78c1a2311604713666f5c834e4c827d9a676acd",
+};
+
+pub const REPORT_JSON: FixtureFile = FixtureFile {
+    path: "data/report.json",
+    contents: b"{\"visits\": 42}\n",
+    hash: "e7a1f81bd699d01b24473a841d5db8005fb8e124528099630d6e0add909cf78a",
+};
+
+pub const SITE_CSS: FixtureFile = FixtureFile {
+    path: "site/styles/site.css",
+    contents: b"body { margin: 0; }\n",
+    hash: "eac0e790573fb6424e6008c9f3a1bdf262add6bb2460a001bb89549fb1ddf482",
+};
+
+pub const FIXTURE_FILES: [FixtureFile; 4] = [HELLO_TXT, PAGE_HTML, REPORT_JSON, SITE_CSS];
+
+/// `config` with the files under `site/team/` in a realm of their own, where `team-key` is valid
+/// instead of `admin-key`. Either can do anything in its realm, and the store stays the local
+/// one of the fixture.
+pub fn with_team_realm(config: ServerConfig) -> ServerConfig {
+    let api_key = |key: &str| {
+        let payload = serde_json::from_value(serde_json::json!({ "permissions": ["*"] }));
+        AuthConfig::ApiKey {
+            header: "X-API-Key".to_string(),
+            keys: BTreeMap::from([(format!("{:x}", Sha256::digest(key)), payload.unwrap())]),
+        }
+    };
+    ServerConfig {
+        auth: api_key("admin-key"),
+        files_source: FileSource::Mounted {
+            mounts: vec![Mount {
+                prefix: "site/team".to_string(),
+                source: FileSource::Memory,
+                auth: Some(api_key("team-key")),
+            }],
+        },
+        ..config
+    }
+}
+
+/// A directory of its own under the system's temp dir, which is removed again when dropped.
+pub struct TempDir(PathBuf);
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Creates a new, empty `TempDir`.
+pub fn temp_dir() -> TempDir {
+    let dir = std::env::temp_dir().join(format!("cdn-test-{}", Uuid::new_v4().simple()));
+    fs::create_dir_all(&dir).unwrap();
+    TempDir(dir)
+}
+
+/// The routes of the server over the app data of `fixture`, put together like `main` does.
+pub async fn app(
+    fixture: &Fixture,
+) -> impl Service<Request, Response = ServiceResponse<impl MessageBody>, Error = Error> {
+    init_service(
+        App::new()
+            .configure(|app| fixture.configure(app))
+            .service(ApiRoute::create_scope())
+            .service(WellKnownRoute::create_scope())
+            .service(FileServeRoute::create_scope()),
+    )
+    .await
+}
+
+/// The app data of a server over a local store in a directory of its own, which is removed
+/// again when dropped.
+pub struct Fixture {
+    // removed again along with the fixture
+    _dir: TempDir,
+    config: Data<ServerConfig>,
+    store: Data<SharedFileStore>,
+    attributes: Data<SharedAttributes>,
+    access_times: Data<SharedAccessTimes>,
+    derivatives: Data<SharedDerivatives>,
+    header_rules: Data<HeaderRules>,
+    mime_types: Data<MimeTypes>,
+    branding: Data<Branding>,
+    home_page: Data<HomePage>,
+    highlighter: Data<Highlighter>,
+    hooks: Data<SharedHooks>,
+    standby: Data<SharedStandby>,
+    transfers: Data<Transfers>,
+    file_handles: Data<FileHandles>,
+    download_throttle: Data<DownloadThrottle>,
+    // what the routes changing files need on top, behind the config's `auth`
+    quarantine: Data<Quarantine>,
+    trash: Data<SharedTrash>,
+    upload_throttle: Data<UploadThrottle>,
+    token_clients: Data<TokenClients>,
+    // left out under the default `jwt` auth, whose secret tests don't set
+    authenticator: Option<Data<dyn Authenticator>>,
+    cors: Data<Cors>,
+    hotlinks: Data<Hotlinks>,
+    geoip: Data<GeoIp>,
+}
+
+impl Fixture {
+    /// Sets everything up from `config`, with the store holding `FIXTURE_FILES`.
+    pub async fn seeded(mut config: ServerConfig) -> Self {
+        let dir = temp_dir();
+        let files_dir = dir.join("files");
+        fs::create_dir_all(&files_dir).unwrap();
+        config.data_dir = dir.join("data").to_string_lossy().into_owned();
+
+        let file_handles = Arc::new(FileHandles::new(&config.file_handles));
+        let store = FileStore::Filesystem(
+            FsFileStore::new(&files_dir)
+                .with_hash_algorithm(config.hash_algorithm)
+                .with_file_handles(&file_handles),
+        );
+        for file in &FIXTURE_FILES {
+            let stream = stream::iter([Ok(Bytes::from_static(file.contents))]).boxed_local();
+            let staged = store.stage_upload(Path::new(file.path), stream, None).await;
+            store.commit_upload(staged.unwrap()).await.unwrap();
+        }
+
+        Fixture {
+            store: Data::new(Arc::new(store)),
+            attributes: Data::new(Arc::new(Attributes::open(&config.data_dir).unwrap())),
+            access_times: Data::new(Arc::new(
+                AccessTimes::open(&config.data_dir, &config.access_times).unwrap(),
+            )),
+            derivatives: Data::new(Arc::new(
+                Derivatives::new(&config.data_dir, &config.derivatives).unwrap(),
+            )),
+            header_rules: Data::new(HeaderRules::new(&config.headers, &config.render).unwrap()),
+            mime_types: Data::new(MimeTypes::new(&config.mime_overrides).unwrap()),
+            branding: Data::new(Branding::load(&config.branding).unwrap()),
+            home_page: Data::new(HomePage::load(&config.home_page).unwrap()),
+            highlighter: Data::new(Highlighter::default()),
+            hooks: Data::new(Arc::new(Hooks::new(&config.hooks, []))),
+            standby: Data::new(Arc::new(Standby::new(&config.data_dir, None))),
+            transfers: Data::new(Transfers::default()),
+            file_handles: Data::from(file_handles),
+            download_throttle: Data::new(DownloadThrottle::new(&config.download_rate_limit)),
+            quarantine: Data::new(
+                Quarantine::open(&config.data_dir, config.hash_algorithm).unwrap(),
+            ),
+            trash: Data::new(Arc::new(
+                Trash::open(&config.data_dir, &config.trash).unwrap(),
+            )),
+            upload_throttle: Data::new(UploadThrottle::new(&config.upload_rate_limits)),
+            token_clients: Data::new(
+                TokenClients::open(&config.data_dir, &config.clients).unwrap(),
+            ),
+            authenticator: authorized::server_authenticator(&config)
+                .ok()
+                .map(Data::from),
+            cors: Data::new(Cors::new(&config.cors).unwrap()),
+            hotlinks: Data::new(Hotlinks::new(&config.hotlinks).unwrap()),
+            geoip: Data::new(GeoIp::load(&config.geoip).unwrap()),
+            config: Data::new(config),
+            _dir: dir,
+        }
+    }
+
+    /// Registers the app data, for `App::configure`.
+    pub fn configure(&self, app: &mut ServiceConfig) {
+        app.app_data(self.config.clone())
+            .app_data(self.store.clone())
+            .app_data(self.attributes.clone())
+            .app_data(self.access_times.clone())
+            .app_data(self.derivatives.clone())
+            .app_data(self.header_rules.clone())
+            .app_data(self.mime_types.clone())
+            .app_data(self.branding.clone())
+            .app_data(self.home_page.clone())
+            .app_data(self.highlighter.clone())
+            .app_data(self.hooks.clone())
+            .app_data(self.standby.clone())
+            .app_data(self.transfers.clone())
+            .app_data(self.file_handles.clone())
+            .app_data(self.download_throttle.clone())
+            .app_data(self.quarantine.clone())
+            .app_data(self.trash.clone())
+            .app_data(self.upload_throttle.clone())
+            .app_data(self.token_clients.clone())
+            .app_data(self.cors.clone())
+            .app_data(self.hotlinks.clone())
+            .app_data(self.geoip.clone());
+        if let Some(authenticator) = &self.authenticator {
+            app.app_data(authenticator.clone());
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use std::{io, net::IpAddr};
+
+use actix_web::{
+    HttpResponse, Result,
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    middleware::Next,
+    web::Data,
+};
+use maxminddb::{Reader, geoip2};
+
+use crate::{config::server::GeoIpConfig, routes::client_ip};
+
+/// The configured GeoIP database and country rules.
+pub struct GeoIp {
+    reader: Option<Reader<Vec<u8>>>,
+    allow: Vec<String>,
+    deny: Vec<String>,
+}
+
+impl GeoIp {
+    pub fn load(config: &GeoIpConfig) -> io::Result<Self> {
+        let reader = match &config.database {
+            Some(path) => Some(Reader::open_readfile(path).map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("failed to open GeoIP database {path}: {err}"),
+                )
+            })?),
+            None => None,
+        };
+
+        if reader.is_none() && !(config.allow.is_empty() && config.deny.is_empty()) {
+            log::warn!("GeoIP allow/deny rules are set without a database, they won't apply");
+        }
+
+        let normalize = |codes: &[String]| codes.iter().map(|c| c.to_ascii_uppercase()).collect();
+        Ok(GeoIp {
+            reader,
+            allow: normalize(&config.allow),
+            deny: normalize(&config.deny),
+        })
+    }
+
+    fn country(&self, ip: IpAddr) -> Option<String> {
+        let result = self.reader.as_ref()?.lookup(ip).ok()?;
+        let country = result.decode::<geoip2::Country>().ok()??;
+        country.country.iso_code.map(str::to_string)
+    }
+
+    fn is_allowed(&self, country: Option<&str>) -> bool {
+        if self.reader.is_none() {
+            return true;
+        }
+
+        match country {
+            Some(country) if self.deny.iter().any(|c| c == country) => false,
+            Some(country) => self.allow.is_empty() || self.allow.iter().any(|c| c == country),
+            None => self.allow.is_empty(),
+        }
+    }
+}
+
+/// Turns away clients from countries the rules don't allow, and logs every request with
+/// the country it came from.
+pub async fn geo_access(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>> {
+    let geoip = req
+        .app_data::<Data<GeoIp>>()
+        .expect("GeoIp is registered as app data")
+        .clone();
+
+    let ip = client_ip(req.request());
+    let country = ip.and_then(|ip| geoip.country(ip));
+    let request_line = format!("{} {}", req.method(), req.path());
+    let client = ip.map_or("-".to_string(), |ip| ip.to_string());
+    let country_code = country.as_deref().unwrap_or("-").to_string();
+
+    let res = if geoip.is_allowed(country.as_deref()) {
+        next.call(req).await?.map_into_left_body()
+    } else {
+        req.into_response(
+            HttpResponse::Forbidden()
+                .body("Not available in your region")
+                .map_into_right_body(),
+        )
+    };
+
+    log::info!(
+        target: "access",
+        "{client} {country_code} \"{request_line}\" {}",
+        res.status().as_u16()
+    );
+    Ok(res)
+}
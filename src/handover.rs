@@ -0,0 +1,141 @@
+use std::{
+    env,
+    fs::File,
+    io::{self, Read, Write},
+    net::{TcpListener, ToSocketAddrs},
+    os::{
+        fd::{AsRawFd, FromRawFd, RawFd},
+        unix::process::CommandExt,
+    },
+    process::Command,
+    time::Duration,
+};
+
+use actix_web::{dev::ServerHandle, rt::task};
+use tokio::signal::unix::{SignalKind, signal};
+
+const LISTEN_FD_VAR: &str = "CDN_LISTEN_FD";
+const READY_FD_VAR: &str = "CDN_READY_FD";
+
+// where the listener and readiness pipe end up in the new process
+const LISTEN_FD: RawFd = 3;
+const READY_FD: RawFd = 4;
+
+// how long the new process gets to start serving before the upgrade is abandoned
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Whether this process was started by another one handing its listener over.
+pub fn is_successor() -> bool {
+    env::var_os(LISTEN_FD_VAR).is_some()
+}
+
+/// Takes over the listener passed down by the previous process, or binds a new one.
+pub fn listener(addr: impl ToSocketAddrs) -> io::Result<TcpListener> {
+    match fd_from_env(LISTEN_FD_VAR) {
+        // SAFETY: the previous process placed its listening socket at this fd before exec
+        Some(fd) => Ok(unsafe { TcpListener::from_raw_fd(fd) }),
+        None => TcpListener::bind(addr),
+    }
+}
+
+/// Lets the previous process know that we're serving, so it can begin shutting down.
+pub fn notify_ready() {
+    let Some(fd) = fd_from_env(READY_FD_VAR) else {
+        return;
+    };
+
+    // SAFETY: the previous process left the write end of a pipe open at this fd
+    let mut pipe = unsafe { File::from_raw_fd(fd) };
+    if let Err(err) = pipe.write_all(&[1]) {
+        log::warn!("Failed to notify previous process that we're ready: {err}");
+    }
+}
+
+/// Waits for SIGUSR2, then hands the listener over to a new instance of the current binary.
+/// Once that instance is serving, this server stops gracefully so in-flight transfers finish.
+pub async fn watch_for_upgrade(listener: TcpListener, server: ServerHandle) {
+    let mut signals = match signal(SignalKind::user_defined2()) {
+        Ok(signals) => signals,
+        Err(err) => {
+            log::warn!("Failed to listen for SIGUSR2, zero-downtime restarts are disabled: {err}");
+            return;
+        }
+    };
+
+    while signals.recv().await.is_some() {
+        log::info!("SIGUSR2 received; handing listener over to a new process");
+
+        match spawn_successor(&listener).await {
+            Ok(pid) => {
+                log::info!("Process {pid} took over, finishing in-flight requests before exiting");
+                server.stop(true).await;
+                return;
+            }
+            Err(err) => log::error!("Handover failed, continuing to serve: {err}"),
+        }
+    }
+}
+
+async fn spawn_successor(listener: &TcpListener) -> io::Result<u32> {
+    let (mut ready_reader, ready_writer) = io::pipe()?;
+    let listen_fd = listener.as_raw_fd();
+    let ready_fd = ready_writer.as_raw_fd();
+
+    let mut command = Command::new(env::current_exe()?);
+    command
+        .args(env::args_os().skip(1))
+        .env(LISTEN_FD_VAR, LISTEN_FD.to_string())
+        .env(READY_FD_VAR, READY_FD.to_string());
+
+    // SAFETY: only async-signal-safe libc calls are made between fork and exec
+    unsafe {
+        command.pre_exec(move || {
+            // move both out of the way first, in case either already sits on a target fd
+            let listen_fd = cvt(libc::fcntl(listen_fd, libc::F_DUPFD, 10))?;
+            let ready_fd = cvt(libc::fcntl(ready_fd, libc::F_DUPFD, 10))?;
+
+            // dup2 doesn't carry over close-on-exec, which is what lets these survive exec
+            cvt(libc::dup2(listen_fd, LISTEN_FD))?;
+            cvt(libc::dup2(ready_fd, READY_FD))?;
+
+            libc::close(listen_fd);
+            libc::close(ready_fd);
+            Ok(())
+        });
+    }
+
+    let mut child = command.spawn()?;
+
+    // drop our end, so the read below sees EOF if the child exits without reporting in
+    drop(ready_writer);
+
+    let ready = task::spawn_blocking(move || ready_reader.read(&mut [0; 1]));
+    match tokio::time::timeout(READY_TIMEOUT, ready).await {
+        Ok(Ok(Ok(1))) => Ok(child.id()),
+        result => {
+            let _ = child.kill();
+            let _ = child.wait();
+
+            Err(match result {
+                Err(_) => io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "new process did not become ready in time",
+                ),
+                Ok(Ok(Err(err))) => err,
+                _ => io::Error::other("new process exited before becoming ready"),
+            })
+        }
+    }
+}
+
+fn fd_from_env(var: &str) -> Option<RawFd> {
+    env::var(var).ok().and_then(|v| v.parse().ok())
+}
+
+fn cvt(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
@@ -0,0 +1,254 @@
+use std::{collections::BTreeMap, io, path::Path};
+
+use actix_web::{
+    HttpResponseBuilder,
+    http::header::{self, HeaderName, HeaderValue},
+};
+use globset::{GlobBuilder, GlobMatcher};
+
+use crate::config::server::{HeaderRule, RenderRule};
+
+// private files are only served to requests with credentials, so a shared cache must never
+// keep one around for whoever asks next, whatever the rules for public files say
+const PRIVATE_HEADERS: [(HeaderName, &str); 2] = [
+    (header::CACHE_CONTROL, "private, no-store"),
+    (header::VARY, "Authorization"),
+];
+
+struct CompiledRule {
+    matcher: GlobMatcher,
+    match_name_only: bool,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    private_headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+/// What an HTML or SVG file allowed to render is sent with.
+pub struct Rendering {
+    matcher: GlobMatcher,
+    match_name_only: bool,
+    pub content_security_policy: Option<HeaderValue>,
+}
+
+/// The configured header rules, validated and ready to match against file paths.
+pub struct HeaderRules {
+    rules: Vec<CompiledRule>,
+    render: Vec<Rendering>,
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+fn compile_pattern(pattern: &str) -> io::Result<GlobMatcher> {
+    // `*` stays within a single segment, `**` is what crosses directories
+    Ok(GlobBuilder::new(pattern)
+        .literal_separator(true)
+        .build()
+        .map_err(|err| invalid(format!("invalid rule pattern '{pattern}': {err}")))?
+        .compile_matcher())
+}
+
+fn matches(matcher: &GlobMatcher, match_name_only: bool, path: &Path) -> bool {
+    if match_name_only {
+        matcher.is_match(path.file_name().map(Path::new).unwrap_or(path))
+    } else {
+        matcher.is_match(path)
+    }
+}
+
+impl HeaderRules {
+    pub fn new(rules: &[HeaderRule], render: &[RenderRule]) -> io::Result<Self> {
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                let matcher = compile_pattern(&rule.pattern)?;
+
+                let compile = |headers: &BTreeMap<String, String>| {
+                    headers
+                        .iter()
+                        .map(|(name, value)| {
+                            let name = HeaderName::try_from(name.as_str())
+                                .map_err(|_| invalid(format!("invalid header name '{name}'")))?;
+                            let value = HeaderValue::try_from(value.as_str()).map_err(|_| {
+                                invalid(format!("invalid value for header '{name}'"))
+                            })?;
+                            Ok((name, value))
+                        })
+                        .collect::<io::Result<_>>()
+                };
+
+                Ok(CompiledRule {
+                    matcher,
+                    match_name_only: !rule.pattern.contains('/'),
+                    headers: compile(&rule.headers)?,
+                    private_headers: compile(&rule.private_headers)?,
+                })
+            })
+            .collect::<io::Result<_>>()?;
+
+        let render = render
+            .iter()
+            .map(|rule| {
+                let content_security_policy = rule
+                    .content_security_policy
+                    .as_deref()
+                    .map(HeaderValue::try_from)
+                    .transpose()
+                    .map_err(|_| {
+                        invalid(format!(
+                            "invalid Content-Security-Policy for '{}'",
+                            rule.pattern
+                        ))
+                    })?;
+                Ok(Rendering {
+                    matcher: compile_pattern(&rule.pattern)?,
+                    match_name_only: !rule.pattern.contains('/'),
+                    content_security_policy,
+                })
+            })
+            .collect::<io::Result<_>>()?;
+
+        Ok(HeaderRules { rules, render })
+    }
+
+    /// Whether HTML or SVG at `path` may be served as what it is rather than as plain text,
+    /// going by the last render rule matching it.
+    pub fn rendering(&self, path: &Path) -> Option<&Rendering> {
+        self.render
+            .iter()
+            .rev()
+            .find(|rule| matches(&rule.matcher, rule.match_name_only, path))
+    }
+
+    /// Inserts the headers of every rule matching `path`, later rules replacing earlier ones.
+    /// A `private` file gets the private headers on top, after all the others.
+    pub fn apply(&self, path: &Path, private: bool, response: &mut HttpResponseBuilder) {
+        let matching: Vec<_> = self
+            .rules
+            .iter()
+            .filter(|rule| matches(&rule.matcher, rule.match_name_only, path))
+            .collect();
+
+        for rule in &matching {
+            for header in &rule.headers {
+                response.insert_header(header.clone());
+            }
+        }
+        if !private {
+            return;
+        }
+
+        for (name, value) in PRIVATE_HEADERS {
+            response.insert_header((name, value));
+        }
+        for rule in &matching {
+            for header in &rule.private_headers {
+                response.insert_header(header.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::HttpResponse;
+
+    use super::*;
+
+    #[test]
+    fn private_files_are_kept_out_of_shared_caches() {
+        let rules = HeaderRules::new(
+            &[
+                HeaderRule {
+                    pattern: "**".to_string(),
+                    headers: BTreeMap::from([(
+                        "Cache-Control".to_string(),
+                        "public, max-age=3600".to_string(),
+                    )]),
+                    private_headers: BTreeMap::new(),
+                },
+                HeaderRule {
+                    pattern: "reports/**".to_string(),
+                    headers: BTreeMap::new(),
+                    private_headers: BTreeMap::from([(
+                        "Cache-Control".to_string(),
+                        "private, max-age=60".to_string(),
+                    )]),
+                },
+            ],
+            &[],
+        )
+        .unwrap();
+        let headers = |path: &str, private: bool| {
+            let mut response = HttpResponse::Ok();
+            rules.apply(Path::new(path), private, &mut response);
+            let response = response.finish();
+            let get = |name| {
+                response
+                    .headers()
+                    .get(name)
+                    .map(|value| value.to_str().unwrap().to_string())
+            };
+            (get(header::CACHE_CONTROL), get(header::VARY))
+        };
+
+        assert_eq!(
+            headers("a.txt", false),
+            (Some("public, max-age=3600".to_string()), None)
+        );
+        assert_eq!(
+            headers("a.txt", true),
+            (
+                Some("private, no-store".to_string()),
+                Some("Authorization".to_string())
+            )
+        );
+        assert_eq!(
+            headers("reports/a.txt", true),
+            (
+                Some("private, max-age=60".to_string()),
+                Some("Authorization".to_string())
+            )
+        );
+    }
+
+    #[test]
+    fn the_last_matching_render_rule_wins() {
+        let rules = HeaderRules::new(
+            &[],
+            &[
+                RenderRule {
+                    pattern: "docs/**".to_string(),
+                    content_security_policy: Some("script-src 'none'".to_string()),
+                },
+                RenderRule {
+                    pattern: "*.svg".to_string(),
+                    content_security_policy: None,
+                },
+            ],
+        )
+        .unwrap();
+        let policy = |path: &str| {
+            rules
+                .rendering(Path::new(path))
+                .map(|rendering| rendering.content_security_policy.clone())
+        };
+
+        assert_eq!(
+            policy("docs/guide/index.html"),
+            Some(Some(HeaderValue::from_static("script-src 'none'")))
+        );
+        assert_eq!(policy("docs/logo.svg"), Some(None));
+        assert_eq!(policy("pastes/a.html"), None);
+        assert!(
+            HeaderRules::new(
+                &[],
+                &[RenderRule {
+                    pattern: "docs/**".to_string(),
+                    content_security_policy: Some("bad\nvalue".to_string()),
+                }],
+            )
+            .is_err()
+        );
+    }
+}
@@ -0,0 +1,86 @@
+use std::{path::Path, sync::OnceLock};
+
+use syntect::{
+    highlighting::{Theme, ThemeSet},
+    html::highlighted_html_for_string,
+    parsing::{SyntaxReference, SyntaxSet},
+};
+
+// larger files are served as they are, rather than held up highlighting them
+pub const MAX_HIGHLIGHTED_BYTES: u64 = 1024 * 1024;
+
+const THEME: &str = "InspiredGitHub";
+
+/// Renders source files as HTML with their syntax highlighted, loading the syntaxes it knows
+/// the first time it's asked, so servers that never highlight anything don't pay for them.
+#[derive(Default)]
+pub struct Highlighter {
+    loaded: OnceLock<(SyntaxSet, Theme)>,
+}
+
+impl Highlighter {
+    fn loaded(&self) -> &(SyntaxSet, Theme) {
+        self.loaded.get_or_init(|| {
+            let mut themes = ThemeSet::load_defaults();
+            let theme = themes
+                .themes
+                .remove(THEME)
+                .expect("InspiredGitHub is one of the default themes");
+            (SyntaxSet::load_defaults_newlines(), theme)
+        })
+    }
+
+    /// The syntax of the file at `path`, by its extension or its name for ones like `Makefile`.
+    fn syntax(&self, path: &Path) -> Option<&SyntaxReference> {
+        let syntaxes = &self.loaded().0;
+        let by_extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(|extension| {
+                syntaxes
+                    .find_syntax_by_extension(extension)
+                    .or_else(|| syntaxes.find_syntax_by_extension(&extension.to_lowercase()))
+            });
+        by_extension.or_else(|| {
+            let name = path.file_name()?.to_str()?;
+            syntaxes.find_syntax_by_extension(name)
+        })
+    }
+
+    /// Whether there's a syntax to highlight the file at `path` with.
+    pub fn knows(&self, path: &Path) -> bool {
+        self.syntax(path).is_some()
+    }
+
+    /// `code` from the file at `path` as HTML, in a `<pre>` with its syntax highlighted.
+    pub fn highlight(&self, path: &Path, code: &str) -> Option<String> {
+        let (syntaxes, theme) = self.loaded();
+        let syntax = self.syntax(path)?;
+        highlighted_html_for_string(code, syntaxes, syntax, theme).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn highlights_code_by_its_extension() {
+        let highlighter = Highlighter::default();
+
+        let html = highlighter
+            .highlight(
+                Path::new("src/main.RS"),
+                "fn main() { println!(\"<b>\"); }\n",
+            )
+            .unwrap();
+        assert!(html.starts_with("<pre style="));
+        assert!(html.matches("<span style=").count() > 3, "{html}");
+        // the code's own markup is shown as text
+        assert!(html.contains("&lt;b&gt;"));
+        assert!(!html.contains("<b>"));
+
+        assert!(highlighter.knows(Path::new("Makefile")));
+        assert!(!highlighter.knows(Path::new("photo.jpeg")));
+    }
+}
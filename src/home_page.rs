@@ -0,0 +1,157 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use actix_web::{HttpRequest, HttpResponse, http::header::ContentType};
+use tokio::sync::Mutex;
+
+use crate::{
+    authorized::request_payload,
+    branding::{Branding, accepts_html, escape_html},
+    config::server::{HomePageConfig, HomePageLink, SERVER_CONFIG_NAME},
+    file_store::{EntryKind, FileStorageCore, FileStore},
+};
+
+// looked for next to the server config
+const TEMPLATE_OVERRIDE: &str = "home.html";
+
+// counting walks the whole store, so the count is only that fresh
+const TOTALS_MAX_AGE: Duration = Duration::from_secs(60);
+
+/// How many files the store holds and how large they are altogether.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StoreTotals {
+    pub files: u64,
+    pub total_bytes: u64,
+}
+
+/// The status page answering requests for `/`, when turned on and nothing else answers them.
+pub struct HomePage {
+    enabled: bool,
+    template: Option<String>,
+    links: Vec<HomePageLink>,
+    totals: Mutex<Option<(Instant, StoreTotals)>>,
+}
+
+impl HomePage {
+    pub fn load(config: &HomePageConfig) -> io::Result<Self> {
+        let config_dir = Path::new(SERVER_CONFIG_NAME)
+            .parent()
+            .unwrap_or(Path::new(""));
+        let template = match fs::read_to_string(config_dir.join(TEMPLATE_OVERRIDE)) {
+            Ok(template) => Some(template),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err),
+        };
+
+        Ok(HomePage {
+            enabled: config.enabled,
+            template,
+            links: config.links.clone(),
+            totals: Mutex::new(None),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// The page for browsers, and the title and totals as JSON for everything else.
+    pub async fn response(
+        &self,
+        req: &HttpRequest,
+        store: &FileStore,
+        branding: &Branding,
+    ) -> io::Result<HttpResponse> {
+        let totals = self.totals(store).await?;
+        if !accepts_html(req) {
+            return Ok(HttpResponse::Ok().json(serde_json::json!({
+                "title": branding.title(),
+                "files": totals.files,
+                "total_bytes": totals.total_bytes,
+            })));
+        }
+
+        // an upload needs credentials, which a browser only sends along once it has them
+        let upload_form = match request_payload(req, Path::new("")).await {
+            Some(_) => UPLOAD_FORM,
+            None => "",
+        };
+        let links: String = self
+            .links
+            .iter()
+            .map(|link| {
+                format!(
+                    "<li><a href=\"{}\">{}</a></li>\n",
+                    escape_html(&link.url),
+                    escape_html(&link.label)
+                )
+            })
+            .collect();
+        let links = if links.is_empty() {
+            links
+        } else {
+            format!("<ul>\n{links}</ul>")
+        };
+
+        let page = match &self.template {
+            Some(template) => template
+                .replace("{{title}}", &escape_html(branding.title()))
+                .replace("{{files}}", &totals.files.to_string())
+                .replace("{{total_bytes}}", &totals.total_bytes.to_string())
+                .replace("{{upload_form}}", upload_form)
+                .replace("{{links}}", &links),
+            None => branding.page(
+                "Status",
+                &format!(
+                    "<p>Serving {} files, {} bytes altogether.</p>\n{upload_form}{links}",
+                    totals.files, totals.total_bytes
+                ),
+            ),
+        };
+        Ok(HttpResponse::Ok()
+            .content_type(ContentType::html())
+            .body(page))
+    }
+
+    async fn totals(&self, store: &FileStore) -> io::Result<StoreTotals> {
+        // held while counting, so that requests coming in meanwhile wait for the one count
+        let mut cached = self.totals.lock().await;
+        if let Some((counted_at, totals)) = *cached
+            && counted_at.elapsed() < TOTALS_MAX_AGE
+        {
+            return Ok(totals);
+        }
+
+        let totals = count_files(store).await?;
+        *cached = Some((Instant::now(), totals));
+        Ok(totals)
+    }
+}
+
+// the file is uploaded under its own name, which only the browser knows until it's picked
+const UPLOAD_FORM: &str = r#"<form method="post" enctype="multipart/form-data" onsubmit="this.action = '/' + encodeURIComponent(this.file.files[0].name)">
+<input type="hidden" name="redirect_to" value="/">
+<input type="file" name="file" required>
+<button>Upload</button>
+</form>
+"#;
+
+async fn count_files(store: &FileStore) -> io::Result<StoreTotals> {
+    let mut totals = StoreTotals::default();
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(dir) = dirs.pop() {
+        for entry in store.list(&dir).await? {
+            match entry.kind {
+                EntryKind::Dir => dirs.push(dir.join(&entry.name)),
+                EntryKind::File => {
+                    totals.files += 1;
+                    totals.total_bytes += entry.size_bytes.unwrap_or_default();
+                }
+            }
+        }
+    }
+    Ok(totals)
+}
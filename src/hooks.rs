@@ -0,0 +1,484 @@
+use std::{cell::Cell, collections::BTreeMap, io, path::Path, rc::Rc, sync::Arc};
+
+use async_stream::try_stream;
+use async_trait::async_trait;
+use futures::{StreamExt, future::LocalBoxFuture};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    access_times::AccessTimes,
+    changelog::{ChangeOp, Changelog},
+    config::server::{HookConfig, HookErrorPolicy, ScanConfig, ScanOverLimit},
+    file_store::{ByteStream, FileMetadata, StoredFile},
+    outbox::{Event, EventKind, Outbox},
+};
+
+/// Name the webhook outbox is registered under, for configuring it in `hooks`.
+pub const WEBHOOKS_HOOK_NAME: &str = "webhooks";
+/// Name the download times are recorded under, for configuring it in `hooks`.
+pub const ACCESS_TIMES_HOOK_NAME: &str = "access_times";
+/// Name the changelog is kept under, for configuring it in `hooks`.
+pub const CHANGELOG_HOOK_NAME: &str = "changelog";
+
+pub type SharedHooks = Arc<Hooks>;
+
+/// Something run around the changes made to files and the serving of them. Embedders can pass
+/// their own to `start_server_with`, alongside the built-in ones.
+#[async_trait(?Send)]
+pub trait Hook: Send + Sync {
+    /// Run once an upload is received but before it's kept, failing turns it away.
+    async fn pre_upload(&self, _path: &Path, _metadata: &FileMetadata) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// A scanner for the contents of an upload to `path`, fed the upload as it's received
+    /// rather than once all of it is.
+    fn scanner(&self, _path: &Path) -> Option<Box<dyn Scanner>> {
+        None
+    }
+
+    /// Run once an upload is served, or a file is put back in place of a deleted one.
+    async fn post_upload(&self, _path: &Path, _metadata: &FileMetadata) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Run before a file is served, failing turns the request away.
+    async fn pre_serve(&self, _path: &Path, _file: &StoredFile) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Run once a file is deleted, whether or not it's kept in the trash.
+    async fn post_delete(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Looks through the contents of one upload, e.g. for viruses or for its file signature not
+/// matching its extension.
+#[async_trait(?Send)]
+pub trait Scanner {
+    /// Looks at the next part of the upload, failing turns the upload away.
+    async fn update(&mut self, chunk: &[u8]) -> io::Result<()>;
+
+    /// Gives the verdict once the scanner is fed all it's going to be, which is only the start
+    /// of the upload when `decision` is `Sampled`. Failing turns the upload away.
+    async fn finish(&mut self, decision: ScanDecision) -> io::Result<()>;
+}
+
+/// How much of an upload the scanners looked at, going by `scanning` in the config.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ScanDecision {
+    Scanned,
+    /// Only its first `max_scan_bytes` were, the upload being larger
+    Sampled,
+    /// None of it was, the upload being larger than `max_scan_bytes`
+    Skipped,
+}
+
+/// What's kept in the metadata of a scanned upload.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScanRecord {
+    pub decision: ScanDecision,
+    pub scanned_bytes: u64,
+}
+
+/// How the scanning of an upload went, known once the stream handed back by `Hooks::scan` is
+/// read to its end.
+pub struct ScanOutcome(Rc<Cell<Option<ScanRecord>>>);
+
+impl ScanOutcome {
+    /// What to record in the upload's metadata, nothing when no hook scans uploads.
+    pub fn record(&self) -> Option<ScanRecord> {
+        self.0.get()
+    }
+}
+
+struct ActiveScanner {
+    hook: String,
+    on_error: HookErrorPolicy,
+    scanner: Box<dyn Scanner>,
+}
+
+/// Runs `call` on every scanner, dropping the ones failing whose policy is to carry on.
+async fn run_scanners(
+    scanners: &mut Vec<ActiveScanner>,
+    mut call: impl AsyncFnMut(&mut dyn Scanner) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut i = 0;
+    while i < scanners.len() {
+        let ActiveScanner {
+            hook,
+            on_error,
+            scanner,
+        } = &mut scanners[i];
+        let Err(err) = call(scanner.as_mut()).await else {
+            i += 1;
+            continue;
+        };
+
+        match on_error {
+            HookErrorPolicy::Abort => {
+                log::warn!("The '{hook}' hook turned an upload away: {err}");
+                // told apart from the upload failing, which is answered differently
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("turned away by the '{hook}' hook: {err}"),
+                ));
+            }
+            HookErrorPolicy::Continue => {
+                log::warn!("The '{hook}' hook failed to scan an upload, carrying on: {err}");
+                scanners.remove(i);
+            }
+        }
+    }
+    Ok(())
+}
+
+struct RegisteredHook {
+    name: String,
+    hook: Arc<dyn Hook>,
+    config: HookConfig,
+}
+
+/// Every enabled hook, in the order they're run.
+pub struct Hooks {
+    hooks: Vec<RegisteredHook>,
+}
+
+impl Hooks {
+    /// Orders `hooks` by their `order` in `config`, then by name.
+    pub fn new(
+        config: &BTreeMap<String, HookConfig>,
+        hooks: impl IntoIterator<Item = (String, Arc<dyn Hook>)>,
+    ) -> Self {
+        let mut hooks: Vec<_> = hooks
+            .into_iter()
+            .map(|(name, hook)| RegisteredHook {
+                config: config.get(&name).cloned().unwrap_or_default(),
+                name,
+                hook,
+            })
+            .filter(|hook| hook.config.enabled)
+            .collect();
+        hooks.sort_by(|a, b| (a.config.order, &a.name).cmp(&(b.config.order, &b.name)));
+
+        Hooks { hooks }
+    }
+
+    pub async fn pre_upload(&self, path: &Path, metadata: &FileMetadata) -> io::Result<()> {
+        self.run("pre_upload", |hook| hook.pre_upload(path, metadata))
+            .await
+    }
+
+    /// Feeds `stream` to the scanners of the hooks as it's read, up to `max_scan_bytes` of it.
+    /// Uploads the client says are larger than that aren't fed to them at all when they're to
+    /// be skipped, the ones found to be only once they are are skipped from there on.
+    pub fn scan<'a>(
+        &self,
+        path: &Path,
+        config: &ScanConfig,
+        declared_size: Option<u64>,
+        mut stream: ByteStream<'a>,
+    ) -> (ByteStream<'a>, ScanOutcome) {
+        let outcome = Rc::new(Cell::new(None));
+        let mut scanners: Vec<_> = self
+            .hooks
+            .iter()
+            .filter_map(|RegisteredHook { name, hook, config }| {
+                Some(ActiveScanner {
+                    hook: name.clone(),
+                    on_error: config.on_error,
+                    scanner: hook.scanner(path)?,
+                })
+            })
+            .collect();
+        if scanners.is_empty() {
+            return (stream, ScanOutcome(outcome));
+        }
+
+        let max_bytes = (config.max_scan_bytes > 0).then_some(config.max_scan_bytes);
+        let over_limit = config.over_limit;
+        let recorded = Rc::clone(&outcome);
+        let stream = try_stream! {
+            let mut scanned_bytes = 0;
+            let mut decision = match (max_bytes, declared_size) {
+                (Some(max), Some(size)) if size > max && over_limit == ScanOverLimit::Skip => {
+                    Some(ScanDecision::Skipped)
+                }
+                _ => None,
+            };
+
+            while let Some(chunk) = stream.next().await {
+                let chunk = chunk?;
+                if decision.is_none() {
+                    let room = max_bytes.map_or(u64::MAX, |max| max - scanned_bytes);
+                    let fed = &chunk[..chunk.len().min(room.try_into().unwrap_or(usize::MAX))];
+                    if fed.len() < chunk.len() && over_limit == ScanOverLimit::Skip {
+                        decision = Some(ScanDecision::Skipped);
+                    } else {
+                        if !fed.is_empty() {
+                            run_scanners(&mut scanners, async |scanner| scanner.update(fed).await)
+                                .await?;
+                            scanned_bytes += fed.len() as u64;
+                        }
+                        if fed.len() < chunk.len() {
+                            run_scanners(&mut scanners, async |scanner| {
+                                scanner.finish(ScanDecision::Sampled).await
+                            })
+                            .await?;
+                            decision = Some(ScanDecision::Sampled);
+                        }
+                    }
+                }
+                yield chunk;
+            }
+
+            let decision = match decision {
+                Some(decision) => decision,
+                None => {
+                    run_scanners(&mut scanners, async |scanner| {
+                        scanner.finish(ScanDecision::Scanned).await
+                    })
+                    .await?;
+                    ScanDecision::Scanned
+                }
+            };
+            recorded.set(Some(ScanRecord {
+                decision,
+                scanned_bytes,
+            }));
+        }
+        .boxed_local();
+
+        (stream, ScanOutcome(outcome))
+    }
+
+    pub async fn post_upload(&self, path: &Path, metadata: &FileMetadata) {
+        // the upload is already served, there's nothing left to turn away
+        let _ = self
+            .run("post_upload", |hook| hook.post_upload(path, metadata))
+            .await;
+    }
+
+    pub async fn pre_serve(&self, path: &Path, file: &StoredFile) -> io::Result<()> {
+        self.run("pre_serve", |hook| hook.pre_serve(path, file))
+            .await
+    }
+
+    pub async fn post_delete(&self, path: &Path) {
+        let _ = self.run("post_delete", |hook| hook.post_delete(path)).await;
+    }
+
+    /// Runs `call` on every hook in order, stopping at the first failing one whose policy
+    /// is to abort.
+    async fn run<'a>(
+        &'a self,
+        stage: &str,
+        call: impl Fn(&'a dyn Hook) -> LocalBoxFuture<'a, io::Result<()>>,
+    ) -> io::Result<()> {
+        for RegisteredHook { name, hook, config } in &self.hooks {
+            let Err(err) = call(hook.as_ref()).await else {
+                continue;
+            };
+
+            match config.on_error {
+                HookErrorPolicy::Abort => {
+                    log::warn!("The '{name}' hook failed its {stage} step: {err}");
+                    return Err(io::Error::new(
+                        err.kind(),
+                        format!("turned away by the '{name}' hook: {err}"),
+                    ));
+                }
+                HookErrorPolicy::Continue => {
+                    log::warn!("The '{name}' hook failed its {stage} step, carrying on: {err}")
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Queues the webhook events for the files that changed.
+#[async_trait(?Send)]
+impl Hook for Outbox {
+    async fn post_upload(&self, path: &Path, metadata: &FileMetadata) -> io::Result<()> {
+        self.publish(Event::new(EventKind::FileUploaded, path).with_hash(&metadata.hash));
+        Ok(())
+    }
+
+    async fn post_delete(&self, path: &Path) -> io::Result<()> {
+        self.publish(Event::new(EventKind::FileDeleted, path));
+        Ok(())
+    }
+}
+
+/// Notes when files are downloaded, and forgets about them once they're deleted.
+#[async_trait(?Send)]
+impl Hook for AccessTimes {
+    async fn pre_serve(&self, path: &Path, _file: &StoredFile) -> io::Result<()> {
+        self.record(path);
+        Ok(())
+    }
+
+    async fn post_delete(&self, path: &Path) -> io::Result<()> {
+        self.remove(path)
+    }
+}
+
+/// Numbers every upload and delete in the changelog.
+#[async_trait(?Send)]
+impl Hook for Changelog {
+    async fn post_upload(&self, path: &Path, metadata: &FileMetadata) -> io::Result<()> {
+        self.append(path, ChangeOp::Upload, Some(&metadata.hash))?;
+        Ok(())
+    }
+
+    async fn post_delete(&self, path: &Path) -> io::Result<()> {
+        self.append(path, ChangeOp::Delete, None)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use actix_web::web::Bytes;
+    use futures::{TryStreamExt, executor::block_on, stream};
+
+    use super::*;
+
+    /// Records its name in `log` when run, failing if `fails`.
+    struct Recording {
+        name: &'static str,
+        fails: bool,
+        log: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    #[async_trait(?Send)]
+    impl Hook for Recording {
+        async fn post_delete(&self, _path: &Path) -> io::Result<()> {
+            self.log.lock().unwrap().push(self.name);
+            if self.fails {
+                return Err(io::Error::other("failed"));
+            }
+            Ok(())
+        }
+    }
+
+    fn hook_config(order: i32, on_error: HookErrorPolicy) -> HookConfig {
+        HookConfig {
+            order,
+            on_error,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn hooks_run_in_order_until_one_aborts() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let hook = |name: &'static str, fails| -> (String, Arc<dyn Hook>) {
+            let log = Arc::clone(&log);
+            (name.to_string(), Arc::new(Recording { name, fails, log }))
+        };
+
+        let config = BTreeMap::from([
+            ("a".to_string(), hook_config(2, HookErrorPolicy::Abort)),
+            ("b".to_string(), hook_config(1, HookErrorPolicy::Continue)),
+            ("c".to_string(), hook_config(0, HookErrorPolicy::Abort)),
+            ("e".to_string(), hook_config(3, HookErrorPolicy::Abort)),
+        ]);
+        let hooks = Hooks::new(
+            &config,
+            [
+                hook("a", true),
+                hook("b", true),
+                hook("c", false),
+                hook("d", false),
+                hook("e", false),
+            ],
+        );
+
+        block_on(hooks.post_delete(Path::new("file.txt")));
+        // ties go by name, and nothing runs past a failing one that aborts
+        assert_eq!(*log.lock().unwrap(), ["c", "d", "b", "a"]);
+    }
+
+    /// Turns away uploads with `EICAR` in what it's fed, noting how much that was.
+    struct Signatures {
+        seen: Arc<Mutex<Vec<u8>>>,
+    }
+
+    #[async_trait(?Send)]
+    impl Scanner for Signatures {
+        async fn update(&mut self, chunk: &[u8]) -> io::Result<()> {
+            self.seen.lock().unwrap().extend_from_slice(chunk);
+            Ok(())
+        }
+
+        async fn finish(&mut self, _decision: ScanDecision) -> io::Result<()> {
+            match self.seen.lock().unwrap().windows(5).any(|w| w == b"EICAR") {
+                true => Err(io::Error::other("found EICAR")),
+                false => Ok(()),
+            }
+        }
+    }
+
+    impl Hook for Signatures {
+        fn scanner(&self, _path: &Path) -> Option<Box<dyn Scanner>> {
+            let seen = Arc::clone(&self.seen);
+            Some(Box::new(Signatures { seen }))
+        }
+    }
+
+    #[test]
+    fn large_uploads_are_sampled_or_skipped() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let signatures: Arc<dyn Hook> = Arc::new(Signatures {
+            seen: Arc::clone(&seen),
+        });
+        let hooks = Hooks::new(&BTreeMap::new(), [("av".to_string(), signatures)]);
+        let scan = |max_scan_bytes, over_limit, declared_size, chunks: &[&'static [u8]]| {
+            seen.lock().unwrap().clear();
+            let config = ScanConfig {
+                max_scan_bytes,
+                over_limit,
+            };
+            let chunks = chunks.iter().map(|chunk| Ok(Bytes::from_static(chunk)));
+            let (stream, outcome) = hooks.scan(
+                Path::new("video.mp4"),
+                &config,
+                declared_size,
+                stream::iter(chunks).boxed_local(),
+            );
+            let read = block_on(stream.try_collect::<Vec<_>>());
+            (read.map(|_| ()), outcome.record())
+        };
+        let record = |decision, scanned_bytes| ScanRecord {
+            decision,
+            scanned_bytes,
+        };
+
+        let (read, scanned) = scan(0, ScanOverLimit::Sample, None, &[b"clean", b" file"]);
+        assert!(read.is_ok());
+        assert_eq!(scanned, Some(record(ScanDecision::Scanned, 10)));
+
+        // only the start is looked at, so what comes after it goes unnoticed
+        let (read, scanned) = scan(6, ScanOverLimit::Sample, None, &[b"clean", b" EICAR"]);
+        assert!(read.is_ok());
+        assert_eq!(scanned, Some(record(ScanDecision::Sampled, 6)));
+        assert_eq!(*seen.lock().unwrap(), b"clean ");
+        let (read, _) = scan(6, ScanOverLimit::Sample, None, &[b"EICAR", b" file"]);
+        assert_eq!(read.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let (read, scanned) = scan(6, ScanOverLimit::Skip, Some(11), &[b"EICAR", b" file"]);
+        assert!(read.is_ok());
+        assert_eq!(scanned, Some(record(ScanDecision::Skipped, 0)));
+        // the client not saying how large it is only gets it as far as the limit
+        let (read, scanned) = scan(6, ScanOverLimit::Skip, None, &[b"EICAR", b" file"]);
+        assert!(read.is_ok());
+        assert_eq!(scanned, Some(record(ScanDecision::Skipped, 5)));
+    }
+}
@@ -0,0 +1,234 @@
+use std::{fs, io};
+
+use actix_web::{
+    HttpResponse, Result,
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::{Method, header},
+    middleware::Next,
+    mime::Mime,
+    web::{Bytes, Data},
+};
+
+use crate::config::server::HotlinkConfig;
+
+enum AllowedHost {
+    Exact(String),
+    // without the `*`, e.g. `.example.com`
+    Subdomains(String),
+}
+
+/// The configured hotlink protection, with the placeholder read in and ready to send.
+pub struct Hotlinks {
+    allowed: Vec<AllowedHost>,
+    allow_no_referer: bool,
+    placeholder: Option<(Mime, Bytes)>,
+}
+
+impl Hotlinks {
+    pub fn new(config: &HotlinkConfig) -> io::Result<Self> {
+        let allowed = config
+            .allowed_hosts
+            .iter()
+            .map(|host| {
+                let host = host.trim().to_ascii_lowercase();
+                match host.strip_prefix('*') {
+                    Some(domain) if domain.starts_with('.') => {
+                        Ok(AllowedHost::Subdomains(domain.to_string()))
+                    }
+                    Some(_) => Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("invalid hotlink host '{host}', only `*.` can start one"),
+                    )),
+                    None => Ok(AllowedHost::Exact(host)),
+                }
+            })
+            .collect::<io::Result<_>>()?;
+
+        let placeholder = match &config.placeholder {
+            Some(path) => {
+                let contents = fs::read(path).map_err(|err| {
+                    io::Error::new(
+                        err.kind(),
+                        format!("Failed to read hotlink placeholder '{path}': {err}"),
+                    )
+                })?;
+                let mime = mime_guess::from_path(path).first_or_octet_stream();
+                Some((mime, Bytes::from(contents)))
+            }
+            None => None,
+        };
+
+        Ok(Hotlinks {
+            allowed,
+            allow_no_referer: config.allow_no_referer,
+            placeholder,
+        })
+    }
+
+    fn is_enabled(&self) -> bool {
+        !self.allowed.is_empty()
+    }
+
+    /// Whether a page on `host` may embed files, the server's own host being `own_host`.
+    fn is_allowed(&self, host: &str, own_host: &str) -> bool {
+        host == own_host
+            || self.allowed.iter().any(|allowed| match allowed {
+                AllowedHost::Exact(allowed) => host == allowed,
+                AllowedHost::Subdomains(domain) => host.ends_with(domain.as_str()),
+            })
+    }
+
+    fn blocked_response(&self) -> HttpResponse {
+        match &self.placeholder {
+            Some((mime, contents)) => HttpResponse::Ok()
+                .content_type(mime.clone())
+                // it's not what's at the URL, so it mustn't be kept as if it were
+                .insert_header((header::CACHE_CONTROL, "no-store"))
+                .body(contents.clone()),
+            None => HttpResponse::Forbidden().body("Embedding files on other sites is not allowed"),
+        }
+    }
+}
+
+/// The host of a URL or `host[:port]`, lowercase and without the port.
+fn host_of(url: &str) -> &str {
+    let authority = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let authority = authority.split(['/', '?', '#']).next().unwrap_or_default();
+    // credentials before the host aren't part of it
+    let host = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+    match host.strip_prefix('[') {
+        // IPv6, whose colons aren't a port
+        Some(ipv6) => ipv6.split(']').next().unwrap_or_default(),
+        None => host.split(':').next().unwrap_or_default(),
+    }
+}
+
+/// Turns away downloads asked for by pages on sites that aren't allowed to embed files,
+/// with the placeholder when there is one.
+pub async fn hotlink_protection(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>> {
+    let hotlinks = req
+        .app_data::<Data<Hotlinks>>()
+        .expect("Hotlinks is registered as app data")
+        .clone();
+    if !hotlinks.is_enabled() || !matches!(*req.method(), Method::GET | Method::HEAD) {
+        return Ok(next.call(req).await?.map_into_left_body());
+    }
+
+    // `Origin` is all some requests say of where they came from
+    let page = req
+        .headers()
+        .get(header::REFERER)
+        .or_else(|| req.headers().get(header::ORIGIN))
+        .map(|page| page.to_str().unwrap_or_default().to_ascii_lowercase());
+    let allowed = match page {
+        None => hotlinks.allow_no_referer,
+        Some(page) => {
+            let own_host = req.connection_info().host().to_ascii_lowercase();
+            hotlinks.is_allowed(host_of(&page), host_of(&own_host))
+        }
+    };
+
+    if allowed {
+        Ok(next.call(req).await?.map_into_left_body())
+    } else {
+        let response = hotlinks.blocked_response();
+        Ok(req.into_response(response.map_into_right_body()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        App, HttpResponse,
+        http::StatusCode,
+        middleware, route,
+        test::{TestRequest, call_service, init_service, read_body},
+    };
+
+    use super::*;
+    use crate::fixtures::temp_dir;
+
+    #[route("/{path:.*}", method = "GET", method = "PUT")]
+    async fn ok() -> HttpResponse {
+        HttpResponse::Ok().body("file")
+    }
+
+    #[actix_web::test]
+    async fn only_allowed_sites_can_embed_files() {
+        let dir = temp_dir();
+        let placeholder = dir.join("placeholder.png");
+        fs::write(&placeholder, "placeholder").unwrap();
+        let mut config = HotlinkConfig {
+            allowed_hosts: vec!["partner.example".to_string(), "*.example.com".to_string()],
+            allow_no_referer: false,
+            ..Default::default()
+        };
+        let app_with = |hotlinks: Hotlinks| {
+            init_service(
+                App::new()
+                    .app_data(Data::new(hotlinks))
+                    .service(ok)
+                    .wrap(middleware::from_fn(hotlink_protection)),
+            )
+        };
+        let app = app_with(Hotlinks::new(&config).unwrap()).await;
+
+        let status = async |referer: Option<&str>| {
+            let mut req = TestRequest::get()
+                .uri("/a.png")
+                .insert_header((header::HOST, "files.test:8080"));
+            if let Some(referer) = referer {
+                req = req.insert_header((header::REFERER, referer));
+            }
+            call_service(&app, req.to_request()).await.status()
+        };
+        for allowed in [
+            "https://partner.example/post",
+            "https://www.example.com/",
+            "https://user@CDN.Example.com:443/a?b",
+            // its own pages
+            "http://files.test:8080/index.html",
+        ] {
+            assert_eq!(status(Some(allowed)).await, StatusCode::OK, "for {allowed}");
+        }
+        for blocked in [
+            "https://example.com/",
+            "https://evil.test/?from=partner.example",
+            "https://partner.example.evil.test/",
+        ] {
+            assert_eq!(
+                status(Some(blocked)).await,
+                StatusCode::FORBIDDEN,
+                "for {blocked}"
+            );
+        }
+        assert_eq!(status(None).await, StatusCode::FORBIDDEN);
+
+        // uploads aren't embedded anywhere
+        let req = TestRequest::put()
+            .uri("/a.png")
+            .insert_header((header::REFERER, "https://evil.test/"))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+
+        config.placeholder = Some(placeholder.to_string_lossy().into_owned());
+        let app = app_with(Hotlinks::new(&config).unwrap()).await;
+        let req = TestRequest::get()
+            .uri("/a.png")
+            .insert_header((header::REFERER, "https://evil.test/"))
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers().get(header::CONTENT_TYPE).unwrap(),
+            "image/png"
+        );
+        assert_eq!(read_body(res).await, "placeholder");
+    }
+}
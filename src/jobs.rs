@@ -0,0 +1,214 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    future::Future,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use actix_web::rt;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
+
+const JOBS_FILE_NAME: &str = "jobs.json";
+
+// finished jobs beyond this many are forgotten, oldest first
+const MAX_RETAINED_JOBS: usize = 1000;
+
+pub type SharedJobRegistry = Arc<JobRegistry>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Running,
+    Completed,
+    Failed,
+    /// The server stopped while the job was still running
+    Interrupted,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct JobProgress {
+    pub done: u64,
+    pub total: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Job {
+    pub id: Uuid,
+    pub kind: String,
+    pub state: JobState,
+    pub progress: JobProgress,
+    /// Non-fatal errors hit along the way, plus the fatal one if the job failed
+    pub errors: Vec<String>,
+    pub result: Option<Value>,
+    pub created_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+/// Keeps track of long-running operations, persisting them so their outcome can still be
+/// looked up after a restart.
+pub struct JobRegistry {
+    file_path: PathBuf,
+    jobs: Mutex<BTreeMap<Uuid, Job>>,
+}
+
+impl JobRegistry {
+    pub fn load(data_dir: impl AsRef<Path>) -> io::Result<Self> {
+        let file_path = data_dir.as_ref().join(JOBS_FILE_NAME);
+
+        let mut jobs: BTreeMap<Uuid, Job> = if file_path.is_file() {
+            serde_json::from_reader(File::open(&file_path)?)?
+        } else {
+            BTreeMap::new()
+        };
+
+        // anything that was running when the server last stopped won't ever finish
+        for job in jobs.values_mut() {
+            if job.state == JobState::Running {
+                job.state = JobState::Interrupted;
+            }
+        }
+
+        let registry = JobRegistry {
+            file_path,
+            jobs: Mutex::new(jobs),
+        };
+        registry.persist();
+
+        Ok(registry)
+    }
+
+    pub fn get(&self, id: &Uuid) -> Option<Job> {
+        self.jobs.lock().unwrap().get(id).cloned()
+    }
+
+    /// Starts `task` in the background on the current worker, returning the new job's id.
+    pub fn spawn<F, Fut>(self: &Arc<Self>, kind: &str, task: F) -> Uuid
+    where
+        F: FnOnce(JobHandle) -> Fut,
+        Fut: Future<Output = io::Result<Value>> + 'static,
+    {
+        let job = Job {
+            id: Uuid::new_v4(),
+            kind: kind.to_string(),
+            state: JobState::Running,
+            progress: JobProgress::default(),
+            errors: Vec::new(),
+            result: None,
+            created_at: Utc::now(),
+            finished_at: None,
+        };
+
+        let id = job.id;
+        self.insert(job);
+
+        let handle = JobHandle {
+            registry: Arc::clone(self),
+            id,
+        };
+        let future = task(handle);
+        let registry = Arc::clone(self);
+
+        rt::spawn(async move {
+            let result = future.await;
+            registry.update(&id, |job| {
+                job.finished_at = Some(Utc::now());
+                match result {
+                    Ok(value) => {
+                        job.state = JobState::Completed;
+                        job.result = Some(value);
+                    }
+                    Err(err) => {
+                        job.state = JobState::Failed;
+                        job.errors.push(err.to_string());
+                    }
+                }
+            });
+            registry.persist();
+        });
+
+        id
+    }
+
+    fn insert(&self, job: Job) {
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.insert(job.id, job);
+
+        if jobs.len() > MAX_RETAINED_JOBS {
+            let mut finished: Vec<_> = jobs
+                .values()
+                .filter(|job| job.state != JobState::Running)
+                .map(|job| (job.created_at, job.id))
+                .collect();
+            finished.sort();
+
+            let excess = jobs.len() - MAX_RETAINED_JOBS;
+            for (_, id) in finished.into_iter().take(excess) {
+                jobs.remove(&id);
+            }
+        }
+
+        drop(jobs);
+        self.persist();
+    }
+
+    fn update(&self, id: &Uuid, f: impl FnOnce(&mut Job)) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(id) {
+            f(job);
+        }
+    }
+
+    fn persist(&self) {
+        let contents = match serde_json::to_vec(&*self.jobs.lock().unwrap()) {
+            Ok(contents) => contents,
+            Err(err) => {
+                log::error!("Failed to serialize jobs: {err}");
+                return;
+            }
+        };
+
+        // write then rename, so a crash mid-write can't leave a truncated file behind
+        let tmp_path = self.file_path.with_extension("json.tmp");
+        let result = self
+            .file_path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::write(&tmp_path, contents))
+            .and_then(|_| fs::rename(&tmp_path, &self.file_path));
+
+        if let Err(err) = result {
+            log::error!(
+                "Failed to persist jobs to {}: {err}",
+                self.file_path.display()
+            );
+        }
+    }
+}
+
+/// Given to a running job for reporting its progress.
+pub struct JobHandle {
+    registry: Arc<JobRegistry>,
+    id: Uuid,
+}
+
+impl JobHandle {
+    pub fn set_total(&self, total: u64) {
+        self.registry
+            .update(&self.id, |job| job.progress.total = Some(total));
+    }
+
+    pub fn advance(&self, amount: u64) {
+        self.registry
+            .update(&self.id, |job| job.progress.done += amount);
+    }
+
+    /// Records an error that doesn't stop the job.
+    pub fn error(&self, message: impl Into<String>) {
+        self.registry
+            .update(&self.id, |job| job.errors.push(message.into()));
+    }
+}
@@ -0,0 +1,27 @@
+use log::LevelFilter;
+
+pub enum LogTarget {
+    Stderr,
+    #[cfg(unix)]
+    Syslog,
+    #[cfg(windows)]
+    EventLog,
+}
+
+pub fn init(target: LogTarget) {
+    match target {
+        // RUST_LOG can still be used to override the level when running in the foreground
+        LogTarget::Stderr => env_logger::Builder::new()
+            .filter_level(LevelFilter::Info)
+            .parse_default_env()
+            .init(),
+
+        #[cfg(unix)]
+        LogTarget::Syslog => syslog::init_unix(syslog::Facility::LOG_DAEMON, LevelFilter::Info)
+            .expect("failed to connect to syslog"),
+
+        #[cfg(windows)]
+        LogTarget::EventLog => eventlog::init(crate::service::SERVICE_NAME, log::Level::Info)
+            .expect("failed to initialize the event log, is the service installed?"),
+    }
+}
@@ -1,45 +1,561 @@
+mod access_times;
+mod archive;
+mod attributes;
 mod authorized;
+mod branding;
 mod cache_map;
+mod changelog;
+mod cli;
 mod config;
+mod cors;
+#[cfg(unix)]
+mod daemon;
+mod derive;
+mod file_handles;
+mod file_names;
 mod file_store;
+#[cfg(test)]
+mod fixtures;
+mod geoip;
+#[cfg(unix)]
+mod handover;
+mod header_rules;
+mod highlight;
+mod home_page;
+mod hooks;
+mod hotlink;
+mod jobs;
+mod logging;
+mod markdown;
+mod mime_types;
+mod mirror;
+mod outbox;
+mod preview;
+mod quarantine;
 mod routes;
+mod scheduler;
+mod selftest;
+#[cfg(windows)]
+mod service;
+mod throttle;
+mod token_clients;
+mod transfers;
+mod trash;
 
-use std::{io, sync::Arc};
+use std::{io, net::SocketAddr, path::Path, process, sync::Arc, time::Duration};
 
-use actix_web::{App, HttpServer, web::Data};
+use actix_web::{App, HttpServer, dev::Server, rt::System, web::Data};
+use clap::Parser;
 
 use crate::{
-    config::server::ServerConfig,
-    file_store::FileStore,
-    routes::{ScopeCreator, api::ApiRoute, serve_files::FileServeRoute},
+    access_times::{AccessTimes, SharedAccessTimes},
+    attributes::{Attributes, SharedAttributes},
+    authorized::Authenticator,
+    branding::Branding,
+    changelog::{Changelog, SharedChangelog},
+    cli::Cli,
+    config::server::{PartialUploadsConfig, ServerConfig},
+    cors::Cors,
+    derive::{Derivatives, SharedDerivatives},
+    file_handles::FileHandles,
+    file_store::{EncryptedFileStore, FileStorageCore, FileStore, QuotaFileStore, StoreTracer},
+    geoip::GeoIp,
+    header_rules::HeaderRules,
+    highlight::Highlighter,
+    home_page::HomePage,
+    hooks::{
+        ACCESS_TIMES_HOOK_NAME, CHANGELOG_HOOK_NAME, Hook, Hooks, SharedHooks, WEBHOOKS_HOOK_NAME,
+    },
+    hotlink::Hotlinks,
+    jobs::JobRegistry,
+    logging::LogTarget,
+    mime_types::MimeTypes,
+    mirror::{Mirror, SharedStandby, Standby},
+    outbox::{Outbox, SharedOutbox},
+    quarantine::Quarantine,
+    routes::{
+        ScopeCreator, api::ApiRoute, serve_files::FileServeRoute, well_known::WellKnownRoute,
+    },
+    scheduler::Scheduler,
+    throttle::{DownloadThrottle, UploadThrottle},
+    token_clients::TokenClients,
+    transfers::Transfers,
+    trash::{SharedTrash, Trash},
 };
 
 pub type SharedFileStore = Arc<FileStore>;
 
-#[actix_web::main]
-async fn main() -> io::Result<()> {
+fn main() -> io::Result<()> {
+    let cli = Cli::parse();
+
+    match cli.command {
+        #[cfg(windows)]
+        Some(cli::Command::Service { action }) => service::handle(action),
+        Some(cli::Command::CheckConfig) => {
+            if !config::check::check_config()? {
+                process::exit(1);
+            }
+            Ok(())
+        }
+        Some(cli::Command::ConfigSchema) => config::check::print_schema(),
+        Some(cli::Command::HashPassword) => {
+            let mut password = String::new();
+            io::stdin().read_line(&mut password)?;
+            let password = password.trim_end_matches(['\r', '\n']);
+            println!("{}", authorized::basic::hash_password(password)?);
+            Ok(())
+        }
+        Some(cli::Command::Selftest) => {
+            logging::init(LogTarget::Stderr);
+            if !System::new().block_on(selftest::run())? {
+                process::exit(1);
+            }
+            Ok(())
+        }
+
+        _ => {
+            // daemonizing forks the process, so it has to happen before the runtime exists
+            #[cfg(unix)]
+            if cli.daemon {
+                daemon::daemonize(&cli.pidfile)?;
+                logging::init(LogTarget::Syslog);
+                return run();
+            }
+
+            logging::init(LogTarget::Stderr);
+            run()
+        }
+    }
+}
+
+/// Runs the server to completion on a new actix system.
+fn run() -> io::Result<()> {
+    System::new().block_on(async { start_server()?.await })
+}
+
+/// Binds and starts the server, must be called from within an actix system.
+pub fn start_server() -> io::Result<Server> {
+    start_server_with(Vec::new())
+}
+
+/// Same as `start_server`, with `hooks` run next to the built-in ones, configured by name
+/// under `hooks` in the config the same way.
+pub fn start_server_with(hooks: Vec<(String, Arc<dyn Hook>)>) -> io::Result<Server> {
     let mut config_file = ServerConfig::new_file();
     config_file.read_and_save()?;
 
     let config = config_file.take().expect("just read from file");
+    start_server_from(config, hooks).map(|(server, _)| server)
+}
+
+/// Same as `start_server_with`, with `config` rather than the one in the config file. Also
+/// returns the address it's listening on, which is only known once bound for port 0.
+pub fn start_server_from(
+    config: ServerConfig,
+    hooks: Vec<(String, Arc<dyn Hook>)>,
+) -> io::Result<(Server, SocketAddr)> {
     let binding = (config.host.clone(), config.port);
 
-    println!("Starting server at http://{}:{}", config.host, config.port);
+    let store_tracer = Arc::new(StoreTracer::new(&config.store_tracing));
+    let file_handles = Arc::new(FileHandles::new(&config.file_handles));
+    let mut file_store = FileStore::open(
+        &config.files_source,
+        &config.memory_cache,
+        config.hash_algorithm,
+        &store_tracer,
+        &file_handles,
+    )?;
+    if let Some(key) = &config.encryption.key {
+        file_store = FileStore::Encrypted(
+            EncryptedFileStore::new(file_store, key)?.with_hash_algorithm(config.hash_algorithm),
+        );
+    }
+    if config.quota.is_limited() {
+        file_store = FileStore::Quota(QuotaFileStore::new(file_store, &config.quota));
+    }
+    let file_store: Data<SharedFileStore> = Data::new(Arc::new(file_store));
+
+    let outbox = Arc::new(Outbox::open(&config.data_dir, &config.webhooks)?);
+    let access_times = Arc::new(AccessTimes::open(&config.data_dir, &config.access_times)?);
+    let changelog = Arc::new(Changelog::open(&config.data_dir, &config.changelog)?);
+    let builtin_hooks: [(String, Arc<dyn Hook>); 3] = [
+        (WEBHOOKS_HOOK_NAME.to_string(), outbox.clone()),
+        (ACCESS_TIMES_HOOK_NAME.to_string(), access_times.clone()),
+        (CHANGELOG_HOOK_NAME.to_string(), changelog.clone()),
+    ];
+    let hooks = Data::new(Arc::new(Hooks::new(
+        &config.hooks,
+        builtin_hooks.into_iter().chain(hooks),
+    )));
+    let outbox = Data::new(outbox);
+    let access_times = Data::new(access_times);
+    let changelog = Data::new(changelog);
+
+    let derivatives = Data::new(Arc::new(Derivatives::new(
+        &config.data_dir,
+        &config.derivatives,
+    )?));
+
+    let attributes = Data::new(Arc::new(Attributes::open(&config.data_dir)?));
+    let trash = Data::new(Arc::new(Trash::open(&config.data_dir, &config.trash)?));
+
+    let mirror = match &config.mirror {
+        Some(mirror) => Some(Mirror::open(
+            mirror,
+            &config.memory_cache,
+            config.hash_algorithm,
+            &store_tracer,
+            &file_handles,
+        )?),
+        None => None,
+    };
+    let standby = Data::new(Arc::new(Standby::new(&config.data_dir, mirror)));
+    let token_clients = Arc::new(TokenClients::open(&config.data_dir, &config.clients)?);
+
+    let mut scheduler = Scheduler::new(&config.scheduler);
+    register_store_tasks(
+        &mut scheduler,
+        &file_store,
+        &hooks,
+        &attributes,
+        &trash,
+        &config.partial_uploads,
+    )?;
+    register_feature_tasks(
+        &mut scheduler,
+        &file_store,
+        &outbox,
+        &derivatives,
+        &access_times,
+        &changelog,
+        &standby,
+    )?;
+    register_auth_tasks(&mut scheduler, &token_clients)?;
+    let scheduler_status = Data::new(scheduler.status());
+    scheduler.start();
+
+    // the first sync can't wait for the schedule, the mirror would be empty until then
+    if standby.following().is_some() {
+        let standby = Arc::clone(&standby);
+        let store = Arc::clone(&file_store);
+        actix_web::rt::spawn(async move {
+            if let Err(err) = sync_mirror(&standby, &store).await {
+                log::error!("Failed to sync mirror: {err}");
+            }
+        });
+    }
+
+    let jobs = Data::new(Arc::new(JobRegistry::load(&config.data_dir)?));
+    let quarantine = Data::new(Quarantine::open(&config.data_dir, config.hash_algorithm)?);
+
+    let header_rules = Data::new(HeaderRules::new(&config.headers, &config.render)?);
+    let mime_types = Data::new(MimeTypes::new(&config.mime_overrides)?);
+    let cors = Data::new(Cors::new(&config.cors)?);
+    let hotlinks = Data::new(Hotlinks::new(&config.hotlinks)?);
+    let branding = Data::new(Branding::load(&config.branding)?);
+    let home_page = Data::new(HomePage::load(&config.home_page)?);
+    let highlighter = Data::new(Highlighter::default());
+    let geoip = Data::new(GeoIp::load(&config.geoip)?);
+    let upload_throttle = Data::new(UploadThrottle::new(&config.upload_rate_limits));
+    let download_throttle = Data::new(DownloadThrottle::new(&config.download_rate_limit));
+    let authenticator: Data<dyn Authenticator> =
+        Data::from(authorized::server_authenticator(&config)?);
+
+    let token_clients = Data::from(token_clients);
+    let store_tracer = Data::from(store_tracer);
+    let file_handles = Data::from(file_handles);
+    let transfers = Data::new(Transfers::default());
 
-    let file_store: Data<SharedFileStore> =
-        Data::new(Arc::new(FileStore::from(&config.files_source)));
     let config_data: Data<ServerConfig> = Data::new(config);
 
-    HttpServer::new(move || {
+    let shutdown_timeout = config_data.shutdown_timeout_secs;
+    let http_server = HttpServer::new(move || {
         // moving config_data into here, to be cloned each time a new worker is spawned
         // (which is what this function closure is for generating)
         App::new()
             .app_data(config_data.clone())
             .app_data(file_store.clone())
+            .app_data(scheduler_status.clone())
+            .app_data(jobs.clone())
+            .app_data(outbox.clone())
+            .app_data(hooks.clone())
+            .app_data(quarantine.clone())
+            .app_data(attributes.clone())
+            .app_data(trash.clone())
+            .app_data(standby.clone())
+            .app_data(access_times.clone())
+            .app_data(changelog.clone())
+            .app_data(derivatives.clone())
+            .app_data(header_rules.clone())
+            .app_data(mime_types.clone())
+            .app_data(cors.clone())
+            .app_data(hotlinks.clone())
+            .app_data(branding.clone())
+            .app_data(home_page.clone())
+            .app_data(highlighter.clone())
+            .app_data(geoip.clone())
+            .app_data(upload_throttle.clone())
+            .app_data(download_throttle.clone())
+            .app_data(token_clients.clone())
+            .app_data(authenticator.clone())
+            .app_data(store_tracer.clone())
+            .app_data(file_handles.clone())
+            .app_data(transfers.clone())
             .service(ApiRoute::create_scope())
+            .service(WellKnownRoute::create_scope())
             .service(FileServeRoute::create_scope())
     })
-    .bind(binding)?
-    .run()
-    .await
+    .shutdown_timeout(shutdown_timeout);
+
+    #[cfg(unix)]
+    let server = {
+        // binding the listener ourselves keeps a handle to it around for handing over on upgrade
+        let listener = handover::listener(binding)?;
+        let upgrade_listener = listener.try_clone()?;
+        let addr = listener.local_addr()?;
+        log::info!("Starting server at http://{addr}");
+
+        let server = http_server.listen(listener)?.run();
+        actix_web::rt::spawn(handover::watch_for_upgrade(
+            upgrade_listener,
+            server.handle(),
+        ));
+        handover::notify_ready();
+
+        (server, addr)
+    };
+
+    #[cfg(not(unix))]
+    let server = {
+        let http_server = http_server.bind(binding)?;
+        let addr = http_server.addrs()[0];
+        log::info!("Starting server at http://{addr}");
+        (http_server.run(), addr)
+    };
+
+    Ok(server)
+}
+
+/// Registers the tasks keeping the store tidy, along with the attributes and the trash of
+/// its files.
+fn register_store_tasks(
+    scheduler: &mut Scheduler,
+    file_store: &SharedFileStore,
+    hooks: &SharedHooks,
+    attributes: &SharedAttributes,
+    trash: &SharedTrash,
+    partial_uploads: &PartialUploadsConfig,
+) -> io::Result<()> {
+    let store = Arc::clone(file_store);
+    scheduler.register("cache_purge", "0 */10 * * * *", move || {
+        let store = Arc::clone(&store);
+        async move {
+            let purged = store.purge_expired_cache();
+            log::debug!("Purged {purged} expired cache entries");
+            Ok(())
+        }
+    })?;
+
+    let store = Arc::clone(file_store);
+    scheduler.register("metadata_retry", "30 * * * * *", move || {
+        let store = Arc::clone(&store);
+        async move {
+            let pending = store.retry_pending_metadata().await;
+            if pending > 0 {
+                log::warn!("The metadata of {pending} files still couldn't be written");
+            }
+            Ok(())
+        }
+    })?;
+
+    let store = Arc::clone(file_store);
+    scheduler.register("metadata_gc", "0 0 4 * * *", move || {
+        let store = Arc::clone(&store);
+        async move {
+            let report = store.collect_garbage().await?;
+            log::debug!(
+                "Collected {} orphaned metadata entries and {} cache entries",
+                report.metadata_removed,
+                report.cache_entries_removed
+            );
+            Ok(())
+        }
+    })?;
+
+    let store = Arc::clone(file_store);
+    let idle_timeout = Duration::from_secs(partial_uploads.idle_timeout_secs);
+    scheduler.register("partial_upload_sweep", "0 */10 * * * *", move || {
+        let store = Arc::clone(&store);
+        async move {
+            let report = store.partial_uploads(Some(idle_timeout)).await?;
+            if report.abandoned_removed > 0 {
+                log::info!(
+                    "Removed {} abandoned partial uploads",
+                    report.abandoned_removed
+                );
+            }
+            log::debug!(
+                "{} partial uploads in progress, taking {} bytes",
+                report.files,
+                report.total_bytes
+            );
+            Ok(())
+        }
+    })?;
+
+    let store = Arc::clone(file_store);
+    let expiry_hooks = Arc::clone(hooks);
+    let expiry_attributes = Arc::clone(attributes);
+    scheduler.register("upload_expiry", "0 * * * * *", move || {
+        let store = Arc::clone(&store);
+        let hooks = Arc::clone(&expiry_hooks);
+        let attributes = Arc::clone(&expiry_attributes);
+        async move {
+            let expired = attributes.expired()?;
+            for path in &expired {
+                let path = Path::new(path);
+                store.remove(path).await?;
+                attributes.remove(path)?;
+                hooks.post_delete(path).await;
+            }
+            log::debug!("Removed {} expired uploads", expired.len());
+            Ok(())
+        }
+    })?;
+
+    let store = Arc::clone(file_store);
+    let delete_hooks = Arc::clone(hooks);
+    let delete_attributes = Arc::clone(attributes);
+    let delete_trash = Arc::clone(trash);
+    scheduler.register("deferred_delete", "0 * * * * *", move || {
+        let store = Arc::clone(&store);
+        let hooks = Arc::clone(&delete_hooks);
+        let attributes = Arc::clone(&delete_attributes);
+        let trash = Arc::clone(&delete_trash);
+        async move {
+            let due = attributes.due_deletes()?;
+            for path in &due {
+                let path = Path::new(path);
+                trash.delete(path, &store, &attributes).await?;
+                hooks.post_delete(path).await;
+            }
+            log::debug!("Removed {} files after their grace period", due.len());
+            Ok(())
+        }
+    })?;
+
+    let trash = Arc::clone(trash);
+    scheduler.register("trash_purge", "0 */10 * * * *", move || {
+        let trash = Arc::clone(&trash);
+        async move {
+            let purged = trash.purge_expired().await?;
+            log::debug!("Purged {purged} files from the trash");
+            Ok(())
+        }
+    })?;
+
+    Ok(())
+}
+
+/// Registers the tasks of what's kept next to the store: webhooks, derivatives, download
+/// times, the changelog and the mirror.
+fn register_feature_tasks(
+    scheduler: &mut Scheduler,
+    file_store: &SharedFileStore,
+    outbox: &SharedOutbox,
+    derivatives: &SharedDerivatives,
+    access_times: &SharedAccessTimes,
+    changelog: &SharedChangelog,
+    standby: &SharedStandby,
+) -> io::Result<()> {
+    let outbox = Arc::clone(outbox);
+    scheduler.register("webhook_delivery", "*/15 * * * * *", move || {
+        let outbox = Arc::clone(&outbox);
+        async move {
+            let delivered = outbox.deliver_due().await?;
+            log::debug!("Delivered {delivered} webhook events");
+            Ok(())
+        }
+    })?;
+
+    let derivatives = Arc::clone(derivatives);
+    scheduler.register("derivative_purge", "0 0 * * * *", move || {
+        let derivatives = Arc::clone(&derivatives);
+        async move {
+            let purged = derivatives.purge_stale()?;
+            log::debug!("Purged {purged} stale derivatives");
+            Ok(())
+        }
+    })?;
+
+    if access_times.is_enabled() {
+        let access_times = Arc::clone(access_times);
+        scheduler.register("access_time_flush", "0 * * * * *", move || {
+            let access_times = Arc::clone(&access_times);
+            async move {
+                let flushed = access_times.flush()?;
+                log::debug!("Recorded downloads of {flushed} files");
+                Ok(())
+            }
+        })?;
+    }
+
+    if changelog.is_enabled() {
+        let changelog = Arc::clone(changelog);
+        scheduler.register("changelog_prune", "0 30 4 * * *", move || {
+            let changelog = Arc::clone(&changelog);
+            async move {
+                let pruned = changelog.prune()?;
+                log::debug!("Pruned {pruned} changes from the changelog");
+                Ok(())
+            }
+        })?;
+    }
+
+    if standby.following().is_some() {
+        let standby = Arc::clone(standby);
+        let store = Arc::clone(file_store);
+        scheduler.register("mirror_sync", "0 */5 * * * *", move || {
+            let standby = Arc::clone(&standby);
+            let store = Arc::clone(&store);
+            async move { sync_mirror(&standby, &store).await }
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Registers the tasks of authentication: writing down which user agents tokens were used from.
+fn register_auth_tasks(
+    scheduler: &mut Scheduler,
+    token_clients: &Arc<TokenClients>,
+) -> io::Result<()> {
+    let token_clients = Arc::clone(token_clients);
+    scheduler.register("token_client_flush", "30 * * * * *", move || {
+        let token_clients = Arc::clone(&token_clients);
+        async move {
+            let flushed = token_clients.flush()?;
+            log::debug!("Recorded requests of {flushed} token user agents");
+            Ok(())
+        }
+    })?;
+
+    Ok(())
+}
+
+async fn sync_mirror(standby: &Standby, store: &FileStore) -> io::Result<()> {
+    // stopped following once promoted
+    let Some(mirror) = standby.following() else {
+        return Ok(());
+    };
+    match mirror.sync(store).await? {
+        Some(report) => log::info!(
+            "Synced mirror: {} copied, {} removed, {} unchanged",
+            report.copied,
+            report.removed,
+            report.unchanged
+        ),
+        None => log::debug!("Skipped mirror sync, the last one is still running"),
+    }
+    Ok(())
 }
@@ -0,0 +1,103 @@
+use pulldown_cmark::{CowStr, Event, Options, Parser, Tag, html};
+
+use crate::branding::escape_html;
+
+/// Sent along with rendered pages, so whatever gets past the sanitizing can't run either.
+pub const CONTENT_SECURITY_POLICY: &str =
+    "default-src 'none'; img-src * data:; style-src 'unsafe-inline'";
+
+// larger files are served as they are, rather than held in memory to render
+pub const MAX_RENDERED_BYTES: u64 = 4 * 1024 * 1024;
+
+/// Whether `path` names a Markdown file, by its extension.
+pub fn is_markdown(path: &std::path::Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("md") || ext.eq_ignore_ascii_case("markdown"))
+}
+
+/// Converts `markdown` to HTML, with any HTML in it shown as text and links only to places a
+/// click can't run scripts from.
+pub fn render(markdown: &str) -> String {
+    let options =
+        Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TASKLISTS;
+    let events = Parser::new_ext(markdown, options).map(|event| match event {
+        Event::Html(html) => Event::Text(html),
+        Event::Start(Tag::Link(kind, url, title)) => {
+            Event::Start(Tag::Link(kind, safe_url(url), title))
+        }
+        Event::Start(Tag::Image(kind, url, title)) => {
+            Event::Start(Tag::Image(kind, safe_url(url), title))
+        }
+        event => event,
+    });
+
+    let mut rendered = String::with_capacity(markdown.len() * 3 / 2);
+    html::push_html(&mut rendered, events);
+    rendered
+}
+
+fn safe_url(url: CowStr) -> CowStr {
+    // a scheme is whatever comes before the first colon, unless a path or query starts first
+    let scheme = url
+        .split_once(':')
+        .map(|(scheme, _)| scheme)
+        .filter(|scheme| !scheme.contains(['/', '?', '#']));
+    match scheme.map(|scheme| scheme.trim().to_ascii_lowercase()) {
+        None => url,
+        Some(scheme) if ["http", "https", "mailto"].contains(&scheme.as_str()) => url,
+        Some(_) => CowStr::Borrowed("#"),
+    }
+}
+
+/// `html` in a minimal page of its own, titled after the file it was rendered from.
+pub fn page(file_name: &str, html: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{title}</title>
+<style>
+body {{ max-width: 48rem; margin: 2rem auto; padding: 0 1.5rem; font-family: system-ui, sans-serif; line-height: 1.6; color: #222; }}
+pre, code {{ background: #f4f5f7; border-radius: 4px; }}
+pre {{ padding: .75rem; overflow-x: auto; }}
+img {{ max-width: 100%; }}
+table {{ border-collapse: collapse; }}
+th, td {{ border: 1px solid #d8dbe0; padding: .25rem .5rem; }}
+</style>
+</head>
+<body>
+{html}</body>
+</html>
+"#,
+        title = escape_html(file_name),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_markdown_without_scripts() {
+        let rendered = render(
+            "# Notes\n\n\
+             Some *text* with <script>alert(1)</script> in it.\n\n\
+             [ok](https://example.com) [relative](other.md#part) \
+             [bad](javascript:alert(1)) [sneaky]( JaVaScript:alert(1))\n\n\
+             <img src=x onerror=alert(1)>\n",
+        );
+
+        assert!(rendered.contains("<h1>Notes</h1>"));
+        assert!(rendered.contains("<em>text</em>"));
+        assert!(rendered.contains("&lt;script&gt;alert(1)&lt;/script&gt;"));
+        assert!(rendered.contains("&lt;img src=x onerror=alert(1)&gt;"));
+        assert!(rendered.contains(r#"<a href="https://example.com">ok</a>"#));
+        assert!(rendered.contains(r#"<a href="other.md#part">relative</a>"#));
+        assert!(!rendered.to_ascii_lowercase().contains("javascript:"));
+        assert!(!rendered.contains("<script"));
+        assert!(!rendered.contains("<img"));
+    }
+}
@@ -0,0 +1,68 @@
+use std::{collections::BTreeMap, io, path::Path};
+
+use actix_web::mime::Mime;
+
+/// The types files are served as, going by their extension, with the configured overrides
+/// taking precedence over the ones that are known already.
+pub struct MimeTypes {
+    // keyed by the lowercase extension, without the leading dot
+    overrides: BTreeMap<String, Mime>,
+}
+
+impl MimeTypes {
+    pub fn new(overrides: &BTreeMap<String, String>) -> io::Result<Self> {
+        let overrides = overrides
+            .iter()
+            .map(|(extension, mime)| {
+                let mime = mime.parse::<Mime>().map_err(|err| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("invalid MIME type for '{extension}': {err}"),
+                    )
+                })?;
+                let extension = extension.trim_start_matches('.').to_ascii_lowercase();
+                Ok((extension, mime))
+            })
+            .collect::<io::Result<_>>()?;
+
+        Ok(MimeTypes { overrides })
+    }
+
+    /// The type of the file at `path`, if its extension tells.
+    pub fn guess(&self, path: &Path) -> Option<Mime> {
+        let overridden = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(|extension| self.overrides.get(&extension.to_ascii_lowercase()));
+        match overridden {
+            Some(mime) => Some(mime.clone()),
+            None => mime_guess::from_path(path).first(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overrides_take_precedence_over_the_known_types() {
+        let types = MimeTypes::new(&BTreeMap::from([
+            (".gcode".to_string(), "text/x-gcode".to_string()),
+            ("JSON".to_string(), "text/plain".to_string()),
+        ]))
+        .unwrap();
+        let guess = |path: &str| types.guess(Path::new(path)).map(|mime| mime.to_string());
+
+        assert_eq!(
+            guess("prints/benchy.GCODE").as_deref(),
+            Some("text/x-gcode")
+        );
+        assert_eq!(guess("data/report.json").as_deref(), Some("text/plain"));
+        assert_eq!(guess("site/site.css").as_deref(), Some("text/css"));
+        assert_eq!(guess("README"), None);
+        assert!(
+            MimeTypes::new(&BTreeMap::from([("x".to_string(), "nonsense".to_string())])).is_err()
+        );
+    }
+}
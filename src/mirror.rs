@@ -0,0 +1,268 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use chrono::Utc;
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+use crate::{
+    config::server::{HashAlgorithm, MemoryCache, MirrorConfig},
+    file_handles::FileHandles,
+    file_store::{
+        EntryKind, FileMetadata, FileStorageCore, FileStore, StoreTracer, StoredFile,
+        StoredFileCore,
+    },
+};
+
+const PROMOTED_FILE_NAME: &str = "promoted";
+
+pub type SharedStandby = Arc<Standby>;
+
+/// How a sync changed the mirror.
+#[derive(Serialize, Debug, Default)]
+pub struct SyncReport {
+    pub copied: usize,
+    pub removed: usize,
+    pub unchanged: usize,
+}
+
+/// The source a read-only store is kept a copy of.
+pub struct Mirror {
+    upstream: FileStore,
+    // syncs can take longer than the interval between them, they're never run side by side
+    sync_lock: Mutex<()>,
+}
+
+impl Mirror {
+    pub fn open(
+        config: &MirrorConfig,
+        cache: &MemoryCache,
+        hash_algorithm: HashAlgorithm,
+        tracer: &Arc<StoreTracer>,
+        handles: &Arc<FileHandles>,
+    ) -> io::Result<Self> {
+        Ok(Mirror {
+            upstream: FileStore::open(&config.upstream, cache, hash_algorithm, tracer, handles)?,
+            sync_lock: Mutex::new(()),
+        })
+    }
+
+    /// Copies over the files of the upstream that `local` is missing or has different versions
+    /// of, then removes the ones it no longer has. Returns `None` when a sync is already running.
+    pub async fn sync(&self, local: &FileStore) -> io::Result<Option<SyncReport>> {
+        let Ok(_guard) = self.sync_lock.try_lock() else {
+            return Ok(None);
+        };
+
+        let upstream = files(&self.upstream).await?;
+        let mut report = SyncReport::default();
+        for (path, metadata) in &upstream {
+            if is_current(local, path, metadata).await {
+                report.unchanged += 1;
+                continue;
+            }
+
+            let Some(file) = self.upstream.get_file(path).await else {
+                continue;
+            };
+            copy(local, path, &file).await?;
+            report.copied += 1;
+        }
+
+        for path in files(local).await?.keys() {
+            if !upstream.contains_key(path) {
+                local.remove(path).await?;
+                report.removed += 1;
+            }
+        }
+
+        Ok(Some(report))
+    }
+
+    /// Like `sync`, for the one file at `path`, which is removed from `local` when the
+    /// upstream no longer has it.
+    pub async fn sync_file(
+        &self,
+        local: &FileStore,
+        path: &Path,
+    ) -> io::Result<Option<SyncReport>> {
+        let Ok(_guard) = self.sync_lock.try_lock() else {
+            return Ok(None);
+        };
+
+        let mut report = SyncReport::default();
+        match self.upstream.get_file(path).await {
+            Some(file) if is_current(local, path, file.metadata()).await => report.unchanged += 1,
+            Some(file) => {
+                copy(local, path, &file).await?;
+                report.copied += 1;
+            }
+            None if local.exists(path).await => {
+                local.remove(path).await?;
+                report.removed += 1;
+            }
+            None => {}
+        }
+
+        Ok(Some(report))
+    }
+}
+
+/// Whether `local` already has the file at `path` the way the upstream's `metadata` says.
+async fn is_current(local: &FileStore, path: &Path, metadata: &FileMetadata) -> bool {
+    local.get_file(path).await.is_some_and(|file| {
+        let local = file.metadata();
+        // not every source knows the hashes of its files, or hashes them the same way
+        local.size_bytes == metadata.size_bytes
+            && (local.hash.is_empty()
+                || metadata.hash.is_empty()
+                || local.algorithm != metadata.algorithm
+                || local.hash == metadata.hash)
+    })
+}
+
+async fn copy(local: &FileStore, path: &Path, file: &StoredFile) -> io::Result<()> {
+    let size_hint = Some(file.metadata().size_bytes);
+    let staged = local
+        .stage_upload(path, file.bytes_stream(), size_hint)
+        .await?;
+    local.commit_upload(staged).await?;
+    Ok(())
+}
+
+/// The mirror the server follows, until it's promoted to take over from its upstream. Being
+/// promoted is kept in the data directory, so a restart doesn't go back to following it.
+pub struct Standby {
+    mirror: Option<Mirror>,
+    promoted_path: PathBuf,
+    promoted: AtomicBool,
+}
+
+impl Standby {
+    pub fn new(data_dir: impl AsRef<Path>, mirror: Option<Mirror>) -> Self {
+        let promoted_path = data_dir.as_ref().join(PROMOTED_FILE_NAME);
+        let promoted = mirror.is_some() && promoted_path.exists();
+        if promoted {
+            log::warn!(
+                "Not following the mirror's upstream, this server was promoted to take over from it"
+            );
+        }
+
+        Standby {
+            mirror,
+            promoted_path,
+            promoted: AtomicBool::new(promoted),
+        }
+    }
+
+    /// The mirror being followed, unless the server isn't one or has been promoted.
+    pub fn following(&self) -> Option<&Mirror> {
+        self.mirror
+            .as_ref()
+            .filter(|_| !self.promoted.load(Ordering::Acquire))
+    }
+
+    /// Stops following the upstream for good, once any sync still running is done, which
+    /// makes the files writable. Returns `false` when there was nothing to stop following.
+    pub async fn promote(&self) -> io::Result<bool> {
+        let Some(mirror) = self.following() else {
+            return Ok(false);
+        };
+        let _guard = mirror.sync_lock.lock().await;
+
+        if let Some(parent) = self.promoted_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&self.promoted_path, Utc::now().to_rfc3339())?;
+        self.promoted.store(true, Ordering::Release);
+        Ok(true)
+    }
+}
+
+/// Every file in `store`, with its metadata.
+async fn files(store: &FileStore) -> io::Result<BTreeMap<PathBuf, FileMetadata>> {
+    let mut files = BTreeMap::new();
+    let mut dirs = VecDeque::from([PathBuf::new()]);
+
+    while let Some(dir) = dirs.pop_front() {
+        let entries = match store.list(&dir).await {
+            Ok(entries) => entries,
+            // an empty store may not have its root yet
+            Err(err) if err.kind() == io::ErrorKind::NotFound && dir.as_os_str().is_empty() => {
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+
+        for entry in entries {
+            let path = dir.join(&entry.name);
+            if entry.kind == EntryKind::Dir {
+                dirs.push_back(path);
+                continue;
+            }
+
+            if let Some(file) = store.get_file(&path).await {
+                files.insert(path, file.metadata().clone());
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{file_store::MemoryFileStore, fixtures::temp_dir};
+    use actix_web::rt::System;
+    use actix_web::web::Bytes;
+    use futures::{StreamExt, stream};
+
+    async fn upload(store: &FileStore, path: &str, contents: &'static [u8]) {
+        let stream = stream::iter([Ok(Bytes::from_static(contents))]).boxed_local();
+        let staged = store.stage_upload(Path::new(path), stream, None).await;
+        store.commit_upload(staged.unwrap()).await.unwrap();
+    }
+
+    fn memory_mirror() -> Mirror {
+        Mirror {
+            upstream: FileStore::Memory(MemoryFileStore::default()),
+            sync_lock: Mutex::new(()),
+        }
+    }
+
+    #[test]
+    fn single_files_follow_the_upstream_until_promoted() {
+        let data_dir = temp_dir();
+        let local = FileStore::Memory(MemoryFileStore::default());
+        let standby = Standby::new(&data_dir, Some(memory_mirror()));
+
+        System::new().block_on(async {
+            let mirror = standby.following().unwrap();
+            upload(&mirror.upstream, "a.txt", b"hello").await;
+            let report = mirror.sync_file(&local, Path::new("a.txt")).await;
+            assert_eq!(report.unwrap().unwrap().copied, 1);
+            assert!(local.exists(Path::new("a.txt")).await);
+
+            mirror.upstream.remove(Path::new("a.txt")).await.unwrap();
+            let report = mirror.sync_file(&local, Path::new("a.txt")).await;
+            assert_eq!(report.unwrap().unwrap().removed, 1);
+            assert!(!local.exists(Path::new("a.txt")).await);
+
+            assert!(standby.promote().await.unwrap());
+            assert!(standby.following().is_none());
+            assert!(!standby.promote().await.unwrap());
+        });
+
+        // still promoted after a restart
+        let restarted = Standby::new(&data_dir, Some(memory_mirror()));
+        assert!(restarted.following().is_none());
+    }
+}
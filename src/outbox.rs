@@ -0,0 +1,357 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use reqwest::header::{AUTHORIZATION, HeaderMap, HeaderValue};
+use rusqlite::{Connection, params};
+use serde::{Deserialize, Serialize};
+
+use crate::config::server::{WebhookConfig, WebhookEventType};
+
+const OUTBOX_FILE_NAME: &str = "outbox.db";
+
+// how many pending deliveries are attempted per run
+const DELIVERY_BATCH_SIZE: i64 = 100;
+
+const MAX_RETRY_DELAY_SECS: i64 = 60 * 60;
+
+pub type SharedOutbox = Arc<Outbox>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum EventKind {
+    #[serde(rename = "file.uploaded")]
+    FileUploaded,
+    #[serde(rename = "file.deleted")]
+    FileDeleted,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Event {
+    pub event: EventKind,
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl EventKind {
+    fn event_type(self) -> WebhookEventType {
+        match self {
+            EventKind::FileUploaded => WebhookEventType::Upload,
+            EventKind::FileDeleted => WebhookEventType::Delete,
+        }
+    }
+}
+
+impl Event {
+    pub fn new(event: EventKind, path: &Path) -> Self {
+        Event {
+            event,
+            path: path.to_string_lossy().into_owned(),
+            hash: None,
+            timestamp: Utc::now(),
+        }
+    }
+
+    pub fn with_hash(mut self, hash: impl Into<String>) -> Self {
+        self.hash = Some(hash.into());
+        self
+    }
+}
+
+/// A delivery that ran out of attempts, kept around until it's requeued.
+#[derive(Serialize, Debug)]
+pub struct DeadLetter {
+    pub id: i64,
+    pub url: String,
+    pub payload: serde_json::Value,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+struct Delivery {
+    id: i64,
+    url: String,
+    payload: String,
+    attempts: u32,
+}
+
+/// Persists every event for every webhook before it's sent, so deliveries survive the
+/// receiver being down as well as this server restarting.
+pub struct Outbox {
+    config: WebhookConfig,
+    client: reqwest::Client,
+    db: Mutex<Connection>,
+}
+
+impl Outbox {
+    pub fn open(data_dir: impl AsRef<Path>, config: &WebhookConfig) -> io::Result<Self> {
+        fs::create_dir_all(&data_dir)?;
+        let db_path: PathBuf = data_dir.as_ref().join(OUTBOX_FILE_NAME);
+
+        let db = Connection::open(db_path).map_err(io::Error::other)?;
+        db.execute_batch(
+            "CREATE TABLE IF NOT EXISTS outbox (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                url TEXT NOT NULL,
+                payload TEXT NOT NULL,
+                attempts INTEGER NOT NULL DEFAULT 0,
+                next_attempt_at INTEGER NOT NULL,
+                last_error TEXT,
+                dead INTEGER NOT NULL DEFAULT 0,
+                created_at INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS outbox_pending ON outbox (dead, next_attempt_at);",
+        )
+        .map_err(io::Error::other)?;
+
+        let mut headers = HeaderMap::new();
+        if let Some(token) = &config.token {
+            let mut value = HeaderValue::try_from(format!("Bearer {token}"))
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+            value.set_sensitive(true);
+            headers.insert(AUTHORIZATION, value);
+        }
+        let client = reqwest::Client::builder()
+            .default_headers(headers)
+            .timeout(Duration::from_secs(config.request_timeout_secs))
+            .build()
+            .map_err(io::Error::other)?;
+
+        Ok(Outbox {
+            config: config.clone(),
+            client,
+            db: Mutex::new(db),
+        })
+    }
+
+    /// Queues `event` for every configured webhook. Failing to do so is only logged, since
+    /// whatever caused the event has already happened by now.
+    pub fn publish(&self, event: Event) {
+        if self.config.urls.is_empty() && self.config.subscriptions.is_empty() {
+            return;
+        }
+
+        if let Err(err) = self.enqueue(&event) {
+            log::error!(
+                "Failed to queue {:?} event for {}: {err}",
+                event.event,
+                event.path
+            );
+        }
+    }
+
+    fn enqueue(&self, event: &Event) -> io::Result<()> {
+        let payload = serde_json::to_string(event)?;
+        let now = Utc::now().timestamp();
+
+        let mut db = self.db.lock().unwrap();
+        let tx = db.transaction().map_err(io::Error::other)?;
+        for url in self.urls_for(event) {
+            tx.execute(
+                "INSERT INTO outbox (url, payload, next_attempt_at, created_at) VALUES (?1, ?2, ?3, ?3)",
+                params![url, payload, now],
+            )
+            .map_err(io::Error::other)?;
+        }
+        tx.commit().map_err(io::Error::other)
+    }
+
+    /// The webhooks `event` is sent to, being every one of `urls` and the subscriptions
+    /// covering it.
+    fn urls_for<'a>(&'a self, event: &Event) -> impl Iterator<Item = &'a String> + use<'a> {
+        let event_type = event.event.event_type();
+        let path = PathBuf::from(&event.path);
+        let subscribed = self
+            .config
+            .subscriptions
+            .iter()
+            .filter(move |subscription| {
+                (subscription.events.is_empty() || subscription.events.contains(&event_type))
+                    && (subscription.prefixes.is_empty()
+                        || subscription
+                            .prefixes
+                            .iter()
+                            .any(|prefix| path.starts_with(prefix.trim_matches('/'))))
+            });
+        self.config
+            .urls
+            .iter()
+            .chain(subscribed.map(|subscription| &subscription.url))
+    }
+
+    /// Attempts every delivery that's due, returning how many succeeded.
+    pub async fn deliver_due(&self) -> io::Result<usize> {
+        let due = self.due_deliveries()?;
+        let mut delivered = 0;
+
+        for delivery in due {
+            let result = self
+                .client
+                .post(&delivery.url)
+                .header("X-Event-Id", delivery.id)
+                .header("Content-Type", "application/json")
+                .body(delivery.payload)
+                .send()
+                .await
+                .and_then(|res| res.error_for_status());
+
+            let db = self.db.lock().unwrap();
+            match result {
+                Ok(_) => {
+                    db.execute("DELETE FROM outbox WHERE id = ?1", [delivery.id])
+                        .map_err(io::Error::other)?;
+                    delivered += 1;
+                }
+                Err(err) => {
+                    let attempts = delivery.attempts + 1;
+                    let dead = attempts >= self.config.max_attempts;
+                    if dead {
+                        log::warn!("Giving up on delivering event to {}: {err}", delivery.url);
+                    }
+
+                    db.execute(
+                        "UPDATE outbox SET attempts = ?2, next_attempt_at = ?3, last_error = ?4, dead = ?5
+                            WHERE id = ?1",
+                        params![
+                            delivery.id,
+                            attempts,
+                            Utc::now().timestamp() + retry_delay_secs(attempts),
+                            err.to_string(),
+                            dead,
+                        ],
+                    )
+                    .map_err(io::Error::other)?;
+                }
+            }
+        }
+
+        Ok(delivered)
+    }
+
+    fn due_deliveries(&self) -> io::Result<Vec<Delivery>> {
+        let db = self.db.lock().unwrap();
+        let mut statement = db
+            .prepare(
+                "SELECT id, url, payload, attempts FROM outbox
+                    WHERE dead = 0 AND next_attempt_at <= ?1
+                    ORDER BY id LIMIT ?2",
+            )
+            .map_err(io::Error::other)?;
+
+        statement
+            .query_map(
+                params![Utc::now().timestamp(), DELIVERY_BATCH_SIZE],
+                |row| {
+                    Ok(Delivery {
+                        id: row.get(0)?,
+                        url: row.get(1)?,
+                        payload: row.get(2)?,
+                        attempts: row.get(3)?,
+                    })
+                },
+            )
+            .and_then(|rows| rows.collect())
+            .map_err(io::Error::other)
+    }
+
+    pub fn dead_letters(&self) -> io::Result<Vec<DeadLetter>> {
+        let db = self.db.lock().unwrap();
+        let mut statement = db
+            .prepare(
+                "SELECT id, url, payload, attempts, last_error, created_at FROM outbox
+                    WHERE dead = 1 ORDER BY id",
+            )
+            .map_err(io::Error::other)?;
+
+        statement
+            .query_map([], |row| {
+                let payload: String = row.get(2)?;
+                let created_at: i64 = row.get(5)?;
+                Ok(DeadLetter {
+                    id: row.get(0)?,
+                    url: row.get(1)?,
+                    payload: serde_json::from_str(&payload).unwrap_or(payload.into()),
+                    attempts: row.get(3)?,
+                    last_error: row.get(4)?,
+                    created_at: DateTime::from_timestamp(created_at, 0).unwrap_or_default(),
+                })
+            })
+            .and_then(|rows| rows.collect())
+            .map_err(io::Error::other)
+    }
+
+    /// Puts a dead letter (or all of them if `id` is `None`) back in line for delivery, with
+    /// a fresh set of attempts. Returns how many were requeued.
+    pub fn requeue(&self, id: Option<i64>) -> io::Result<usize> {
+        let db = self.db.lock().unwrap();
+        db.execute(
+            "UPDATE outbox SET dead = 0, attempts = 0, next_attempt_at = ?1
+                WHERE dead = 1 AND (?2 IS NULL OR id = ?2)",
+            params![Utc::now().timestamp(), id],
+        )
+        .map_err(io::Error::other)
+    }
+}
+
+/// Exponential backoff starting at 2 seconds, capped at an hour.
+fn retry_delay_secs(attempts: u32) -> i64 {
+    2_i64
+        .checked_pow(attempts)
+        .map_or(MAX_RETRY_DELAY_SECS, |delay| {
+            delay.min(MAX_RETRY_DELAY_SECS)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::server::WebhookSubscription, fixtures::temp_dir};
+
+    #[test]
+    fn events_go_to_the_webhooks_subscribed_to_them() {
+        let data_dir = temp_dir();
+        let config = WebhookConfig {
+            urls: vec!["http://everything".to_string()],
+            subscriptions: vec![
+                WebhookSubscription {
+                    url: "http://releases".to_string(),
+                    prefixes: vec!["/releases/".to_string()],
+                    events: vec![WebhookEventType::Upload],
+                },
+                WebhookSubscription {
+                    url: "http://deletes".to_string(),
+                    prefixes: Vec::new(),
+                    events: vec![WebhookEventType::Delete],
+                },
+            ],
+            ..Default::default()
+        };
+        let outbox = Outbox::open(&data_dir, &config).unwrap();
+
+        let urls_for = |kind, path: &str| -> Vec<_> {
+            let event = Event::new(kind, Path::new(path));
+            outbox.urls_for(&event).map(String::as_str).collect()
+        };
+        assert_eq!(
+            urls_for(EventKind::FileUploaded, "releases/v1/app.zip"),
+            ["http://everything", "http://releases"]
+        );
+        // prefixes match whole directories, not just the start of the name
+        assert_eq!(
+            urls_for(EventKind::FileUploaded, "releases-old/app.zip"),
+            ["http://everything"]
+        );
+        assert_eq!(
+            urls_for(EventKind::FileDeleted, "releases/v1/app.zip"),
+            ["http://everything", "http://deletes"]
+        );
+
+        drop(outbox);
+    }
+}
@@ -0,0 +1,129 @@
+use std::io;
+
+use actix_web::mime::{self, Mime};
+use futures::StreamExt;
+
+use crate::file_store::ByteStream;
+
+// however few lines are asked for, reading stops here, so one endless line can't make a
+// preview read the whole file
+pub const MAX_PREVIEW_BYTES: u64 = 1024 * 1024;
+
+/// The start of a file, cut short where the preview asked for ends.
+pub struct Preview {
+    pub contents: Vec<u8>,
+    /// Whether the file goes on past it
+    pub truncated: bool,
+    pub is_utf8: bool,
+}
+
+/// Whether files of type `mime` read as text, counting ones of no known type.
+pub fn is_text(mime: Option<&Mime>) -> bool {
+    let Some(mime) = mime else {
+        return true;
+    };
+    mime.type_() == mime::TEXT
+        || [mime::JSON, mime::XML, mime::JAVASCRIPT].contains(&mime.subtype())
+        || mime
+            .suffix()
+            .is_some_and(|suffix| suffix == mime::JSON || suffix == mime::XML)
+        || ["yaml", "x-yaml", "toml", "x-sh", "sql"].contains(&mime.subtype().as_str())
+}
+
+/// Reads up to `lines` lines and `bytes` bytes from the start of `stream`, whichever ends
+/// first, and no more than `MAX_PREVIEW_BYTES` either way.
+pub async fn read(
+    mut stream: ByteStream<'_>,
+    lines: Option<u64>,
+    bytes: Option<u64>,
+) -> io::Result<Preview> {
+    let max_bytes = bytes.unwrap_or(u64::MAX).min(MAX_PREVIEW_BYTES) as usize;
+    let mut lines_left = lines.unwrap_or(u64::MAX);
+    let mut contents = Vec::new();
+    let mut truncated = false;
+
+    while lines_left > 0 && contents.len() < max_bytes {
+        let Some(chunk) = stream.next().await.transpose()? else {
+            break;
+        };
+        let mut end = chunk.len().min(max_bytes - contents.len());
+        for (i, byte) in chunk[..end].iter().enumerate() {
+            if *byte == b'\n' {
+                lines_left -= 1;
+                if lines_left == 0 {
+                    end = i + 1;
+                    break;
+                }
+            }
+        }
+        contents.extend_from_slice(&chunk[..end]);
+        truncated = end < chunk.len();
+    }
+    // the file could end right where the preview does, which only the next read can tell
+    if !truncated && (lines_left == 0 || contents.len() == max_bytes) {
+        while let Some(chunk) = stream.next().await.transpose()? {
+            if !chunk.is_empty() {
+                truncated = true;
+                break;
+            }
+        }
+    }
+
+    // a cut through the middle of a character leaves a part of it that isn't text
+    let is_utf8 = match std::str::from_utf8(&contents) {
+        Ok(_) => true,
+        Err(err) if err.error_len().is_none() && truncated => {
+            contents.truncate(err.valid_up_to());
+            true
+        }
+        Err(_) => false,
+    };
+    Ok(Preview {
+        contents,
+        truncated,
+        is_utf8,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::web::Bytes;
+    use futures::stream;
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn previews_stop_at_the_lines_or_bytes_asked_for() {
+        let text = "first line\nsecond line\nthird ünïcode line\n";
+        // split across chunks, even through a character
+        let chunks = || {
+            stream::iter(
+                text.as_bytes()
+                    .chunks(7)
+                    .map(|chunk| Ok(Bytes::copy_from_slice(chunk))),
+            )
+            .boxed_local()
+        };
+        let preview = async |lines, bytes| {
+            let preview = read(chunks(), lines, bytes).await.unwrap();
+            assert!(preview.is_utf8);
+            (
+                String::from_utf8(preview.contents).unwrap(),
+                preview.truncated,
+            )
+        };
+
+        assert_eq!(
+            preview(Some(2), None).await,
+            ("first line\nsecond line\n".to_string(), true)
+        );
+        assert_eq!(preview(Some(1), Some(5)).await, ("first".to_string(), true));
+        // "third ü" is cut through the ü
+        assert_eq!(
+            preview(Some(3), Some(30)).await,
+            ("first line\nsecond line\nthird ".to_string(), true)
+        );
+        assert_eq!(preview(Some(3), None).await, (text.to_string(), false));
+        assert_eq!(preview(None, Some(1000)).await, (text.to_string(), false));
+    }
+}
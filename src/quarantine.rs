@@ -0,0 +1,101 @@
+use std::{
+    collections::VecDeque,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+
+use crate::{
+    config::server::HashAlgorithm,
+    file_store::{
+        EntryKind, FileMetadata, FileStorageCore, FileStore, FsFileStore, StoredFileCore,
+    },
+};
+
+const QUARANTINE_DIR_NAME: &str = "quarantine";
+
+/// An upload waiting for approval.
+#[derive(Serialize, Debug)]
+pub struct Pending {
+    pub path: String,
+    pub size_bytes: u64,
+    pub hash: String,
+}
+
+/// Uploads held back from being served until they're approved, kept on local disk whatever
+/// the files are served from.
+pub struct Quarantine {
+    store: FileStore,
+}
+
+impl Quarantine {
+    /// Uploads are hashed with `hash_algorithm`, the same as the served ones they'll become.
+    pub fn open(data_dir: impl AsRef<Path>, hash_algorithm: HashAlgorithm) -> io::Result<Self> {
+        let dir = data_dir.as_ref().join(QUARANTINE_DIR_NAME);
+        fs::create_dir_all(&dir)?;
+
+        Ok(Quarantine {
+            store: FileStore::Filesystem(FsFileStore::new(dir).with_hash_algorithm(hash_algorithm)),
+        })
+    }
+
+    /// Where quarantined uploads are staged and committed to, at the path they'd be served from.
+    pub fn store(&self) -> &FileStore {
+        &self.store
+    }
+
+    /// Moves the upload at `path` into `target`, making it visible there.
+    pub async fn approve(&self, path: &Path, target: &FileStore) -> io::Result<FileMetadata> {
+        let file = self.store.get_file(path).await.ok_or(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no upload awaiting approval at this path",
+        ))?;
+
+        let staged = target
+            .stage_upload(path, file.bytes_stream(), Some(file.metadata().size_bytes))
+            .await?;
+        let metadata = target.commit_upload(staged).await?;
+        self.store.remove(path).await?;
+        Ok(metadata)
+    }
+
+    /// Throws away the upload at `path`, returning whether there was one.
+    pub async fn reject(&self, path: &Path) -> io::Result<bool> {
+        let existed = self.store.exists(path).await;
+        self.store.remove(path).await?;
+        Ok(existed)
+    }
+
+    /// Every upload awaiting approval, ordered by path.
+    pub async fn pending(&self) -> io::Result<Vec<Pending>> {
+        let mut pending = Vec::new();
+        let mut dirs = VecDeque::from([PathBuf::new()]);
+
+        while let Some(dir) = dirs.pop_front() {
+            for entry in self.store.list(&dir).await? {
+                let path = dir.join(&entry.name);
+                if entry.kind == EntryKind::Dir {
+                    dirs.push_back(path);
+                    continue;
+                }
+
+                let Some(file) = self.store.get_file(&path).await else {
+                    continue;
+                };
+                pending.push(Pending {
+                    path: path
+                        .iter()
+                        .map(|s| s.to_string_lossy())
+                        .collect::<Vec<_>>()
+                        .join("/"),
+                    size_bytes: file.metadata().size_bytes,
+                    hash: file.metadata().hash.clone(),
+                });
+            }
+        }
+
+        pending.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(pending)
+    }
+}
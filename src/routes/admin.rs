@@ -0,0 +1,141 @@
+use std::io;
+
+use actix_web::{
+    HttpResponse, Result, delete, get,
+    http::header,
+    post,
+    web::{Data, Path, ReqData},
+};
+use serde_json::json;
+
+use crate::{
+    SharedFileStore,
+    authorized::{AuthPayload, Permission},
+    hooks::SharedHooks,
+    mirror::SharedStandby,
+    quarantine::Quarantine,
+    routes::{
+        etag,
+        file_path::{FilePath, encode_path},
+        upload_file::{mirror_read_only, upload_conflict},
+    },
+    token_clients::TokenClients,
+    transfers::Transfers,
+};
+
+#[get("/admin/quarantine")]
+pub async fn quarantined(
+    auth: ReqData<AuthPayload>,
+    quarantine: Data<Quarantine>,
+) -> Result<HttpResponse> {
+    auth.require(Permission::Approve)?;
+
+    Ok(match quarantine.pending().await {
+        Ok(pending) => HttpResponse::Ok().json(pending),
+        Err(err) => {
+            log::error!("Error listing quarantined uploads: {err}");
+            HttpResponse::InternalServerError().body("Failed to list quarantined uploads")
+        }
+    })
+}
+
+#[post("/admin/approve/{path:.*}")]
+pub async fn approve(
+    path: FilePath,
+    auth: ReqData<AuthPayload>,
+    quarantine: Data<Quarantine>,
+    file_store: Data<SharedFileStore>,
+    hooks: Data<SharedHooks>,
+    standby: Data<SharedStandby>,
+) -> Result<HttpResponse> {
+    auth.require(Permission::Approve)?;
+
+    if let Some(response) = mirror_read_only(&standby) {
+        return Ok(response);
+    }
+    if let Some(conflict) = upload_conflict(&file_store, &path).await {
+        return Ok(conflict);
+    }
+
+    Ok(match quarantine.approve(&path, &file_store).await {
+        Ok(metadata) => {
+            hooks.post_upload(&path, &metadata).await;
+
+            HttpResponse::Created()
+                .insert_header((header::ETAG, etag::strong(&metadata.hash)))
+                .insert_header((header::LOCATION, format!("/{}", encode_path(&*path))))
+                .finish()
+        }
+        Err(err) => match err.kind() {
+            io::ErrorKind::NotFound => HttpResponse::NotFound().body(err.to_string()),
+            io::ErrorKind::InvalidInput => {
+                HttpResponse::BadRequest().body(format!("Invalid input: {err}"))
+            }
+            _ => {
+                log::error!("Error approving upload: {err}");
+                HttpResponse::InternalServerError().body("Failed to approve upload")
+            }
+        },
+    })
+}
+
+#[post("/admin/reject/{path:.*}")]
+pub async fn reject(
+    path: FilePath,
+    auth: ReqData<AuthPayload>,
+    quarantine: Data<Quarantine>,
+) -> Result<HttpResponse> {
+    auth.require(Permission::Approve)?;
+
+    Ok(match quarantine.reject(&path).await {
+        Ok(true) => HttpResponse::Ok().json(json!({ "rejected": true })),
+        Ok(false) => HttpResponse::NotFound().body("no upload awaiting approval at this path"),
+        Err(err) if err.kind() == io::ErrorKind::InvalidInput => {
+            HttpResponse::BadRequest().body(format!("Invalid input: {err}"))
+        }
+        Err(err) => {
+            log::error!("Error rejecting upload: {err}");
+            HttpResponse::InternalServerError().body("Failed to reject upload")
+        }
+    })
+}
+
+#[get("/admin/tokens")]
+pub async fn tokens(
+    auth: ReqData<AuthPayload>,
+    token_clients: Data<TokenClients>,
+) -> Result<HttpResponse> {
+    auth.require(Permission::Tokens)?;
+
+    Ok(match token_clients.seen() {
+        Ok(seen) => HttpResponse::Ok().json(seen),
+        Err(err) => {
+            log::error!("Error listing tokens: {err}");
+            HttpResponse::InternalServerError().body("Failed to list tokens")
+        }
+    })
+}
+
+#[get("/admin/requests")]
+pub async fn requests(
+    auth: ReqData<AuthPayload>,
+    transfers: Data<Transfers>,
+) -> Result<HttpResponse> {
+    auth.require(Permission::Requests)?;
+    Ok(HttpResponse::Ok().json(transfers.list()))
+}
+
+#[delete("/admin/requests/{id}")]
+pub async fn abort_request(
+    id: Path<u64>,
+    auth: ReqData<AuthPayload>,
+    transfers: Data<Transfers>,
+) -> Result<HttpResponse> {
+    auth.require(Permission::Requests)?;
+
+    Ok(if transfers.abort(*id) {
+        HttpResponse::Ok().json(json!({ "aborted": true }))
+    } else {
+        HttpResponse::NotFound().body("no upload or download in progress with this id")
+    })
+}
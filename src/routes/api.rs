@@ -1,11 +1,27 @@
-use actix_multipart::form::MultipartFormConfig;
 use actix_web::{Scope, dev::HttpServiceFactory, middleware};
 
 use crate::{
     authorized::is_authorized,
+    cors::cors,
     routes::{
         ScopeCreator,
-        upload_file::{delete_file, upload_file},
+        admin::{abort_request, approve, quarantined, reject, requests, tokens},
+        browse::{file_info, list_dir, search},
+        bundle::{archive, bundle},
+        changes::changes,
+        jobs::{create_job, job_status},
+        outbox::{dead_letters, requeue_all, requeue_one},
+        put_file::put_file,
+        scheduler::{
+            collect_garbage, file_handles, partial_uploads, scheduler_status, store_stats,
+            store_usage,
+        },
+        stale::{delete_stale_files, stale_files},
+        standby::{promote, replicate},
+        trash::{restore_file, trashed},
+        upload_file::{delete_file, undelete_file, upload_file},
+        verify::verify_file,
+        versions::{restore_version, versions},
     },
 };
 
@@ -14,9 +30,50 @@ pub struct ApiRoute;
 impl ScopeCreator for ApiRoute {
     fn create_scope() -> impl HttpServiceFactory {
         Scope::new("/api")
-            .app_data(MultipartFormConfig::default())
             .wrap(middleware::from_fn(is_authorized))
-            .service(upload_file)
-            .service(delete_file)
+            // outside of the authorization, preflight requests are sent without credentials
+            .wrap(middleware::from_fn(cors))
+            .service(scheduler_status)
+            .service(store_stats)
+            .service(file_handles)
+            .service(store_usage)
+            .service(partial_uploads)
+            .service(collect_garbage)
+            .service(file_info)
+            .service(verify_file)
+            .service(list_dir)
+            .service(search)
+            .service(bundle)
+            .service(archive)
+            .service(changes)
+            .service(create_job)
+            .service(job_status)
+            .service(dead_letters)
+            .service(requeue_all)
+            .service(requeue_one)
+            .service(quarantined)
+            .service(approve)
+            .service(reject)
+            .service(tokens)
+            .service(requests)
+            .service(abort_request)
+            .service(replicate)
+            .service(promote)
+            .service(stale_files)
+            .service(delete_stale_files)
+            .service(undelete_file)
+            .service(trashed)
+            .service(restore_file)
+            .service(versions)
+            .service(restore_version)
+            // `/{path:.*}` matches every other route above, so files are only written and
+            // deleted here once none of them did. Files named like one of those routes are
+            // still uploaded and deleted on their own URL.
+            .service(
+                Scope::new("")
+                    .service(upload_file)
+                    .service(put_file)
+                    .service(delete_file),
+            )
     }
 }
@@ -0,0 +1,487 @@
+use std::{
+    cmp::Reverse,
+    collections::VecDeque,
+    io,
+    path::{Path, PathBuf},
+};
+
+use actix_web::{
+    HttpMessage, HttpRequest, HttpResponse, Result, get,
+    http::header::{self, ContentType, HeaderValue, Quality},
+    mime::{self, Mime},
+    web::{self, Data, Query},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    SharedFileStore,
+    access_times::SharedAccessTimes,
+    attributes::SharedAttributes,
+    authorized::{Permission, RealmPayloads, payload_for},
+    branding::{Branding, escape_html},
+    config::server::DirectoryListingRule,
+    file_store::{DirEntry, Entry, EntryKind, FileStorageCore, FileStore, StoredFileCore},
+    mime_types::MimeTypes,
+    routes::file_path::{FilePath, encode_path},
+};
+
+// searching stops after this many matches, rather than walking the entire store
+const MAX_SEARCH_RESULTS: usize = 500;
+
+#[get("/info/{path:.*}")]
+pub async fn file_info(
+    req: HttpRequest,
+    path: FilePath,
+    file_store: Data<SharedFileStore>,
+    attributes: Data<SharedAttributes>,
+    access_times: Data<SharedAccessTimes>,
+    mime_types: Data<MimeTypes>,
+) -> Result<HttpResponse> {
+    payload_for(&req, &path).await?.require(Permission::Stat)?;
+
+    let file = match file_store.stat(&path).await {
+        Some(Entry::File(file)) => file,
+        Some(Entry::Dir) => {
+            return Ok(HttpResponse::Ok().json(json!({
+                "path": path.to_string_lossy(),
+                "kind": EntryKind::Dir,
+            })));
+        }
+        None => return Ok(HttpResponse::NotFound().body("File does not exist")),
+    };
+
+    let metadata = file.metadata();
+    let attributes = match attributes.get(&path) {
+        Ok(attributes) => attributes.unwrap_or_default(),
+        Err(err) => {
+            log::error!("Error looking up file attributes: {err}");
+            return Ok(HttpResponse::InternalServerError().body("Failed to look up file"));
+        }
+    };
+    let accessed_at = match access_times.get(&path) {
+        Ok(accessed_at) => accessed_at,
+        Err(err) => {
+            log::error!("Error looking up when the file was downloaded: {err}");
+            return Ok(HttpResponse::InternalServerError().body("Failed to look up file"));
+        }
+    };
+    Ok(HttpResponse::Ok().json(json!({
+        "path": path.to_string_lossy(),
+        "kind": EntryKind::File,
+        "size_bytes": metadata.size_bytes,
+        "hash": metadata.hash,
+        "hash_algorithm": metadata.algorithm,
+        "created_at": metadata.created_at,
+        "modified_at": metadata.modified_at,
+        "scan": metadata.scan,
+        "accessed_at": accessed_at,
+        "content_type": mime_types
+            .guess(&path)
+            .unwrap_or(mime::APPLICATION_OCTET_STREAM)
+            .to_string(),
+        "tags": attributes.tags,
+        "visibility": attributes.visibility,
+        "expires_at": attributes.expires_at,
+    })))
+}
+
+/// The representations a listing can be sent as, picked from the `Accept` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ListingFormat {
+    Json,
+    Html,
+    Text,
+}
+
+impl ListingFormat {
+    const ALL: [ListingFormat; 3] = [
+        ListingFormat::Json,
+        ListingFormat::Html,
+        ListingFormat::Text,
+    ];
+
+    fn mime(self) -> Mime {
+        match self {
+            ListingFormat::Json => mime::APPLICATION_JSON,
+            ListingFormat::Html => mime::TEXT_HTML,
+            ListingFormat::Text => mime::TEXT_PLAIN,
+        }
+    }
+
+    fn negotiate(req: &HttpRequest) -> Self {
+        // scripts that don't say what they want get the machine listing
+        let Some(accept) = req.get_header::<header::Accept>() else {
+            return ListingFormat::Json;
+        };
+
+        // a format goes by the most specific range it falls under, which rules it out with
+        // `q=0`, and is ranked by that range's quality and then by how early it's listed
+        let rank = |format: ListingFormat| {
+            let mime = format.mime();
+            let (_, quality, listed) = accept
+                .iter()
+                .enumerate()
+                .filter_map(|(i, range)| {
+                    let specificity = match (range.item.type_(), range.item.subtype()) {
+                        (mime::STAR, mime::STAR) => 0,
+                        (type_, mime::STAR) if type_ == mime.type_() => 1,
+                        (type_, subtype) if type_ == mime.type_() && subtype == mime.subtype() => 2,
+                        _ => return None,
+                    };
+                    Some((specificity, range.quality, Reverse(i)))
+                })
+                .max()?;
+            (quality > Quality::ZERO).then_some((quality, listed))
+        };
+
+        // ties go to the format listed first in `ALL`
+        Self::ALL
+            .into_iter()
+            .rev()
+            .filter_map(|format| Some((rank(format)?, format)))
+            .max_by_key(|(rank, _)| *rank)
+            .map_or(ListingFormat::Json, |(_, format)| format)
+    }
+
+    /// Lets caches know the same URL is sent in another format for another `Accept`.
+    fn vary(mut response: HttpResponse) -> HttpResponse {
+        response
+            .headers_mut()
+            .append(header::VARY, HeaderValue::from_static("Accept"));
+        response
+    }
+}
+
+#[get("/list/{path:.*}")]
+pub async fn list_dir(
+    req: HttpRequest,
+    path: FilePath,
+    file_store: Data<SharedFileStore>,
+    branding: Data<Branding>,
+) -> Result<HttpResponse> {
+    payload_for(&req, &path).await?.require(Permission::List)?;
+
+    if let Some(Entry::File(_)) = file_store.stat(&path).await {
+        return Ok(HttpResponse::Conflict().body("Path is a file"));
+    }
+
+    let entries = match file_store.list(&path).await {
+        Ok(entries) => entries,
+        Err(err) => return Ok(list_error_response(err)),
+    };
+
+    Ok(ListingFormat::vary(match ListingFormat::negotiate(&req) {
+        ListingFormat::Json => HttpResponse::Ok().json(entries),
+        ListingFormat::Html => HttpResponse::Ok()
+            .content_type(ContentType::html())
+            .body(index_page(&branding, &path, &entries, "/api/list/")),
+        ListingFormat::Text => text_listing(&entries),
+    }))
+}
+
+/// An entry of a listing on the file route, which goes as far as each file's hash.
+#[derive(Serialize)]
+struct ListedEntry {
+    #[serde(flatten)]
+    entry: DirEntry,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hash: Option<String>,
+}
+
+/// Whether the directory at `dir` is listed on the file route, going by the rule with the
+/// longest prefix of it.
+pub fn is_listed(rules: &[DirectoryListingRule], dir: &Path) -> bool {
+    let prefix = |rule: &DirectoryListingRule| Path::new(rule.prefix.trim_matches('/')).to_owned();
+    rules
+        .iter()
+        .filter(|rule| dir.starts_with(prefix(rule)))
+        .max_by_key(|rule| prefix(rule).components().count())
+        .is_some_and(|rule| rule.enabled)
+}
+
+/// Lists the directory at `dir` for the file route, as `GET /api/list/{dir}` would but with
+/// the hash of each file, and without the private ones unless the request has credentials.
+pub async fn public_listing(
+    req: &HttpRequest,
+    dir: &Path,
+    store: &FileStore,
+    attributes: &SharedAttributes,
+    branding: &Branding,
+) -> io::Result<HttpResponse> {
+    let entries = store.list(dir).await?;
+    let (attributes, owned_dir) = (SharedAttributes::clone(attributes), dir.to_path_buf());
+    let entries = web::block(move || {
+        entries
+            .into_iter()
+            .map(|entry| {
+                let is_private = entry.kind == EntryKind::File
+                    && attributes.is_private(&owned_dir.join(&entry.name));
+                (entry, is_private)
+            })
+            .collect::<Vec<_>>()
+    })
+    .await
+    .map_err(io::Error::other)?;
+
+    // private files are left out unless the credentials are valid in the realm of each
+    let mut payloads = RealmPayloads::new(req);
+    let mut visible = Vec::with_capacity(entries.len());
+    for (entry, is_private) in entries {
+        if !is_private || payloads.get(&dir.join(&entry.name)).await.is_some() {
+            visible.push(entry);
+        }
+    }
+    let entries = visible;
+
+    Ok(ListingFormat::vary(match ListingFormat::negotiate(req) {
+        ListingFormat::Json => {
+            let mut listed = Vec::with_capacity(entries.len());
+            for entry in entries {
+                let hash = match entry.kind {
+                    EntryKind::File => store
+                        .get_file(&dir.join(&entry.name))
+                        .await
+                        .map(|file| file.metadata().hash.clone()),
+                    EntryKind::Dir => None,
+                };
+                listed.push(ListedEntry { entry, hash });
+            }
+            HttpResponse::Ok().json(listed)
+        }
+        ListingFormat::Html => HttpResponse::Ok()
+            .content_type(ContentType::html())
+            .body(index_page(branding, dir, &entries, "/")),
+        ListingFormat::Text => text_listing(&entries),
+    }))
+}
+
+fn text_listing(entries: &[DirEntry]) -> HttpResponse {
+    // directories keep a trailing slash, so they can be told apart from files
+    let lines: String = entries
+        .iter()
+        .map(|entry| match entry.kind {
+            EntryKind::Dir => format!("{}/\n", entry.name),
+            EntryKind::File => format!("{}\n", entry.name),
+        })
+        .collect();
+
+    HttpResponse::Ok()
+        .content_type(ContentType::plaintext())
+        .body(lines)
+}
+
+/// Renders a listing as a page linking to the files, and to the subdirectories' listings
+/// under `list_root`.
+fn index_page(branding: &Branding, dir: &Path, entries: &[DirEntry], list_root: &str) -> String {
+    let mut rows = String::new();
+    if let Some(parent) = dir.parent() {
+        let parent = encode_path(parent);
+        rows.push_str(&format!(
+            "<tr><td><a href=\"{list_root}{parent}\">../</a></td><td></td><td></td></tr>\n"
+        ));
+    }
+
+    for entry in entries {
+        let href = encode_path(dir.join(&entry.name));
+        let name = escape_html(&entry.name);
+        rows.push_str(&match entry.kind {
+            EntryKind::Dir => {
+                format!(
+                    "<tr><td><a href=\"{list_root}{href}\">{name}/</a></td><td></td><td></td></tr>\n"
+                )
+            }
+            EntryKind::File => format!(
+                "<tr><td><a href=\"/{href}\">{name}</a></td><td>{}</td><td>{}</td></tr>\n",
+                entry.size_bytes.unwrap_or_default(),
+                entry
+                    .modified_at
+                    .map(|at| at.format("%Y-%m-%d %H:%M UTC").to_string())
+                    .unwrap_or_default()
+            ),
+        });
+    }
+
+    let heading = format!("/{}", to_url_path(dir));
+    branding.page(
+        &heading,
+        &format!(
+            "<table>\n<tr><th>Name</th><th>Size (bytes)</th><th>Modified</th></tr>\n{rows}</table>"
+        ),
+    )
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    /// Case-insensitive substring of the file or directory name
+    q: String,
+    /// Directory to search under, defaults to the root
+    #[serde(default)]
+    dir: PathBuf,
+}
+
+#[derive(Serialize)]
+struct SearchResult {
+    path: String,
+    #[serde(flatten)]
+    entry: DirEntry,
+}
+
+#[get("/search")]
+pub async fn search(
+    req: HttpRequest,
+    query: Query<SearchQuery>,
+    file_store: Data<SharedFileStore>,
+) -> Result<HttpResponse> {
+    payload_for(&req, &query.dir)
+        .await?
+        .require(Permission::List)?;
+
+    // what's found in mounts under it is only listed with `list` in their own realm
+    let mut payloads = RealmPayloads::new(&req);
+    let needle = query.q.to_lowercase();
+    let mut results = Vec::new();
+    let mut pending = VecDeque::from([query.dir.clone()]);
+
+    while let Some(dir) = pending.pop_front() {
+        let entries = match file_store.list(&dir).await {
+            Ok(entries) => entries,
+            // only the starting directory has to exist, the rest may vanish while walking
+            Err(err) if dir == query.dir => return Ok(list_error_response(err)),
+            Err(_) => continue,
+        };
+
+        for entry in entries {
+            let path = dir.join(&entry.name);
+            if entry.kind == EntryKind::Dir {
+                pending.push_back(path.clone());
+            }
+
+            if entry.name.to_lowercase().contains(&needle)
+                && payloads
+                    .get(&path)
+                    .await
+                    .is_some_and(|payload| payload.has(Permission::List))
+            {
+                results.push(SearchResult {
+                    path: to_url_path(&path),
+                    entry,
+                });
+
+                if results.len() >= MAX_SEARCH_RESULTS {
+                    return Ok(HttpResponse::Ok().json(results));
+                }
+            }
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(results))
+}
+
+fn to_url_path(path: &Path) -> String {
+    let segments: Vec<_> = path.iter().map(|s| s.to_string_lossy()).collect();
+    segments.join("/")
+}
+
+fn list_error_response(err: io::Error) -> HttpResponse {
+    match err.kind() {
+        io::ErrorKind::NotFound | io::ErrorKind::NotADirectory => {
+            HttpResponse::NotFound().body("Directory does not exist")
+        }
+        io::ErrorKind::InvalidInput => {
+            HttpResponse::BadRequest().body(format!("Invalid input: {err}"))
+        }
+        _ => {
+            log::error!("Error listing directory: {err}");
+            HttpResponse::InternalServerError().body("Failed to list directory")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::{TestRequest, call_service};
+
+    use super::*;
+    use crate::{
+        config::server::{AuthConfig, DirectoryListingRule, ServerConfig},
+        fixtures::{Fixture, app},
+    };
+
+    #[test]
+    fn listings_are_sent_in_the_format_asked_for() {
+        let negotiate = |accept: Option<&str>| {
+            let mut req = TestRequest::default();
+            if let Some(accept) = accept {
+                req = req.insert_header((header::ACCEPT, accept));
+            }
+            ListingFormat::negotiate(&req.to_http_request())
+        };
+
+        assert_eq!(negotiate(None), ListingFormat::Json);
+        assert_eq!(negotiate(Some("*/*")), ListingFormat::Json);
+        assert_eq!(negotiate(Some("text/*")), ListingFormat::Html);
+        assert_eq!(negotiate(Some("text/plain")), ListingFormat::Text);
+        assert_eq!(negotiate(Some("image/png")), ListingFormat::Json);
+        // what a browser sends
+        assert_eq!(
+            negotiate(Some(
+                "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"
+            )),
+            ListingFormat::Html
+        );
+        assert_eq!(
+            negotiate(Some("text/html;q=0.5, text/plain;q=0.9")),
+            ListingFormat::Text
+        );
+        assert_eq!(
+            negotiate(Some("text/plain, application/json")),
+            ListingFormat::Text
+        );
+        // ruled out by the most specific range naming it
+        assert_eq!(
+            negotiate(Some("*/*, application/json;q=0")),
+            ListingFormat::Html
+        );
+        assert_eq!(
+            negotiate(Some("text/*, text/html;q=0")),
+            ListingFormat::Text
+        );
+    }
+
+    #[actix_web::test]
+    async fn listings_vary_by_accept() {
+        let fixture = Fixture::seeded(ServerConfig {
+            auth: AuthConfig::None(
+                serde_json::from_value(json!({ "permissions": ["*"] })).unwrap(),
+            ),
+            directory_listings: vec![DirectoryListingRule {
+                prefix: String::new(),
+                enabled: true,
+            }],
+            ..Default::default()
+        })
+        .await;
+        let app = app(&fixture).await;
+
+        // the same listing on the file route too
+        for uri in ["/api/list/site", "/site/"] {
+            let req = TestRequest::get()
+                .uri(uri)
+                .insert_header((header::ACCEPT, "text/plain"))
+                .to_request();
+            let res = call_service(&app, req).await;
+            assert!(
+                res.headers()
+                    .get_all(header::VARY)
+                    .any(|vary| vary == "Accept"),
+                "for {uri}"
+            );
+            assert_eq!(
+                res.headers().get(header::CONTENT_TYPE).unwrap(),
+                "text/plain; charset=utf-8",
+                "for {uri}"
+            );
+        }
+    }
+}
@@ -0,0 +1,315 @@
+use std::{
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use actix_web::{
+    HttpRequest, HttpResponse, Responder, Result, get,
+    http::header::{ContentDisposition, DispositionParam, DispositionType},
+    post,
+    web::{self, Data, Json, Query},
+};
+use path_clean::PathClean;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    SharedFileStore,
+    archive::ArchiveFormat,
+    attributes::SharedAttributes,
+    authorized::{Permission, RealmPayloads, payload_for},
+    file_store::{Entry, EntryKind, FileStorageCore, FileStore, StoredFile},
+    routes::file_path::FilePath,
+};
+
+// each of them is opened before the archive starts
+const MAX_BUNDLE_FILES: usize = 1000;
+
+#[derive(Deserialize, Debug)]
+pub struct BundleRequest {
+    paths: Vec<PathBuf>,
+    #[serde(default)]
+    format: ArchiveFormat,
+}
+
+/// Turns a requested path into the name it gets in the archive, which never leaves it.
+fn archive_name(path: &Path) -> Option<String> {
+    let mut parts = Vec::new();
+    for component in path.clean().components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_str()?.to_string()),
+            Component::RootDir | Component::CurDir => {}
+            _ => return None,
+        }
+    }
+
+    (!parts.is_empty()).then(|| parts.join("/"))
+}
+
+/// Downloads the files at the given paths as one archive, each of them needing the request's
+/// credentials to be valid in its realm.
+#[post("/bundle")]
+pub async fn bundle(
+    req: HttpRequest,
+    request: Json<BundleRequest>,
+    file_store: Data<SharedFileStore>,
+) -> impl Responder {
+    let BundleRequest { paths, format } = request.into_inner();
+    if paths.is_empty() || paths.len() > MAX_BUNDLE_FILES {
+        return HttpResponse::BadRequest()
+            .body(format!("Expected between 1 and {MAX_BUNDLE_FILES} paths"));
+    }
+
+    // everything is looked up before anything is sent, so a missing file is still a 404
+    let mut payloads = RealmPayloads::new(&req);
+    let mut files = Vec::new();
+    let mut missing = Vec::new();
+    let mut forbidden = Vec::new();
+    for path in paths {
+        let Some(name) = archive_name(&path) else {
+            missing.push(path.display().to_string());
+            continue;
+        };
+        if files.iter().any(|(existing, _)| *existing == name) {
+            continue;
+        }
+        if payloads.get(Path::new(&name)).await.is_none() {
+            forbidden.push(name);
+            continue;
+        }
+
+        match file_store.get_file(Path::new(&name)).await {
+            Some(file) => files.push((name, file)),
+            None => missing.push(name),
+        }
+    }
+
+    if !missing.is_empty() {
+        return HttpResponse::NotFound().json(json!({ "missing": missing }));
+    }
+    if !forbidden.is_empty() {
+        return HttpResponse::Forbidden().json(json!({ "forbidden": forbidden }));
+    }
+
+    archive_response(format, "bundle", files)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ArchiveQuery {
+    #[serde(default)]
+    format: ArchiveFormat,
+}
+
+/// Downloads everything under the directory at `path` as one archive, needing the `list`
+/// permission since it tells what's in there, in the realm of each file.
+#[get("/archive/{path:.*}")]
+pub async fn archive(
+    req: HttpRequest,
+    path: FilePath,
+    query: Query<ArchiveQuery>,
+    file_store: Data<SharedFileStore>,
+    attributes: Data<SharedAttributes>,
+) -> Result<HttpResponse> {
+    payload_for(&req, &path).await?.require(Permission::List)?;
+
+    let access = Access::Permitted(Permission::List);
+    Ok(
+        match directory_files(&req, &file_store, &attributes, &path, access).await {
+            Ok(files) => archive_response(query.format, &archive_file_name(&path), files),
+            Err(response) => response,
+        },
+    )
+}
+
+/// What the archive of the directory at `dir` is downloaded as, without its extension.
+pub fn archive_file_name(dir: &Path) -> String {
+    dir.file_name().map_or("files".to_string(), |name| {
+        name.to_string_lossy().into_owned()
+    })
+}
+
+/// Which of the files under a directory a request gets.
+#[derive(Debug, Clone, Copy)]
+pub enum Access {
+    /// The public ones, and the private ones in realms its credentials are valid in
+    Visible,
+    /// The ones in realms its credentials are valid in with the permission
+    Permitted(Permission),
+}
+
+/// Every file under the directory at `dir` that `req` has `access` to, named by its path
+/// relative to it. Mounts under it can be realms of their own, so each file is checked
+/// against the one it's in.
+pub async fn directory_files(
+    req: &HttpRequest,
+    store: &FileStore,
+    attributes: &SharedAttributes,
+    dir: &Path,
+    access: Access,
+) -> Result<Vec<(String, Arc<StoredFile>)>, HttpResponse> {
+    if !matches!(store.stat(dir).await, Some(Entry::Dir)) {
+        return Err(HttpResponse::NotFound().body("Directory does not exist"));
+    }
+
+    let mut paths = Vec::new();
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(relative) = dirs.pop() {
+        let entries = store.list(&dir.join(&relative)).await.map_err(|err| {
+            log::error!("Error listing directory: {err}");
+            HttpResponse::InternalServerError().body("Failed to list directory")
+        })?;
+        for entry in entries {
+            match entry.kind {
+                EntryKind::Dir => dirs.push(relative.join(&entry.name)),
+                EntryKind::File => paths.push(relative.join(&entry.name)),
+            }
+        }
+        if paths.len() > MAX_BUNDLE_FILES {
+            return Err(HttpResponse::BadRequest().body(format!(
+                "Directory holds more than {MAX_BUNDLE_FILES} files"
+            )));
+        }
+    }
+
+    let (attributes, owned_dir) = (SharedAttributes::clone(attributes), dir.to_path_buf());
+    let paths = web::block(move || {
+        paths
+            .into_iter()
+            .map(|path| {
+                let is_private = attributes.is_private(&owned_dir.join(&path));
+                (path, is_private)
+            })
+            .collect::<Vec<_>>()
+    })
+    .await
+    .map_err(|_| HttpResponse::InternalServerError().body("Failed to list directory"))?;
+
+    let mut payloads = RealmPayloads::new(req);
+    let mut files = Vec::with_capacity(paths.len());
+    for (path, is_private) in paths {
+        let Some(name) = archive_name(&path) else {
+            continue;
+        };
+        let is_allowed = match access {
+            Access::Visible => !is_private || payloads.get(&dir.join(&path)).await.is_some(),
+            Access::Permitted(permission) => payloads
+                .get(&dir.join(&path))
+                .await
+                .is_some_and(|payload| payload.has(permission)),
+        };
+        if !is_allowed {
+            continue;
+        }
+        // deleted since it was listed, which leaves it out as if it was gone already
+        if let Some(file) = store.get_file(&dir.join(&path)).await {
+            files.push((name, file));
+        }
+    }
+    // the same order every time, so the same files make the same archive
+    files.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(files)
+}
+
+/// Streams `files` as an archive downloaded as `name`.
+pub fn archive_response(
+    format: ArchiveFormat,
+    name: &str,
+    files: Vec<(String, Arc<StoredFile>)>,
+) -> HttpResponse {
+    let stream = match format.stream(files) {
+        Ok(stream) => stream,
+        Err(err) => return HttpResponse::BadRequest().body(format!("Invalid input: {err}")),
+    };
+
+    HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(format!(
+                "{name}.{}",
+                format.extension()
+            ))],
+        })
+        .streaming(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::StatusCode,
+        test::{TestRequest, call_service, read_body},
+    };
+
+    use super::*;
+    use crate::{
+        config::server::{DirectoryListingRule, ServerConfig, UploadProfile, Visibility},
+        fixtures::{Fixture, HELLO_TXT, PAGE_HTML, app, with_team_realm},
+    };
+
+    #[actix_web::test]
+    async fn files_of_a_mount_are_only_archived_in_its_own_realm() {
+        let mut config = with_team_realm(ServerConfig::default());
+        config.directory_listings.push(DirectoryListingRule {
+            prefix: String::new(),
+            enabled: true,
+        });
+        config.upload_profiles.insert(
+            "private".to_string(),
+            UploadProfile {
+                visibility: Visibility::Private,
+                ..Default::default()
+            },
+        );
+        let fixture = Fixture::seeded(config).await;
+        let app = app(&fixture).await;
+        let send = async |req: TestRequest, key: &str| {
+            let req = req.insert_header(("X-API-Key", key)).to_request();
+            let res = call_service(&app, req).await;
+            (res.status(), read_body(res).await)
+        };
+        let contains =
+            |body: &[u8], needle: &str| body.windows(needle.len()).any(|w| w == needle.as_bytes());
+
+        for path in [
+            "/api/site/team/notes.txt",
+            "/api/site/team/secret.txt?profile=private",
+        ] {
+            let req = TestRequest::put().uri(path).set_payload("team only");
+            assert_eq!(
+                send(req, "team-key").await.0,
+                StatusCode::CREATED,
+                "for {path}"
+            );
+        }
+
+        // the parent directory is the server's, the files under the mount still aren't
+        let (status, body) = send(TestRequest::get().uri("/api/archive/site"), "admin-key").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(contains(&body, "page.html"));
+        assert!(!contains(&body, "notes.txt"));
+        assert!(!contains(&body, "secret.txt"));
+
+        let (status, body) = send(TestRequest::get().uri("/site?zip=1"), "admin-key").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(contains(&body, "team/notes.txt"));
+        assert!(!contains(&body, "secret.txt"));
+        let (_, body) = send(TestRequest::get().uri("/site?zip=1"), "team-key").await;
+        assert!(contains(&body, "team/secret.txt"));
+
+        let bundle_of = |paths: &[&str]| {
+            TestRequest::post()
+                .uri("/api/bundle")
+                .set_json(json!({ "paths": paths }))
+        };
+        let (status, body) = send(
+            bundle_of(&[HELLO_TXT.path, "site/team/notes.txt"]),
+            "admin-key",
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body, json!({ "forbidden": ["site/team/notes.txt"] }));
+        let (status, _) = send(bundle_of(&[HELLO_TXT.path, PAGE_HTML.path]), "admin-key").await;
+        assert_eq!(status, StatusCode::OK);
+    }
+}
@@ -0,0 +1,49 @@
+use actix_web::{
+    HttpResponse, Result, get,
+    web::{Data, Query, ReqData},
+};
+use serde::Deserialize;
+
+use crate::{
+    authorized::{AuthPayload, Permission},
+    changelog::SharedChangelog,
+};
+
+// a client further behind than this asks again from where the last batch left off
+const MAX_CHANGES: usize = 1000;
+
+#[derive(Deserialize)]
+pub struct ChangesQuery {
+    /// The last change the client saw, `0` for every change still kept
+    #[serde(default)]
+    since: i64,
+    limit: Option<usize>,
+}
+
+/// The uploads and deletes made after change `?since=`, oldest first, for clients catching up
+/// after being offline. `410` when some of them were pruned already, the files have to be
+/// listed again then.
+#[get("/changes")]
+pub async fn changes(
+    query: Query<ChangesQuery>,
+    auth: ReqData<AuthPayload>,
+    changelog: Data<SharedChangelog>,
+) -> Result<HttpResponse> {
+    auth.require(Permission::Changes)?;
+
+    let limit = query.limit.unwrap_or(MAX_CHANGES).clamp(1, MAX_CHANGES);
+    Ok(match changelog.since(query.since, limit) {
+        Ok(Some(changes)) => HttpResponse::Ok().json(changes),
+        Ok(None) => HttpResponse::Gone().body(format!(
+            "Changes since {} are no longer kept, list the files again",
+            query.since
+        )),
+        Err(err) if err.kind() == std::io::ErrorKind::Unsupported => {
+            HttpResponse::Conflict().body(err.to_string())
+        }
+        Err(err) => {
+            log::error!("Error reading the changelog: {err}");
+            HttpResponse::InternalServerError().body("Failed to read the changelog")
+        }
+    })
+}
@@ -0,0 +1,107 @@
+/// The strong ETag sent for `tag`, e.g. a file's hash.
+pub fn strong(tag: &str) -> String {
+    format!("\"{tag}\"")
+}
+
+/// The tag an ETag sent by `strong` was made from.
+pub fn tag_of(etag: &str) -> &str {
+    let etag = etag.trim();
+    etag.strip_prefix('"')
+        .and_then(|etag| etag.strip_suffix('"'))
+        .unwrap_or(etag)
+}
+
+/// A request header that's compared against the ETag of what would be sent.
+#[derive(Debug, Clone, Copy)]
+pub enum Condition {
+    /// Any of a list of ETags, or `*` for anything, weakly compared since an equivalent
+    /// response is as good as the one already cached
+    IfNoneMatch,
+    /// A single ETag, strongly compared since ranges of a file only fit together byte for byte
+    IfRange,
+}
+
+impl Condition {
+    /// Whether `value`, sent in this header, names the ETag `strong(tag)`.
+    pub fn matches(self, value: &str, tag: &str) -> bool {
+        match self {
+            Condition::IfNoneMatch => {
+                value.trim() == "*" || entity_tags(value).any(|(_, sent)| sent == tag)
+            }
+            Condition::IfRange => {
+                let mut sent = entity_tags(value);
+                matches!(
+                    (sent.next(), sent.next()),
+                    (Some((false, sent)), None) if sent == tag
+                )
+            }
+        }
+    }
+}
+
+/// The ETags listed in `value`, as whether each is weak and the tag it's made from. Bare tags
+/// are taken as strong ones, as sent back by clients that saw them unquoted before.
+fn entity_tags(value: &str) -> impl Iterator<Item = (bool, &str)> {
+    let mut rest = value;
+    std::iter::from_fn(move || {
+        rest = rest.trim_start_matches(|c: char| c == ',' || c.is_ascii_whitespace());
+        if rest.is_empty() {
+            return None;
+        }
+
+        let weak = match rest.strip_prefix("W/") {
+            Some(after) => {
+                rest = after;
+                true
+            }
+            None => false,
+        };
+        // a quoted tag can hold commas, so it ends at the closing quote rather than the next one
+        let (tag, after) = match rest.strip_prefix('"') {
+            Some(quoted) => match quoted.find('"') {
+                Some(end) => (&quoted[..end], &quoted[end + 1..]),
+                None => (quoted, ""),
+            },
+            None => rest.split_at(rest.find(',').unwrap_or(rest.len())),
+        };
+        rest = after;
+        Some((weak, tag.trim()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn if_none_match_takes_lists_weak_tags_and_anything() {
+        for value in [
+            "\"abc\"",
+            "W/\"abc\"",
+            "\"x,y\", W/\"abc\"",
+            "\"other\",\"abc\"",
+            "*",
+            "abc",
+        ] {
+            assert!(Condition::IfNoneMatch.matches(value, "abc"), "for {value}");
+        }
+        for value in ["\"other\"", "\"abc-html\"", "", "\"ab\"c"] {
+            assert!(!Condition::IfNoneMatch.matches(value, "abc"), "for {value}");
+        }
+    }
+
+    #[test]
+    fn if_range_takes_one_strong_tag() {
+        assert!(Condition::IfRange.matches("\"abc\"", "abc"));
+        assert!(Condition::IfRange.matches("abc", "abc"));
+        for value in ["W/\"abc\"", "*", "\"abc\", \"abc\"", "\"other\""] {
+            assert!(!Condition::IfRange.matches(value, "abc"), "for {value}");
+        }
+    }
+
+    #[test]
+    fn tags_come_back_out_of_etags() {
+        assert_eq!(tag_of(&strong("abc")), "abc");
+        assert_eq!(tag_of("abc"), "abc");
+    }
+}
@@ -0,0 +1,296 @@
+use std::{
+    future::{Ready, ready},
+    ops::Deref,
+    path::{Component, Path, PathBuf},
+    str::Utf8Error,
+};
+
+use actix_web::{
+    FromRequest, HttpRequest, HttpResponse,
+    dev::Payload,
+    error::{ErrorBadRequest, InternalError},
+    http::{Method, header},
+    web::Data,
+};
+use percent_encoding::{AsciiSet, CONTROLS, percent_decode_str, utf8_percent_encode};
+
+use crate::{
+    config::server::{PathPolicy, ServerConfig},
+    file_names::normalize_name,
+};
+
+/// Name of the tail match segment every file route uses, i.e. `/{path:.*}`.
+pub const PATH_PARAM: &str = "path";
+
+// characters that can't appear literally in a path segment, separators never make it
+// into a segment after decoding, so they're left alone
+const PATH_ENCODE_SET: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// The file a request refers to, with the raw URL path normalized so that every
+/// way of spelling the same file ends up as the same relative path.
+#[derive(Debug, Clone)]
+pub struct FilePath(PathBuf);
+
+impl Deref for FilePath {
+    type Target = Path;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl FromRequest for FilePath {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let (prefix, raw) = split_raw_path(req);
+        let Ok(normalized) = decode_path(raw) else {
+            return ready(Err(ErrorBadRequest(
+                "Path is not valid percent-encoded UTF-8",
+            )));
+        };
+
+        let policy = req
+            .app_data::<Data<ServerConfig>>()
+            .map(|config| config.path_policy)
+            .unwrap_or_default();
+
+        // only reads are redirected, bouncing an upload would make the client send the body twice
+        let is_read = matches!(*req.method(), Method::GET | Method::HEAD);
+        if normalized.changed && policy == PathPolicy::Redirect && is_read {
+            let mut location = format!("{prefix}{}", encode_path(&normalized.path));
+            if !req.query_string().is_empty() {
+                location.push('?');
+                location.push_str(req.query_string());
+            }
+
+            let response = HttpResponse::PermanentRedirect()
+                .insert_header((header::LOCATION, location))
+                .finish();
+
+            return ready(Err(InternalError::from_response(
+                "non-canonical path",
+                response,
+            )
+            .into()));
+        }
+
+        ready(Ok(FilePath(PathBuf::from(normalized.path))))
+    }
+}
+
+/// Splits the request's path into the route prefix and the still percent-encoded file path.
+fn split_raw_path(req: &HttpRequest) -> (&str, &str) {
+    // the router only hands out partially decoded paths, so find where the file path starts in
+    // that and cut the same prefix from the raw path, which is fine since route prefixes are
+    // plain ascii. if the client encoded the prefix itself, fall back to the router's version
+    let match_info = req.match_info();
+    let matched = match_info.get(PATH_PARAM).unwrap_or_default();
+    let Some(prefix) = match_info.as_str().strip_suffix(matched) else {
+        return ("/", matched);
+    };
+
+    match req.uri().path().strip_prefix(prefix) {
+        Some(raw) => (prefix, raw),
+        None => (prefix, matched),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Normalized {
+    path: String,
+    changed: bool,
+}
+
+/// Decodes a raw, percent-encoded URL path into a normalized relative path. Empty and `.`
+/// segments are dropped, `..` segments are resolved (never above the root), encoded
+/// separators are treated like real ones and names are normalized by [`normalize_name`].
+/// Fails if the decoded bytes aren't valid UTF-8.
+fn decode_path(raw: &str) -> Result<Normalized, Utf8Error> {
+    let mut segments: Vec<String> = Vec::new();
+    let mut changed = false;
+
+    for raw_segment in raw.split('/') {
+        let decoded = percent_decode_str(raw_segment).decode_utf8()?;
+
+        for segment in decoded.split(['/', '\\']) {
+            match segment {
+                "" | "." => changed = true,
+                ".." => {
+                    segments.pop();
+                    changed = true;
+                }
+                _ => {
+                    let name = normalize_name(segment);
+                    changed |= name != segment;
+                    segments.push(name.into_owned());
+                }
+            }
+        }
+
+        if decoded.contains(['/', '\\']) {
+            changed = true;
+        }
+    }
+
+    Ok(Normalized {
+        path: segments.join("/"),
+        // an empty path has nothing to canonicalize, that's just the root
+        changed: changed && !raw.is_empty(),
+    })
+}
+
+/// The relative path a raw, percent-encoded URL path refers to, normalized the same way
+/// [`FilePath`] does it, or `None` if it doesn't decode to UTF-8.
+pub fn normalize_path(raw: &str) -> Option<PathBuf> {
+    decode_path(raw)
+        .ok()
+        .map(|normalized| PathBuf::from(normalized.path))
+}
+
+/// Percent-encodes a relative file path for use in a URL, the inverse of [`decode_path`].
+pub fn encode_path(path: impl AsRef<Path>) -> String {
+    let segments: Vec<_> = path
+        .as_ref()
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(segment) => Some(segment.to_string_lossy()),
+            _ => None,
+        })
+        .map(|segment| utf8_percent_encode(&segment, PATH_ENCODE_SET).to_string())
+        .collect();
+
+    segments.join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        App,
+        http::StatusCode,
+        test::{TestRequest, call_and_read_body, call_service, init_service},
+        web,
+    };
+
+    use super::*;
+
+    fn unchanged(path: &str) -> Normalized {
+        Normalized {
+            path: path.to_string(),
+            changed: false,
+        }
+    }
+
+    #[test]
+    fn decodes_reserved_and_non_ascii_characters() {
+        let decoded = decode_path("a%20b/c+d%23e/%C3%BCber%3F.txt").unwrap();
+        assert_eq!(decoded, unchanged("a b/c+d#e/über?.txt"));
+    }
+
+    #[test]
+    fn normalizes_separators_and_dot_segments() {
+        for raw in [
+            "a//b.txt",
+            "a/b.txt/",
+            "./a/b.txt",
+            "a%2Fb.txt",
+            "a%5Cb.txt",
+            "x/../a/b.txt",
+        ] {
+            let decoded = decode_path(raw).unwrap();
+            assert_eq!(decoded.path, "a/b.txt", "for {raw}");
+            assert!(decoded.changed, "for {raw}");
+        }
+
+        assert_eq!(decode_path("../../a").unwrap().path, "a");
+    }
+
+    #[test]
+    fn composes_decomposed_names() {
+        // "über" with the ü sent as a u and a combining diaeresis, as macOS does
+        let decoded = decode_path("u%CC%88ber/a.txt").unwrap();
+        assert_eq!(decoded.path, "über/a.txt");
+        assert!(decoded.changed);
+    }
+
+    #[test]
+    fn rejects_invalid_utf8() {
+        assert!(decode_path("%FF.txt").is_err());
+    }
+
+    #[test]
+    fn encoding_round_trips() {
+        let names = [
+            "a b.txt",
+            "c++/notes #1.md",
+            "100%/what?.txt",
+            "ünïcödé/日本語.txt",
+            "quotes \"and\" <angles>",
+        ];
+
+        for name in names {
+            let encoded = encode_path(name);
+            assert!(encoded.is_ascii(), "{encoded} should be plain ascii");
+            assert_eq!(decode_path(&encoded).unwrap(), unchanged(name));
+        }
+    }
+
+    async fn echo(path: FilePath) -> HttpResponse {
+        HttpResponse::Ok().body(path.to_string_lossy().into_owned())
+    }
+
+    #[actix_web::test]
+    async fn extracts_from_the_raw_request_path() {
+        let app = init_service(App::new().route("/api/{path:.*}", web::get().to(echo))).await;
+
+        let req = TestRequest::get()
+            .uri("/api/a%2Bb+c/%25%20%C3%BC.txt")
+            .to_request();
+        let body = call_and_read_body(&app, req).await;
+        assert_eq!(body, "a+b+c/% ü.txt");
+
+        let req = TestRequest::get().uri("/api/%FF").to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn redirects_reads_to_the_canonical_path() {
+        let config = ServerConfig {
+            path_policy: PathPolicy::Redirect,
+            ..Default::default()
+        };
+        let app = init_service(
+            App::new()
+                .app_data(Data::new(config))
+                .route("/{path:.*}", web::get().to(echo))
+                .route("/{path:.*}", web::post().to(echo)),
+        )
+        .await;
+
+        let req = TestRequest::get()
+            .uri("/a%20b//c%23.txt/?dl=1")
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(
+            res.headers().get(header::LOCATION).unwrap(),
+            "/a%20b/c%23.txt?dl=1"
+        );
+
+        let req = TestRequest::post().uri("/a//b").to_request();
+        let body = call_and_read_body(&app, req).await;
+        assert_eq!(body, "a/b");
+    }
+}
@@ -0,0 +1,159 @@
+use std::path::PathBuf;
+
+use actix_web::{
+    HttpResponse, Responder, get,
+    http::header,
+    post,
+    web::{Data, Json, Path, Query},
+};
+use serde::Deserialize;
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    SharedFileStore,
+    file_store::{Entry, FileStorageCore, FileStore, StoredFileCore},
+    hooks::SharedHooks,
+    jobs::{JobHandle, SharedJobRegistry},
+    mirror::SharedStandby,
+    routes::upload_file::mirror_read_only,
+};
+
+/// Operations that take too long to answer within a single request.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum JobRequest {
+    BulkDelete { paths: Vec<PathBuf> },
+}
+
+/// `?dry_run=true` answers with what would be deleted, leaving it all as it is.
+#[derive(Deserialize)]
+pub struct DryRunQuery {
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[post("/jobs")]
+pub async fn create_job(
+    request: Json<JobRequest>,
+    query: Query<DryRunQuery>,
+    jobs: Data<SharedJobRegistry>,
+    file_store: Data<SharedFileStore>,
+    hooks: Data<SharedHooks>,
+    standby: Data<SharedStandby>,
+) -> impl Responder {
+    // every job there is changes files
+    if let Some(response) = mirror_read_only(&standby) {
+        return response;
+    }
+    if query.dry_run {
+        return HttpResponse::Ok().json(match request.into_inner() {
+            JobRequest::BulkDelete { paths } => bulk_delete_preview(&file_store, &paths).await,
+        });
+    }
+
+    let store = SharedFileStore::clone(&file_store);
+    let hooks = SharedHooks::clone(&hooks);
+    let id = match request.into_inner() {
+        JobRequest::BulkDelete { paths } => jobs.spawn("bulk_delete", |handle| {
+            bulk_delete(handle, store, hooks, paths)
+        }),
+    };
+
+    HttpResponse::Accepted()
+        .insert_header((header::LOCATION, format!("/api/jobs/{id}")))
+        .json(json!({ "id": id }))
+}
+
+#[get("/jobs/{id}")]
+pub async fn job_status(id: Path<Uuid>, jobs: Data<SharedJobRegistry>) -> impl Responder {
+    match jobs.get(&id) {
+        Some(job) => HttpResponse::Ok().json(job),
+        None => HttpResponse::NotFound().body("No such job"),
+    }
+}
+
+pub async fn bulk_delete(
+    handle: JobHandle,
+    store: SharedFileStore,
+    hooks: SharedHooks,
+    paths: Vec<PathBuf>,
+) -> std::io::Result<serde_json::Value> {
+    handle.set_total(paths.len() as u64);
+
+    let mut deleted = 0;
+    for path in paths {
+        if let Some(Entry::Dir) = store.stat(&path).await {
+            handle.error(format!("{}: is a directory", path.display()));
+            handle.advance(1);
+            continue;
+        }
+
+        match store.remove(&path).await {
+            Ok(_) => {
+                hooks.post_delete(&path).await;
+                deleted += 1;
+            }
+            Err(err) => handle.error(format!("{}: {err}", path.display())),
+        }
+
+        handle.advance(1);
+        // removals may be blocking, let the worker serve requests between them
+        tokio::task::yield_now().await;
+    }
+
+    Ok(json!({ "deleted": deleted }))
+}
+
+/// What `bulk_delete` would do with `paths`, in the same shape as its result, along with the
+/// files it would remove and how much room that would free up.
+pub async fn bulk_delete_preview(store: &FileStore, paths: &[PathBuf]) -> serde_json::Value {
+    let mut files = Vec::new();
+    let mut errors = Vec::new();
+    for path in paths {
+        match store.stat(path).await {
+            Some(Entry::File(file)) => files.push((path, file.metadata().size_bytes)),
+            Some(Entry::Dir) => errors.push(format!("{}: is a directory", path.display())),
+            // removing what's already gone counts as done
+            None => {}
+        }
+    }
+
+    json!({
+        "dry_run": true,
+        "deleted": files.len(),
+        "total_bytes": files.iter().map(|(_, size_bytes)| size_bytes).sum::<u64>(),
+        "files": files
+            .iter()
+            .map(|(path, size_bytes)| json!({ "path": path, "size_bytes": size_bytes }))
+            .collect::<Vec<_>>(),
+        "errors": errors,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::web::Bytes;
+    use futures::{StreamExt, stream};
+
+    use super::*;
+    use crate::file_store::MemoryFileStore;
+
+    #[actix_web::test]
+    async fn dry_runs_report_without_deleting() {
+        let store = FileStore::Memory(MemoryFileStore::default());
+        for path in ["a.txt", "docs/b.txt"] {
+            let stream = stream::iter([Ok(Bytes::from_static(b"hello"))]).boxed_local();
+            let staged = store.stage_upload(path.as_ref(), stream, None).await;
+            store.commit_upload(staged.unwrap()).await.unwrap();
+        }
+
+        let paths = ["a.txt", "docs", "missing.txt"].map(PathBuf::from);
+        let preview = bulk_delete_preview(&store, &paths).await;
+        assert_eq!(preview["deleted"], 1);
+        assert_eq!(preview["total_bytes"], 5);
+        assert_eq!(preview["files"][0]["path"], "a.txt");
+        assert_eq!(preview["errors"][0], "docs: is a directory");
+        assert!(store.exists("a.txt".as_ref()).await);
+    }
+}
@@ -1,9 +1,83 @@
-use actix_web::dev::HttpServiceFactory;
+use std::{
+    any::type_name,
+    net::{IpAddr, SocketAddr},
+};
 
+use actix_web::{HttpRequest, dev::HttpServiceFactory, error::ErrorInternalServerError, web::Data};
+
+use crate::config::server::ServerConfig;
+
+pub mod admin;
 pub mod api;
+pub mod browse;
+pub mod bundle;
+pub mod changes;
+pub mod etag;
+pub mod file_path;
+pub mod jobs;
+pub mod outbox;
+pub mod put_file;
+pub mod scheduler;
 pub mod serve_files;
+pub mod stale;
+pub mod standby;
+pub mod trash;
 pub mod upload_file;
+pub mod verify;
+pub mod versions;
+pub mod well_known;
 
 pub trait ScopeCreator {
     fn create_scope() -> impl HttpServiceFactory;
 }
+
+/// The app data of type `T`, for extractors taking several of them at once. Missing is a
+/// mistake in setting up the app, answered the way the `Data` extractor answers it.
+pub fn app_data<T: ?Sized + 'static>(req: &HttpRequest) -> actix_web::Result<Data<T>> {
+    req.app_data::<Data<T>>().cloned().ok_or_else(|| {
+        log::debug!("Missing app data `Data<{}>`", type_name::<T>());
+        ErrorInternalServerError("Requested application data is not configured correctly.")
+    })
+}
+
+/// The address of the client making `req`, taken from `Forwarded`/`X-Forwarded-For` when
+/// the config trusts the proxy in front to set them.
+pub fn client_ip(req: &HttpRequest) -> Option<IpAddr> {
+    let trust_forwarded_for = req
+        .app_data::<Data<ServerConfig>>()
+        .is_some_and(|config| config.trust_forwarded_for);
+    if !trust_forwarded_for {
+        return req.peer_addr().map(|addr| addr.ip());
+    }
+
+    // forwarded addresses may or may not come with a port
+    let addr = req.connection_info().realip_remote_addr()?.to_string();
+    addr.parse::<IpAddr>()
+        .ok()
+        .or_else(|| addr.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    #[test]
+    fn forwarded_addresses_are_only_taken_when_trusted() {
+        let client_ip_of = |trust_forwarded_for| {
+            let req = TestRequest::default()
+                .peer_addr("192.0.2.1:4000".parse().unwrap())
+                .insert_header(("X-Forwarded-For", "198.51.100.7:5000, 192.0.2.1"))
+                .app_data(Data::new(ServerConfig {
+                    trust_forwarded_for,
+                    ..Default::default()
+                }))
+                .to_http_request();
+            client_ip(&req)
+        };
+
+        assert_eq!(client_ip_of(false), Some(IpAddr::from([192, 0, 2, 1])));
+        assert_eq!(client_ip_of(true), Some(IpAddr::from([198, 51, 100, 7])));
+    }
+}
@@ -0,0 +1,39 @@
+use actix_web::{
+    HttpResponse, Responder, get, post,
+    web::{Data, Path},
+};
+use serde_json::json;
+
+use crate::outbox::SharedOutbox;
+
+#[get("/outbox/dead")]
+pub async fn dead_letters(outbox: Data<SharedOutbox>) -> impl Responder {
+    match outbox.dead_letters() {
+        Ok(letters) => HttpResponse::Ok().json(letters),
+        Err(err) => {
+            log::error!("Error listing dead letters: {err}");
+            HttpResponse::InternalServerError().body("Failed to list dead letters")
+        }
+    }
+}
+
+#[post("/outbox/dead/requeue")]
+pub async fn requeue_all(outbox: Data<SharedOutbox>) -> impl Responder {
+    requeue(&outbox, None)
+}
+
+#[post("/outbox/dead/{id}/requeue")]
+pub async fn requeue_one(id: Path<i64>, outbox: Data<SharedOutbox>) -> impl Responder {
+    requeue(&outbox, Some(*id))
+}
+
+fn requeue(outbox: &SharedOutbox, id: Option<i64>) -> HttpResponse {
+    match outbox.requeue(id) {
+        Ok(0) if id.is_some() => HttpResponse::NotFound().body("No such dead letter"),
+        Ok(requeued) => HttpResponse::Ok().json(json!({ "requeued": requeued })),
+        Err(err) => {
+            log::error!("Error requeuing dead letters: {err}");
+            HttpResponse::InternalServerError().body("Failed to requeue dead letters")
+        }
+    }
+}
@@ -0,0 +1,406 @@
+use std::{cell::RefCell, io, mem, rc::Rc};
+
+use actix_web::{
+    HttpRequest, HttpResponse, Responder,
+    http::header::HeaderName,
+    put,
+    web::{Bytes, Payload, Query},
+};
+use async_stream::try_stream;
+use base64::{Engine, prelude::BASE64_STANDARD};
+use futures::StreamExt;
+
+use crate::{
+    authorized::payload_for,
+    config::server::HashAlgorithm,
+    file_store::{ByteStream, FileStorageCore},
+    routes::{
+        file_path::FilePath,
+        upload_file::{
+            Checksum, Upload, UploadOptions, UploadTarget, WriteState, apply_profile, cant_check,
+            declared_size, mirror_read_only, upload_error_response,
+        },
+    },
+    transfers::{TransferClient, TransferKind},
+};
+
+// digests of the whole body, as in RFC 9530, only used for raw uploads since a multipart
+// body is more than just the file
+const DIGEST_HEADERS: [HeaderName; 2] = [
+    HeaderName::from_static("repr-digest"),
+    HeaderName::from_static("content-digest"),
+];
+
+// names the algorithm of a hex digest a raw upload ends with, for clients streaming a file
+// whose digest they only know once it's sent. Trailers would be the place for it, but they
+// never make it through to the handlers
+const TRAILING_DIGEST: HeaderName = HeaderName::from_static("trailing-digest");
+
+// both algorithms make 32 byte digests
+const HEX_DIGEST_LEN: usize = 64;
+
+/// Uploads the raw request body, optionally checked against a `Repr-Digest` or
+/// `Content-Digest` header, or the hex digest it ends with under `Trailing-Digest`.
+#[put("/{path:.*}")]
+pub async fn put_file(
+    req: HttpRequest,
+    path: FilePath,
+    options: Query<UploadOptions>,
+    payload: Payload,
+    state: WriteState,
+) -> impl Responder {
+    let WriteState {
+        config,
+        standby,
+        file_store,
+        quarantine,
+        attributes,
+        hooks,
+        throttle,
+        transfers,
+        ..
+    } = &state;
+    if let Some(response) = mirror_read_only(standby) {
+        return response;
+    }
+
+    let (path, profile) = match apply_profile(&path, &options, config) {
+        Ok(applied) => applied,
+        Err(response) => return response,
+    };
+
+    // checked in the realm of where the file goes, which a profile can move it out of
+    let auth = match payload_for(&req, &path).await {
+        Ok(auth) => auth,
+        Err(err) => return err.error_response(),
+    };
+
+    let target = UploadTarget::for_token(&auth, file_store, quarantine);
+    if let Some(conflict) = target.conflict(&path).await {
+        return conflict;
+    }
+
+    let expected = match expected_digest(&req, config.hash_algorithm) {
+        Ok(expected) => expected,
+        Err(message) => return HttpResponse::BadRequest().body(message),
+    };
+    let trailing = match trailing_digest(&req) {
+        Ok(Some(_)) if expected.is_some() => {
+            return HttpResponse::BadRequest()
+                .body("Send the digest either in a header or after the body, not both");
+        }
+        Ok(Some(algorithm)) if algorithm != config.hash_algorithm => {
+            return HttpResponse::BadRequest().body(cant_check(algorithm, config.hash_algorithm));
+        }
+        Ok(trailing) => trailing,
+        Err(message) => return HttpResponse::BadRequest().body(message),
+    };
+
+    let stream = payload
+        .map(|chunk| chunk.map_err(|err| io::Error::other(err.to_string())))
+        .boxed_local();
+    let (stream, tail) = match trailing {
+        Some(algorithm) => {
+            let (stream, tail) = hold_back_tail(stream, HEX_DIGEST_LEN);
+            (stream, Some((algorithm, tail)))
+        }
+        None => (stream, None),
+    };
+    let stream = throttle.throttle(auth.class(), stream);
+    let stream = transfers.track(
+        TransferKind::Upload,
+        &path,
+        TransferClient::of(&req),
+        stream,
+    );
+    let (stream, scanned) = hooks.scan(&path, &config.scanning, declared_size(&req), stream);
+
+    let upload = Upload {
+        path: &path,
+        profile,
+        target: &target,
+    };
+    match target
+        .store()
+        .stage_upload(&path, stream, declared_size(&req))
+        .await
+    {
+        Ok(mut staged) => {
+            staged.record_scan(scanned.record());
+            let expected = match tail {
+                Some((algorithm, tail)) => {
+                    let tail = tail.take();
+                    if tail.len() < HEX_DIGEST_LEN {
+                        return HttpResponse::BadRequest()
+                            .body("Body ends before the digest it should end with");
+                    }
+                    Some((algorithm, String::from_utf8_lossy(&tail).into_owned()))
+                }
+                None => expected,
+            };
+            upload.commit(staged, expected, attributes, hooks).await
+        }
+        Err(err) => upload_error_response(err),
+    }
+}
+
+/// The algorithm of the hex digest the body ends with, if the request said it does.
+fn trailing_digest(req: &HttpRequest) -> Result<Option<HashAlgorithm>, String> {
+    let Some(value) = req.headers().get(TRAILING_DIGEST) else {
+        return Ok(None);
+    };
+    value
+        .to_str()
+        .ok()
+        .and_then(|name| HashAlgorithm::from_name(name.trim()))
+        .map(Some)
+        .ok_or_else(|| "Trailing-Digest has to be sha256 or blake3".to_string())
+}
+
+/// Passes `stream` on without its last `len` bytes, which are left in the returned cell once
+/// it's been read to the end.
+fn hold_back_tail(
+    mut stream: ByteStream<'_>,
+    len: usize,
+) -> (ByteStream<'_>, Rc<RefCell<Vec<u8>>>) {
+    let tail = Rc::new(RefCell::new(Vec::new()));
+    let held = Rc::clone(&tail);
+    let stream = try_stream! {
+        let mut buffer = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            buffer.extend_from_slice(&chunk?);
+            if buffer.len() > len {
+                let rest = buffer.split_off(buffer.len() - len);
+                yield Bytes::from(mem::replace(&mut buffer, rest));
+            }
+        }
+        *held.borrow_mut() = buffer;
+    }
+    .boxed_local();
+
+    (stream, tail)
+}
+
+/// Gets the digest uploads are hashed with from the request's digest headers as hex, if it
+/// sent one. One worked out with the other algorithm can't be checked, which is better told
+/// before the body is read than after.
+fn expected_digest(
+    req: &HttpRequest,
+    hashed_with: HashAlgorithm,
+) -> Result<Option<Checksum>, String> {
+    let mut unusable = None;
+    for name in &DIGEST_HEADERS {
+        let Some(value) = req.headers().get(name) else {
+            continue;
+        };
+
+        let value = value
+            .to_str()
+            .map_err(|_| format!("Invalid {name} header"))?;
+
+        // a dictionary of `algorithm=:base64:` members, only sha-256 and blake3 are understood
+        for member in value.split(',') {
+            let Some((algorithm, digest)) = member.trim().split_once('=') else {
+                continue;
+            };
+
+            let algorithm = algorithm.trim().to_ascii_lowercase();
+            let Some(algorithm) = HashAlgorithm::from_name(&algorithm.replace('-', "")) else {
+                continue;
+            };
+            if algorithm != hashed_with {
+                unusable = Some(algorithm);
+                continue;
+            }
+
+            let digest = digest
+                .trim()
+                .strip_prefix(':')
+                .and_then(|d| d.strip_suffix(':'))
+                .and_then(|d| BASE64_STANDARD.decode(d).ok())
+                .ok_or_else(|| format!("Invalid {} digest in {name} header", algorithm.as_str()))?;
+
+            let hex: String = digest.iter().map(|b| format!("{b:02x}")).collect();
+            return Ok(Some((algorithm, hex)));
+        }
+    }
+
+    match unusable {
+        Some(algorithm) => Err(cant_check(algorithm, hashed_with)),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::{StatusCode, header},
+        test::{TestRequest, call_service, read_body},
+    };
+    use serde_json::json;
+    use sha2::{Digest, Sha256};
+
+    use super::*;
+    use crate::{
+        config::server::{AuthConfig, ServerConfig, UploadProfile},
+        fixtures::{Fixture, app, with_team_realm},
+    };
+
+    async fn anyone_can_upload() -> Fixture {
+        Fixture::seeded(open_config()).await
+    }
+
+    fn open_config() -> ServerConfig {
+        ServerConfig {
+            auth: AuthConfig::None(
+                serde_json::from_value(json!({ "permissions": ["*"] })).unwrap(),
+            ),
+            ..Default::default()
+        }
+    }
+
+    #[actix_web::test]
+    async fn raw_uploads_can_end_with_their_digest() {
+        let fixture = anyone_can_upload().await;
+        let app = app(&fixture).await;
+        let contents = b"streamed without knowing its hash up front\n";
+        let digest = format!("{:x}", Sha256::digest(contents));
+
+        let put = async |path: &str, body: Vec<u8>| {
+            let req = TestRequest::put()
+                .uri(path)
+                .insert_header((TRAILING_DIGEST, "sha256"))
+                .set_payload(body)
+                .to_request();
+            call_service(&app, req).await
+        };
+
+        let res = put(
+            "/api/streamed.txt",
+            [&contents[..], digest.as_bytes()].concat(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let etag = format!("\"{digest}\"");
+        assert_eq!(res.headers().get(header::ETAG).unwrap(), etag.as_str());
+        let req = TestRequest::get()
+            .uri("/api/info/streamed.txt")
+            .to_request();
+        let info: serde_json::Value =
+            serde_json::from_slice(&read_body(call_service(&app, req).await).await).unwrap();
+        assert_eq!(info["size_bytes"], contents.len());
+
+        // the digest of something else
+        let wrong = format!("{:x}", Sha256::digest(b"something else"));
+        let res = put("/api/wrong.txt", [&contents[..], wrong.as_bytes()].concat()).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let res = put("/api/short.txt", b"too short".to_vec()).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let req = TestRequest::get().uri("/api/info/wrong.txt").to_request();
+        assert_eq!(
+            call_service(&app, req).await.status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[actix_web::test]
+    async fn digests_of_the_wrong_algorithm_are_turned_away_up_front() {
+        let fixture = Fixture::seeded(ServerConfig {
+            hash_algorithm: HashAlgorithm::Blake3,
+            ..open_config()
+        })
+        .await;
+        let app = app(&fixture).await;
+        let contents = b"hashed with blake3";
+        let sha256 = BASE64_STANDARD.encode(Sha256::digest(contents));
+        let blake3 = BASE64_STANDARD.encode(blake3::hash(contents).as_bytes());
+
+        let put = async |path: &str, header: (HeaderName, String)| {
+            let req = TestRequest::put()
+                .uri(path)
+                .insert_header(header)
+                .set_payload(contents.to_vec())
+                .to_request();
+            call_service(&app, req).await
+        };
+
+        let res = put(
+            "/api/a.txt",
+            (DIGEST_HEADERS[0].clone(), format!("sha-256=:{sha256}:")),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            read_body(res).await,
+            "Can't check a sha256 checksum, uploads are hashed with blake3"
+        );
+        let res = put("/api/a.txt", (TRAILING_DIGEST, "sha256".to_string())).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        // the one that can be checked is picked out of several
+        let res = put(
+            "/api/a.txt",
+            (
+                DIGEST_HEADERS[0].clone(),
+                format!("sha-256=:{sha256}:, blake3=:{blake3}:"),
+            ),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let res = put(
+            "/api/b.txt",
+            (DIGEST_HEADERS[1].clone(), format!("blake3=:{sha256}:")),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn changes_are_checked_in_the_realm_of_the_file_they_change() {
+        let mut config = with_team_realm(ServerConfig::default());
+        config.upload_profiles.insert(
+            "team".to_string(),
+            UploadProfile {
+                prefix: "site/team".to_string(),
+                ..Default::default()
+            },
+        );
+        let fixture = Fixture::seeded(config).await;
+        let app = app(&fixture).await;
+        let send = async |req: TestRequest, key: &str| {
+            let req = req.insert_header(("X-API-Key", key)).to_request();
+            call_service(&app, req).await.status()
+        };
+        let put = |path: &str| TestRequest::put().uri(path).set_payload("team only");
+
+        // about `info/site/team/a.txt` in the server's realm, however the URL starts
+        let form = TestRequest::post()
+            .uri("/api/info/site/team/a.txt")
+            .insert_header((header::CONTENT_TYPE, "multipart/form-data; boundary=x"))
+            .set_payload("--x--\r\n");
+        assert_eq!(send(form, "team-key").await, StatusCode::FORBIDDEN);
+        let res = send(put("/api/info/site/team/a.txt"), "team-key").await;
+        assert_eq!(res, StatusCode::FORBIDDEN);
+        let delete = || TestRequest::delete().uri("/api/info/site/team/a.txt");
+        assert_eq!(send(delete(), "team-key").await, StatusCode::FORBIDDEN);
+        // a profile can move an upload into the team's realm, where it's checked again
+        let res = send(put("/api/a.txt?profile=team"), "admin-key").await;
+        assert_eq!(res, StatusCode::FORBIDDEN);
+
+        // nothing was written on either side
+        let info = |path: &str| TestRequest::get().uri(path);
+        let res = send(info("/api/info/info/site/team/a.txt"), "admin-key").await;
+        assert_eq!(res, StatusCode::NOT_FOUND);
+        let res = send(info("/api/info/site/team/a.txt"), "team-key").await;
+        assert_eq!(res, StatusCode::NOT_FOUND);
+
+        let res = send(put("/api/site/team/a.txt"), "team-key").await;
+        assert_eq!(res, StatusCode::CREATED);
+        let res = send(
+            TestRequest::delete().uri("/api/site/team/a.txt"),
+            "admin-key",
+        )
+        .await;
+        assert_eq!(res, StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,62 @@
+use actix_web::{HttpResponse, Responder, get, post, web::Data};
+
+use crate::{
+    SharedFileStore, file_handles::FileHandles, file_store::StoreTracer, scheduler::SchedulerStatus,
+};
+
+#[get("/scheduler")]
+pub async fn scheduler_status(status: Data<SchedulerStatus>) -> impl Responder {
+    let status = status.lock().unwrap().clone();
+    HttpResponse::Ok().json(status)
+}
+
+/// How long each kind of store operation has been taking, per backend.
+#[get("/store/stats")]
+pub async fn store_stats(tracer: Data<StoreTracer>) -> impl Responder {
+    HttpResponse::Ok().json(tracer.stats())
+}
+
+/// How much is stored against the quota, which is only kept track of while there is one.
+#[get("/store/usage")]
+pub async fn store_usage(file_store: Data<SharedFileStore>) -> impl Responder {
+    match file_store.usage().await {
+        Some(Ok(usage)) => HttpResponse::Ok().json(usage),
+        Some(Err(err)) => {
+            log::error!("Error counting store usage: {err}");
+            HttpResponse::InternalServerError().body("Failed to count store usage")
+        }
+        None => HttpResponse::NotFound().body("No quota is configured"),
+    }
+}
+
+/// How many uploads are being written to local files and how much room they take so far, along
+/// with any left behind that the `partial_upload_sweep` task hasn't removed yet.
+#[get("/store/partial_uploads")]
+pub async fn partial_uploads(file_store: Data<SharedFileStore>) -> impl Responder {
+    match file_store.partial_uploads(None).await {
+        Ok(report) => HttpResponse::Ok().json(report),
+        Err(err) => {
+            log::error!("Error counting partial uploads: {err}");
+            HttpResponse::InternalServerError().body("Failed to count partial uploads")
+        }
+    }
+}
+
+/// How many local files are held open by downloads and the file caches, against the limit past
+/// which downloads are refused.
+#[get("/store/file_handles")]
+pub async fn file_handles(handles: Data<FileHandles>) -> impl Responder {
+    HttpResponse::Ok().json(handles.usage())
+}
+
+/// Runs the `metadata_gc` task's pass right away, rather than waiting for its schedule.
+#[post("/store/gc")]
+pub async fn collect_garbage(file_store: Data<SharedFileStore>) -> impl Responder {
+    match file_store.collect_garbage().await {
+        Ok(report) => HttpResponse::Ok().json(report),
+        Err(err) => {
+            log::error!("Error collecting garbage: {err}");
+            HttpResponse::InternalServerError().body("Failed to collect garbage")
+        }
+    }
+}
@@ -1,91 +1,1613 @@
-use std::path::Path;
+use std::{
+    future::{Ready, ready},
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
 
 use actix_web::{
-    HttpRequest, HttpResponse, Responder, Scope,
-    dev::HttpServiceFactory,
-    error, get,
-    http::header::{self, ContentType},
-    middleware::Compress,
-    mime,
+    FromRequest, HttpMessage, HttpRequest, HttpResponse, HttpResponseBuilder, Responder, Scope,
+    body::{MessageBody, SizedStream},
+    dev::{HttpServiceFactory, Payload, ServiceRequest, ServiceResponse},
+    guard,
+    http::{
+        Method, StatusCode,
+        header::{self, ContentRangeSpec, ContentType, HeaderName, HeaderValue, HttpDate},
+    },
+    middleware::{self, Compress, Next},
+    mime, route,
     web::{self, Bytes, Data, Query},
 };
-use futures::stream;
+use base64::{Engine, prelude::BASE64_STANDARD};
+use chrono::SubsecRound;
+use futures::{StreamExt, TryStreamExt, future, stream};
+use mime::Mime;
 use serde::{Deserialize, Deserializer};
+use uuid::Uuid;
 
 use crate::{
     SharedFileStore,
-    file_store::{FileStorageCore, StoredFileCore},
-    routes::ScopeCreator,
+    archive::ArchiveFormat,
+    attributes::SharedAttributes,
+    authorized::{is_authorized, request_payload},
+    branding::{Branding, accepts_html},
+    config::server::{FallbackRule, HashAlgorithm, ImageFormat, PathPolicy, ServerConfig},
+    cors::cors,
+    derive::{Derived, SharedDerivatives},
+    file_handles::FileHandles,
+    file_store::{
+        ByteStream, Entry, FileMetadata, FileStorageCore, FileStore, StoredFile, StoredFileCore,
+        verified,
+    },
+    geoip::geo_access,
+    header_rules::HeaderRules,
+    highlight::{self, Highlighter},
+    home_page::HomePage,
+    hooks::SharedHooks,
+    hotlink::hotlink_protection,
+    markdown,
+    mime_types::MimeTypes,
+    preview,
+    routes::{
+        ScopeCreator, app_data,
+        browse::{is_listed, public_listing},
+        bundle::{Access, archive_file_name, archive_response, directory_files},
+        etag::{self, Condition},
+        file_path::FilePath,
+        put_file::put_file,
+        upload_file::{delete_file, upload_file},
+    },
+    throttle::DownloadThrottle,
+    transfers::{TransferClient, TransferKind, Transfers},
 };
 
+// RFC 9530, the digest of the whole file whichever part of it is sent
+const REPR_DIGEST: HeaderName = HeaderName::from_static("repr-digest");
+const WANT_REPR_DIGEST: HeaderName = HeaderName::from_static("want-repr-digest");
+// whether the file goes on past a preview of it
+const PREVIEW_TRUNCATED: HeaderName = HeaderName::from_static("x-preview-truncated");
+
 pub struct FileServeRoute;
 
 impl ScopeCreator for FileServeRoute {
     fn create_scope() -> impl HttpServiceFactory {
-        Scope::new("").wrap(Compress::default()).service(serve_file)
+        Scope::new("")
+            .wrap(Compress::default())
+            .wrap(middleware::from_fn(drop_encoded_digest))
+            .wrap(middleware::from_fn(hotlink_protection))
+            .wrap(middleware::from_fn(geo_access))
+            .wrap(middleware::from_fn(cors))
+            // a scope of its own, so only the methods that change files need credentials
+            .service(
+                Scope::new("")
+                    .guard(
+                        guard::Any(guard::Post())
+                            .or(guard::Put())
+                            .or(guard::Delete()),
+                    )
+                    .wrap(middleware::from_fn(is_authorized))
+                    .service(upload_file)
+                    .service(put_file)
+                    .service(delete_file),
+            )
+            .service(serve_file)
     }
 }
 
-fn string_bool<'de, D: Deserializer<'de>>(d: D) -> Result<bool, D::Error> {
-    let s = String::deserialize(d)?;
-    match &*s.to_ascii_lowercase() {
-        // empty string is also true, since its presence is enough
-        "" | "y" | "yes" | "t" | "true" | "1" => Ok(true),
-        _ => Ok(false),
+/// Drops the `Repr-Digest` of a response the compression went on to encode, which it doesn't
+/// match anymore. Wrapped around the compression, so it sees the response as it's sent.
+async fn drop_encoded_digest(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> actix_web::Result<ServiceResponse<impl MessageBody>> {
+    let mut res = next.call(req).await?;
+    let is_encoded = res
+        .headers()
+        .get(header::CONTENT_ENCODING)
+        .is_some_and(|encoding| encoding != "identity");
+    if is_encoded {
+        res.headers_mut().remove(REPR_DIGEST);
+    }
+    Ok(res)
+}
+
+/// The `Repr-Digest` of a file, from the hash it's stored with. Only SHA-256 has a name
+/// clients know it by, and it's left out when `Want-Repr-Digest` doesn't ask for it.
+fn repr_digest(req: &HttpRequest, metadata: &FileMetadata) -> Option<String> {
+    if metadata.algorithm != HashAlgorithm::Sha256 {
+        return None;
     }
+    if let Some(want) = req.headers().get(WANT_REPR_DIGEST) {
+        // a dictionary of `algorithm=weight` members, a weight of 0 turning one down
+        let wants_sha256 = want
+            .to_str()
+            .ok()?
+            .split(',')
+            .filter_map(|member| member.trim().split_once('='))
+            .any(|(algorithm, weight)| {
+                algorithm.trim().eq_ignore_ascii_case("sha-256")
+                    && weight.trim().parse::<u8>().is_ok_and(|weight| weight > 0)
+            });
+        if !wants_sha256 {
+            return None;
+        }
+    }
+
+    let hash = &metadata.hash;
+    let digest = (0..hash.len())
+        .step_by(2)
+        .map(|at| u8::from_str_radix(hash.get(at..at + 2)?, 16).ok())
+        .collect::<Option<Vec<_>>>()?;
+    Some(format!("sha-256=:{}:", BASE64_STANDARD.encode(digest)))
+}
+
+fn is_truthy(s: &str) -> bool {
+    // empty string is also true, since its presence is enough
+    matches!(
+        &*s.to_ascii_lowercase(),
+        "" | "y" | "yes" | "t" | "true" | "1"
+    )
+}
+
+fn string_bool<'de, D: Deserializer<'de>>(d: D) -> Result<bool, D::Error> {
+    String::deserialize(d).map(|s| is_truthy(&s))
 }
 
 #[derive(Deserialize)]
 struct FileOptions {
     #[serde(default, alias = "dl", deserialize_with = "string_bool")]
     download: bool,
+    /// Name of a derivative preset to serve instead of the file itself
+    derive: Option<String>,
+    /// Downloads a directory that's listed as a zip of everything under it
+    #[serde(default, deserialize_with = "string_bool")]
+    zip: bool,
+    /// Scales an image down to at most this wide
+    w: Option<u32>,
+    /// Scales an image down to at most this high
+    h: Option<u32>,
+    /// Converts an image to this format
+    format: Option<ImageFormat>,
+    /// Whether to render a Markdown file as HTML, which browsers get by default when
+    /// `render_markdown` is on
+    render: Option<String>,
+    /// Sends only up to this many lines from the start of a text file
+    preview: Option<u64>,
+    /// Sends only up to this many bytes from the start of a text file
+    preview_bytes: Option<u64>,
+    /// Whether to render a source file as HTML with its syntax highlighted
+    highlight: Option<String>,
+}
+
+impl FileOptions {
+    fn resizes(&self) -> bool {
+        self.w.is_some() || self.h.is_some() || self.format.is_some()
+    }
+}
+
+/// Looks up `path`, as missing if it's private and the request has no valid credentials,
+/// along with whether it's private.
+async fn visible_file(
+    req: &HttpRequest,
+    store: &FileStore,
+    attributes: &SharedAttributes,
+    path: &Path,
+) -> Option<(Arc<StoredFile>, bool)> {
+    // looked up on the blocking pool, so a slow disk under the database doesn't hold up
+    // every other request on the worker; failing closed like `is_private` itself
+    let (attributes, owned_path) = (Arc::clone(attributes), path.to_path_buf());
+    let is_private = web::block(move || attributes.is_private(&owned_path))
+        .await
+        .unwrap_or(true);
+    if is_private && request_payload(req, path).await.is_none() {
+        return None;
+    }
+    let file = store.get_file(path).await?;
+    Some((file, is_private))
+}
+
+/// The file rendered by `render` into a page of its own, tagged as `kind` of rendering.
+async fn rendered_page(
+    req: &HttpRequest,
+    found: &Found,
+    header_rules: &HeaderRules,
+    kind: &str,
+    render: impl FnOnce(String) -> String + Send + 'static,
+) -> HttpResponse {
+    let Found {
+        file,
+        path,
+        private,
+        ..
+    } = found;
+
+    // tied to the file's hash, and apart from the file's own ETag
+    let etag = format!("{}-{kind}", file.metadata().hash);
+    if req
+        .headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| Condition::IfNoneMatch.matches(v, &etag))
+    {
+        return HttpResponse::NotModified().finish();
+    }
+
+    let contents = match file.bytes_stream().try_collect::<Vec<_>>().await {
+        Ok(chunks) => chunks.concat(),
+        Err(err) => {
+            log::error!("Error reading '{}' to render: {err}", path.display());
+            return HttpResponse::InternalServerError().body("Failed to read file");
+        }
+    };
+    // rendering a large file takes long enough to hold up the worker's other requests
+    let contents = String::from_utf8_lossy(&contents).into_owned();
+    let Ok(html) = web::block(move || render(contents)).await else {
+        return HttpResponse::InternalServerError().body("Failed to render file");
+    };
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+
+    let mut response = HttpResponse::Ok();
+    header_rules.apply(path, *private, &mut response);
+    response
+        .content_type(ContentType::html())
+        .insert_header((header::ETAG, etag::strong(&etag)))
+        .insert_header((
+            header::CONTENT_SECURITY_POLICY,
+            markdown::CONTENT_SECURITY_POLICY,
+        ))
+        // whether it's rendered can depend on whether it's a browser asking
+        .append_header((header::VARY, "Accept"))
+        .body(markdown::page(&file_name, &html))
+}
+
+fn rule_prefix(rule: &FallbackRule) -> &Path {
+    Path::new(rule.prefix.trim_matches('/'))
+}
+
+/// The rule with the longest prefix `path` is under, if any.
+fn fallback_rule<'a>(rules: &'a [FallbackRule], path: &Path) -> Option<&'a FallbackRule> {
+    rules
+        .iter()
+        .filter(|rule| path.starts_with(rule_prefix(rule)))
+        .max_by_key(|rule| rule_prefix(rule).components().count())
+}
+
+fn fallback_path(rule: &FallbackRule) -> PathBuf {
+    rule_prefix(rule).join(&rule.fallback)
+}
+
+/// `file` as the page saying what was asked for isn't there.
+fn not_found_page(
+    req: &HttpRequest,
+    file: &StoredFile,
+    path: &Path,
+    private: bool,
+    header_rules: &HeaderRules,
+) -> HttpResponse {
+    let mut response = HttpResponse::NotFound();
+    header_rules.apply(path, private, &mut response);
+    response.content_type(ContentType::html());
+    let size_bytes = file.metadata().size_bytes;
+    match req.method() {
+        &Method::HEAD => response.body(SizedStream::new(
+            size_bytes,
+            stream::empty::<io::Result<Bytes>>(),
+        )),
+        _ => response.body(SizedStream::new(size_bytes, file.bytes_stream())),
+    }
+}
+
+/// The first of `index_files` there is in the directory at `dir`, if it's a directory.
+async fn index_file(
+    req: &HttpRequest,
+    store: &FileStore,
+    attributes: &SharedAttributes,
+    index_files: &[String],
+    dir: &Path,
+) -> Option<(Arc<StoredFile>, bool, PathBuf)> {
+    if index_files.is_empty() || !matches!(store.stat(dir).await, Some(Entry::Dir)) {
+        return None;
+    }
+
+    for name in index_files {
+        let path = dir.join(name);
+        if let Some((file, private)) = visible_file(req, store, attributes, &path).await {
+            return Some((file, private, path));
+        }
+    }
+    None
 }
 
-#[get("/{file_path:.*}")]
+/// Types a browser renders as a page of its own, running any scripts in it.
+fn is_markup(mime: &Mime) -> bool {
+    mime.subtype() == mime::HTML || mime.subtype() == mime::SVG || mime.subtype() == "xhtml"
+}
+
+fn is_fallback_file(rules: &[FallbackRule], path: &Path) -> bool {
+    rules.iter().any(|rule| fallback_path(rule) == path)
+}
+
+/// The app data serving files takes, as one extractor.
+pub struct ServeState {
+    store: Data<SharedFileStore>,
+    derivatives: Data<SharedDerivatives>,
+    header_rules: Data<HeaderRules>,
+    mime_types: Data<MimeTypes>,
+    branding: Data<Branding>,
+    home_page: Data<HomePage>,
+    attributes: Data<SharedAttributes>,
+    config: Data<ServerConfig>,
+    hooks: Data<SharedHooks>,
+    transfers: Data<Transfers>,
+    file_handles: Data<FileHandles>,
+    download_throttle: Data<DownloadThrottle>,
+    highlighter: Data<Highlighter>,
+}
+
+impl FromRequest for ServeState {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let state = || -> actix_web::Result<Self> {
+            Ok(ServeState {
+                store: app_data(req)?,
+                derivatives: app_data(req)?,
+                header_rules: app_data(req)?,
+                mime_types: app_data(req)?,
+                branding: app_data(req)?,
+                home_page: app_data(req)?,
+                attributes: app_data(req)?,
+                config: app_data(req)?,
+                hooks: app_data(req)?,
+                transfers: app_data(req)?,
+                file_handles: app_data(req)?,
+                download_throttle: app_data(req)?,
+                highlighter: app_data(req)?,
+            })
+        };
+        ready(state())
+    }
+}
+
+/// The file a request is answered with.
+struct Found {
+    file: Arc<StoredFile>,
+    /// Where the file is, which for an index or fallback file isn't the path asked for
+    path: PathBuf,
+    private: bool,
+    /// Whether it's the index file of the directory asked for
+    is_index: bool,
+}
+
+#[route("/{path:.*}", method = "GET", method = "HEAD")]
 pub async fn serve_file(
     req: HttpRequest,
-    path: web::Path<String>,
+    file_path: FilePath,
     query: Query<FileOptions>,
-    store: Data<SharedFileStore>,
+    state: ServeState,
 ) -> impl Responder {
-    let file_path = path.into_inner();
+    // one more open file risks running out of descriptors for everything else, the client is
+    // better off coming back once some of the downloads have finished
+    if state.file_handles.refuses_more() {
+        let mut response = state.branding.error_response(
+            &req,
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Too many downloads in progress",
+        );
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from_static("1"));
+        return response;
+    }
 
-    let Some(file) = store.get_file(Path::new(&file_path)) else {
-        return HttpResponse::NotFound().body("File does not exist");
+    // asked of a directory, before its index file would answer instead
+    if query.zip
+        && is_listed(&state.config.directory_listings, &file_path)
+        && matches!(state.store.stat(&file_path).await, Some(Entry::Dir))
+    {
+        return zipped_directory(&req, &state, &file_path).await;
+    }
+
+    let fallback = fallback_rule(&state.config.fallbacks, &file_path);
+    let Some(found) = find_file(&req, &state, &file_path, fallback).await else {
+        return not_found(&req, &state, &file_path, fallback).await;
     };
+    // relative links in the page only resolve against the directory with a trailing slash,
+    // which redirecting to canonical paths would take off again
+    if found.is_index
+        && state.config.path_policy == PathPolicy::Normalize
+        && !req.path().ends_with('/')
+    {
+        let mut location = format!("{}/", req.path());
+        if !req.query_string().is_empty() {
+            location.push('?');
+            location.push_str(req.query_string());
+        }
+        return HttpResponse::PermanentRedirect()
+            .insert_header((header::LOCATION, location))
+            .finish();
+    }
+    if let Err(err) = state.hooks.pre_serve(&found.path, &found.file).await {
+        return state
+            .branding
+            .error_response(&req, StatusCode::FORBIDDEN, &err.to_string());
+    }
+
+    if query.preview.is_some() || query.preview_bytes.is_some() {
+        return text_preview(&req, &state, &query, &found).await;
+    }
+
+    let transformed = query.derive.is_some() || query.resizes() || query.download;
+    if !transformed
+        && query.highlight.as_deref().is_some_and(is_truthy)
+        && let Some(response) = highlighted(&req, &state, &found).await
+    {
+        return response;
+    }
+    if !transformed && let Some(response) = rendered_markdown(&req, &state, &query, &found).await {
+        return response;
+    }
+
+    match derived(&req, &state, &query, &found).await {
+        Ok(derived) => send_file(&req, &state, &query, &found, derived).await,
+        Err(response) => response,
+    }
+}
+
+/// A listed directory as a zip of everything under it.
+async fn zipped_directory(req: &HttpRequest, state: &ServeState, dir: &Path) -> HttpResponse {
+    match directory_files(req, &state.store, &state.attributes, dir, Access::Visible).await {
+        Ok(files) => archive_response(ArchiveFormat::Zip, &archive_file_name(dir), files),
+        Err(response) => response,
+    }
+}
+
+/// The file at `path`, or the index file of the directory at `path`, or else the fallback
+/// of an app under `fallback`.
+async fn find_file(
+    req: &HttpRequest,
+    state: &ServeState,
+    path: &Path,
+    fallback: Option<&FallbackRule>,
+) -> Option<Found> {
+    let (store, attributes) = (&state.store, &state.attributes);
+    if let Some((file, private)) = visible_file(req, store, attributes, path).await {
+        let path = path.to_path_buf();
+        return Some(Found {
+            file,
+            path,
+            private,
+            is_index: false,
+        });
+    }
+    if let Some((file, private, path)) =
+        index_file(req, store, attributes, &state.config.index_files, path).await
+    {
+        return Some(Found {
+            file,
+            path,
+            private,
+            is_index: true,
+        });
+    }
+
+    let path = fallback_path(fallback.filter(|rule| !rule.not_found)?);
+    let (file, private) = visible_file(req, store, attributes, &path).await?;
+    Some(Found {
+        file,
+        path,
+        private,
+        is_index: false,
+    })
+}
+
+/// Answers a request for `path` there's no file for: with the listing of a directory, the
+/// home page, the favicon or else a page saying it isn't there.
+async fn not_found(
+    req: &HttpRequest,
+    state: &ServeState,
+    path: &Path,
+    fallback: Option<&FallbackRule>,
+) -> HttpResponse {
+    let branding = &state.branding;
+    if is_listed(&state.config.directory_listings, path)
+        && matches!(state.store.stat(path).await, Some(Entry::Dir))
+    {
+        return match public_listing(req, path, &state.store, &state.attributes, branding).await {
+            Ok(response) => response,
+            Err(err) => {
+                log::error!("Error listing directory: {err}");
+                branding.error_response(
+                    req,
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Failed to list directory",
+                )
+            }
+        };
+    }
+
+    if path.as_os_str().is_empty() && state.home_page.is_enabled() {
+        return match state.home_page.response(req, &state.store, branding).await {
+            Ok(response) => response,
+            Err(err) => {
+                log::error!("Error counting files for the home page: {err}");
+                branding.error_response(
+                    req,
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Failed to count files",
+                )
+            }
+        };
+    }
 
-    let file = file.as_ref();
-    let hash = &file.metadata().hash;
+    // a favicon uploaded to the store takes precedence over the branding one
+    if path.as_os_str() == "favicon.ico" {
+        return HttpResponse::Ok()
+            .content_type("image/x-icon")
+            .body(branding.favicon());
+    }
+
+    if let Some(rule) = fallback.filter(|rule| rule.not_found) {
+        let page = fallback_path(rule);
+        if let Some((file, private)) =
+            visible_file(req, &state.store, &state.attributes, &page).await
+        {
+            return not_found_page(req, &file, &page, private, &state.header_rules);
+        }
+    }
 
-    if let Some(etag) = req
+    branding.error_response(req, StatusCode::NOT_FOUND, "File does not exist")
+}
+
+/// The start of a text file, as much of it as the query asks for.
+async fn text_preview(
+    req: &HttpRequest,
+    state: &ServeState,
+    query: &FileOptions,
+    found: &Found,
+) -> HttpResponse {
+    if !preview::is_text(state.mime_types.guess(&found.path).as_ref()) {
+        return state.branding.error_response(
+            req,
+            StatusCode::BAD_REQUEST,
+            "Only text files can be previewed",
+        );
+    }
+
+    let stream = found.file.bytes_stream();
+    match preview::read(stream, query.preview, query.preview_bytes).await {
+        Ok(preview) => {
+            let mut response = HttpResponse::Ok();
+            state
+                .header_rules
+                .apply(&found.path, found.private, &mut response);
+            // plain text whatever the file is, so a preview of a page never renders
+            response
+                .content_type(match preview.is_utf8 {
+                    true => mime::TEXT_PLAIN_UTF_8,
+                    false => mime::TEXT_PLAIN,
+                })
+                .insert_header((PREVIEW_TRUNCATED, preview.truncated.to_string()))
+                .body(preview.contents)
+        }
+        Err(err) => {
+            log::error!("Error reading '{}' to preview: {err}", found.path.display());
+            HttpResponse::InternalServerError().body("Failed to read file")
+        }
+    }
+}
+
+/// The file as a page with its syntax highlighted, unless it's too large for that and is
+/// sent as it is instead.
+async fn highlighted(req: &HttpRequest, state: &ServeState, found: &Found) -> Option<HttpResponse> {
+    if !state.highlighter.knows(&found.path) {
+        return Some(state.branding.error_response(
+            req,
+            StatusCode::BAD_REQUEST,
+            "No syntax is known to highlight this file with",
+        ));
+    }
+    if found.file.metadata().size_bytes > highlight::MAX_HIGHLIGHTED_BYTES {
+        return None;
+    }
+
+    let (highlighter, path) = (Data::clone(&state.highlighter), found.path.clone());
+    let render = move |code: String| highlighter.highlight(&path, &code).unwrap_or_default();
+    Some(rendered_page(req, found, &state.header_rules, "hl", render).await)
+}
+
+/// A Markdown file rendered as a page, when the query asks for it or a browser is asking and
+/// `render_markdown` is on.
+async fn rendered_markdown(
+    req: &HttpRequest,
+    state: &ServeState,
+    query: &FileOptions,
+    found: &Found,
+) -> Option<HttpResponse> {
+    let render_markdown = match &query.render {
+        Some(render) => is_truthy(render),
+        None => state.config.render_markdown && accepts_html(req),
+    };
+    if !render_markdown
+        || !markdown::is_markdown(&found.path)
+        || found.file.metadata().size_bytes > markdown::MAX_RENDERED_BYTES
+    {
+        return None;
+    }
+
+    let render = |markdown: String| markdown::render(&markdown);
+    Some(rendered_page(req, found, &state.header_rules, "md", render).await)
+}
+
+/// The derivative of the file the query asks for, by preset or by resizing an image.
+async fn derived(
+    req: &HttpRequest,
+    state: &ServeState,
+    query: &FileOptions,
+    found: &Found,
+) -> Result<Option<Derived>, HttpResponse> {
+    let derivatives = &state.derivatives;
+    let bad_request = |message: &str| {
+        state
+            .branding
+            .error_response(req, StatusCode::BAD_REQUEST, message)
+    };
+
+    let derived = match &query.derive {
+        Some(_) if query.resizes() => {
+            return Err(bad_request("A preset can't be combined with resizing"));
+        }
+        Some(preset) => derivatives.get(preset, &found.file).await,
+        None if query.resizes() => {
+            let is_image = state
+                .mime_types
+                .guess(&found.path)
+                .is_some_and(|mime| mime.type_() == mime::IMAGE);
+            if !is_image {
+                return Err(bad_request("Only images can be resized"));
+            }
+            derivatives
+                .resize(query.w, query.h, query.format, &found.file)
+                .await
+        }
+        None => return Ok(None),
+    };
+
+    match derived {
+        Ok(derived) => Ok(Some(derived)),
+        Err(err) if err.kind() == io::ErrorKind::InvalidInput => {
+            Err(bad_request(&format!("Invalid input: {err}")))
+        }
+        Err(err) => {
+            log::error!("Error deriving file: {err}");
+            Err(HttpResponse::InternalServerError().body("Failed to derive file"))
+        }
+    }
+}
+
+/// Sends the file, or `derived` from it, as much of it as the request's conditions and
+/// ranges ask for.
+async fn send_file(
+    req: &HttpRequest,
+    state: &ServeState,
+    query: &FileOptions,
+    found: &Found,
+    derived: Option<Derived>,
+) -> HttpResponse {
+    let Found {
+        file,
+        path: served_path,
+        private,
+        ..
+    } = found;
+
+    // derivatives are made whenever they're first asked for, long after the file changed,
+    // and the header only goes down to the second, which comparisons against it have to too
+    let last_modified = match derived {
+        Some(_) => None,
+        None => file
+            .metadata()
+            .modified_at
+            .map(|at| SystemTime::from(at.trunc_subsecs(0))),
+    };
+    let (etag, source, derived_type): (_, &dyn StoredFileCore, _) = match &derived {
+        Some(derived) => (
+            derived.etag.clone(),
+            &derived.file,
+            derived.content_type.clone(),
+        ),
+        None => (file.metadata().hash.clone(), &**file, None),
+    };
+    let size_bytes = source.metadata().size_bytes;
+
+    if let Some(if_none_match) = req
         .headers()
         .get(header::IF_NONE_MATCH)
         .and_then(|v| v.to_str().ok())
-        && etag == hash
+    {
+        if Condition::IfNoneMatch.matches(if_none_match, &etag) {
+            return HttpResponse::NotModified().finish();
+        }
+    // only looked at without an ETag to go by, which is the more precise of the two
+    } else if let Some(last_modified) = last_modified
+        && let Some(since) = req.get_header::<header::IfModifiedSince>()
+        && HttpDate::from(last_modified) <= since.0
     {
         return HttpResponse::NotModified().finish();
     }
 
-    let bytes_iter = file.bytes_iter();
-
-    HttpResponse::Ok()
-        .insert_header((header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
-        .insert_header((header::ETAG, hash.to_string()))
-        .content_type(if query.download {
-            ContentType::octet_stream()
-        } else {
-            // try to guess mime type from file extension, except HTML files to prevent
-            // rendering, default to text/plain; charset=utf-8
-            ContentType(
-                mime_guess::from_path(&file_path)
-                    .first()
-                    .filter(|m| m.subtype() != mime::HTML)
-                    .unwrap_or(mime::TEXT_PLAIN_UTF_8),
+    let ranges = match requested_ranges(req, &etag, last_modified, size_bytes) {
+        RequestedRanges::Whole => None,
+        RequestedRanges::Ranges(ranges) => Some(ranges),
+        RequestedRanges::Unsatisfiable => {
+            return HttpResponse::RangeNotSatisfiable()
+                .insert_header(header::ContentRange(ContentRangeSpec::Unregistered {
+                    unit: "bytes".to_string(),
+                    resp: format!("*/{size_bytes}"),
+                }))
+                .finish();
+        }
+    };
+
+    let mut response = match ranges {
+        Some(_) => HttpResponse::PartialContent(),
+        None => HttpResponse::Ok(),
+    };
+    response.insert_header((header::ACCEPT_RANGES, "bytes"));
+
+    // derivatives come out of the transforms already compressed, so there's nothing to
+    // gain from having the middleware compress them again, and parts of a file have to be
+    // sent as they are for the ranges to mean anything
+    if derived_type.is_some() || ranges.is_some() {
+        response.insert_header((header::CONTENT_ENCODING, "identity"));
+    }
+
+    state
+        .header_rules
+        .apply(served_path, *private, &mut response);
+    // browsers would have been sent it rendered instead
+    if state.config.render_markdown && markdown::is_markdown(served_path) {
+        response.append_header((header::VARY, "Accept"));
+    }
+    if let Some(warning) = deletion_warning(&state.attributes, served_path).await {
+        response.insert_header((header::WARNING, warning));
+    }
+    if let Some(last_modified) = last_modified {
+        response.insert_header(header::LastModified(last_modified.into()));
+    }
+
+    let content_type = match derived_type {
+        _ if query.download => ContentType::octet_stream(),
+        Some(derived_type) => ContentType(derived_type),
+        None => guessed_type(state, found, &mut response),
+    };
+
+    let (body_len, bytes_stream) = match ranges.as_deref() {
+        None if state.config.verify_reads && derived.is_none() => (
+            size_bytes,
+            verified(file.bytes_stream(), served_path, file.metadata()),
+        ),
+        None => (size_bytes, source.bytes_stream()),
+        // a part of a file can't be checked against the hash of all of it, so ranges never are
+        Some(&[(start, len)]) => {
+            response.insert_header(content_range(start, len, size_bytes));
+            (len, source.range_stream(start, len))
+        }
+        Some(ranges) => {
+            let boundary = Uuid::new_v4().simple().to_string();
+            response.content_type(format!("multipart/byteranges; boundary={boundary}"));
+            byte_ranges(source, ranges, &boundary, &content_type)
+        }
+    };
+    // every part of a multipart body has a type of its own instead
+    if ranges.is_none_or(|ranges| ranges.len() == 1) {
+        response.content_type(content_type);
+    }
+
+    response.insert_header((header::ETAG, etag::strong(&etag)));
+    if derived.is_none()
+        && let Some(digest) = repr_digest(req, file.metadata())
+    {
+        response.insert_header((REPR_DIGEST, digest));
+    }
+
+    // the same headers as the download would have, including how long it is, without reading
+    // any of the file for a body that's never sent
+    if req.method() == Method::HEAD {
+        return response.body(SizedStream::new(
+            body_len,
+            stream::empty::<io::Result<Bytes>>(),
+        ));
+    }
+
+    response
+        // a sized body lets the Content-Length header be sent when the compression
+        // middleware leaves the response alone, instead of always using chunked encoding
+        .body(SizedStream::new(
+            body_len,
+            state.transfers.track(
+                TransferKind::Download,
+                served_path,
+                TransferClient::of(req),
+                state.download_throttle.throttle(req, bytes_stream),
+            ),
+        ))
+}
+
+/// The `Warning` for a file that's scheduled to be deleted once its grace period is over.
+async fn deletion_warning(attributes: &SharedAttributes, path: &Path) -> Option<String> {
+    let deleting_at = {
+        let (attributes, path) = (SharedAttributes::clone(attributes), path.to_path_buf());
+        web::block(move || attributes.deleting_at(&path))
+            .await
+            .unwrap_or_else(|err| Err(io::Error::other(err)))
+    };
+    match deleting_at {
+        Ok(delete_at) => delete_at.map(|delete_at| {
+            format!(
+                "299 - \"Scheduled for deletion at {}\"",
+                delete_at.to_rfc3339()
             )
-        })
-        .streaming(stream::iter(bytes_iter.map(|r| {
-            r.as_ref()
-                .map(|b| Bytes::copy_from_slice(b))
-                .map_err(|_| error::ErrorInternalServerError("File read error"))
-        })))
+        }),
+        Err(err) => {
+            log::warn!("Error looking up pending deletion: {err}");
+            None
+        }
+    }
+}
+
+/// The type of the file guessed from its extension, setting the rendering rule's
+/// `Content-Security-Policy` on `response` for markup that's let render.
+fn guessed_type(
+    state: &ServeState,
+    found: &Found,
+    response: &mut HttpResponseBuilder,
+) -> ContentType {
+    // HTML and SVG files aren't rendered unless it's a directory's index, an app's fallback
+    // or a render rule allows it, and sent as text/plain; charset=utf-8 instead
+    let rendering = state.header_rules.rendering(&found.path);
+    let renders = found.is_index
+        || is_fallback_file(&state.config.fallbacks, &found.path)
+        || rendering.is_some();
+    let guessed = state.mime_types.guess(&found.path);
+    if renders
+        && guessed.as_ref().is_some_and(is_markup)
+        && let Some(policy) = rendering.and_then(|r| r.content_security_policy.clone())
+    {
+        response.insert_header((header::CONTENT_SECURITY_POLICY, policy));
+    }
+    ContentType(
+        guessed
+            .filter(|m| !is_markup(m) || renders)
+            .unwrap_or(mime::TEXT_PLAIN_UTF_8),
+    )
+}
+
+// more ranges than this in one request are more likely an attempt to make the server do a lot
+// of work for a small request than a client that needs them, the whole file is sent instead
+const MAX_RANGES: usize = 16;
+
+/// What a request's `Range` header asks for, as the `(start, len)` of each range.
+enum RequestedRanges {
+    Whole,
+    Ranges(Vec<(u64, u64)>),
+    Unsatisfiable,
+}
+
+/// The ranges of the `size_bytes` long file that the request asks for. An `If-Range` that no
+/// longer matches the file asks for the whole of it again, since the ranges were meant for a
+/// different version.
+fn requested_ranges(
+    req: &HttpRequest,
+    etag: &str,
+    last_modified: Option<SystemTime>,
+    size_bytes: u64,
+) -> RequestedRanges {
+    let Some(header::Range::Bytes(specs)) = req.get_header::<header::Range>() else {
+        return RequestedRanges::Whole;
+    };
+
+    if let Some(if_range) = req
+        .headers()
+        .get(header::IF_RANGE)
+        .and_then(|v| v.to_str().ok())
+    {
+        let unchanged = Condition::IfRange.matches(if_range, etag)
+            || if_range.parse::<HttpDate>().is_ok_and(|date| {
+                last_modified.is_some_and(|last_modified| HttpDate::from(last_modified) == date)
+            });
+        if !unchanged {
+            return RequestedRanges::Whole;
+        }
+    }
+    if specs.len() > MAX_RANGES {
+        return RequestedRanges::Whole;
+    }
+
+    let ranges: Vec<_> = specs
+        .iter()
+        .filter_map(|spec| spec.to_satisfiable_range(size_bytes))
+        .map(|(start, end)| (start, end - start + 1))
+        .collect();
+    if ranges.is_empty() {
+        RequestedRanges::Unsatisfiable
+    } else {
+        RequestedRanges::Ranges(ranges)
+    }
+}
+
+fn content_range(start: u64, len: u64, size_bytes: u64) -> header::ContentRange {
+    header::ContentRange(ContentRangeSpec::Bytes {
+        range: Some((start, start + len - 1)),
+        instance_length: Some(size_bytes),
+    })
+}
+
+/// Several ranges of `source` as a `multipart/byteranges` body, along with its length.
+fn byte_ranges(
+    source: &dyn StoredFileCore,
+    ranges: &[(u64, u64)],
+    boundary: &str,
+    content_type: &ContentType,
+) -> (u64, ByteStream<'static>) {
+    let mut body_len = 0;
+    let mut parts = Vec::with_capacity(ranges.len() + 1);
+    for &(start, len) in ranges {
+        let head = format!(
+            "\r\n--{boundary}\r\n{}: {content_type}\r\n{}: {}\r\n\r\n",
+            header::CONTENT_TYPE,
+            header::CONTENT_RANGE,
+            content_range(start, len, source.metadata().size_bytes),
+        );
+        body_len += head.len() as u64 + len;
+        parts.push(stream::once(future::ready(Ok(Bytes::from(head)))).boxed_local());
+        parts.push(source.range_stream(start, len));
+    }
+    let tail = format!("\r\n--{boundary}--\r\n");
+    body_len += tail.len() as u64;
+    parts.push(stream::once(future::ready(Ok(Bytes::from(tail)))).boxed_local());
+
+    (body_len, stream::iter(parts).flatten().boxed_local())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use actix_web::{
+        body::{BodySize, MessageBody},
+        test::{TestRequest, call_service, read_body},
+    };
+
+    use super::*;
+    use crate::{
+        config::server::{
+            DirectoryListingRule, FallbackRule, FileHandlesConfig, HeaderRule, HomePageConfig,
+            RenderRule,
+        },
+        fixtures::{
+            FIXTURE_FILES, Fixture, HELLO_TXT, PAGE_HTML, REPORT_JSON, SITE_CSS, app,
+            with_team_realm,
+        },
+    };
+
+    fn header_rule(pattern: &str, name: &str, value: &str) -> HeaderRule {
+        HeaderRule {
+            pattern: pattern.to_string(),
+            headers: BTreeMap::from([(name.to_string(), value.to_string())]),
+            private_headers: BTreeMap::new(),
+        }
+    }
+
+    async fn fixture() -> Fixture {
+        let mut config = ServerConfig::default();
+        config.headers.extend([
+            header_rule("site/**", "Cache-Control", "public, max-age=3600"),
+            header_rule("site/styles/*.css", "Cache-Control", "public, immutable"),
+            header_rule("*.json", "Cache-Control", "no-cache"),
+        ]);
+        Fixture::seeded(config).await
+    }
+
+    #[actix_web::test]
+    async fn only_changes_to_a_files_own_url_need_credentials() {
+        let fixture = Fixture::seeded(with_team_realm(ServerConfig::default())).await;
+        let app = app(&fixture).await;
+        let uri = format!("/{}", HELLO_TXT.path);
+
+        for req in [
+            TestRequest::post().uri(&uri),
+            TestRequest::put().uri(&uri).set_payload("changed"),
+            TestRequest::delete().uri(&uri),
+        ] {
+            let res = call_service(&app, req.to_request()).await;
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        }
+
+        let res = call_service(&app, TestRequest::get().uri(&uri).to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(read_body(res).await, HELLO_TXT.contents);
+
+        // the same changes under /api, where they started out
+        let api_uri = format!("/api/{}", HELLO_TXT.path);
+        let req = TestRequest::delete().uri(&api_uri);
+        let res = call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        for (req, status) in [
+            (
+                TestRequest::put().uri(&api_uri).set_payload("changed"),
+                StatusCode::CREATED,
+            ),
+            (TestRequest::delete().uri(&uri), StatusCode::OK),
+            (TestRequest::delete().uri(&api_uri), StatusCode::NOT_FOUND),
+        ] {
+            let req = req.insert_header(("X-API-Key", "admin-key")).to_request();
+            assert_eq!(call_service(&app, req).await.status(), status);
+        }
+    }
+
+    #[actix_web::test]
+    async fn serves_files_with_the_exact_headers() {
+        let fixture = fixture().await;
+        let app = app(&fixture).await;
+
+        // (file, Content-Type, Cache-Control)
+        let golden = [
+            (HELLO_TXT, "text/plain", None),
+            // never rendered unless it's an index, a fallback or a render rule allows it
+            (
+                PAGE_HTML,
+                "text/plain; charset=utf-8",
+                Some("public, max-age=3600"),
+            ),
+            (REPORT_JSON, "application/json", Some("no-cache")),
+            (SITE_CSS, "text/css", Some("public, immutable")),
+        ];
+
+        for (file, content_type, cache_control) in golden {
+            let req = TestRequest::get()
+                .uri(&format!("/{}", file.path))
+                .insert_header((header::ORIGIN, "https://app.example.com"))
+                .to_request();
+            let res = call_service(&app, req).await;
+            assert_eq!(res.status(), StatusCode::OK, "for {}", file.path);
+
+            let headers = res.headers();
+            let header = |name| headers.get(name).map(|v| v.to_str().unwrap());
+            let etag = format!("\"{}\"", file.hash);
+            assert_eq!(
+                header(header::ETAG),
+                Some(etag.as_str()),
+                "for {}",
+                file.path
+            );
+            assert_eq!(
+                header(header::CONTENT_TYPE),
+                Some(content_type),
+                "for {}",
+                file.path
+            );
+            assert_eq!(
+                header(header::CACHE_CONTROL),
+                cache_control,
+                "for {}",
+                file.path
+            );
+            // any site's scripts may read files by default
+            assert_eq!(
+                header(header::ACCESS_CONTROL_ALLOW_ORIGIN),
+                Some("*"),
+                "for {}",
+                file.path
+            );
+            // sent as Content-Length, rather than with chunked encoding
+            assert_eq!(
+                res.response().body().size(),
+                BodySize::Sized(file.contents.len() as u64),
+                "for {}",
+                file.path
+            );
+            assert_eq!(read_body(res).await, file.contents, "for {}", file.path);
+        }
+
+        let req = TestRequest::get()
+            .uri(&format!("/{}?dl=1", HELLO_TXT.path))
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(
+            res.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/octet-stream"
+        );
+    }
+
+    #[actix_web::test]
+    async fn answers_matching_etags_with_not_modified() {
+        let fixture = fixture().await;
+        let app = app(&fixture).await;
+
+        for file in &FIXTURE_FILES {
+            let req = TestRequest::get()
+                .uri(&format!("/{}", file.path))
+                .insert_header((header::IF_NONE_MATCH, format!("\"{}\"", file.hash)))
+                .to_request();
+            let res = call_service(&app, req).await;
+            assert_eq!(res.status(), StatusCode::NOT_MODIFIED, "for {}", file.path);
+        }
+
+        // any of a list matches, weakly compared, as does anything at all
+        for if_none_match in [
+            format!("\"other\", W/\"{}\"", HELLO_TXT.hash),
+            "*".to_string(),
+        ] {
+            let req = TestRequest::get()
+                .uri(&format!("/{}", HELLO_TXT.path))
+                .insert_header((header::IF_NONE_MATCH, if_none_match.as_str()))
+                .to_request();
+            let res = call_service(&app, req).await;
+            assert_eq!(
+                res.status(),
+                StatusCode::NOT_MODIFIED,
+                "for {if_none_match}"
+            );
+        }
+        let req = TestRequest::get()
+            .uri(&format!("/{}", HELLO_TXT.path))
+            .insert_header((header::IF_NONE_MATCH, "\"other\""))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn answers_files_unchanged_since_with_not_modified() {
+        let fixture = fixture().await;
+        let app = app(&fixture).await;
+
+        let uri = format!("/{}", HELLO_TXT.path);
+        let res = call_service(&app, TestRequest::get().uri(&uri).to_request()).await;
+        let last_modified = res.headers().get(header::LAST_MODIFIED).unwrap().clone();
+
+        let req = TestRequest::get()
+            .uri(&uri)
+            .insert_header((header::IF_MODIFIED_SINCE, last_modified))
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+
+        let req = TestRequest::get()
+            .uri(&uri)
+            .insert_header((header::IF_MODIFIED_SINCE, "Sat, 01 Jan 2000 00:00:00 GMT"))
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn serves_the_ranges_asked_for() {
+        let fixture = fixture().await;
+        let app = app(&fixture).await;
+        let uri = format!("/{}", HELLO_TXT.path);
+        let request = |range: &str| {
+            TestRequest::get()
+                .uri(&uri)
+                .insert_header((header::RANGE, range))
+        };
+
+        let res = call_service(&app, request("bytes=7-11").to_request()).await;
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        let content_range = res.headers().get(header::CONTENT_RANGE).unwrap();
+        assert_eq!(content_range, "bytes 7-11/14");
+        assert_eq!(read_body(res).await, "world");
+
+        let res = call_service(&app, request("bytes=0-4,-2").to_request()).await;
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        let content_type = res.headers().get(header::CONTENT_TYPE).unwrap();
+        let content_type = content_type.to_str().unwrap().to_string();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap();
+        let expected = format!(
+            "\r\n--{boundary}\r\ncontent-type: text/plain\r\ncontent-range: bytes 0-4/14\r\n\r\nHello\
+             \r\n--{boundary}\r\ncontent-type: text/plain\r\ncontent-range: bytes 12-13/14\r\n\r\n!\n\
+             \r\n--{boundary}--\r\n"
+        );
+        assert_eq!(read_body(res).await, expected);
+
+        let res = call_service(&app, request("bytes=14-").to_request()).await;
+        assert_eq!(res.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(
+            res.headers().get(header::CONTENT_RANGE).unwrap(),
+            "bytes */14"
+        );
+
+        // ranges of a version that's since been replaced get the whole file instead
+        let req = request("bytes=7-11")
+            .insert_header((header::IF_RANGE, "outdated"))
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(read_body(res).await, HELLO_TXT.contents);
+        for (if_range, status) in [
+            (HELLO_TXT.hash.to_string(), StatusCode::PARTIAL_CONTENT),
+            (
+                format!("\"{}\"", HELLO_TXT.hash),
+                StatusCode::PARTIAL_CONTENT,
+            ),
+            (format!("W/\"{}\"", HELLO_TXT.hash), StatusCode::OK),
+        ] {
+            let req = request("bytes=7-11")
+                .insert_header((header::IF_RANGE, if_range.as_str()))
+                .to_request();
+            let res = call_service(&app, req).await;
+            assert_eq!(res.status(), status, "for {if_range}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn previews_the_start_of_text_files() {
+        let fixture = fixture().await;
+        let app = app(&fixture).await;
+
+        let req = TestRequest::get()
+            .uri(&format!("/{}?preview_bytes=5", HELLO_TXT.path))
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers().get(header::CONTENT_TYPE).unwrap(),
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(res.headers().get(PREVIEW_TRUNCATED).unwrap(), "true");
+        assert_eq!(read_body(res).await, "Hello");
+
+        // pages are previewed as the text they are
+        let req = TestRequest::get()
+            .uri(&format!("/{}?preview=200", PAGE_HTML.path))
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(
+            res.headers().get(header::CONTENT_TYPE).unwrap(),
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(res.headers().get(PREVIEW_TRUNCATED).unwrap(), "false");
+        assert_eq!(read_body(res).await, PAGE_HTML.contents);
+    }
+
+    #[actix_web::test]
+    async fn highlights_source_files_on_request() {
+        let fixture = fixture().await;
+        let app = app(&fixture).await;
+
+        let req = TestRequest::get()
+            .uri(&format!("/{}?highlight=1", SITE_CSS.path))
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers().get(header::CONTENT_TYPE).unwrap(),
+            "text/html; charset=utf-8"
+        );
+        assert!(res.headers().contains_key(header::CONTENT_SECURITY_POLICY));
+        assert_eq!(
+            res.headers().get(header::ETAG).unwrap().to_str().unwrap(),
+            format!("\"{}-hl\"", SITE_CSS.hash)
+        );
+        let body = read_body(res).await;
+        assert!(std::str::from_utf8(&body).unwrap().contains("<pre style="));
+
+        // without it, the file as it is
+        let req = TestRequest::get()
+            .uri(&format!("/{}?highlight=0", SITE_CSS.path))
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(read_body(res).await, SITE_CSS.contents);
+    }
+
+    #[actix_web::test]
+    async fn answers_head_requests_with_the_headers_alone() {
+        let fixture = fixture().await;
+        let app = app(&fixture).await;
+        let uri = format!("/{}", REPORT_JSON.path);
+
+        let get = call_service(&app, TestRequest::get().uri(&uri).to_request()).await;
+        let head = call_service(
+            &app,
+            TestRequest::default()
+                .method(Method::HEAD)
+                .uri(&uri)
+                .to_request(),
+        )
+        .await;
+        assert_eq!(head.status(), StatusCode::OK);
+        for name in [
+            header::ETAG,
+            header::CONTENT_TYPE,
+            header::CACHE_CONTROL,
+            header::LAST_MODIFIED,
+        ] {
+            assert_eq!(
+                head.headers().get(&name),
+                get.headers().get(&name),
+                "for {name}"
+            );
+        }
+        assert_eq!(
+            head.response().body().size(),
+            BodySize::Sized(REPORT_JSON.contents.len() as u64)
+        );
+        assert!(read_body(head).await.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn lists_directories_only_where_enabled() {
+        let config = ServerConfig {
+            directory_listings: vec![
+                DirectoryListingRule {
+                    prefix: "site".to_string(),
+                    enabled: true,
+                },
+                DirectoryListingRule {
+                    prefix: "site/styles".to_string(),
+                    enabled: false,
+                },
+            ],
+            ..Default::default()
+        };
+        let fixture = Fixture::seeded(config).await;
+        let app = app(&fixture).await;
+
+        let req = TestRequest::get()
+            .uri("/site")
+            .insert_header((header::ACCEPT, "application/json"))
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let listing: serde_json::Value = serde_json::from_slice(&read_body(res).await).unwrap();
+        assert_eq!(listing[0]["name"], "page.html");
+        assert_eq!(listing[0]["hash"], PAGE_HTML.hash);
+        assert_eq!(listing[0]["size_bytes"], PAGE_HTML.contents.len());
+        assert_eq!(listing[1]["name"], "styles");
+        assert_eq!(listing[1]["kind"], "dir");
+
+        let req = TestRequest::get()
+            .uri("/site")
+            .insert_header((header::ACCEPT, "text/html"))
+            .to_request();
+        let res = call_service(&app, req).await;
+        let page = String::from_utf8(read_body(res).await.to_vec()).unwrap();
+        assert!(page.contains("<a href=\"/site/page.html\">page.html</a>"));
+        assert!(page.contains("<a href=\"/site/styles\">styles/</a>"));
+
+        for dir in ["/site/styles", "/data"] {
+            let req = TestRequest::get().uri(dir).to_request();
+            let res = call_service(&app, req).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND, "for {dir}");
+        }
+
+        // everything under it, subdirectories included, stored as is
+        let req = TestRequest::get().uri("/site?zip=1").to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/zip"
+        );
+        let zip = read_body(res).await;
+        let contains = |needle: &[u8]| zip.windows(needle.len()).any(|w| w == needle);
+        assert!(contains(b"page.html"));
+        assert!(contains(b"styles/site.css"));
+        assert!(contains(SITE_CSS.contents));
+        let req = TestRequest::get().uri("/data?zip=1").to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn sends_the_digest_of_files_unless_turned_down_or_encoded() {
+        let fixture = fixture().await;
+        let app = app(&fixture).await;
+        let digest = |headers: &[(HeaderName, &str)]| {
+            let mut req = TestRequest::get().uri(&format!("/{}", HELLO_TXT.path));
+            for header in headers {
+                req = req.insert_header(header.clone());
+            }
+            let app = &app;
+            async move {
+                let res = call_service(app, req.to_request()).await;
+                let digest = res.headers().get(REPR_DIGEST);
+                digest.map(|digest| digest.to_str().unwrap().to_string())
+            }
+        };
+
+        let expected = "sha-256=:2QFMRiSESqW6wxR3PWtomtRn+k4dGlChuKmdWpX3L/U=:";
+        assert_eq!(digest(&[]).await.as_deref(), Some(expected));
+        assert_eq!(
+            digest(&[(WANT_REPR_DIGEST, "sha-512=3, sha-256=1")])
+                .await
+                .as_deref(),
+            Some(expected)
+        );
+        assert_eq!(digest(&[(WANT_REPR_DIGEST, "sha-256=0")]).await, None);
+        assert_eq!(digest(&[(WANT_REPR_DIGEST, "sha-512=10")]).await, None);
+        assert_eq!(digest(&[(header::ACCEPT_ENCODING, "gzip")]).await, None);
+    }
+
+    #[actix_web::test]
+    async fn serves_the_index_of_directories() {
+        let config = ServerConfig {
+            index_files: vec!["index.html".to_string(), "page.html".to_string()],
+            ..Default::default()
+        };
+        let fixture = Fixture::seeded(config).await;
+        let app = app(&fixture).await;
+
+        // relative links in it have to resolve against the directory
+        let req = TestRequest::get().uri("/site?a=1").to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(res.headers().get(header::LOCATION).unwrap(), "/site/?a=1");
+
+        let req = TestRequest::get().uri("/site/").to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers().get(header::CONTENT_TYPE).unwrap(),
+            "text/html"
+        );
+        assert_eq!(read_body(res).await, PAGE_HTML.contents);
+
+        let req = TestRequest::get().uri("/data/").to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn missing_files_get_the_not_found_page() {
+        let fallback = |prefix: &str, fallback: &str, not_found| FallbackRule {
+            prefix: prefix.to_string(),
+            fallback: fallback.to_string(),
+            not_found,
+        };
+        let config = ServerConfig {
+            fallbacks: vec![
+                fallback("site", "page.html", true),
+                fallback("site/app", "../page.html", false),
+            ],
+            ..Default::default()
+        };
+        let fixture = Fixture::seeded(config).await;
+        let app = app(&fixture).await;
+
+        let req = TestRequest::get().uri("/site/missing.css").to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            res.headers().get(header::CONTENT_TYPE).unwrap(),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(read_body(res).await, PAGE_HTML.contents);
+
+        // a longer prefix's app fallback still answers as the page itself
+        let req = TestRequest::get().uri("/site/app/route").to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(read_body(res).await, PAGE_HTML.contents);
+
+        // outside the prefix, the built-in one
+        let req = TestRequest::get().uri("/data/missing.json").to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(read_body(res).await, "File does not exist");
+    }
+
+    #[actix_web::test]
+    async fn render_rules_let_html_render_with_their_policy() {
+        let config = ServerConfig {
+            render: vec![RenderRule {
+                pattern: "site/**".to_string(),
+                content_security_policy: Some("script-src 'none'".to_string()),
+            }],
+            ..Default::default()
+        };
+        let fixture = Fixture::seeded(config).await;
+        let app = app(&fixture).await;
+
+        let req = TestRequest::get()
+            .uri(&format!("/{}", PAGE_HTML.path))
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers().get(header::CONTENT_TYPE).unwrap(),
+            "text/html"
+        );
+        assert_eq!(
+            res.headers().get(header::CONTENT_SECURITY_POLICY).unwrap(),
+            "script-src 'none'"
+        );
+
+        // only markup is sent with the policy
+        let req = TestRequest::get()
+            .uri(&format!("/{}", SITE_CSS.path))
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.headers().get(header::CONTENT_TYPE).unwrap(), "text/css");
+        assert!(!res.headers().contains_key(header::CONTENT_SECURITY_POLICY));
+    }
+
+    #[test]
+    fn svg_and_xhtml_count_as_markup() {
+        for (path, markup) in [
+            ("logo.svg", true),
+            ("page.xhtml", true),
+            ("page.htm", true),
+            ("logo.png", false),
+            ("notes.txt", false),
+        ] {
+            let mime = mime_guess::from_path(path).first().unwrap();
+            assert_eq!(is_markup(&mime), markup, "for {path}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn downloads_are_refused_at_the_file_handle_limit() {
+        let config = ServerConfig {
+            file_handles: FileHandlesConfig { max_open: 1 },
+            ..Default::default()
+        };
+        let fixture = Fixture::seeded(config).await;
+        let app = app(&fixture).await;
+
+        // not read yet, so its file is still open
+        let req = TestRequest::get()
+            .uri(&format!("/{}", HELLO_TXT.path))
+            .to_request();
+        let downloading = call_service(&app, req).await;
+        assert_eq!(downloading.status(), StatusCode::OK);
+
+        let req = TestRequest::get()
+            .uri(&format!("/{}", REPORT_JSON.path))
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers().get(header::RETRY_AFTER).unwrap(), "1");
+    }
+
+    #[actix_web::test]
+    async fn the_root_answers_with_the_home_page() {
+        let config = ServerConfig {
+            home_page: HomePageConfig {
+                enabled: true,
+                links: Vec::new(),
+            },
+            ..Default::default()
+        };
+        let fixture = Fixture::seeded(config).await;
+        let app = app(&fixture).await;
+
+        let req = TestRequest::get().uri("/").to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let status: serde_json::Value = serde_json::from_slice(&read_body(res).await).unwrap();
+        assert_eq!(status["files"], FIXTURE_FILES.len());
+        assert_eq!(
+            status["total_bytes"],
+            FIXTURE_FILES
+                .iter()
+                .map(|file| file.contents.len())
+                .sum::<usize>()
+        );
+
+        // no upload form without credentials to upload with
+        let req = TestRequest::get()
+            .uri("/")
+            .insert_header((header::ACCEPT, "text/html"))
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let page = String::from_utf8(read_body(res).await.to_vec()).unwrap();
+        assert!(page.contains("Serving 4 files"));
+        assert!(!page.contains("<form"));
+    }
 }
@@ -0,0 +1,211 @@
+use std::{io, path::PathBuf, time::Duration};
+
+use actix_web::{
+    HttpResponse, Result, get,
+    http::header,
+    post,
+    web::{Data, Query, ReqData},
+};
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    SharedFileStore,
+    access_times::SharedAccessTimes,
+    authorized::{AuthPayload, Permission},
+    file_store::{EntryKind, FileStorageCore, FileStore},
+    hooks::SharedHooks,
+    jobs::SharedJobRegistry,
+    mirror::SharedStandby,
+    routes::{
+        jobs::{bulk_delete, bulk_delete_preview},
+        upload_file::mirror_read_only,
+    },
+};
+
+#[derive(Deserialize)]
+pub struct StaleQuery {
+    /// How long a file has gone without being downloaded, e.g. `90d`, `12h` or `3600`
+    older_than: String,
+    /// Answers with what would be deleted instead, leaving it all as it is
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// A file nobody downloaded for a while.
+#[derive(Serialize)]
+struct StaleFile {
+    path: PathBuf,
+    size_bytes: u64,
+    /// When it was last downloaded or uploaded, whichever came later, as far as it's known
+    last_used_at: DateTime<Utc>,
+}
+
+/// Lists the files not downloaded within `?older_than=`, along with how much deleting them
+/// would free up.
+#[get("/admin/stale")]
+pub async fn stale_files(
+    query: Query<StaleQuery>,
+    auth: ReqData<AuthPayload>,
+    file_store: Data<SharedFileStore>,
+    access_times: Data<SharedAccessTimes>,
+) -> Result<HttpResponse> {
+    auth.require(Permission::Stale)?;
+
+    let Some(older_than) = parse_age(&query.older_than) else {
+        return Ok(HttpResponse::BadRequest().body("Invalid 'older_than', e.g. 90d, 12h or 3600"));
+    };
+    Ok(
+        match find_stale(&file_store, &access_times, older_than).await {
+            Ok(stale) => HttpResponse::Ok().json(json!({
+                "older_than_secs": older_than.as_secs(),
+                "tracked_since": access_times.tracked_since(),
+                "total_bytes": stale.iter().map(|file| file.size_bytes).sum::<u64>(),
+                "files": stale,
+            })),
+            Err(err) => stale_error_response(err),
+        },
+    )
+}
+
+/// Deletes every file `GET /admin/stale` lists for the same `?older_than=`, as a bulk delete job.
+#[post("/admin/stale")]
+pub async fn delete_stale_files(
+    query: Query<StaleQuery>,
+    auth: ReqData<AuthPayload>,
+    file_store: Data<SharedFileStore>,
+    access_times: Data<SharedAccessTimes>,
+    jobs: Data<SharedJobRegistry>,
+    hooks: Data<SharedHooks>,
+    standby: Data<SharedStandby>,
+) -> Result<HttpResponse> {
+    auth.require(Permission::Stale)?;
+
+    if let Some(response) = mirror_read_only(&standby) {
+        return Ok(response);
+    }
+    let Some(older_than) = parse_age(&query.older_than) else {
+        return Ok(HttpResponse::BadRequest().body("Invalid 'older_than', e.g. 90d, 12h or 3600"));
+    };
+    let stale = match find_stale(&file_store, &access_times, older_than).await {
+        Ok(stale) => stale,
+        Err(err) => return Ok(stale_error_response(err)),
+    };
+
+    let paths: Vec<_> = stale.into_iter().map(|file| file.path).collect();
+    if query.dry_run {
+        return Ok(HttpResponse::Ok().json(bulk_delete_preview(&file_store, &paths).await));
+    }
+
+    let store = SharedFileStore::clone(&file_store);
+    let hooks = SharedHooks::clone(&hooks);
+    let id = jobs.spawn("bulk_delete", |handle| {
+        bulk_delete(handle, store, hooks, paths)
+    });
+
+    Ok(HttpResponse::Accepted()
+        .insert_header((header::LOCATION, format!("/api/jobs/{id}")))
+        .json(json!({ "id": id })))
+}
+
+/// Walks the whole store for the files last used longer than `older_than` ago, least
+/// recently used first. Files never downloaded count from when they were uploaded, or from
+/// when downloads started being recorded if that was later.
+async fn find_stale(
+    store: &FileStore,
+    access_times: &SharedAccessTimes,
+    older_than: Duration,
+) -> io::Result<Vec<StaleFile>> {
+    let Some(tracked_since) = access_times.tracked_since() else {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "download times aren't being recorded",
+        ));
+    };
+    // nothing has been around since before the dawn of time
+    let Some(cutoff) = TimeDelta::from_std(older_than)
+        .ok()
+        .and_then(|age| Utc::now().checked_sub_signed(age))
+    else {
+        return Ok(Vec::new());
+    };
+    let accessed = access_times.all()?;
+
+    let mut stale = Vec::new();
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(dir) = dirs.pop() {
+        for entry in store.list(&dir).await? {
+            let path = dir.join(&entry.name);
+            if entry.kind == EntryKind::Dir {
+                dirs.push(path);
+                continue;
+            }
+
+            let accessed_at = accessed
+                .get(path.to_string_lossy().as_ref())
+                .copied()
+                .unwrap_or(tracked_since);
+            let last_used_at = entry
+                .modified_at
+                .map_or(accessed_at, |t| t.max(accessed_at));
+            if last_used_at < cutoff {
+                stale.push(StaleFile {
+                    path,
+                    size_bytes: entry.size_bytes.unwrap_or_default(),
+                    last_used_at,
+                });
+            }
+        }
+    }
+
+    stale.sort_by_key(|file| file.last_used_at);
+    Ok(stale)
+}
+
+/// Parses a number of seconds, optionally followed by a unit from `s` up to `w`.
+fn parse_age(age: &str) -> Option<Duration> {
+    let age = age.trim();
+    let (number, unit_secs) = match age.char_indices().last()? {
+        (at, 's') => (&age[..at], 1),
+        (at, 'm') => (&age[..at], 60),
+        (at, 'h') => (&age[..at], 60 * 60),
+        (at, 'd') => (&age[..at], 24 * 60 * 60),
+        (at, 'w') => (&age[..at], 7 * 24 * 60 * 60),
+        _ => (age, 1),
+    };
+    let secs = number.parse::<u64>().ok()?.checked_mul(unit_secs)?;
+    Some(Duration::from_secs(secs))
+}
+
+fn stale_error_response(err: io::Error) -> HttpResponse {
+    match err.kind() {
+        io::ErrorKind::Unsupported => HttpResponse::Conflict().body(err.to_string()),
+        _ => {
+            log::error!("Error looking for stale files: {err}");
+            HttpResponse::InternalServerError().body("Failed to look for stale files")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ages_are_parsed_with_or_without_a_unit() {
+        assert_eq!(
+            parse_age("90d"),
+            Some(Duration::from_secs(90 * 24 * 60 * 60))
+        );
+        assert_eq!(parse_age("12h"), Some(Duration::from_secs(12 * 60 * 60)));
+        assert_eq!(parse_age("3600"), Some(Duration::from_secs(3600)));
+        assert_eq!(
+            parse_age("2w"),
+            Some(Duration::from_secs(14 * 24 * 60 * 60))
+        );
+        assert_eq!(parse_age("d"), None);
+        assert_eq!(parse_age("-1d"), None);
+        assert_eq!(parse_age(""), None);
+    }
+}
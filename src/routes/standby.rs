@@ -0,0 +1,63 @@
+use std::path::Path;
+
+use actix_web::{
+    HttpResponse, Result, post,
+    web::{Data, Json, ReqData},
+};
+use serde_json::json;
+
+use crate::{
+    SharedFileStore,
+    authorized::{AuthPayload, Permission},
+    mirror::SharedStandby,
+    outbox::Event,
+};
+
+/// Takes a webhook event from the upstream, syncing the one file it's about rather than
+/// waiting for the next full sync.
+#[post("/standby/events")]
+pub async fn replicate(
+    event: Json<Event>,
+    auth: ReqData<AuthPayload>,
+    standby: Data<SharedStandby>,
+    file_store: Data<SharedFileStore>,
+) -> Result<HttpResponse> {
+    auth.require(Permission::Replicate)?;
+
+    let Some(mirror) = standby.following() else {
+        return Ok(HttpResponse::Conflict().body("Not following an upstream"));
+    };
+
+    Ok(
+        match mirror.sync_file(&file_store, Path::new(&event.path)).await {
+            Ok(Some(report)) => HttpResponse::Ok().json(report),
+            // the upstream retries the delivery, by which time the sync will be done
+            Ok(None) => HttpResponse::ServiceUnavailable().body("A sync is already running"),
+            Err(err) => {
+                log::error!("Error syncing '{}' from the upstream: {err}", event.path);
+                HttpResponse::InternalServerError().body("Failed to sync file")
+            }
+        },
+    )
+}
+
+/// Stops following the upstream for good, making the files writable.
+#[post("/admin/promote")]
+pub async fn promote(
+    auth: ReqData<AuthPayload>,
+    standby: Data<SharedStandby>,
+) -> Result<HttpResponse> {
+    auth.require(Permission::Promote)?;
+
+    Ok(match standby.promote().await {
+        Ok(true) => {
+            log::warn!("Promoted to take over from the mirror's upstream");
+            HttpResponse::Ok().json(json!({ "promoted": true }))
+        }
+        Ok(false) => HttpResponse::Conflict().body("Not following an upstream"),
+        Err(err) => {
+            log::error!("Error promoting the mirror: {err}");
+            HttpResponse::InternalServerError().body("Failed to promote the mirror")
+        }
+    })
+}
@@ -0,0 +1,77 @@
+use std::io;
+
+use actix_web::{
+    HttpRequest, HttpResponse, Result, get,
+    http::header,
+    post,
+    web::{Data, ReqData},
+};
+
+use crate::{
+    SharedFileStore,
+    attributes::SharedAttributes,
+    authorized::{AuthPayload, Permission, payload_for},
+    hooks::SharedHooks,
+    mirror::SharedStandby,
+    routes::{
+        etag,
+        file_path::{FilePath, encode_path},
+        upload_file::{mirror_read_only, upload_conflict},
+    },
+    trash::SharedTrash,
+};
+
+#[get("/trash")]
+pub async fn trashed(auth: ReqData<AuthPayload>, trash: Data<SharedTrash>) -> Result<HttpResponse> {
+    auth.require(Permission::Trash)?;
+
+    Ok(match trash.list().await {
+        Ok(trashed) => HttpResponse::Ok().json(trashed),
+        Err(err) => {
+            log::error!("Error listing the trash: {err}");
+            HttpResponse::InternalServerError().body("Failed to list the trash")
+        }
+    })
+}
+
+#[post("/restore/{path:.*}")]
+pub async fn restore_file(
+    req: HttpRequest,
+    path: FilePath,
+    trash: Data<SharedTrash>,
+    file_store: Data<SharedFileStore>,
+    attributes: Data<SharedAttributes>,
+    hooks: Data<SharedHooks>,
+    standby: Data<SharedStandby>,
+) -> Result<HttpResponse> {
+    payload_for(&req, &path).await?.require(Permission::Trash)?;
+
+    if let Some(response) = mirror_read_only(&standby) {
+        return Ok(response);
+    }
+    if let Some(conflict) = upload_conflict(&file_store, &path).await {
+        return Ok(conflict);
+    }
+
+    Ok(match trash.restore(&path, &file_store, &attributes).await {
+        Ok(metadata) => {
+            hooks.post_upload(&path, &metadata).await;
+
+            HttpResponse::Created()
+                .insert_header((header::ETAG, etag::strong(&metadata.hash)))
+                .insert_header((header::LOCATION, format!("/{}", encode_path(&*path))))
+                .finish()
+        }
+        Err(err) => match err.kind() {
+            io::ErrorKind::NotFound => HttpResponse::NotFound().body(err.to_string()),
+            io::ErrorKind::InvalidInput => {
+                HttpResponse::BadRequest().body(format!("Invalid input: {err}"))
+            }
+            io::ErrorKind::StorageFull => HttpResponse::InsufficientStorage().body(err.to_string()),
+            _ => {
+                log::error!("Error restoring file: {err}");
+                HttpResponse::InternalServerError().body("Failed to restore file")
+            }
+        },
+    })
+}
@@ -1,62 +1,697 @@
 use std::{
-    io::{self, BufReader},
-    path::PathBuf,
+    future::{Ready, ready},
+    io,
+    path::{Path, PathBuf},
+    time::Duration,
 };
 
-use actix_multipart::form::{MultipartForm, tempfile::TempFile};
+use actix_multipart::{Field, Multipart, MultipartError};
 use actix_web::{
-    HttpResponse, Responder, delete, post,
-    web::{self, Data},
+    FromRequest, HttpRequest, HttpResponse, Responder,
+    body::to_bytes,
+    delete,
+    dev::Payload,
+    http::header::{self, HeaderName},
+    post,
+    web::{self, Data, Query},
 };
+use chrono::Utc;
+use futures::{StreamExt, TryStreamExt};
+use percent_encoding::{NON_ALPHANUMERIC, utf8_percent_encode};
+use serde::Deserialize;
+use serde_json::json;
+use uuid::Uuid;
 
-use crate::{SharedFileStore, file_store::FileStorageCore};
+use crate::{
+    SharedFileStore,
+    attributes::{FileAttributes, SharedAttributes},
+    authorized::{AuthPayload, payload_for},
+    config::server::{HashAlgorithm, ServerConfig, UploadProfile},
+    file_store::{Entry, FileStorageCore, FileStore, StagedUpload},
+    hooks::SharedHooks,
+    mirror::{SharedStandby, Standby},
+    quarantine::Quarantine,
+    routes::{
+        app_data, etag,
+        file_path::{FilePath, encode_path},
+        jobs::{DryRunQuery, bulk_delete_preview},
+    },
+    throttle::UploadThrottle,
+    transfers::{TransferClient, TransferKind, Transfers},
+    trash::SharedTrash,
+};
+
+/// Name of the multipart field holding the file contents.
+const FILE_FIELD: &str = "file";
+
+/// Name of the optional multipart field with a page on this server to send a browser on to
+/// once the upload is done, for plain HTML forms.
+const REDIRECT_FIELD: &str = "redirect_to";
+
+// sent along with a committed upload whose metadata couldn't be written yet, which a restart
+// before the retries get it written would lose
+const METADATA_PENDING: HeaderName = HeaderName::from_static("x-metadata-pending");
+
+#[derive(Deserialize)]
+pub struct UploadOptions {
+    /// Name of an upload profile from the config
+    profile: Option<String>,
+}
+
+/// Where an upload is put and the profile it's made with, if the request picked one.
+pub fn apply_profile<'a>(
+    path: &Path,
+    options: &UploadOptions,
+    config: &'a ServerConfig,
+) -> Result<(PathBuf, Option<&'a UploadProfile>), HttpResponse> {
+    let Some(name) = &options.profile else {
+        return Ok((path.to_path_buf(), None));
+    };
+    let Some(profile) = config.upload_profiles.get(name) else {
+        return Err(HttpResponse::BadRequest().body(format!("No upload profile named '{name}'")));
+    };
+
+    let mut target = Path::new(profile.prefix.trim_matches('/')).join(path);
+    if profile.random_name {
+        let mut name = Uuid::new_v4().simple().to_string();
+        if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
+            name = format!("{name}.{extension}");
+        }
+        target.set_file_name(name);
+    }
+
+    Ok((target, Some(profile)))
+}
 
-#[derive(Debug, MultipartForm)]
-struct UploadFileForm {
-    file: TempFile,
+/// The app data the routes changing files take, as one extractor.
+pub struct WriteState {
+    pub config: Data<ServerConfig>,
+    pub standby: Data<SharedStandby>,
+    pub file_store: Data<SharedFileStore>,
+    pub quarantine: Data<Quarantine>,
+    pub attributes: Data<SharedAttributes>,
+    pub hooks: Data<SharedHooks>,
+    pub throttle: Data<UploadThrottle>,
+    pub transfers: Data<Transfers>,
+    pub trash: Data<SharedTrash>,
+}
+
+impl FromRequest for WriteState {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let state = || -> actix_web::Result<Self> {
+            Ok(WriteState {
+                config: app_data(req)?,
+                standby: app_data(req)?,
+                file_store: app_data(req)?,
+                quarantine: app_data(req)?,
+                attributes: app_data(req)?,
+                hooks: app_data(req)?,
+                throttle: app_data(req)?,
+                transfers: app_data(req)?,
+                trash: app_data(req)?,
+            })
+        };
+        ready(state())
+    }
 }
 
-// I would love for these routes to only have different HTTP methods
-// with the same path (i.e. GET /:file, POST /:file, and DELETE /:file).
-// However, due to there needing to be different guards/middleware per these routes
-// and an issue with actix-web (https://github.com/actix/actix-web/issues/2904), it can't happen
-// without some hackery on my part, which I don't want to do right now
+// these share the URL files are served from (i.e. GET /:file, POST /:file and DELETE /:file),
+// behind a scope guarded by method since the downloads don't need credentials. They're also
+// still under /api, where they started out
 
 #[post("/{path:.*}")]
 pub async fn upload_file(
-    path: web::Path<String>,
-    MultipartForm(form): MultipartForm<UploadFileForm>,
-    file_store: Data<SharedFileStore>,
+    req: HttpRequest,
+    path: FilePath,
+    options: Query<UploadOptions>,
+    mut multipart: Multipart,
+    state: WriteState,
 ) -> impl Responder {
-    let path = PathBuf::from(path.into_inner());
+    let WriteState {
+        config,
+        standby,
+        file_store,
+        quarantine,
+        attributes,
+        hooks,
+        throttle,
+        transfers,
+        ..
+    } = &state;
+    // only known once the form is read, whatever is turned away before that is answered as is
+    let mut redirect_to = None;
+    let response = async {
+        if let Some(response) = mirror_read_only(standby) {
+            return response;
+        }
 
-    match file_store.upload(&path, BufReader::new(form.file.file.into_file())) {
-        Ok(_) => HttpResponse::Created().finish(),
-        Err(err) if err.kind() == io::ErrorKind::InvalidInput => {
+        let (path, profile) = match apply_profile(&path, &options, config) {
+            Ok(applied) => applied,
+            Err(response) => return response,
+        };
+
+        // checked in the realm of where the file goes, which a profile can move it out of
+        let auth = match payload_for(&req, &path).await {
+            Ok(auth) => auth,
+            Err(err) => return err.error_response(),
+        };
+
+        let target = UploadTarget::for_token(&auth, file_store, quarantine);
+        if let Some(conflict) = target.conflict(&path).await {
+            return conflict;
+        }
+
+        // stream the file field straight into the store, rather than staging it in a temp file
+        let field = loop {
+            match multipart.try_next().await {
+                Ok(Some(field)) if field.name() == Some(FILE_FIELD) => break field,
+                Ok(Some(mut field)) if field.name() == Some(REDIRECT_FIELD) => {
+                    match read_redirect_field(&mut field).await {
+                        Ok(to) => redirect_to = Some(to),
+                        Err(response) => return response,
+                    }
+                }
+                Ok(Some(_)) => continue,
+                Ok(None) => {
+                    return HttpResponse::BadRequest()
+                        .body(format!("Missing '{FILE_FIELD}' field"));
+                }
+                Err(err) => {
+                    return HttpResponse::BadRequest().body(format!("Invalid form: {err}"));
+                }
+            }
+        };
+
+        let stream = field
+            .map(|chunk| chunk.map_err(|err| io::Error::other(err.to_string())))
+            .boxed_local();
+        let stream = throttle.throttle(auth.class(), stream);
+        let stream = transfers.track(
+            TransferKind::Upload,
+            &path,
+            TransferClient::of(&req),
+            stream,
+        );
+        let (stream, scanned) = hooks.scan(&path, &config.scanning, declared_size(&req), stream);
+
+        let mut staged = match target
+            .store()
+            .stage_upload(&path, stream, declared_size(&req))
+            .await
+        {
+            Ok(staged) => staged,
+            Err(err) => return upload_error_response(err),
+        };
+        staged.record_scan(scanned.record());
+
+        // clients streaming the file only know its digest once it's sent, so it comes after
+        let expected = match read_trailing_fields(&mut multipart, &mut redirect_to).await {
+            Ok(expected) => expected,
+            Err(response) => return response,
+        };
+
+        let upload = Upload {
+            path: &path,
+            profile,
+            target: &target,
+        };
+        upload.commit(staged, expected, attributes, hooks).await
+    }
+    .await;
+
+    match redirect_to {
+        Some(redirect_to) => see_other(&redirect_to, response).await,
+        None => response,
+    }
+}
+
+/// Where an upload goes, depending on whether the token's uploads need approval first.
+pub enum UploadTarget<'a> {
+    Served(&'a FileStore),
+    Quarantined {
+        quarantine: &'a Quarantine,
+        served: &'a FileStore,
+    },
+}
+
+impl<'a> UploadTarget<'a> {
+    pub fn for_token(
+        auth: &AuthPayload,
+        served: &'a FileStore,
+        quarantine: &'a Quarantine,
+    ) -> Self {
+        if auth.is_quarantined() {
+            UploadTarget::Quarantined { quarantine, served }
+        } else {
+            UploadTarget::Served(served)
+        }
+    }
+
+    pub fn store(&self) -> &'a FileStore {
+        match self {
+            UploadTarget::Served(store) => store,
+            UploadTarget::Quarantined { quarantine, .. } => quarantine.store(),
+        }
+    }
+
+    pub async fn conflict(&self, path: &Path) -> Option<HttpResponse> {
+        match self {
+            UploadTarget::Served(store) => upload_conflict(store, path).await,
+            // checked against the served files too, so approving it later has a chance
+            UploadTarget::Quarantined { quarantine, served } => {
+                match upload_conflict(served, path).await {
+                    Some(conflict) => Some(conflict),
+                    None => upload_conflict(quarantine.store(), path).await,
+                }
+            }
+        }
+    }
+}
+
+pub struct Upload<'a> {
+    pub path: &'a Path,
+    pub profile: Option<&'a UploadProfile>,
+    pub target: &'a UploadTarget<'a>,
+}
+
+impl Upload<'_> {
+    /// Commits `staged` unless its hash differs from the `expected` hex digest.
+    pub async fn commit(
+        &self,
+        staged: StagedUpload<'_>,
+        expected: Option<Checksum>,
+        attributes: &SharedAttributes,
+        hooks: &SharedHooks,
+    ) -> HttpResponse {
+        // a form's checksum field comes after the file, so it's only known by now
+        if let Some((algorithm, _)) = &expected
+            && *algorithm != staged.metadata().algorithm
+        {
+            return HttpResponse::BadRequest()
+                .body(cant_check(*algorithm, staged.metadata().algorithm));
+        }
+        if let Some((_, expected)) = expected
+            && !expected.eq_ignore_ascii_case(&staged.metadata().hash)
+        {
+            // dropping the staged upload discards it
+            return HttpResponse::BadRequest().body(format!(
+                "Checksum mismatch, expected {expected} but received {}",
+                staged.metadata().hash
+            ));
+        }
+        if let Err(err) = hooks.pre_upload(self.path, staged.metadata()).await {
+            return HttpResponse::UnprocessableEntity().body(err.to_string());
+        }
+
+        // set before the file is visible, a file briefly private by mistake is better than
+        // one briefly public by mistake
+        let (attributes, path) = (SharedAttributes::clone(attributes), self.path.to_path_buf());
+        let kept = self.profile.map(FileAttributes::from_profile);
+        let result = web::block(move || match kept {
+            Some(kept) => attributes.set(&path, &kept),
+            None => attributes.remove(&path),
+        })
+        .await
+        .unwrap_or_else(|err| Err(io::Error::other(err)));
+        if let Err(err) = result {
+            return upload_error_response(err);
+        }
+
+        let metadata = match self.target.store().commit_upload(staged).await {
+            Ok(metadata) => metadata,
+            Err(err) => return upload_error_response(err),
+        };
+        let mut response = match self.target {
+            // nothing is served yet, so there's nothing to tell anyone about either
+            UploadTarget::Quarantined { .. } => HttpResponse::Accepted(),
+            UploadTarget::Served(_) => {
+                hooks.post_upload(self.path, &metadata).await;
+                HttpResponse::Created()
+            }
+        };
+        // served already, though its hash is only kept in memory until it can be written
+        if metadata.pending {
+            response.insert_header((METADATA_PENDING, "true"));
+        }
+
+        match self.target {
+            UploadTarget::Quarantined { .. } => response.json(json!({
+                "quarantined": true,
+                "hash": metadata.hash,
+                "path": self.path.to_string_lossy(),
+            })),
+            // hand back what's needed for conditional requests, without a follow-up lookup
+            UploadTarget::Served(_) => response
+                .insert_header((header::ETAG, etag::strong(&metadata.hash)))
+                .insert_header((header::LOCATION, format!("/{}", encode_path(self.path))))
+                .finish(),
+        }
+    }
+}
+
+/// Turns writes away while the server follows a mirror's upstream, its files only change
+/// through syncs until it's promoted.
+pub fn mirror_read_only(standby: &Standby) -> Option<HttpResponse> {
+    standby.following().is_some().then(|| {
+        HttpResponse::MethodNotAllowed()
+            .insert_header((header::ALLOW, "GET, HEAD"))
+            .body("Files can't be changed on a mirror")
+    })
+}
+
+/// Checks that a file can be put at `path`, i.e. that it isn't a directory and none of its
+/// parents are files.
+pub async fn upload_conflict(file_store: &FileStore, path: &Path) -> Option<HttpResponse> {
+    if matches!(file_store.stat(path).await, Some(Entry::Dir)) {
+        return Some(HttpResponse::Conflict().body("Path is a directory"));
+    }
+
+    for parent in path.ancestors().skip(1) {
+        if matches!(file_store.stat(parent).await, Some(Entry::File(_))) {
+            return Some(HttpResponse::Conflict().body("A parent of the path is a file"));
+        }
+    }
+
+    None
+}
+
+pub fn upload_error_response(err: io::Error) -> HttpResponse {
+    match err.kind() {
+        io::ErrorKind::InvalidInput => {
             HttpResponse::BadRequest().body(format!("Invalid input: {err}"))
         }
-        Err(err) => {
-            eprintln!("Error uploading file: {err}");
+        // read-only sources, such as another instance
+        io::ErrorKind::Unsupported => HttpResponse::MethodNotAllowed()
+            .insert_header((header::ALLOW, "GET, HEAD"))
+            .body(err.to_string()),
+        io::ErrorKind::StorageFull => HttpResponse::InsufficientStorage().body(err.to_string()),
+        // turned away by the scanner of a hook
+        io::ErrorKind::InvalidData => HttpResponse::UnprocessableEntity().body(err.to_string()),
+        // cut short through /api/admin/requests
+        io::ErrorKind::ConnectionAborted => {
+            HttpResponse::ServiceUnavailable().body("Upload was aborted")
+        }
+        _ => {
+            log::error!("Error uploading file: {err}");
             HttpResponse::InternalServerError().body("Failed to upload file")
         }
     }
 }
 
+/// How large the client said the upload is. The `Content-Length` of a form covers its other
+/// fields too, which are next to nothing beside a file large enough for it to matter.
+pub fn declared_size(req: &HttpRequest) -> Option<u64> {
+    req.headers()
+        .get(header::CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+/// A hex digest the upload is expected to have, along with what it was worked out with.
+pub type Checksum = (HashAlgorithm, String);
+
+/// Reads the rest of the form, returning the checksum field's value if there is one, and
+/// picking up `redirect_to` when it comes after the file. The checksum field holds the hex
+/// digest of the file and is named after its algorithm, i.e. `sha256` or `blake3`.
+async fn read_trailing_fields(
+    multipart: &mut Multipart,
+    redirect_to: &mut Option<String>,
+) -> Result<Option<Checksum>, HttpResponse> {
+    let invalid =
+        |err: MultipartError| HttpResponse::BadRequest().body(format!("Invalid form: {err}"));
+    let mut checksum = None;
+
+    while let Some(mut field) = multipart.try_next().await.map_err(invalid)? {
+        if field.name() == Some(REDIRECT_FIELD) {
+            *redirect_to = Some(read_redirect_field(&mut field).await?);
+            continue;
+        }
+
+        let algorithm = field.name().and_then(HashAlgorithm::from_name);
+        // anything longer than a hex digest is wrong anyway, no need to buffer it
+        let value = read_text_field(&mut field, 128).await.map_err(invalid)?;
+        if let Some(algorithm) = algorithm {
+            checksum = Some((algorithm, value));
+        }
+    }
+
+    Ok(checksum)
+}
+
+/// Reads the whole of a text field, keeping no more than about `max_len` bytes of it.
+async fn read_text_field(field: &mut Field, max_len: usize) -> Result<String, MultipartError> {
+    let mut value = Vec::new();
+    while let Some(chunk) = field.try_next().await? {
+        if value.len() < max_len {
+            value.extend_from_slice(&chunk);
+        }
+    }
+    Ok(String::from_utf8_lossy(&value).trim().to_string())
+}
+
+/// Reads the `redirect_to` field, which has to be a path on this server, so that a form
+/// posted here can't be used to send browsers anywhere else.
+async fn read_redirect_field(field: &mut Field) -> Result<String, HttpResponse> {
+    let to = read_text_field(field, 2048)
+        .await
+        .map_err(|err| HttpResponse::BadRequest().body(format!("Invalid form: {err}")))?;
+    if !is_local_path(&to) {
+        return Err(HttpResponse::BadRequest().body(format!(
+            "Invalid '{REDIRECT_FIELD}' field, it has to be a path starting with '/'"
+        )));
+    }
+    Ok(to)
+}
+
+fn is_local_path(to: &str) -> bool {
+    // `//host` and `/\host` are taken by browsers as another host
+    to.starts_with('/')
+        && !to.starts_with("//")
+        && !to.starts_with("/\\")
+        && !to.chars().any(char::is_control)
+}
+
+/// Sends the browser on to `redirect_to` with `303 See Other`, with how the upload went in the
+/// query: the `status` it was answered with, along with the file's `location` and `hash`, or
+/// the `error` it was turned away with.
+async fn see_other(redirect_to: &str, response: HttpResponse) -> HttpResponse {
+    let status = response.status();
+    let mut outcome = vec![("status", status.as_str().to_string())];
+    let header_value = |name| response.headers().get(name).and_then(|v| v.to_str().ok());
+    if let Some(location) = header_value(header::LOCATION) {
+        outcome.push(("location", location.to_string()));
+    }
+    // the hash rather than the ETag made from it
+    if let Some(etag) = header_value(header::ETAG) {
+        outcome.push(("hash", etag::tag_of(etag).to_string()));
+    }
+    if !status.is_success() {
+        let body = to_bytes(response.into_body()).await.unwrap_or_default();
+        outcome.push(("error", String::from_utf8_lossy(&body).into_owned()));
+    }
+
+    // the query goes ahead of any fragment, which browsers keep for the page they land on
+    let (base, fragment) = match redirect_to.split_once('#') {
+        Some((base, fragment)) => (base, format!("#{fragment}")),
+        None => (redirect_to, String::new()),
+    };
+    let separator = if base.contains('?') { '&' } else { '?' };
+    let query = outcome
+        .iter()
+        .map(|(name, value)| format!("{name}={}", utf8_percent_encode(value, NON_ALPHANUMERIC)))
+        .collect::<Vec<_>>()
+        .join("&");
+
+    HttpResponse::SeeOther()
+        .insert_header((
+            header::LOCATION,
+            format!("{base}{separator}{query}{fragment}"),
+        ))
+        .finish()
+}
+
+pub fn cant_check(algorithm: HashAlgorithm, hashed_with: HashAlgorithm) -> String {
+    format!(
+        "Can't check a {} checksum, uploads are hashed with {}",
+        algorithm.as_str(),
+        hashed_with.as_str()
+    )
+}
+
 #[delete("/{path:.*}")]
 pub async fn delete_file(
-    path: web::Path<String>,
-    file_store: Data<SharedFileStore>,
+    req: HttpRequest,
+    path: FilePath,
+    query: Query<DryRunQuery>,
+    state: WriteState,
 ) -> impl Responder {
-    let path = PathBuf::from(path.into_inner());
+    let WriteState {
+        config,
+        standby,
+        file_store,
+        attributes,
+        hooks,
+        trash,
+        ..
+    } = &state;
+    if let Err(err) = payload_for(&req, &path).await {
+        return err.error_response();
+    }
+    if let Some(response) = mirror_read_only(standby) {
+        return response;
+    }
+
+    // nothing is deleted or published for a file that isn't there
+    match file_store.stat(&path).await {
+        Some(Entry::File(_)) => {}
+        Some(Entry::Dir) => return HttpResponse::Conflict().body("Path is a directory"),
+        None => return HttpResponse::NotFound().body("File does not exist"),
+    }
+    if query.dry_run {
+        return HttpResponse::Ok()
+            .json(bulk_delete_preview(file_store, &[path.to_path_buf()]).await);
+    }
+
+    // the file stays as it is until the scheduler gets to it
+    if config.delete_grace_secs > 0 {
+        let delete_at = Utc::now() + Duration::from_secs(config.delete_grace_secs);
+        let marked = {
+            let (attributes, path) = (SharedAttributes::clone(attributes), path.to_path_buf());
+            web::block(move || attributes.mark_deleted(&path, delete_at))
+                .await
+                .unwrap_or_else(|err| Err(io::Error::other(err)))
+        };
+        return match marked {
+            Ok(delete_at) => HttpResponse::Accepted().json(json!({
+                "path": path.to_string_lossy(),
+                "delete_at": delete_at,
+            })),
+            Err(err) => {
+                log::error!("Error marking file for deletion: {err}");
+                HttpResponse::InternalServerError().body("Failed to delete file")
+            }
+        };
+    }
 
-    match file_store.remove(&path) {
-        Ok(_) => HttpResponse::Ok().body("File deleted"),
+    match trash.delete(&path, file_store, attributes).await {
+        Ok(_) => {
+            hooks.post_delete(&path).await;
+            if config.trash.enabled {
+                HttpResponse::Ok().body("File moved to the trash")
+            } else {
+                HttpResponse::Ok().body("File deleted")
+            }
+        }
         Err(err) if err.kind() == io::ErrorKind::InvalidInput => {
             HttpResponse::BadRequest().body(format!("Invalid input: {err}"))
         }
         Err(err) => {
-            eprintln!("Error deleting file: {err}");
+            log::error!("Error deleting file: {err}");
             HttpResponse::InternalServerError().body("Failed to delete file")
         }
     }
 }
+
+#[post("/undelete/{path:.*}")]
+pub async fn undelete_file(
+    req: HttpRequest,
+    path: FilePath,
+    attributes: Data<SharedAttributes>,
+    standby: Data<SharedStandby>,
+) -> impl Responder {
+    if let Err(err) = payload_for(&req, &path).await {
+        return err.error_response();
+    }
+    if let Some(response) = mirror_read_only(&standby) {
+        return response;
+    }
+
+    match attributes.undelete(&path) {
+        Ok(true) => HttpResponse::Ok().body("Deletion cancelled"),
+        Ok(false) => HttpResponse::NotFound().body("File is not pending deletion"),
+        Err(err) => {
+            log::error!("Error cancelling deletion: {err}");
+            HttpResponse::InternalServerError().body("Failed to cancel deletion")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::StatusCode,
+        test::{TestRequest, call_service},
+    };
+
+    use super::*;
+    use crate::{
+        config::server::AuthConfig,
+        fixtures::{Fixture, HELLO_TXT, app},
+    };
+
+    fn location(response: &HttpResponse) -> &str {
+        response
+            .headers()
+            .get(header::LOCATION)
+            .unwrap()
+            .to_str()
+            .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn forms_are_sent_on_with_how_the_upload_went() {
+        let created = HttpResponse::Created()
+            .insert_header((header::ETAG, "\"abc\""))
+            .insert_header((header::LOCATION, "/docs/a b.txt"))
+            .finish();
+        let response = see_other("/done.html#top", created).await;
+        assert_eq!(response.status(), 303);
+        assert_eq!(
+            location(&response),
+            "/done.html?status=201&location=%2Fdocs%2Fa%20b%2Etxt&hash=abc#top"
+        );
+
+        let turned_away = HttpResponse::Conflict().body("Path is a directory");
+        let response = see_other("/done.html?tool=1", turned_away).await;
+        assert_eq!(
+            location(&response),
+            "/done.html?tool=1&status=409&error=Path%20is%20a%20directory"
+        );
+    }
+
+    #[test]
+    fn redirects_stay_on_this_server() {
+        assert!(is_local_path("/uploaded.html"));
+        assert!(!is_local_path("https://example.com/"));
+        assert!(!is_local_path("//example.com/"));
+        assert!(!is_local_path("/\\example.com/"));
+        assert!(!is_local_path("/a\r\nSet-Cookie: x"));
+        assert!(!is_local_path("uploaded.html"));
+    }
+
+    #[actix_web::test]
+    async fn only_files_that_are_there_are_deleted() {
+        let fixture = Fixture::seeded(ServerConfig {
+            auth: AuthConfig::None(
+                serde_json::from_value(json!({ "permissions": ["*"] })).unwrap(),
+            ),
+            ..Default::default()
+        })
+        .await;
+        let app = app(&fixture).await;
+
+        let delete = async |path: &str| {
+            let req = TestRequest::delete().uri(path).to_request();
+            call_service(&app, req).await.status()
+        };
+        let hello = format!("/api/{}", HELLO_TXT.path);
+        assert_eq!(delete(&hello).await, StatusCode::OK);
+        assert_eq!(delete(&hello).await, StatusCode::NOT_FOUND);
+        assert_eq!(delete("/api/never/there.txt").await, StatusCode::NOT_FOUND);
+    }
+}
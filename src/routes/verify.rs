@@ -0,0 +1,55 @@
+use actix_web::{HttpRequest, HttpResponse, Result, get, web::Data};
+use serde_json::json;
+
+use crate::{
+    SharedFileStore,
+    authorized::{Permission, payload_for},
+    file_store::{Entry, FileStorageCore, StoredFileCore, rehash},
+    routes::file_path::FilePath,
+};
+
+/// Reads the whole file, checking it still hashes to what its metadata says.
+#[get("/verify/{path:.*}")]
+pub async fn verify_file(
+    req: HttpRequest,
+    path: FilePath,
+    file_store: Data<SharedFileStore>,
+) -> Result<HttpResponse> {
+    payload_for(&req, &path).await?.require(Permission::Stat)?;
+
+    let file = match file_store.stat(&path).await {
+        Some(Entry::File(file)) => file,
+        Some(Entry::Dir) => return Ok(HttpResponse::Conflict().body("Path is a directory")),
+        None => return Ok(HttpResponse::NotFound().body("File does not exist")),
+    };
+    let expected = file.metadata();
+    if expected.hash.is_empty() {
+        return Ok(HttpResponse::UnprocessableEntity().body("The file's hash isn't known"));
+    }
+
+    let actual = match rehash(file.bytes_stream(), expected).await {
+        Ok(actual) => actual,
+        Err(err) => {
+            log::error!("Error reading '{}' to verify it: {err}", path.display());
+            return Ok(HttpResponse::InternalServerError().body("Failed to read file"));
+        }
+    };
+
+    let intact = actual.hash == expected.hash && actual.size_bytes == expected.size_bytes;
+    if !intact {
+        log::error!(
+            "'{}' is corrupted, it hashes to {} rather than {}",
+            path.display(),
+            actual.hash,
+            expected.hash
+        );
+    }
+
+    Ok(HttpResponse::Ok().json(json!({
+        "path": path.to_string_lossy(),
+        "intact": intact,
+        "hash_algorithm": expected.algorithm,
+        "expected": { "hash": expected.hash, "size_bytes": expected.size_bytes },
+        "actual": { "hash": actual.hash, "size_bytes": actual.size_bytes },
+    })))
+}
@@ -0,0 +1,130 @@
+use std::io;
+
+use actix_web::{
+    HttpRequest, HttpResponse, Result,
+    body::SizedStream,
+    get,
+    http::header::{self, ContentType},
+    post,
+    web::{Data, Query},
+};
+use serde::Deserialize;
+
+use crate::{
+    SharedFileStore,
+    authorized::{Permission, payload_for},
+    file_store::{FileStorageCore, StoredFileCore},
+    hooks::SharedHooks,
+    mirror::SharedStandby,
+    routes::{
+        etag,
+        file_path::{FilePath, encode_path},
+        upload_file::{mirror_read_only, upload_conflict},
+    },
+};
+
+#[derive(Deserialize)]
+struct VersionQuery {
+    version: Option<u32>,
+}
+
+/// Lists the earlier versions of a file, or downloads one of them with `?version=<n>`.
+#[get("/versions/{path:.*}")]
+pub async fn versions(
+    req: HttpRequest,
+    path: FilePath,
+    query: Query<VersionQuery>,
+    file_store: Data<SharedFileStore>,
+) -> Result<HttpResponse> {
+    payload_for(&req, &path)
+        .await?
+        .require(Permission::Versions)?;
+
+    let Some(version) = query.version else {
+        return Ok(match file_store.versions(&path).await {
+            Ok(versions) => HttpResponse::Ok().json(versions),
+            Err(err) => versions_error_response(err),
+        });
+    };
+
+    let file = match file_store.get_version(&path, version).await {
+        Ok(Some(file)) => file,
+        Ok(None) => return Ok(HttpResponse::NotFound().body("Version does not exist")),
+        Err(err) => return Ok(versions_error_response(err)),
+    };
+
+    // never rendered, it's not what's served at the path anymore
+    Ok(HttpResponse::Ok()
+        .insert_header((header::ETAG, etag::strong(&file.metadata().hash)))
+        .content_type(ContentType::octet_stream())
+        .body(SizedStream::new(
+            file.metadata().size_bytes,
+            file.bytes_stream(),
+        )))
+}
+
+/// Makes `?version=<n>` of a file the current one again, which keeps the current one as the
+/// next version in turn.
+#[post("/versions/{path:.*}")]
+pub async fn restore_version(
+    req: HttpRequest,
+    path: FilePath,
+    query: Query<VersionQuery>,
+    file_store: Data<SharedFileStore>,
+    hooks: Data<SharedHooks>,
+    standby: Data<SharedStandby>,
+) -> Result<HttpResponse> {
+    payload_for(&req, &path)
+        .await?
+        .require(Permission::Versions)?;
+
+    if let Some(response) = mirror_read_only(&standby) {
+        return Ok(response);
+    }
+    let Some(version) = query.version else {
+        return Ok(HttpResponse::BadRequest().body("Missing 'version' to restore"));
+    };
+
+    let file = match file_store.get_version(&path, version).await {
+        Ok(Some(file)) => file,
+        Ok(None) => return Ok(HttpResponse::NotFound().body("Version does not exist")),
+        Err(err) => return Ok(versions_error_response(err)),
+    };
+    if let Some(conflict) = upload_conflict(&file_store, &path).await {
+        return Ok(conflict);
+    }
+
+    let restored = match file_store
+        .stage_upload(&path, file.bytes_stream(), Some(file.metadata().size_bytes))
+        .await
+    {
+        Ok(staged) => file_store.commit_upload(staged).await,
+        Err(err) => Err(err),
+    };
+
+    Ok(match restored {
+        Ok(metadata) => {
+            hooks.post_upload(&path, &metadata).await;
+
+            HttpResponse::Created()
+                .insert_header((header::ETAG, etag::strong(&metadata.hash)))
+                .insert_header((header::LOCATION, format!("/{}", encode_path(&*path))))
+                .finish()
+        }
+        Err(err) => versions_error_response(err),
+    })
+}
+
+fn versions_error_response(err: io::Error) -> HttpResponse {
+    match err.kind() {
+        io::ErrorKind::InvalidInput => {
+            HttpResponse::BadRequest().body(format!("Invalid input: {err}"))
+        }
+        io::ErrorKind::StorageFull => HttpResponse::InsufficientStorage().body(err.to_string()),
+        io::ErrorKind::Unsupported => HttpResponse::NotFound().body(err.to_string()),
+        _ => {
+            log::error!("Error handling file versions: {err}");
+            HttpResponse::InternalServerError().body("Failed to handle file versions")
+        }
+    }
+}
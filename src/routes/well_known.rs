@@ -0,0 +1,76 @@
+use std::path::Path;
+
+use actix_web::{
+    HttpResponse, Responder, body::SizedStream, dev::HttpServiceFactory, get, guard::GuardContext,
+    http::header::ContentType, mime, web::Data,
+};
+
+use crate::{
+    config::server::ServerConfig,
+    file_store::{FsFile, StoredFileCore},
+    routes::{ScopeCreator, file_path::FilePath},
+};
+
+/// Paths that are about the site rather than the files in it, registered ahead of the
+/// catch-all file route so they never fall through to a store lookup.
+pub struct WellKnownRoute;
+
+impl ScopeCreator for WellKnownRoute {
+    fn create_scope() -> impl HttpServiceFactory {
+        (robots_txt, well_known)
+    }
+}
+
+fn robots_enabled(ctx: &GuardContext) -> bool {
+    ctx.app_data::<Data<ServerConfig>>()
+        .is_some_and(|config| config.robots.enabled)
+}
+
+// when disabled the guard fails, and the request goes on to the file route as usual
+#[get("/robots.txt", guard = "robots_enabled")]
+pub async fn robots_txt(config: Data<ServerConfig>) -> impl Responder {
+    let mut body = String::from("User-agent: *\n");
+
+    if config.robots.disallow.is_empty() {
+        body.push_str("Disallow:\n");
+    }
+    for path in &config.robots.disallow {
+        body.push_str(&format!("Disallow: {path}\n"));
+    }
+
+    if let Some(sitemap) = &config.robots.sitemap {
+        body.push_str(&format!("Sitemap: {sitemap}\n"));
+    }
+
+    HttpResponse::Ok()
+        .content_type(ContentType::plaintext())
+        .body(body)
+}
+
+#[get("/.well-known/{path:.*}")]
+pub async fn well_known(path: FilePath, config: Data<ServerConfig>) -> impl Responder {
+    // the path is already normalized, so joining it can't escape the directory
+    let full_path = Path::new(&config.well_known_dir).join(&*path);
+    let is_file = tokio::fs::metadata(&full_path)
+        .await
+        .is_ok_and(|metadata| metadata.is_file());
+    if path.as_os_str().is_empty() || !is_file {
+        return HttpResponse::NotFound().body("File does not exist");
+    }
+
+    let Ok(file) = FsFile::open(&full_path).await else {
+        return HttpResponse::NotFound().body("File does not exist");
+    };
+
+    HttpResponse::Ok()
+        .content_type(
+            mime_guess::from_path(&full_path)
+                .first()
+                .filter(|m| m.subtype() != mime::HTML)
+                .unwrap_or(mime::TEXT_PLAIN_UTF_8),
+        )
+        .body(SizedStream::new(
+            file.metadata().size_bytes,
+            file.bytes_stream(),
+        ))
+}
@@ -0,0 +1,166 @@
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    io,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use actix_web::rt;
+use chrono::{DateTime, Utc};
+use cron::Schedule;
+use futures::{FutureExt, future::LocalBoxFuture};
+use serde::Serialize;
+
+use crate::config::server::SchedulerConfig;
+
+type TaskFn = Box<dyn Fn() -> LocalBoxFuture<'static, io::Result<()>>>;
+
+/// Shared view of every registered task, keyed by task name.
+pub type SchedulerStatus = Arc<Mutex<BTreeMap<&'static str, TaskStatus>>>;
+
+#[derive(Serialize, Clone, Debug)]
+pub struct TaskStatus {
+    pub enabled: bool,
+    pub schedule: String,
+    pub running: bool,
+    pub next_run: Option<DateTime<Utc>>,
+    pub last_run: Option<LastRun>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct LastRun {
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub error: Option<String>,
+}
+
+struct Task {
+    name: &'static str,
+    schedule: Schedule,
+    run: TaskFn,
+}
+
+/// Runs periodic background jobs on cron-like schedules, which can be overridden
+/// or disabled per task in the config.
+pub struct Scheduler {
+    config: SchedulerConfig,
+    tasks: Vec<Task>,
+    status: SchedulerStatus,
+}
+
+impl Scheduler {
+    pub fn new(config: &SchedulerConfig) -> Self {
+        Scheduler {
+            config: config.clone(),
+            tasks: Vec::new(),
+            status: Arc::default(),
+        }
+    }
+
+    /// Registers a task, `default_schedule` is a cron expression (including seconds)
+    /// used unless the config sets a different one.
+    pub fn register<F, Fut>(
+        &mut self,
+        name: &'static str,
+        default_schedule: &str,
+        task: F,
+    ) -> io::Result<()>
+    where
+        F: Fn() -> Fut + 'static,
+        Fut: Future<Output = io::Result<()>> + 'static,
+    {
+        let task_config = self.config.tasks.get(name);
+        let enabled = task_config.is_none_or(|c| c.enabled);
+        let expression = task_config
+            .and_then(|c| c.schedule.as_deref())
+            .unwrap_or(default_schedule);
+
+        let schedule = Schedule::from_str(expression).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid schedule for task '{name}': {err}"),
+            )
+        })?;
+
+        self.status.lock().unwrap().insert(
+            name,
+            TaskStatus {
+                enabled,
+                schedule: expression.to_string(),
+                running: false,
+                next_run: None,
+                last_run: None,
+            },
+        );
+
+        if enabled {
+            self.tasks.push(Task {
+                name,
+                schedule,
+                run: Box::new(move || task().boxed_local()),
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn status(&self) -> SchedulerStatus {
+        Arc::clone(&self.status)
+    }
+
+    /// Spawns every enabled task onto the current actix system.
+    pub fn start(self) {
+        let registered = self.status.lock().unwrap();
+        for name in self.config.tasks.keys() {
+            if !registered.contains_key(name.as_str()) {
+                log::warn!("Config has settings for unknown scheduled task '{name}'");
+            }
+        }
+        drop(registered);
+
+        for task in self.tasks {
+            rt::spawn(run_task(task, Arc::clone(&self.status)));
+        }
+    }
+}
+
+async fn run_task(task: Task, status: SchedulerStatus) {
+    let update = |f: &dyn Fn(&mut TaskStatus)| {
+        if let Some(task_status) = status.lock().unwrap().get_mut(task.name) {
+            f(task_status);
+        }
+    };
+
+    loop {
+        let Some(next_run) = task.schedule.upcoming(Utc).next() else {
+            log::warn!("Scheduled task '{}' has no upcoming runs", task.name);
+            return;
+        };
+
+        update(&|s| s.next_run = Some(next_run));
+        rt::time::sleep((next_run - Utc::now()).to_std().unwrap_or_default()).await;
+
+        let started_at = Utc::now();
+        update(&|s| s.running = true);
+
+        let error = match (task.run)().await {
+            Ok(_) => None,
+            Err(err) => {
+                log::warn!("Scheduled task '{}' failed: {err}", task.name);
+                Some(err.to_string())
+            }
+        };
+
+        let last_run = LastRun {
+            started_at,
+            finished_at: Utc::now(),
+            error,
+        };
+
+        update(&|s| {
+            s.running = false;
+            s.last_run = Some(last_run.clone());
+        });
+    }
+}
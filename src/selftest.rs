@@ -0,0 +1,171 @@
+use std::{fs, io};
+
+use actix_web::rt;
+use actix_web::web::Bytes;
+use reqwest::{Client, Response, StatusCode, header};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{config::server::ServerConfig, start_server_from};
+
+const API_KEY_HEADER: &str = "X-API-Key";
+const FILE_PATH: &str = "selftest/hello.txt";
+const CONTENTS: &[u8] = b"Hello from the self-test!\n";
+
+/// Starts a server on a free port over a store in a directory of its own, then uploads,
+/// downloads and deletes a file through it the way clients would, printing how each step
+/// went. Returns whether they all passed.
+pub async fn run() -> io::Result<bool> {
+    let dir = std::env::temp_dir().join(format!("cdn-selftest-{}", Uuid::new_v4().simple()));
+    let key = Uuid::new_v4().simple().to_string();
+    // written out the way it would be in the config file, with the defaults for the rest
+    let config: ServerConfig = serde_json::from_value(json!({
+        "host": "127.0.0.1",
+        "port": 0,
+        "files_source": {
+            "type": "local",
+            "base_dir": dir.join("files"),
+        },
+        "data_dir": dir.join("data"),
+        "auth": {
+            "type": "api_key",
+            "header": API_KEY_HEADER,
+            "keys": { format!("{:x}", Sha256::digest(&key)): { "permissions": ["*"] } },
+        },
+    }))?;
+
+    let passed = match start_server_from(config, Vec::new()) {
+        Ok((server, addr)) => {
+            let handle = server.handle();
+            rt::spawn(server);
+            let passed = run_steps(&format!("http://{addr}"), &key).await;
+            handle.stop(true).await;
+            passed
+        }
+        Err(err) => {
+            eprintln!("FAILED to start the server: {err}");
+            false
+        }
+    };
+
+    if let Err(err) = fs::remove_dir_all(&dir) {
+        log::warn!("Failed to clean up '{}': {err}", dir.display());
+    }
+    Ok(passed)
+}
+
+/// Runs every step against the server at `base_url`, stopping at the first failing one.
+async fn run_steps(base_url: &str, key: &str) -> bool {
+    let client = Client::new();
+    let url = format!("{base_url}/{FILE_PATH}");
+
+    let Some(etag) = step("upload", upload(&client, &url, key)).await else {
+        return false;
+    };
+    step("download", download(&client, &url, &etag))
+        .await
+        .is_some()
+        && step(
+            "conditional download",
+            conditional_download(&client, &url, &etag),
+        )
+        .await
+        .is_some()
+        && step("range download", range_download(&client, &url))
+            .await
+            .is_some()
+        && step("delete", delete(&client, &url, key)).await.is_some()
+}
+
+type StepError = Box<dyn std::error::Error>;
+
+async fn step<T>(name: &str, result: impl Future<Output = Result<T, StepError>>) -> Option<T> {
+    match result.await {
+        Ok(value) => {
+            println!("ok      {name}");
+            Some(value)
+        }
+        Err(err) => {
+            println!("FAILED  {name}: {err}");
+            None
+        }
+    }
+}
+
+/// Returns the ETag the upload was answered with, for the steps after it.
+async fn upload(client: &Client, url: &str, key: &str) -> Result<String, StepError> {
+    let res = client
+        .put(url)
+        .header(API_KEY_HEADER, key)
+        .body(CONTENTS)
+        .send()
+        .await?;
+    expect_status(&res, StatusCode::CREATED)?;
+    let etag = res.headers().get(header::ETAG).ok_or("no ETag")?;
+    Ok(etag.to_str()?.to_string())
+}
+
+async fn download(client: &Client, url: &str, etag: &str) -> Result<(), StepError> {
+    let res = client.get(url).send().await?;
+    expect_status(&res, StatusCode::OK)?;
+    if res
+        .headers()
+        .get(header::ETAG)
+        .and_then(|v| v.to_str().ok())
+        != Some(etag)
+    {
+        return Err("ETag differs from the upload's".into());
+    }
+    expect_body(res, CONTENTS).await
+}
+
+async fn conditional_download(client: &Client, url: &str, etag: &str) -> Result<(), StepError> {
+    let res = client
+        .get(url)
+        .header(header::IF_NONE_MATCH, etag)
+        .send()
+        .await?;
+    expect_status(&res, StatusCode::NOT_MODIFIED)
+}
+
+async fn range_download(client: &Client, url: &str) -> Result<(), StepError> {
+    let res = client
+        .get(url)
+        .header(header::RANGE, "bytes=6-9")
+        .send()
+        .await?;
+    expect_status(&res, StatusCode::PARTIAL_CONTENT)?;
+    expect_body(res, &CONTENTS[6..10]).await
+}
+
+/// Deletes the file, then checks it's no longer served.
+async fn delete(client: &Client, url: &str, key: &str) -> Result<(), StepError> {
+    let res = client
+        .delete(url)
+        .header(API_KEY_HEADER, key)
+        .send()
+        .await?;
+    expect_status(&res, StatusCode::OK)?;
+    let res = client.get(url).send().await?;
+    expect_status(&res, StatusCode::NOT_FOUND)
+}
+
+fn expect_status(res: &Response, expected: StatusCode) -> Result<(), StepError> {
+    match res.status() {
+        status if status == expected => Ok(()),
+        status => Err(format!("expected {expected}, got {status}").into()),
+    }
+}
+
+async fn expect_body(res: Response, expected: &[u8]) -> Result<(), StepError> {
+    let body = res.bytes().await?;
+    if body != expected {
+        return Err(format!(
+            "expected {:?}, got {body:?}",
+            Bytes::copy_from_slice(expected)
+        )
+        .into());
+    }
+    Ok(())
+}
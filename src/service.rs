@@ -0,0 +1,176 @@
+use std::{env, ffi::OsString, io, sync::mpsc, thread, time::Duration};
+
+use windows_service::{
+    define_windows_service,
+    service::{
+        ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode,
+        ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+    },
+    service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle},
+    service_dispatcher,
+    service_manager::{ServiceManager, ServiceManagerAccess},
+};
+
+use crate::{
+    cli::ServiceAction,
+    logging::{self, LogTarget},
+};
+
+pub const SERVICE_NAME: &str = "cdn";
+const SERVICE_DISPLAY_NAME: &str = "CDN File Server";
+const SERVICE_TYPE: ServiceType = ServiceType::OWN_PROCESS;
+
+pub fn handle(action: ServiceAction) -> io::Result<()> {
+    let result = match action {
+        ServiceAction::Install => install(),
+        ServiceAction::Uninstall => uninstall(),
+        ServiceAction::Start => start(),
+        ServiceAction::Stop => stop(),
+        ServiceAction::Run => service_dispatcher::start(SERVICE_NAME, ffi_service_main),
+    };
+
+    result.map_err(io::Error::other)
+}
+
+fn install() -> windows_service::Result<()> {
+    let manager_access = ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE;
+    let manager = ServiceManager::local_computer(None::<&str>, manager_access)?;
+
+    let service_info = ServiceInfo {
+        name: SERVICE_NAME.into(),
+        display_name: SERVICE_DISPLAY_NAME.into(),
+        service_type: SERVICE_TYPE,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: env::current_exe().map_err(windows_service::Error::Winapi)?,
+        launch_arguments: vec!["service".into(), "run".into()],
+        dependencies: vec![],
+        account_name: None, // run as LocalSystem
+        account_password: None,
+    };
+
+    let service = manager.create_service(&service_info, ServiceAccess::CHANGE_CONFIG)?;
+    service.set_description("Serves and manages files over HTTP")?;
+
+    // registering the event source is what lets the event viewer render our messages,
+    // not being able to do so shouldn't prevent the service from being usable though
+    if let Err(err) = eventlog::register(SERVICE_NAME) {
+        eprintln!("Failed to register event log source: {err}");
+    }
+
+    println!("Installed service '{SERVICE_NAME}'");
+    Ok(())
+}
+
+fn uninstall() -> windows_service::Result<()> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+    let access = ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE;
+    let service = manager.open_service(SERVICE_NAME, access)?;
+
+    if service.query_status()?.current_state != ServiceState::Stopped {
+        service.stop()?;
+    }
+
+    service.delete()?;
+    let _ = eventlog::deregister(SERVICE_NAME);
+
+    println!("Uninstalled service '{SERVICE_NAME}'");
+    Ok(())
+}
+
+fn start() -> windows_service::Result<()> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+    let service = manager.open_service(SERVICE_NAME, ServiceAccess::START)?;
+    service.start::<OsString>(&[])
+}
+
+fn stop() -> windows_service::Result<()> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+    let service = manager.open_service(SERVICE_NAME, ServiceAccess::STOP)?;
+    service.stop().map(|_| ())
+}
+
+define_windows_service!(ffi_service_main, service_main);
+
+fn service_main(_arguments: Vec<OsString>) {
+    logging::init(LogTarget::EventLog);
+
+    if let Err(err) = run_service() {
+        log::error!("Service failed: {err}");
+    }
+}
+
+fn run_service() -> windows_service::Result<()> {
+    let (shutdown_tx, shutdown_rx) = mpsc::channel();
+
+    let status_handle =
+        service_control_handler::register(SERVICE_NAME, move |control| match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                let _ = shutdown_tx.send(());
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        })?;
+
+    // services are started from System32, but config and files live next to the binary
+    if let Some(dir) = env::current_exe()
+        .ok()
+        .and_then(|p| p.parent().map(|p| p.to_owned()))
+    {
+        env::set_current_dir(dir).map_err(windows_service::Error::Winapi)?;
+    }
+
+    set_status(
+        &status_handle,
+        ServiceState::Running,
+        ServiceControlAccept::STOP,
+        0,
+    )?;
+
+    let result = actix_web::rt::System::new().block_on(async move {
+        let server = crate::start_server()?;
+        let handle = server.handle();
+
+        // the control handler can't await anything, so relay its stop request from here
+        thread::spawn(move || {
+            if shutdown_rx.recv().is_ok() {
+                futures::executor::block_on(handle.stop(true));
+            }
+        });
+
+        server.await
+    });
+
+    let exit_code = match result {
+        Ok(_) => 0,
+        Err(err) => {
+            log::error!("Server exited with an error: {err}");
+            1
+        }
+    };
+
+    set_status(
+        &status_handle,
+        ServiceState::Stopped,
+        ServiceControlAccept::empty(),
+        exit_code,
+    )
+}
+
+fn set_status(
+    handle: &ServiceStatusHandle,
+    state: ServiceState,
+    controls_accepted: ServiceControlAccept,
+    exit_code: u32,
+) -> windows_service::Result<()> {
+    handle.set_service_status(ServiceStatus {
+        service_type: SERVICE_TYPE,
+        current_state: state,
+        controls_accepted,
+        exit_code: ServiceExitCode::Win32(exit_code),
+        checkpoint: 0,
+        wait_hint: Duration::default(),
+        process_id: None,
+    })
+}
@@ -0,0 +1,194 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use actix_web::HttpRequest;
+use async_stream::try_stream;
+use futures::StreamExt;
+
+use crate::{
+    config::server::{DownloadRateLimit, UploadRateLimit},
+    file_store::ByteStream,
+    routes::client_ip,
+};
+
+/// The class of tokens without one of their own, and of those whose class isn't configured.
+const DEFAULT_CLASS: &str = "default";
+
+// capacity left unused carries over for this long, so an upload that briefly stalled isn't
+// held back any further once it picks up again
+const BURST: Duration = Duration::from_secs(1);
+
+/// A token bucket, which works out when each chunk fits within the rate.
+struct RateLimiter {
+    bytes_per_sec: u64,
+    next_free: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    fn new(bytes_per_sec: u64) -> Self {
+        RateLimiter {
+            bytes_per_sec,
+            next_free: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Reserves `len` bytes, returning how long to wait before they may be taken in.
+    fn reserve(&self, len: usize) -> Duration {
+        let now = Instant::now();
+        let mut next_free = self.next_free.lock().unwrap();
+
+        let start = (*next_free).max(now.checked_sub(BURST).unwrap_or(now));
+        *next_free = start + Duration::from_secs_f64(len as f64 / self.bytes_per_sec as f64);
+        next_free.saturating_duration_since(now)
+    }
+}
+
+struct ClassLimits {
+    per_connection: Option<u64>,
+    // shared by every upload of the class
+    global: Option<Arc<RateLimiter>>,
+}
+
+/// Slows uploads down to the limits of their token's class, leaving downloads alone.
+pub struct UploadThrottle {
+    classes: HashMap<String, ClassLimits>,
+}
+
+impl UploadThrottle {
+    pub fn new(limits: &BTreeMap<String, UploadRateLimit>) -> Self {
+        // a limit of 0 would never let anything through, it's taken as no limit instead
+        let classes = limits
+            .iter()
+            .map(|(class, limit)| {
+                let limits = ClassLimits {
+                    per_connection: limit.per_connection_bytes_per_sec.filter(|&rate| rate > 0),
+                    global: limit
+                        .global_bytes_per_sec
+                        .filter(|&rate| rate > 0)
+                        .map(|rate| Arc::new(RateLimiter::new(rate))),
+                };
+                (class.clone(), limits)
+            })
+            .collect();
+
+        UploadThrottle { classes }
+    }
+
+    /// Holds back each chunk of `stream` until the limits for tokens of `class` allow it.
+    pub fn throttle<'a>(&self, class: Option<&str>, stream: ByteStream<'a>) -> ByteStream<'a> {
+        let Some(limits) = class
+            .and_then(|class| self.classes.get(class))
+            .or_else(|| self.classes.get(DEFAULT_CLASS))
+        else {
+            return stream;
+        };
+
+        let mut limiters: Vec<Arc<RateLimiter>> = limits.global.iter().cloned().collect();
+        if let Some(rate) = limits.per_connection {
+            limiters.push(Arc::new(RateLimiter::new(rate)));
+        }
+        if limiters.is_empty() {
+            return stream;
+        }
+
+        throttled(stream, limiters)
+    }
+}
+
+/// Slows downloads down to the limits per connection and per client address.
+pub struct DownloadThrottle {
+    per_connection: Option<u64>,
+    per_ip: Option<u64>,
+    // only kept while some download to the address holds on to its limiter
+    ips: Mutex<HashMap<IpAddr, Arc<RateLimiter>>>,
+}
+
+impl DownloadThrottle {
+    pub fn new(limit: &DownloadRateLimit) -> Self {
+        DownloadThrottle {
+            per_connection: limit.per_connection_bytes_per_sec.filter(|&rate| rate > 0),
+            per_ip: limit.per_ip_bytes_per_sec.filter(|&rate| rate > 0),
+            ips: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn ip_limiter(&self, ip: IpAddr, rate: u64) -> Arc<RateLimiter> {
+        let mut ips = self.ips.lock().unwrap();
+        ips.retain(|_, limiter| Arc::strong_count(limiter) > 1);
+        Arc::clone(
+            ips.entry(ip)
+                .or_insert_with(|| Arc::new(RateLimiter::new(rate))),
+        )
+    }
+
+    /// Holds back each chunk of `stream`, sent in answer to `req`, until the limits allow it.
+    pub fn throttle<'a>(&self, req: &HttpRequest, stream: ByteStream<'a>) -> ByteStream<'a> {
+        let mut limiters = Vec::new();
+        if let Some(rate) = self.per_connection {
+            limiters.push(Arc::new(RateLimiter::new(rate)));
+        }
+        if let Some(rate) = self.per_ip
+            && let Some(ip) = client_ip(req)
+        {
+            limiters.push(self.ip_limiter(ip, rate));
+        }
+        if limiters.is_empty() {
+            return stream;
+        }
+
+        throttled(stream, limiters)
+    }
+}
+
+fn throttled<'a>(mut stream: ByteStream<'a>, limiters: Vec<Arc<RateLimiter>>) -> ByteStream<'a> {
+    try_stream! {
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            // not reading on is what slows the client down, once the socket's buffers fill up
+            let wait = limiters
+                .iter()
+                .map(|limiter| limiter.reserve(chunk.len()))
+                .max()
+                .unwrap_or_default();
+            if !wait.is_zero() {
+                tokio::time::sleep(wait).await;
+            }
+            yield chunk;
+        }
+    }
+    .boxed_local()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn downloads_to_one_address_share_its_limit() {
+        let throttle = DownloadThrottle::new(&DownloadRateLimit {
+            per_ip_bytes_per_sec: Some(1000),
+            ..Default::default()
+        });
+        let ip = |last| IpAddr::from([192, 0, 2, last]);
+
+        let first = throttle.ip_limiter(ip(1), 1000);
+        let second = throttle.ip_limiter(ip(1), 1000);
+        let elsewhere = throttle.ip_limiter(ip(2), 1000);
+        // a second of bytes each, the second download waiting behind the first
+        let waits = [&first, &second, &elsewhere].map(|limiter| limiter.reserve(1000));
+        assert!(
+            waits[1] > waits[0] + Duration::from_millis(900),
+            "{waits:?}"
+        );
+        assert!(waits[2] < Duration::from_millis(1100), "{waits:?}");
+
+        // forgotten once no download holds on to it
+        drop((first, second));
+        throttle.ip_limiter(ip(2), 1000);
+        assert!(!throttle.ips.lock().unwrap().contains_key(&ip(1)));
+    }
+}
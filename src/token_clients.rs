@@ -0,0 +1,242 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+    fs, io,
+    path::Path,
+    sync::Mutex,
+};
+
+use chrono::{DateTime, Utc};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use rusqlite::{Connection, params};
+use serde::Serialize;
+
+use crate::config::server::ClientRule;
+
+const CLIENTS_FILE_NAME: &str = "clients.db";
+
+/// A user agent seen sending requests with a token.
+#[derive(Serialize, Debug)]
+pub struct SeenUserAgent {
+    pub user_agent: String,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub requests: u64,
+    /// Of those requests, how many were turned away for not matching the token's client
+    pub rejected: u64,
+}
+
+/// Credentials that were used, known by the id their authenticator gave them, e.g. a hash of
+/// the token or the username.
+#[derive(Serialize, Debug)]
+pub struct SeenToken {
+    pub token_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client: Option<String>,
+    pub user_agents: Vec<SeenUserAgent>,
+}
+
+/// Requests made with a token from a user agent since the last flush.
+struct PendingRequests {
+    client: Option<String>,
+    first_seen: i64,
+    last_seen: i64,
+    requests: i64,
+    rejected: i64,
+}
+
+/// The user agents each token was used from, and the ones tokens of a registered client are
+/// allowed to be used from. Requests are only counted in memory, and written together by the
+/// `token_client_flush` task, so authenticating doesn't wait for the database.
+pub struct TokenClients {
+    db: Mutex<Connection>,
+    rules: BTreeMap<String, GlobSet>,
+    pending: Mutex<HashMap<(String, String), PendingRequests>>,
+}
+
+impl TokenClients {
+    pub fn open(
+        data_dir: impl AsRef<Path>,
+        rules: &BTreeMap<String, ClientRule>,
+    ) -> io::Result<Self> {
+        let rules = rules
+            .iter()
+            .map(|(client, rule)| {
+                let mut set = GlobSetBuilder::new();
+                for pattern in &rule.user_agents {
+                    set.add(Glob::new(pattern).map_err(|err| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("invalid user agent pattern for client '{client}': {err}"),
+                        )
+                    })?);
+                }
+                Ok((client.clone(), set.build().map_err(io::Error::other)?))
+            })
+            .collect::<io::Result<_>>()?;
+
+        fs::create_dir_all(&data_dir)?;
+        let db = Connection::open(data_dir.as_ref().join(CLIENTS_FILE_NAME))
+            .map_err(io::Error::other)?;
+        db.execute_batch(
+            "CREATE TABLE IF NOT EXISTS user_agents (
+                token_id TEXT NOT NULL,
+                client TEXT,
+                user_agent TEXT NOT NULL,
+                first_seen INTEGER NOT NULL,
+                last_seen INTEGER NOT NULL,
+                requests INTEGER NOT NULL,
+                rejected INTEGER NOT NULL,
+                PRIMARY KEY (token_id, user_agent)
+            );",
+        )
+        .map_err(io::Error::other)?;
+
+        Ok(TokenClients {
+            db: Mutex::new(db),
+            rules,
+            pending: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Whether a token of `client` may be used from `user_agent`. Tokens without a client, or
+    /// of one without a rule, may be used from anywhere.
+    pub fn is_allowed(&self, client: Option<&str>, user_agent: &str) -> bool {
+        client
+            .and_then(|client| self.rules.get(client))
+            .is_none_or(|rule| rule.is_match(user_agent))
+    }
+
+    /// Counts a request made with the credentials known as `token_id` from `user_agent`, to be
+    /// written with the next flush.
+    pub fn record(
+        &self,
+        token_id: &str,
+        client: Option<&str>,
+        user_agent: &str,
+        is_rejected: bool,
+    ) {
+        let now = Utc::now().timestamp();
+        let mut pending = self.pending.lock().unwrap();
+        let seen = pending
+            .entry((token_id.to_string(), user_agent.to_string()))
+            .or_insert_with(|| PendingRequests {
+                client: client.map(str::to_string),
+                first_seen: now,
+                last_seen: now,
+                requests: 0,
+                rejected: 0,
+            });
+        seen.last_seen = now;
+        seen.requests += 1;
+        seen.rejected += is_rejected as i64;
+    }
+
+    /// Writes every request counted since the last flush, returning how many token and user
+    /// agent pairs they were of.
+    pub fn flush(&self) -> io::Result<usize> {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        let mut db = self.db.lock().unwrap();
+        let tx = db.transaction().map_err(io::Error::other)?;
+        for ((token_id, user_agent), seen) in &pending {
+            tx.execute(
+                "INSERT INTO user_agents
+                    (token_id, client, user_agent, first_seen, last_seen, requests, rejected)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                    ON CONFLICT (token_id, user_agent) DO UPDATE SET
+                        last_seen = ?5, requests = requests + ?6, rejected = rejected + ?7",
+                params![
+                    token_id,
+                    seen.client,
+                    user_agent,
+                    seen.first_seen,
+                    seen.last_seen,
+                    seen.requests,
+                    seen.rejected,
+                ],
+            )
+            .map_err(io::Error::other)?;
+        }
+        tx.commit().map_err(io::Error::other)?;
+        Ok(pending.len())
+    }
+
+    /// Every token seen so far with the user agents it was used from, the most recently used
+    /// ones first.
+    pub fn seen(&self) -> io::Result<Vec<SeenToken>> {
+        // written first, so the ones counted since the last flush are listed too
+        self.flush()?;
+        let db = self.db.lock().unwrap();
+        let mut statement = db
+            .prepare(
+                "SELECT token_id, client, user_agent, first_seen, last_seen, requests, rejected
+                    FROM user_agents ORDER BY token_id, last_seen DESC",
+            )
+            .map_err(io::Error::other)?;
+        let rows = statement
+            .query_map([], |row| {
+                let timestamp = |i| {
+                    row.get::<_, i64>(i)
+                        .map(|t| DateTime::from_timestamp(t, 0).unwrap_or_default())
+                };
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, Option<String>>(1)?,
+                    SeenUserAgent {
+                        user_agent: row.get(2)?,
+                        first_seen: timestamp(3)?,
+                        last_seen: timestamp(4)?,
+                        requests: row.get::<_, i64>(5)? as u64,
+                        rejected: row.get::<_, i64>(6)? as u64,
+                    },
+                ))
+            })
+            .map_err(io::Error::other)?;
+
+        let mut tokens: Vec<SeenToken> = Vec::new();
+        for row in rows {
+            let (token_id, client, user_agent) = row.map_err(io::Error::other)?;
+            match tokens.last_mut() {
+                Some(token) if token.token_id == token_id => token.user_agents.push(user_agent),
+                _ => tokens.push(SeenToken {
+                    token_id,
+                    client,
+                    user_agents: vec![user_agent],
+                }),
+            }
+        }
+
+        tokens.sort_by_key(|token| Reverse(token.user_agents[0].last_seen));
+        Ok(tokens)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::temp_dir;
+
+    #[test]
+    fn requests_are_written_in_batches() {
+        let data_dir = temp_dir();
+        let token_clients = TokenClients::open(&data_dir, &BTreeMap::new()).unwrap();
+
+        for is_rejected in [false, false, true] {
+            token_clients.record("token", Some("app"), "curl/8.0", is_rejected);
+        }
+        token_clients.record("token", Some("app"), "wget/1.21", false);
+        assert_eq!(token_clients.flush().unwrap(), 2);
+        assert_eq!(token_clients.flush().unwrap(), 0);
+
+        token_clients.record("token", Some("app"), "curl/8.0", false);
+        let seen = token_clients.seen().unwrap();
+        assert_eq!(seen.len(), 1);
+        assert_eq!(seen[0].client.as_deref(), Some("app"));
+        let curl = seen[0]
+            .user_agents
+            .iter()
+            .find(|seen| seen.user_agent == "curl/8.0")
+            .unwrap();
+        assert_eq!((curl.requests, curl.rejected), (4, 1));
+    }
+}
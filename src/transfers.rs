@@ -0,0 +1,171 @@
+use std::{
+    collections::BTreeMap,
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use actix_web::{HttpRequest, http::header};
+use async_stream::try_stream;
+use chrono::{DateTime, Utc};
+use futures::{
+    StreamExt,
+    stream::{AbortHandle, Abortable},
+};
+use serde::Serialize;
+
+use crate::file_store::ByteStream;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferKind {
+    Upload,
+    Download,
+}
+
+/// Who a transfer is with, as far as the request tells.
+#[derive(Serialize, Debug, Clone)]
+pub struct TransferClient {
+    pub address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+}
+
+impl TransferClient {
+    pub fn of(req: &HttpRequest) -> Self {
+        TransferClient {
+            address: req
+                .connection_info()
+                .realip_remote_addr()
+                .map(str::to_string),
+            user_agent: req
+                .headers()
+                .get(header::USER_AGENT)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string),
+        }
+    }
+}
+
+/// A transfer as it stands.
+#[derive(Serialize, Debug)]
+pub struct TransferInfo {
+    pub id: u64,
+    pub kind: TransferKind,
+    pub path: PathBuf,
+    pub client: TransferClient,
+    pub started_at: DateTime<Utc>,
+    pub duration_secs: f64,
+    pub bytes_transferred: u64,
+}
+
+struct Active {
+    kind: TransferKind,
+    path: PathBuf,
+    client: TransferClient,
+    started_at: DateTime<Utc>,
+    bytes: Arc<AtomicU64>,
+    abort: AbortHandle,
+}
+
+type ActiveMap = Arc<Mutex<BTreeMap<u64, Active>>>;
+
+/// The uploads and downloads in progress, any of which can be cut short.
+#[derive(Default)]
+pub struct Transfers {
+    next_id: AtomicU64,
+    active: ActiveMap,
+}
+
+// takes the transfer off the list however its stream ends, even when it's just dropped
+struct Finished {
+    active: ActiveMap,
+    id: u64,
+}
+
+impl Drop for Finished {
+    fn drop(&mut self) {
+        self.active.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl Transfers {
+    /// Lists the transfer for as long as `stream` is being read, counting the bytes through it.
+    pub fn track<'a>(
+        &self,
+        kind: TransferKind,
+        path: &Path,
+        client: TransferClient,
+        stream: ByteStream<'a>,
+    ) -> ByteStream<'a> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let bytes = Arc::new(AtomicU64::new(0));
+        let (abort, registration) = AbortHandle::new_pair();
+
+        self.active.lock().unwrap().insert(
+            id,
+            Active {
+                kind,
+                path: path.to_path_buf(),
+                client,
+                started_at: Utc::now(),
+                bytes: Arc::clone(&bytes),
+                abort,
+            },
+        );
+        let finished = Finished {
+            active: Arc::clone(&self.active),
+            id,
+        };
+
+        let mut stream = Abortable::new(stream, registration);
+        try_stream! {
+            let _finished = finished;
+            while let Some(chunk) = stream.next().await {
+                let chunk = chunk?;
+                bytes.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+                yield chunk;
+            }
+            // otherwise an aborted upload would look complete, and be committed as it is
+            if stream.is_aborted() {
+                Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "transfer was aborted",
+                ))?;
+            }
+        }
+        .boxed_local()
+    }
+
+    pub fn list(&self) -> Vec<TransferInfo> {
+        let now = Utc::now();
+        let active = self.active.lock().unwrap();
+        active
+            .iter()
+            .map(|(&id, transfer)| TransferInfo {
+                id,
+                kind: transfer.kind,
+                path: transfer.path.clone(),
+                client: transfer.client.clone(),
+                started_at: transfer.started_at,
+                duration_secs: (now - transfer.started_at).as_seconds_f64(),
+                bytes_transferred: transfer.bytes.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    /// Cuts the transfer short, returning false if there's none with the id.
+    pub fn abort(&self, id: u64) -> bool {
+        let active = self.active.lock().unwrap();
+        match active.get(&id) {
+            Some(transfer) => {
+                transfer.abort.abort();
+                true
+            }
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use std::{
+    fs, io,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::Serialize;
+
+use crate::{
+    attributes::{Attributes, FileAttributes},
+    config::server::TrashConfig,
+    file_store::{FileMetadata, FileStorageCore, FileStore, FsFileStore, StoredFileCore},
+};
+
+const TRASH_DIR_NAME: &str = "trash";
+const TRASH_FILE_NAME: &str = "trash.db";
+
+pub type SharedTrash = Arc<Trash>;
+
+/// A deleted file waiting in the trash.
+#[derive(Serialize, Debug)]
+pub struct Trashed {
+    pub path: String,
+    pub size_bytes: u64,
+    pub hash: String,
+    pub deleted_at: DateTime<Utc>,
+    /// When it's gone for good
+    pub purge_at: DateTime<Utc>,
+}
+
+/// Deleted files kept around for a while before they're gone for good, on local disk whatever
+/// the files are served from. A path only has its latest deletion kept.
+pub struct Trash {
+    store: FileStore,
+    db: Mutex<Connection>,
+    // files already in the trash can still be restored while it's disabled
+    enabled: bool,
+    retention: Duration,
+}
+
+impl Trash {
+    pub fn open(data_dir: impl AsRef<Path>, config: &TrashConfig) -> io::Result<Self> {
+        let dir = data_dir.as_ref().join(TRASH_DIR_NAME);
+        fs::create_dir_all(&dir)?;
+
+        let db =
+            Connection::open(data_dir.as_ref().join(TRASH_FILE_NAME)).map_err(io::Error::other)?;
+        db.execute_batch(
+            "CREATE TABLE IF NOT EXISTS trash (
+                path TEXT PRIMARY KEY,
+                deleted_at INTEGER NOT NULL,
+                attributes TEXT
+            );",
+        )
+        .map_err(io::Error::other)?;
+
+        Ok(Trash {
+            store: FileStore::Filesystem(FsFileStore::new(dir)),
+            db: Mutex::new(db),
+            enabled: config.enabled,
+            retention: Duration::from_secs(config.retention_secs),
+        })
+    }
+
+    /// Deletes the file at `path` from `store` along with its attributes, moving both into the
+    /// trash while it's enabled.
+    pub async fn delete(
+        &self,
+        path: &Path,
+        store: &FileStore,
+        attributes: &Attributes,
+    ) -> io::Result<()> {
+        if !self.enabled {
+            store.remove(path).await?;
+            return attributes.remove(path);
+        }
+        let Some(file) = store.get_file(path).await else {
+            return Ok(());
+        };
+
+        let staged = self
+            .store
+            .stage_upload(path, file.bytes_stream(), Some(file.metadata().size_bytes))
+            .await?;
+        self.store.commit_upload(staged).await?;
+
+        let kept = match attributes.get(path)? {
+            Some(kept) => Some(serde_json::to_string(&kept)?),
+            None => None,
+        };
+        self.db
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT OR REPLACE INTO trash (path, deleted_at, attributes) VALUES (?1, ?2, ?3)",
+                params![path.to_string_lossy(), Utc::now().timestamp(), kept],
+            )
+            .map_err(io::Error::other)?;
+
+        store.remove(path).await?;
+        attributes.remove(path)
+    }
+
+    /// Moves the file at `path` back into `target` with the attributes it had.
+    pub async fn restore(
+        &self,
+        path: &Path,
+        target: &FileStore,
+        attributes: &Attributes,
+    ) -> io::Result<FileMetadata> {
+        let not_found = || io::Error::new(io::ErrorKind::NotFound, "no deleted file at this path");
+        let file = self.store.get_file(path).await.ok_or_else(not_found)?;
+        let kept: Option<String> = self
+            .db
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT attributes FROM trash WHERE path = ?1",
+                [path.to_string_lossy()],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(io::Error::other)?
+            .ok_or_else(not_found)?;
+
+        // set before the file is visible again, the same as for uploads
+        match kept {
+            Some(kept) => attributes.set(path, &serde_json::from_str::<FileAttributes>(&kept)?)?,
+            None => attributes.remove(path)?,
+        }
+
+        let staged = target
+            .stage_upload(path, file.bytes_stream(), Some(file.metadata().size_bytes))
+            .await?;
+        let metadata = target.commit_upload(staged).await?;
+        self.forget(path).await?;
+        Ok(metadata)
+    }
+
+    /// Every file in the trash, most recently deleted first.
+    pub async fn list(&self) -> io::Result<Vec<Trashed>> {
+        let rows: Vec<(String, i64)> = {
+            let db = self.db.lock().unwrap();
+            let mut statement = db
+                .prepare("SELECT path, deleted_at FROM trash ORDER BY deleted_at DESC, path")
+                .map_err(io::Error::other)?;
+            statement
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+                .map_err(io::Error::other)?
+                .collect::<Result<_, _>>()
+                .map_err(io::Error::other)?
+        };
+
+        let mut trashed = Vec::new();
+        for (path, deleted_at) in rows {
+            let Some(file) = self.store.get_file(Path::new(&path)).await else {
+                continue;
+            };
+            let deleted_at = DateTime::from_timestamp(deleted_at, 0).unwrap_or_default();
+            trashed.push(Trashed {
+                size_bytes: file.metadata().size_bytes,
+                hash: file.metadata().hash.clone(),
+                deleted_at,
+                purge_at: deleted_at + self.retention,
+                path,
+            });
+        }
+
+        Ok(trashed)
+    }
+
+    /// Removes the files that have been in the trash for longer than it keeps them, returning
+    /// how many there were.
+    pub async fn purge_expired(&self) -> io::Result<usize> {
+        let cutoff = Utc::now() - self.retention;
+        let expired: Vec<String> = {
+            let db = self.db.lock().unwrap();
+            let mut statement = db
+                .prepare("SELECT path FROM trash WHERE deleted_at <= ?1")
+                .map_err(io::Error::other)?;
+            statement
+                .query_map([cutoff.timestamp()], |row| row.get(0))
+                .map_err(io::Error::other)?
+                .collect::<Result<_, _>>()
+                .map_err(io::Error::other)?
+        };
+
+        for path in &expired {
+            self.forget(Path::new(path)).await?;
+        }
+        Ok(expired.len())
+    }
+
+    async fn forget(&self, path: &Path) -> io::Result<()> {
+        self.store.remove(path).await?;
+        self.db
+            .lock()
+            .unwrap()
+            .execute(
+                "DELETE FROM trash WHERE path = ?1",
+                [path.to_string_lossy()],
+            )
+            .map_err(io::Error::other)?;
+        Ok(())
+    }
+}